@@ -93,6 +93,10 @@ pub struct HandshakeState {
     pub server_app_secret: Option<Vec<u8>>,
     /// Exporter master secret for RFC 8446 key export
     pub exporter_master_secret: Option<Vec<u8>>,
+    /// ALPN protocols offered in the ClientHello, in preference order
+    pub alpn_protocols: Vec<String>,
+    /// ALPN protocol selected by the server in EncryptedExtensions
+    pub alpn_protocol: Option<Vec<u8>>,
 }
 
 impl HandshakeState {
@@ -117,6 +121,8 @@ impl HandshakeState {
             client_app_secret: None,
             server_app_secret: None,
             exporter_master_secret: None,
+            alpn_protocols: vec!["http/1.1".to_string()],
+            alpn_protocol: None,
         })
     }
 
@@ -212,37 +218,17 @@ impl HandshakeState {
         extensions.push(sig_algs.len() as u8);
         extensions.extend_from_slice(&sig_algs);
 
-        // ALPN (Application-Layer Protocol Negotiation) - advertise http/1.1
-        let alpn = self.build_alpn_extension();
-        extensions.push((EXT_ALPN >> 8) as u8);
-        extensions.push(EXT_ALPN as u8);
-        extensions.push((alpn.len() >> 8) as u8);
-        extensions.push(alpn.len() as u8);
-        extensions.extend_from_slice(&alpn);
-
-        extensions
-    }
-
-    fn build_alpn_extension(&self) -> Vec<u8> {
-        // ALPN protocol list - only advertise http/1.1
-        // We don't support HTTP/2, so advertising h2 causes servers to send
-        // HTTP/2 binary frames that we can't parse.
-        // Format: length(2) + [ length(1) + protocol_name ]*
-        let protocols: &[&[u8]] = &[b"http/1.1"];
-        let mut ext = Vec::new();
-
-        // Calculate total list length
-        let list_len: usize = protocols.iter().map(|p| 1 + p.len()).sum();
-        ext.push((list_len >> 8) as u8);
-        ext.push(list_len as u8);
-
-        // Add each protocol entry
-        for protocol in protocols {
-            ext.push(protocol.len() as u8);
-            ext.extend_from_slice(protocol);
+        // ALPN (Application-Layer Protocol Negotiation)
+        if !self.alpn_protocols.is_empty() {
+            let alpn = build_alpn_extension(&self.alpn_protocols);
+            extensions.push((EXT_ALPN >> 8) as u8);
+            extensions.push(EXT_ALPN as u8);
+            extensions.push((alpn.len() >> 8) as u8);
+            extensions.push(alpn.len() as u8);
+            extensions.extend_from_slice(&alpn);
         }
 
-        ext
+        extensions
     }
 
     fn build_sni_extension(&self) -> Vec<u8> {
@@ -682,6 +668,77 @@ async fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
     Ok(uint8_array.to_vec())
 }
 
+/// Build the ALPN extension body for a ClientHello
+///
+/// Format: length(2) + [ length(1) + protocol_name ]*
+pub fn build_alpn_extension(protocols: &[String]) -> Vec<u8> {
+    let mut ext = Vec::new();
+
+    // Calculate total list length
+    let list_len: usize = protocols.iter().map(|p| 1 + p.len()).sum();
+    ext.push((list_len >> 8) as u8);
+    ext.push(list_len as u8);
+
+    // Add each protocol entry
+    for protocol in protocols {
+        ext.push(protocol.len() as u8);
+        ext.extend_from_slice(protocol.as_bytes());
+    }
+
+    ext
+}
+
+/// Find the server's ALPN selection in a length-prefixed extensions block
+///
+/// Used for TLS 1.3 EncryptedExtensions and the TLS 1.2 ServerHello. The
+/// server must pick exactly one of the protocols we offered (RFC 7301).
+pub fn parse_alpn_selection(data: &[u8], offered: &[String]) -> Result<Option<Vec<u8>>> {
+    if data.len() < 2 {
+        return Err(TlsError::handshake("Extensions block too short"));
+    }
+    let ext_len = ((data[0] as usize) << 8) | (data[1] as usize);
+    let ext_end = 2 + ext_len;
+    if ext_end > data.len() {
+        return Err(TlsError::handshake("Extensions block truncated"));
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= ext_end {
+        let ext_type = ((data[pos] as u16) << 8) | (data[pos + 1] as u16);
+        let ext_data_len = ((data[pos + 2] as usize) << 8) | (data[pos + 3] as usize);
+        pos += 4;
+        if pos + ext_data_len > ext_end {
+            return Err(TlsError::handshake("Extension data overflow"));
+        }
+        let ext_data = &data[pos..pos + ext_data_len];
+        pos += ext_data_len;
+
+        if ext_type != EXT_ALPN {
+            continue;
+        }
+
+        // Protocol list length (2) + exactly one entry: length(1) + name
+        if ext_data.len() < 3 {
+            return Err(TlsError::handshake("ALPN extension too short"));
+        }
+        let list_len = ((ext_data[0] as usize) << 8) | (ext_data[1] as usize);
+        let name_len = ext_data[2] as usize;
+        if list_len != ext_data.len() - 2 || name_len == 0 || 3 + name_len != ext_data.len() {
+            return Err(TlsError::handshake("Malformed ALPN extension"));
+        }
+        let selected = &ext_data[3..];
+        if !offered.iter().any(|p| p.as_bytes() == selected) {
+            return Err(TlsError::handshake(format!(
+                "Server selected unoffered ALPN protocol {:?}",
+                String::from_utf8_lossy(selected)
+            )));
+        }
+        return Ok(Some(selected.to_vec()));
+    }
+
+    Ok(None)
+}
+
 /// Parse a handshake message header
 pub fn parse_handshake_header(data: &[u8]) -> Result<(u8, usize)> {
     if data.len() < 4 {
@@ -782,6 +839,37 @@ pub fn parse_finished(data: &[u8]) -> Result<Vec<u8>> {
 /// Maximum handshake message size (matches TLS spec 24-bit length field: 0 to 2^24-1)
 pub const MAX_HANDSHAKE_MESSAGE_SIZE: usize = (1 << 24) - 1;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    fn alpn_block(protocol: &[u8]) -> Vec<u8> {
+        let ext = build_alpn_extension(&[String::from_utf8(protocol.to_vec()).unwrap()]);
+        let mut block = vec![0, (4 + ext.len()) as u8];
+        block.extend_from_slice(&[0, EXT_ALPN as u8, 0, ext.len() as u8]);
+        block.extend_from_slice(&ext);
+        block
+    }
+
+    #[portable_test]
+    fn test_alpn_selection() {
+        let offered = vec!["h2".to_string(), "http/1.1".to_string()];
+
+        assert_eq!(
+            build_alpn_extension(&offered),
+            b"\x00\x0c\x02h2\x08http/1.1".to_vec()
+        );
+        assert_eq!(
+            parse_alpn_selection(&alpn_block(b"h2"), &offered).unwrap(),
+            Some(b"h2".to_vec())
+        );
+        assert_eq!(parse_alpn_selection(&[0, 0], &offered).unwrap(), None);
+        assert!(parse_alpn_selection(&alpn_block(b"spdy/3"), &offered).is_err());
+        assert!(parse_alpn_selection(&[0, 9, 0, 16], &offered).is_err());
+    }
+}
+
 #[cfg(kani)]
 mod verification {
     use super::*;
@@ -828,6 +916,13 @@ mod verification {
         let _ = parse_finished(&data);
     }
 
+    #[kani::proof]
+    #[kani::unwind(12)]
+    fn parse_alpn_selection_never_panics() {
+        let data: [u8; 10] = kani::any();
+        let _ = parse_alpn_selection(&data, &[]);
+    }
+
     #[kani::proof]
     #[kani::unwind(5)]
     fn handshake_header_length_bounded() {
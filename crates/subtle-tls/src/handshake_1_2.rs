@@ -12,6 +12,7 @@
 
 use crate::crypto::{self, EcdhKeyPair};
 use crate::error::{Result, TlsError};
+use crate::handshake::{build_alpn_extension, parse_alpn_selection};
 use crate::prf::{self, KeyMaterial};
 use tracing::debug;
 
@@ -42,6 +43,7 @@ pub const EXT_EC_POINT_FORMATS: u16 = 11;
 pub const EXT_SUPPORTED_GROUPS: u16 = 10;
 pub const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
 pub const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+pub const EXT_ALPN: u16 = 16;
 
 // TLS 1.2 Cipher suites we support (in preference order)
 pub const TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: u16 = 0xC02F;
@@ -168,6 +170,10 @@ pub struct Handshake12State {
     pub server_public_key: Option<Vec<u8>>,
     /// Server's chosen curve
     pub server_curve: Option<u16>,
+    /// ALPN protocols offered in the ClientHello, in preference order
+    pub alpn_protocols: Vec<String>,
+    /// ALPN protocol selected by the server in ServerHello
+    pub alpn_protocol: Option<Vec<u8>>,
 }
 
 impl Handshake12State {
@@ -187,6 +193,8 @@ impl Handshake12State {
             key_material: None,
             server_public_key: None,
             server_curve: None,
+            alpn_protocols: vec!["http/1.1".to_string()],
+            alpn_protocol: None,
         })
     }
 
@@ -286,6 +294,16 @@ impl Handshake12State {
         extensions.push(1); // Length
         extensions.push(0); // Empty renegotiated_connection
 
+        // ALPN (Application-Layer Protocol Negotiation)
+        if !self.alpn_protocols.is_empty() {
+            let alpn = build_alpn_extension(&self.alpn_protocols);
+            extensions.push((EXT_ALPN >> 8) as u8);
+            extensions.push(EXT_ALPN as u8);
+            extensions.push((alpn.len() >> 8) as u8);
+            extensions.push(alpn.len() as u8);
+            extensions.extend_from_slice(&alpn);
+        }
+
         extensions
     }
 
@@ -373,6 +391,12 @@ impl Handshake12State {
         if compression != 0 {
             return Err(TlsError::handshake("Server selected non-null compression"));
         }
+        pos += 1;
+
+        // Extensions are optional in TLS 1.2
+        if pos < data.len() {
+            self.alpn_protocol = parse_alpn_selection(&data[pos..], &self.alpn_protocols)?;
+        }
 
         Ok(())
     }
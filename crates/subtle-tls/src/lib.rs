@@ -148,6 +148,14 @@ where
             TlsStreamWrapper::Tls12(s) => s.peer_certificate(),
         }
     }
+
+    /// Get the ALPN protocol selected by the server, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            TlsStreamWrapper::Tls13(s) => s.alpn_protocol(),
+            TlsStreamWrapper::Tls12(s) => s.alpn_protocol(),
+        }
+    }
 }
 
 impl TlsConnector {
@@ -33,6 +33,8 @@ pub struct TlsStream<S> {
     peer_certificate: Option<Vec<u8>>,
     /// TLS keying material (for export_keying_material)
     keying_material: Option<KeyingMaterial>,
+    /// ALPN protocol selected by the server
+    alpn_protocol: Option<Vec<u8>>,
     /// Buffer for accumulating encrypted record data being read
     record_read_buffer: Vec<u8>,
     /// Buffer for pending write data (encrypted, ready to send to transport)
@@ -54,6 +56,7 @@ where
         info!("Starting TLS 1.3 handshake with {}", server_name);
 
         let mut handshake = HandshakeState::new(server_name).await?;
+        handshake.alpn_protocols = config.alpn_protocols.clone();
         let mut record_layer = RecordLayer::new();
 
         // Step 1: Send ClientHello
@@ -159,6 +162,7 @@ where
             read_pos: 0,
             peer_certificate,
             keying_material,
+            alpn_protocol: handshake.alpn_protocol.take(),
             record_read_buffer: Vec::new(),
             record_write_buffer: Vec::new(),
         })
@@ -169,6 +173,11 @@ where
         self.peer_certificate.as_deref()
     }
 
+    /// Get the ALPN protocol selected by the server, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
     async fn read_server_hello(stream: &mut S, record_layer: &mut RecordLayer) -> Result<Vec<u8>> {
         loop {
             let (content_type, data) = record_layer.read_record(stream).await?;
@@ -307,6 +316,10 @@ where
                             HANDSHAKE_ENCRYPTED_EXTENSIONS => {
                                 debug!("Received EncryptedExtensions");
                                 handshake.update_transcript(&msg_data);
+                                handshake.alpn_protocol = handshake::parse_alpn_selection(
+                                    msg_body,
+                                    &handshake.alpn_protocols,
+                                )?;
                                 got_encrypted_extensions = true;
                             }
                             HANDSHAKE_CERTIFICATE => {
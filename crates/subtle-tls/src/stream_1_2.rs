@@ -28,6 +28,8 @@ pub struct TlsStream12<S> {
     read_pos: usize,
     /// DER-encoded peer certificate
     peer_certificate: Option<Vec<u8>>,
+    /// ALPN protocol selected by the server
+    alpn_protocol: Option<Vec<u8>>,
 }
 
 impl<S> TlsStream12<S>
@@ -39,6 +41,7 @@ where
         info!("Starting TLS 1.2 handshake with {}", server_name);
 
         let mut handshake = Handshake12State::new(server_name)?;
+        handshake.alpn_protocols = config.alpn_protocols.clone();
         let mut record_layer = RecordLayer12::new();
 
         // Step 1: Send ClientHello
@@ -152,6 +155,7 @@ where
             read_buffer: Vec::new(),
             read_pos: 0,
             peer_certificate,
+            alpn_protocol: handshake.alpn_protocol.take(),
         })
     }
 
@@ -292,6 +296,11 @@ where
         self.peer_certificate.as_deref()
     }
 
+    /// Get the ALPN protocol selected by the server, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
     /// Read application data
     pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // First drain buffer
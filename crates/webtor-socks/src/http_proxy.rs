@@ -0,0 +1,447 @@
+//! HTTP/1.1 forward proxy: `CONNECT` tunnels and absolute-URI requests
+//!
+//! Each client connection carries one request. `CONNECT host:port` opens a
+//! tunnel; a request such as `GET http://host/path` is rewritten to origin
+//! form, sent upstream with `Connection: close`, and the response is relayed
+//! back verbatim. Only `http` URIs can be proxied this way: clients reach
+//! `https` sites through `CONNECT`.
+
+use crate::{isolation_for_credentials, Connector};
+use base64::Engine;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+use webtor::{IsolationKey, TorError, TorErrorKind, Url};
+
+/// Largest request head the proxy will buffer
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Headers that only concern the client-proxy hop
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Status line for a failed upstream connection
+pub fn status_for_error(error: &TorError) -> (u16, &'static str) {
+    match error.kind() {
+        TorErrorKind::Timeout => (504, "Gateway Timeout"),
+        _ => (502, "Bad Gateway"),
+    }
+}
+
+/// Accept HTTP proxy connections on `listener` until it fails
+pub async fn serve<C: Connector>(listener: TcpListener, connector: C) -> io::Result<()> {
+    info!("HTTP proxy listening on {}", listener.local_addr()?);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let connector = connector.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&connector, socket).await {
+                debug!("HTTP proxy connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// A parsed request head
+struct RequestHead {
+    method: String,
+    target: String,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl RequestHead {
+    fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Isolation key for the request's `Proxy-Authorization` credentials
+    fn isolation(&self) -> Option<IsolationKey> {
+        let value = self.header("proxy-authorization")?;
+        let value = String::from_utf8_lossy(value);
+        let (scheme, credentials) = value.trim().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("basic") {
+            if let Ok(decoded) =
+                base64::engine::general_purpose::STANDARD.decode(credentials.trim())
+            {
+                let (username, password) = match decoded.iter().position(|&b| b == b':') {
+                    Some(colon) => (&decoded[..colon], &decoded[colon + 1..]),
+                    None => (&decoded[..], &[][..]),
+                };
+                return Some(isolation_for_credentials("http", username, password));
+            }
+        }
+        // Other schemes are opaque tokens: still a distinct group per value
+        Some(isolation_for_credentials(
+            "http",
+            value.trim().as_bytes(),
+            b"",
+        ))
+    }
+}
+
+/// Serve one HTTP proxy client
+pub async fn handle_connection<C, S>(connector: &C, mut client: S) -> io::Result<()>
+where
+    C: Connector,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, leftover) = match read_head(&mut client).await? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    let head = match head {
+        Ok(head) => head,
+        Err(msg) => return write_status(&mut client, 400, "Bad Request", msg).await,
+    };
+    let isolation = head.isolation();
+
+    if head.method.eq_ignore_ascii_case("CONNECT") {
+        let Some((host, port)) = parse_authority(&head.target) else {
+            return write_status(&mut client, 400, "Bad Request", "Invalid CONNECT target").await;
+        };
+        debug!("HTTP CONNECT {}:{}", host, port);
+        let mut upstream = match connector.connect(&host, port, isolation).await {
+            Ok(upstream) => upstream,
+            Err(e) => return write_error(&mut client, &host, port, &e).await,
+        };
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        // Clients may pipeline the first bytes of the tunnel behind the head
+        upstream.write_all(&leftover).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    let url = match Url::parse(&head.target) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+        Ok(_) => {
+            return write_status(
+                &mut client,
+                501,
+                "Not Implemented",
+                "Only http:// URIs can be proxied; use CONNECT for https",
+            )
+            .await
+        }
+        Err(_) => {
+            return write_status(
+                &mut client,
+                400,
+                "Bad Request",
+                "Proxy requests need an absolute URI",
+            )
+            .await
+        }
+    };
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    debug!("HTTP {} {}", head.method, url);
+
+    let mut upstream = match connector.connect(&host, port, isolation).await {
+        Ok(upstream) => upstream,
+        Err(e) => return write_error(&mut client, &host, port, &e).await,
+    };
+    upstream.write_all(&origin_request(&head, &url)).await?;
+    upstream.write_all(&leftover).await?;
+    upstream.flush().await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Rewrite an absolute-URI request to origin form for the upstream server
+fn origin_request(head: &RequestHead, url: &Url) -> Vec<u8> {
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let mut out = format!("{} {} HTTP/1.1\r\n", head.method, path).into_bytes();
+    if head.header("host").is_none() {
+        let host = url.host_str().unwrap_or_default();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        out.extend_from_slice(format!("Host: {}\r\n", host).as_bytes());
+    }
+    for (name, value) in &head.headers {
+        if HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"Connection: close\r\n\r\n");
+    out
+}
+
+/// `host:port` from a CONNECT target, with IPv6 brackets removed
+fn parse_authority(target: &str) -> Option<(String, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse().ok().filter(|&p| p != 0)?;
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// Read the request head, returning it with any bytes read past its end
+///
+/// Returns `None` if the client closed the connection before sending
+/// anything.
+async fn read_head<S>(
+    client: &mut S,
+) -> io::Result<Option<(Result<RequestHead, &'static str>, Vec<u8>)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => {
+                let head = RequestHead {
+                    method: request.method.unwrap_or_default().to_string(),
+                    target: request.path.unwrap_or_default().to_string(),
+                    headers: request
+                        .headers
+                        .iter()
+                        .map(|h| (h.name.to_string(), h.value.to_vec()))
+                        .collect(),
+                };
+                return Ok(Some((Ok(head), buf[len..].to_vec())));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => continue,
+            Ok(httparse::Status::Partial) => {
+                return Ok(Some((Err("Request head too large"), Vec::new())))
+            }
+            Err(_) => return Ok(Some((Err("Malformed request"), Vec::new()))),
+        }
+    }
+}
+
+async fn write_error<S>(client: &mut S, host: &str, port: u16, error: &TorError) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    warn!("Proxy connection to {}:{} failed: {}", host, port, error);
+    let (status, reason) = status_for_error(error);
+    let body = format!("{} ({})", error, error.kind().as_code());
+    write_status(client, status, reason, &body).await
+}
+
+async fn write_status<S>(client: &mut S, status: u16, reason: &str, body: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let body = format!("{}\n", body);
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::DuplexStream;
+
+    /// Target and isolation key of every request, in order
+    type Seen = Arc<Mutex<Vec<(String, Option<IsolationKey>)>>>;
+
+    /// Connector whose upstream answers any request with a fixed response,
+    /// after capturing what the proxy sent
+    #[derive(Clone, Default)]
+    struct Upstream {
+        seen: Seen,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Connector for Upstream {
+        type Stream = DuplexStream;
+
+        async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+            isolation: Option<IsolationKey>,
+        ) -> webtor::Result<DuplexStream> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((format!("{}:{}", host, port), isolation));
+            match host {
+                "slow.example" => return Err(TorError::timeout("stream")),
+                "down.example" => return Err(TorError::circuit_creation("no exit")),
+                _ => {}
+            }
+            let (near, mut far) = tokio::io::duplex(4096);
+            let sent = self.sent.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = far.read(&mut buf).await.unwrap_or(0);
+                sent.lock().unwrap().extend_from_slice(&buf[..n]);
+                let _ = far.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            });
+            Ok(near)
+        }
+
+        async fn resolve(
+            &self,
+            _host: &str,
+            _isolation: Option<IsolationKey>,
+        ) -> webtor::Result<Vec<IpAddr>> {
+            unreachable!()
+        }
+
+        async fn resolve_ptr(
+            &self,
+            _addr: IpAddr,
+            _isolation: Option<IsolationKey>,
+        ) -> webtor::Result<Vec<String>> {
+            unreachable!()
+        }
+    }
+
+    /// Send `request` through the proxy and return the whole response
+    async fn exchange(connector: &Upstream, request: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(8192);
+        let connector = connector.clone();
+        let proxy = tokio::spawn(async move {
+            let _ = handle_connection(&connector, server).await;
+        });
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        proxy.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn connect_opens_tunnel() {
+        let connector = Upstream::default();
+        let response = exchange(
+            &connector,
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nhello",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 Connection Established\r\n\r\n"));
+        assert_eq!(&*connector.sent.lock().unwrap(), b"hello");
+        assert_eq!(
+            connector.seen.lock().unwrap()[0],
+            ("example.com:443".to_string(), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn absolute_uri_is_rewritten_to_origin_form() {
+        let connector = Upstream::default();
+        let response = exchange(
+            &connector,
+            "GET http://example.com:8080/a/b?c=d HTTP/1.1\r\n\
+             Host: example.com:8080\r\n\
+             Proxy-Connection: keep-alive\r\n\
+             Accept: */*\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+
+        let sent = String::from_utf8(connector.sent.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("GET /a/b?c=d HTTP/1.1\r\n"));
+        assert!(sent.contains("Host: example.com:8080\r\n"));
+        assert!(sent.contains("Accept: */*\r\n"));
+        assert!(sent.ends_with("Connection: close\r\n\r\n"));
+        assert!(!sent.to_ascii_lowercase().contains("proxy-connection"));
+        assert_eq!(
+            connector.seen.lock().unwrap()[0].0,
+            "example.com:8080".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_authorization_selects_isolation_key() {
+        let connector = Upstream::default();
+        // alice:1
+        exchange(
+            &connector,
+            "GET http://example.com/ HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Proxy-Authorization: Basic YWxpY2U6MQ==\r\n\r\n",
+        )
+        .await;
+        let sent = String::from_utf8(connector.sent.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(!sent.contains("Proxy-Authorization"));
+
+        let key = connector.seen.lock().unwrap()[0].1.clone();
+        assert_eq!(key, Some(isolation_for_credentials("http", b"alice", b"1")));
+    }
+
+    #[tokio::test]
+    async fn tor_errors_map_to_gateway_statuses() {
+        let connector = Upstream::default();
+        let response = exchange(&connector, "CONNECT slow.example:443 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(response.contains("TIMEOUT"));
+
+        let response = exchange(
+            &connector,
+            "GET http://down.example/ HTTP/1.1\r\nHost: down.example\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_rejected_locally() {
+        let connector = Upstream::default();
+        let response = exchange(&connector, "GET /relative HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 "));
+
+        let response = exchange(&connector, "GET https://example.com/ HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 501 "));
+
+        let response = exchange(&connector, "CONNECT example.com HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 "));
+
+        assert!(connector.seen.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+//! Local SOCKS5 and HTTP proxies that route connections through webtor
+//!
+//! The SOCKS proxy speaks SOCKS5 CONNECT plus Tor's RESOLVE (0xF0) and
+//! RESOLVE_PTR (0xF1) extensions; the HTTP proxy serves `CONNECT` tunnels
+//! and absolute-URI requests for clients that only speak HTTP proxies.
+//! Credentials do not gate access: as with Tor's `IsolateSOCKSAuth`, every
+//! distinct pair of SOCKS or `Proxy-Authorization` credentials gets its own
+//! circuits.
+//!
+//! Protocol handling is generic over [`Connector`], implemented for
+//! [`TorClient`] and for loopback transports in tests.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+pub mod http_proxy;
+pub mod socks;
+
+pub use socks::{handle_connection, serve};
+
+use sha3::{Digest, Sha3_256};
+use std::future::Future;
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use webtor::{DataStream, IsolationKey, TorClient};
+
+/// Where proxied connections and lookups go
+pub trait Connector: Clone + Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Open a stream to `host:port`
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: Option<IsolationKey>,
+    ) -> impl Future<Output = webtor::Result<Self::Stream>> + Send;
+
+    /// Resolve `host` to its addresses
+    fn resolve(
+        &self,
+        host: &str,
+        isolation: Option<IsolationKey>,
+    ) -> impl Future<Output = webtor::Result<Vec<IpAddr>>> + Send;
+
+    /// Look up the hostnames for `addr`
+    fn resolve_ptr(
+        &self,
+        addr: IpAddr,
+        isolation: Option<IsolationKey>,
+    ) -> impl Future<Output = webtor::Result<Vec<String>>> + Send;
+}
+
+impl Connector for TorClient {
+    type Stream = Compat<DataStream>;
+
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: Option<IsolationKey>,
+    ) -> webtor::Result<Self::Stream> {
+        Ok(TorClient::connect(self, host, port, isolation)
+            .await?
+            .compat())
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        isolation: Option<IsolationKey>,
+    ) -> webtor::Result<Vec<IpAddr>> {
+        self.resolve_with_isolation(host, isolation).await
+    }
+
+    async fn resolve_ptr(
+        &self,
+        addr: IpAddr,
+        isolation: Option<IsolationKey>,
+    ) -> webtor::Result<Vec<String>> {
+        self.resolve_ptr_with_isolation(addr, isolation).await
+    }
+}
+
+/// Isolation key for a set of proxy credentials
+///
+/// The credentials are hashed so they never show up in logs, and length
+/// prefixed so `("ab", "c")` and `("a", "bc")` stay distinct.
+pub fn isolation_for_credentials(scheme: &str, username: &[u8], password: &[u8]) -> IsolationKey {
+    let mut hasher = Sha3_256::new();
+    hasher.update((username.len() as u64).to_be_bytes());
+    hasher.update(username);
+    hasher.update(password);
+    let digest = hasher.finalize();
+    IsolationKey::from_string(format!("{}:{}", scheme, hex::encode(&digest[..16])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_map_to_distinct_keys() {
+        let key = isolation_for_credentials("socks", b"alice", b"secret");
+        assert_eq!(key, isolation_for_credentials("socks", b"alice", b"secret"));
+        assert!(!key.0.contains("alice") && !key.0.contains("secret"));
+
+        assert_ne!(key, isolation_for_credentials("socks", b"alice", b"other"));
+        assert_ne!(
+            isolation_for_credentials("socks", b"ab", b"c"),
+            isolation_for_credentials("socks", b"a", b"bc")
+        );
+        assert_ne!(key, isolation_for_credentials("http", b"alice", b"secret"));
+    }
+}
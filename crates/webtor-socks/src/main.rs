@@ -0,0 +1,226 @@
+//! webtor-socks: expose a webtor client as a local SOCKS5 (and HTTP) proxy
+//!
+//! Usage:
+//!   webtor-socks --webtunnel-url <URL> --fingerprint <FP> [--listen 127.0.0.1:9150]
+//!                [--http-listen 127.0.0.1:8118]
+//!
+//! The bridge can also be given through `WEBTUNNEL_URL` and
+//! `WEBTUNNEL_FINGERPRINT`, matching the integration tests.
+
+// Native only; on wasm32 the binary is empty so workspace checks still pass
+#![cfg_attr(target_arch = "wasm32", no_main)]
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use webtor::{StreamIsolationPolicy, TorClient, TorClientOptions};
+
+const USAGE: &str = "\
+Usage: webtor-socks --webtunnel-url <URL> --fingerprint <FP> [options]
+
+Options:
+  --listen <ADDR>         Address to accept SOCKS5 clients on [default: 127.0.0.1:9150]
+  --http-listen <ADDR>    Also accept HTTP proxy clients (CONNECT and http:// URIs) on ADDR
+  --webtunnel-url <URL>   WebTunnel bridge URL (or WEBTUNNEL_URL)
+  --fingerprint <FP>      Bridge fingerprint (or WEBTUNNEL_FINGERPRINT)
+  --isolation <POLICY>    per-domain, per-subdomain, per-origin or none [default: per-domain]
+  -h, --help              Print this message";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    listen: SocketAddr,
+    http_listen: Option<SocketAddr>,
+    webtunnel_url: String,
+    fingerprint: String,
+    isolation: StreamIsolationPolicy,
+}
+
+fn parse_isolation(value: &str) -> Result<StreamIsolationPolicy, String> {
+    match value {
+        "per-domain" => Ok(StreamIsolationPolicy::PerDomain),
+        "per-subdomain" => Ok(StreamIsolationPolicy::PerSubdomain),
+        "per-origin" => Ok(StreamIsolationPolicy::PerOrigin),
+        "none" => Ok(StreamIsolationPolicy::None),
+        other => Err(format!("Unknown isolation policy: {}", other)),
+    }
+}
+
+/// Parse command line arguments, falling back to `env` for the bridge
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Args>, String> {
+    let mut listen = "127.0.0.1:9150".to_string();
+    let mut http_listen = None;
+    let mut webtunnel_url = env("WEBTUNNEL_URL");
+    let mut fingerprint = env("WEBTUNNEL_FINGERPRINT");
+    let mut isolation = StreamIsolationPolicy::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--listen" => listen = value()?,
+            "--http-listen" => http_listen = Some(value()?),
+            "--webtunnel-url" => webtunnel_url = Some(value()?),
+            "--fingerprint" => fingerprint = Some(value()?),
+            "--isolation" => isolation = parse_isolation(&value()?)?,
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    let parse_addr = |addr: &str| {
+        addr.parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid listen address {}: {}", addr, e))
+    };
+    Ok(Some(Args {
+        listen: parse_addr(&listen)?,
+        http_listen: http_listen.as_deref().map(parse_addr).transpose()?,
+        webtunnel_url: webtunnel_url.ok_or("A WebTunnel bridge URL is required")?,
+        fingerprint: fingerprint.ok_or("A bridge fingerprint is required")?,
+        isolation,
+    }))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let args = match parse_args(std::env::args().skip(1), |key| std::env::var(key).ok()) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let listener = match TcpListener::bind(args.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", args.listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let http_listener = match args.http_listen {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Failed to listen on {}: {}", addr, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    let options = TorClientOptions {
+        stream_isolation: args.isolation,
+        ..TorClientOptions::webtunnel(args.webtunnel_url, args.fingerprint)
+    };
+    let client = match TorClient::new(options).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create Tor client: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    info!("Bootstrapping Tor client...");
+    if let Err(e) = client.bootstrap().await {
+        error!("Bootstrap failed: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let http_proxy = async {
+        match http_listener {
+            Some(listener) => webtor_socks::http_proxy::serve(listener, client.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let result = tokio::select! {
+        result = webtor_socks::serve(listener, client.clone()) => result,
+        result = http_proxy => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down");
+            Ok(())
+        }
+    };
+    client.close().await;
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Proxy failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn bridge_from_flags_or_env() {
+        let parsed = parse_args(
+            args(&[
+                "--webtunnel-url",
+                "https://b.example/x",
+                "--fingerprint",
+                "AA",
+            ]),
+            |_| None,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(parsed.listen, "127.0.0.1:9150".parse().unwrap());
+        assert_eq!(parsed.http_listen, None);
+        assert_eq!(parsed.webtunnel_url, "https://b.example/x");
+        assert_eq!(parsed.isolation, StreamIsolationPolicy::PerDomain);
+
+        let parsed = parse_args(
+            args(&[
+                "--listen",
+                "0.0.0.0:1080",
+                "--http-listen",
+                "127.0.0.1:8118",
+                "--isolation",
+                "per-origin",
+            ]),
+            |key| Some(format!("env:{}", key)),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(parsed.listen, "0.0.0.0:1080".parse().unwrap());
+        assert_eq!(parsed.http_listen, Some("127.0.0.1:8118".parse().unwrap()));
+        assert_eq!(parsed.fingerprint, "env:WEBTUNNEL_FINGERPRINT");
+        assert_eq!(parsed.isolation, StreamIsolationPolicy::PerOrigin);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse_args(args(&[]), |_| None).is_err());
+        assert!(parse_args(args(&["--listen"]), |_| None).is_err());
+        assert!(parse_args(args(&["--http-listen", "nope"]), |_| Some("x".into())).is_err());
+        assert!(parse_args(args(&["--bogus"]), |_| Some("x".into())).is_err());
+        assert!(parse_args(args(&["--isolation", "weird"]), |_| Some("x".into())).is_err());
+        assert_eq!(parse_args(args(&["--help"]), |_| None), Ok(None));
+    }
+}
@@ -0,0 +1,508 @@
+//! SOCKS5 server side (RFC 1928, RFC 1929 and Tor's extensions)
+
+use crate::{isolation_for_credentials, Connector};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+use webtor::{IsolationKey, TorError, TorErrorKind};
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+/// Tor extension: resolve a hostname (socks-extensions.txt)
+const CMD_RESOLVE: u8 = 0xF0;
+/// Tor extension: reverse-resolve an address
+const CMD_RESOLVE_PTR: u8 = 0xF1;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    /// The reply Tor would send for a failed request
+    pub fn for_error(error: &TorError) -> Self {
+        match error {
+            TorError::Dns(_) => Reply::HostUnreachable,
+            _ => match error.kind() {
+                TorErrorKind::Timeout => Reply::TtlExpired,
+                TorErrorKind::Configuration => Reply::NotAllowed,
+                TorErrorKind::Network | TorErrorKind::Circuit | TorErrorKind::Bootstrap => {
+                    Reply::NetworkUnreachable
+                }
+                _ => Reply::GeneralFailure,
+            },
+        }
+    }
+}
+
+/// Destination of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl Address {
+    fn host(&self) -> String {
+        match self {
+            Address::Ip(ip) => ip.to_string(),
+            Address::Domain(domain) => domain.clone(),
+        }
+    }
+}
+
+const UNSPECIFIED: Address = Address::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+/// Accept SOCKS connections on `listener` until it fails
+pub async fn serve<C: Connector>(listener: TcpListener, connector: C) -> io::Result<()> {
+    info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let connector = connector.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&connector, socket).await {
+                debug!("SOCKS connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serve one SOCKS client
+pub async fn handle_connection<C, S>(connector: &C, mut client: S) -> io::Result<()>
+where
+    C: Connector,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let isolation = negotiate(&mut client).await?;
+
+    let (command, address, port) = match read_request(&mut client).await? {
+        Ok(request) => request,
+        Err(reply) => return write_reply(&mut client, reply, &UNSPECIFIED, 0).await,
+    };
+
+    match command {
+        CMD_CONNECT => {
+            debug!("SOCKS CONNECT {}:{}", address.host(), port);
+            let mut upstream = match connector.connect(&address.host(), port, isolation).await {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("CONNECT {}:{} failed: {}", address.host(), port, e);
+                    return write_reply(&mut client, Reply::for_error(&e), &UNSPECIFIED, 0).await;
+                }
+            };
+            write_reply(&mut client, Reply::Succeeded, &UNSPECIFIED, 0).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+            Ok(())
+        }
+        CMD_RESOLVE => {
+            let result = match &address {
+                Address::Ip(ip) => Ok(vec![*ip]),
+                Address::Domain(domain) => connector.resolve(domain, isolation).await,
+            };
+            match result.map(|addrs| addrs.into_iter().next()) {
+                Ok(Some(ip)) => {
+                    write_reply(&mut client, Reply::Succeeded, &Address::Ip(ip), 0).await
+                }
+                Ok(None) => write_reply(&mut client, Reply::HostUnreachable, &UNSPECIFIED, 0).await,
+                Err(e) => {
+                    warn!("RESOLVE {} failed: {}", address.host(), e);
+                    write_reply(&mut client, Reply::for_error(&e), &UNSPECIFIED, 0).await
+                }
+            }
+        }
+        CMD_RESOLVE_PTR => {
+            let ip = match address {
+                Address::Ip(ip) => ip,
+                Address::Domain(_) => {
+                    return write_reply(
+                        &mut client,
+                        Reply::AddressTypeNotSupported,
+                        &UNSPECIFIED,
+                        0,
+                    )
+                    .await
+                }
+            };
+            match connector.resolve_ptr(ip, isolation).await {
+                Ok(names) => match names.into_iter().find(|name| name.len() <= 255) {
+                    Some(name) => {
+                        write_reply(&mut client, Reply::Succeeded, &Address::Domain(name), 0).await
+                    }
+                    None => write_reply(&mut client, Reply::HostUnreachable, &UNSPECIFIED, 0).await,
+                },
+                Err(e) => {
+                    warn!("RESOLVE_PTR {} failed: {}", ip, e);
+                    write_reply(&mut client, Reply::for_error(&e), &UNSPECIFIED, 0).await
+                }
+            }
+        }
+        _ => write_reply(&mut client, Reply::CommandNotSupported, &UNSPECIFIED, 0).await,
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Method selection and optional username/password subnegotiation
+///
+/// Returns the isolation key for the client's credentials, if it sent any.
+async fn negotiate<S>(client: &mut S) -> io::Result<Option<IsolationKey>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = client.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(protocol_error("Only SOCKS5 is supported"));
+    }
+    let mut methods = vec![0; client.read_u8().await? as usize];
+    client.read_exact(&mut methods).await?;
+
+    // Prefer credentials when offered: they carry the isolation group
+    let method = if methods.contains(&METHOD_USERNAME_PASSWORD) {
+        METHOD_USERNAME_PASSWORD
+    } else if methods.contains(&METHOD_NO_AUTH) {
+        METHOD_NO_AUTH
+    } else {
+        METHOD_NO_ACCEPTABLE
+    };
+    client.write_all(&[SOCKS_VERSION, method]).await?;
+
+    match method {
+        METHOD_NO_AUTH => Ok(None),
+        METHOD_USERNAME_PASSWORD => {
+            if client.read_u8().await? != AUTH_VERSION {
+                return Err(protocol_error("Bad username/password version"));
+            }
+            let mut username = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut username).await?;
+            let mut password = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut password).await?;
+
+            // Any credentials are accepted; they only select circuits
+            client.write_all(&[AUTH_VERSION, 0x00]).await?;
+            Ok(Some(isolation_for_credentials(
+                "socks", &username, &password,
+            )))
+        }
+        _ => Err(protocol_error("No acceptable authentication method")),
+    }
+}
+
+/// Read a request, or the reply to send when it can't be served
+async fn read_request<S>(client: &mut S) -> io::Result<Result<(u8, Address, u16), Reply>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 4];
+    client.read_exact(&mut header).await?;
+    let [version, command, _reserved, atyp] = header;
+    if version != SOCKS_VERSION {
+        return Err(protocol_error("Only SOCKS5 is supported"));
+    }
+
+    let address = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            client.read_exact(&mut octets).await?;
+            Address::Ip(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            client.read_exact(&mut octets).await?;
+            Address::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        ATYP_DOMAIN => {
+            let mut domain = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut domain).await?;
+            match String::from_utf8(domain) {
+                Ok(domain) => Address::Domain(domain),
+                Err(_) => return Ok(Err(Reply::AddressTypeNotSupported)),
+            }
+        }
+        _ => return Ok(Err(Reply::AddressTypeNotSupported)),
+    };
+    let port = client.read_u16().await?;
+
+    Ok(Ok((command, address, port)))
+}
+
+async fn write_reply<S>(
+    client: &mut S,
+    reply: Reply,
+    address: &Address,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![SOCKS_VERSION, reply as u8, 0x00];
+    match address {
+        Address::Ip(IpAddr::V4(ip)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Ip(IpAddr::V6(ip)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Domain(domain) => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+    client.write_all(&buf).await?;
+    client.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::DuplexStream;
+
+    /// Target and isolation key of every request, in order
+    type Seen = Arc<Mutex<Vec<(String, Option<IsolationKey>)>>>;
+
+    /// Connector that loops streams back to an in-process echo task and
+    /// records the isolation key of every request
+    #[derive(Clone, Default)]
+    struct Loopback {
+        seen: Seen,
+    }
+
+    impl Connector for Loopback {
+        type Stream = DuplexStream;
+
+        async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+            isolation: Option<IsolationKey>,
+        ) -> webtor::Result<DuplexStream> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((format!("{}:{}", host, port), isolation));
+            if host == "refused.example" {
+                return Err(TorError::timeout("stream"));
+            }
+            let (near, mut far) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(&mut far);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+            Ok(near)
+        }
+
+        async fn resolve(
+            &self,
+            host: &str,
+            isolation: Option<IsolationKey>,
+        ) -> webtor::Result<Vec<IpAddr>> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((host.to_string(), isolation));
+            match host {
+                "example.com" => Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]),
+                _ => Err(TorError::dns("NXDOMAIN")),
+            }
+        }
+
+        async fn resolve_ptr(
+            &self,
+            addr: IpAddr,
+            isolation: Option<IsolationKey>,
+        ) -> webtor::Result<Vec<String>> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((addr.to_string(), isolation));
+            Ok(vec!["ptr.example".to_string()])
+        }
+    }
+
+    /// Run a SOCKS session against `connector`, returning the client side
+    fn start(connector: &Loopback) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(4096);
+        let connector = connector.clone();
+        tokio::spawn(async move {
+            let _ = handle_connection(&connector, server).await;
+        });
+        client
+    }
+
+    async fn greet(client: &mut DuplexStream, credentials: Option<(&str, &str)>) {
+        match credentials {
+            None => {
+                client.write_all(&[5, 1, METHOD_NO_AUTH]).await.unwrap();
+                let mut reply = [0; 2];
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply, [5, METHOD_NO_AUTH]);
+            }
+            Some((user, pass)) => {
+                client
+                    .write_all(&[5, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD])
+                    .await
+                    .unwrap();
+                let mut reply = [0; 2];
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply, [5, METHOD_USERNAME_PASSWORD]);
+
+                let mut auth = vec![AUTH_VERSION, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(pass.len() as u8);
+                auth.extend_from_slice(pass.as_bytes());
+                client.write_all(&auth).await.unwrap();
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply, [AUTH_VERSION, 0]);
+            }
+        }
+    }
+
+    async fn request_domain(client: &mut DuplexStream, command: u8, domain: &str, port: u16) {
+        let mut request = vec![5, command, 0, ATYP_DOMAIN, domain.len() as u8];
+        request.extend_from_slice(domain.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+    }
+
+    /// Read a reply, returning its code and bound address bytes
+    async fn read_reply(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 4];
+        client.read_exact(&mut header).await.unwrap();
+        let len = match header[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => client.read_u8().await.unwrap() as usize,
+            other => panic!("bad atyp {}", other),
+        };
+        let mut addr = vec![0; len];
+        client.read_exact(&mut addr).await.unwrap();
+        client.read_u16().await.unwrap();
+        (header[1], addr)
+    }
+
+    #[tokio::test]
+    async fn connect_relays_data() {
+        let connector = Loopback::default();
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        request_domain(&mut client, CMD_CONNECT, "example.com", 80).await;
+        assert_eq!(read_reply(&mut client).await.0, Reply::Succeeded as u8);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let seen = connector.seen.lock().unwrap();
+        assert_eq!(seen[0], ("example.com:80".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn credentials_select_isolation_key() {
+        let connector = Loopback::default();
+        for (user, pass) in [("alice", "1"), ("bob", "1"), ("alice", "1")] {
+            let mut client = start(&connector);
+            greet(&mut client, Some((user, pass))).await;
+            request_domain(&mut client, CMD_CONNECT, "example.com", 443).await;
+            assert_eq!(read_reply(&mut client).await.0, Reply::Succeeded as u8);
+        }
+
+        let seen = connector.seen.lock().unwrap();
+        let keys: Vec<_> = seen.iter().map(|(_, key)| key.clone().unwrap()).collect();
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[0], keys[2]);
+        assert_eq!(keys[0], isolation_for_credentials("socks", b"alice", b"1"));
+    }
+
+    #[tokio::test]
+    async fn failed_connect_maps_error_to_reply() {
+        let connector = Loopback::default();
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        request_domain(&mut client, CMD_CONNECT, "refused.example", 80).await;
+        assert_eq!(read_reply(&mut client).await.0, Reply::TtlExpired as u8);
+    }
+
+    #[tokio::test]
+    async fn resolve_extensions() {
+        let connector = Loopback::default();
+
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        request_domain(&mut client, CMD_RESOLVE, "example.com", 0).await;
+        assert_eq!(
+            read_reply(&mut client).await,
+            (Reply::Succeeded as u8, vec![192, 0, 2, 7])
+        );
+
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        request_domain(&mut client, CMD_RESOLVE, "missing.example", 0).await;
+        assert_eq!(
+            read_reply(&mut client).await.0,
+            Reply::HostUnreachable as u8
+        );
+
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        client
+            .write_all(&[5, CMD_RESOLVE_PTR, 0, ATYP_IPV4, 192, 0, 2, 7, 0, 0])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_reply(&mut client).await,
+            (Reply::Succeeded as u8, b"ptr.example".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_requests_are_refused() {
+        let connector = Loopback::default();
+
+        // BIND
+        let mut client = start(&connector);
+        greet(&mut client, None).await;
+        request_domain(&mut client, 0x02, "example.com", 80).await;
+        assert_eq!(
+            read_reply(&mut client).await.0,
+            Reply::CommandNotSupported as u8
+        );
+
+        // No acceptable method
+        let mut client = start(&connector);
+        client.write_all(&[5, 1, 0x01]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, METHOD_NO_ACCEPTABLE]);
+
+        assert!(connector.seen.lock().unwrap().is_empty());
+    }
+}
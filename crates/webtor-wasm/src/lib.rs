@@ -1,12 +1,24 @@
 //! WebAssembly bindings for webtor
 
+use futures::Stream;
 use gloo_console::{error as console_error, log as console_log, warn as console_warn};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
 use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::future_to_promise;
-use webtor::{TorClient as NativeTorClient, TorClientOptions as NativeTorClientOptions, TorError};
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use webtor::config::{ChannelEventCallback, ClientAuthKey, OnionServiceConfig};
+use webtor::onion_service::OnionServiceHandle;
+use webtor::tor_websocket::{CLOSE_ABNORMAL, CLOSE_NORMAL};
+use webtor::{
+    ChannelEvent, DataStream, HttpRequest, RedirectPolicy, RequestBody, ResponseBody,
+    StreamingResponse, TorClient as NativeTorClient, TorClientOptions as NativeTorClientOptions,
+    TorError, TorWebSocket as NativeTorWebSocket, Url, WebSocketMessage, WebSocketOptions,
+};
 
 /// Structured error for JavaScript consumption
 /// Provides machine-readable error classification for UX and retry decisions
@@ -76,9 +88,56 @@ fn tor_error_to_js(e: TorError) -> JsValue {
     JsTorError::from(e).into_js_value()
 }
 
-/// Helper to convert headers HashMap to JsValue, with fallback to empty object on error
-fn headers_to_js(headers: &std::collections::HashMap<String, String>) -> JsValue {
-    serde_wasm_bindgen::to_value(headers).unwrap_or_else(|_| js_sys::Object::new().into())
+/// Helper to convert headers to an array of `[name, value]` pairs, in order
+///
+/// Repeated headers such as `Set-Cookie` stay separate entries; the array
+/// can be passed straight to `new Headers(...)`.
+fn headers_to_js(headers: &HeaderMap) -> JsValue {
+    let pairs = js_sys::Array::new();
+    for (name, value) in headers {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        pairs.push(&js_sys::Array::of2(
+            &JsValue::from_str(name.as_str()),
+            &JsValue::from_str(&value),
+        ));
+    }
+    pairs.into()
+}
+
+/// Values of `name` joined with ", ", as `Headers.get()` does
+fn header_to_js(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<_> = headers
+        .get_all(name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// Helper to read request headers from JavaScript
+///
+/// Accepts a plain object, a `Headers` instance or an iterable of
+/// `[name, value]` pairs; pairs may repeat a name.
+fn js_headers(headers: JsValue) -> Result<HeaderMap, JsValue> {
+    let mut map = HeaderMap::new();
+    if headers.is_undefined() || headers.is_null() {
+        return Ok(map);
+    }
+
+    let entries = match js_sys::try_iter(&headers)? {
+        Some(_) => js_sys::Array::from(&headers),
+        None => js_sys::Object::entries(headers.unchecked_ref()),
+    };
+    let pairs: Vec<(String, String)> = serde_wasm_bindgen::from_value(entries.into())
+        .map_err(|e| JsValue::from_str(&format!("Invalid headers object: {}", e)))?;
+    for (name, value) in pairs {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| JsValue::from_str(&format!("Invalid header name {:?}: {}", name, e)))?;
+        let value = HeaderValue::from_str(&value)
+            .map_err(|e| JsValue::from_str(&format!("Invalid value for {}: {}", name, e)))?;
+        map.append(name, value);
+    }
+    Ok(map)
 }
 
 // Thread-local log callback for forwarding logs to JavaScript (WASM is single-threaded)
@@ -173,6 +232,61 @@ impl TorClientOptions {
             inner: NativeTorClientOptions::snowflake_webrtc(),
         }
     }
+
+    /// Add a client authorization key for an onion service
+    ///
+    /// `secretKey` is a base32 x25519 secret key or a line from a Tor
+    /// `.auth_private` file.
+    #[wasm_bindgen(js_name = addOnionClientAuth)]
+    pub fn add_onion_client_auth(&mut self, onion_address: String, secret_key: String) {
+        self.inner
+            .onion_client_auth
+            .insert(onion_address, ClientAuthKey::new(secret_key));
+    }
+
+    /// Keep a cookie jar for HTTP requests, partitioned by isolation key
+    #[wasm_bindgen(js_name = setCookies)]
+    pub fn set_cookies(&mut self, enabled: bool) {
+        self.inner.cookies = enabled;
+    }
+
+    /// Reconnect in the background when the bridge connection dies (default:
+    /// true); when disabled, the next request reconnects
+    #[wasm_bindgen(js_name = setAutoReconnect)]
+    pub fn set_auto_reconnect(&mut self, enabled: bool) {
+        self.inner.auto_reconnect = enabled;
+    }
+
+    /// Call `callback` with `{ type, ... }` when the bridge connection is
+    /// lost and while it reconnects
+    ///
+    /// `type` is `"lost"` (with `reason`), `"reconnecting"` (with
+    /// `attempt`), `"reconnected"` or `"reconnectFailed"` (with `error`).
+    #[wasm_bindgen(js_name = setChannelEventCallback)]
+    pub fn set_channel_event_callback(&mut self, callback: js_sys::Function) {
+        let callback = JsCallback(callback);
+        self.inner.on_channel_event = Some(ChannelEventCallback(Arc::new(
+            move |event: ChannelEvent| {
+                let event = serde_wasm_bindgen::to_value(&event).unwrap_or(JsValue::NULL);
+                if let Err(e) = callback.call(&event) {
+                    console_warn!(format!("Channel event callback threw: {:?}", e));
+                }
+            },
+        )));
+    }
+}
+
+/// A JS function the native client can hold
+struct JsCallback(js_sys::Function);
+
+// WASM is single-threaded
+unsafe impl Send for JsCallback {}
+unsafe impl Sync for JsCallback {}
+
+impl JsCallback {
+    fn call(&self, arg: &JsValue) -> Result<JsValue, JsValue> {
+        self.0.call1(&JsValue::NULL, arg)
+    }
 }
 
 /// JavaScript-friendly TorClient
@@ -228,9 +342,11 @@ impl TorClient {
 
                     let js_response = JsHttpResponse {
                         status: response.status,
-                        headers: headers_to_js(&response.headers),
+                        headers: response.headers,
                         body: response.body,
                         url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
                     };
 
                     Ok(JsValue::from(js_response))
@@ -264,9 +380,11 @@ impl TorClient {
 
                     let js_response = JsHttpResponse {
                         status: response.status,
-                        headers: headers_to_js(&response.headers),
+                        headers: response.headers,
                         body: response.body,
                         url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
                     };
 
                     Ok(JsValue::from(js_response))
@@ -294,8 +412,11 @@ impl TorClient {
         };
 
         future_to_promise(async move {
-            let mut headers = std::collections::HashMap::new();
-            headers.insert("Content-Type".to_string(), "application/json".to_string());
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
 
             match client
                 .request(
@@ -312,9 +433,11 @@ impl TorClient {
 
                     let js_response = JsHttpResponse {
                         status: response.status,
-                        headers: headers_to_js(&response.headers),
+                        headers: response.headers,
                         body: response.body,
                         url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
                     };
 
                     Ok(JsValue::from(js_response))
@@ -328,14 +451,20 @@ impl TorClient {
     }
 
     /// Make a generic HTTP request with full control over method, headers, body, and timeout
+    ///
+    /// `body` may be a `Uint8Array`, string, `Blob` or `ReadableStream`;
+    /// streams are uploaded as they are read. Aborting `signal` rejects with
+    /// its reason and closes the request's stream, leaving the circuit and
+    /// other requests alone.
     #[wasm_bindgen(js_name = request)]
     pub fn request(
         &self,
         method: String,
         url: String,
         headers: JsValue,
-        body: Option<Vec<u8>>,
+        body: JsValue,
         timeout_ms: Option<u32>,
+        signal: Option<web_sys::AbortSignal>,
     ) -> js_sys::Promise {
         console_log!(format!("Starting {} request to: {}", method, url));
 
@@ -353,28 +482,39 @@ impl TorClient {
                 .parse()
                 .map_err(|e| JsValue::from_str(&format!("Invalid HTTP method: {}", e)))?;
 
-            let headers_map: std::collections::HashMap<String, String> =
-                if headers.is_undefined() || headers.is_null() {
-                    std::collections::HashMap::new()
-                } else {
-                    serde_wasm_bindgen::from_value(headers)
-                        .map_err(|e| JsValue::from_str(&format!("Invalid headers object: {}", e)))?
-                };
+            let headers_map = js_headers(headers)?;
 
             let timeout = timeout_ms.map(|ms| std::time::Duration::from_millis(ms as u64));
 
-            match client
-                .request(method_parsed, &url, headers_map, body, timeout)
-                .await
-            {
+            let body = js_body(body)?;
+            let abort = signal.as_ref().map(abort_promise);
+            let result = until_aborted(abort.as_ref(), async {
+                Ok(match body {
+                    JsBody::Bytes(body) => {
+                        client
+                            .request(method_parsed, &url, headers_map, body, timeout)
+                            .await
+                    }
+                    JsBody::Stream(body) => {
+                        client
+                            .request_with_body(method_parsed, &url, headers_map, body, timeout)
+                            .await
+                    }
+                })
+            })
+            .await?;
+
+            match result {
                 Ok(response) => {
                     console_log!("Request completed successfully");
 
                     let js_response = JsHttpResponse {
                         status: response.status,
-                        headers: headers_to_js(&response.headers),
+                        headers: response.headers,
                         body: response.body,
                         url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
                     };
 
                     Ok(JsValue::from(js_response))
@@ -387,6 +527,130 @@ impl TorClient {
         })
     }
 
+    /// Make an HTTP request whose body is exposed as a `ReadableStream`
+    ///
+    /// Resolves once the response headers arrive; the body streams in as it
+    /// is read instead of being buffered. Aborting `signal` also errors the
+    /// body stream.
+    #[wasm_bindgen(js_name = requestStreaming)]
+    pub fn request_streaming(
+        &self,
+        method: String,
+        url: String,
+        headers: JsValue,
+        body: JsValue,
+        timeout_ms: Option<u32>,
+        signal: Option<web_sys::AbortSignal>,
+    ) -> js_sys::Promise {
+        console_log!(format!("Starting streaming {} request to: {}", method, url));
+
+        let client = match &self.inner {
+            Some(client) => client.clone(),
+            None => {
+                return future_to_promise(async move {
+                    Err(JsTorError::not_initialized().into_js_value())
+                });
+            }
+        };
+
+        future_to_promise(async move {
+            let method_parsed: http::Method = method
+                .parse()
+                .map_err(|e| JsValue::from_str(&format!("Invalid HTTP method: {}", e)))?;
+
+            let headers_map = js_headers(headers)?;
+
+            let timeout = timeout_ms.map(|ms| std::time::Duration::from_millis(ms as u64));
+
+            let body = match js_body(body)? {
+                JsBody::Bytes(body) => body.map(RequestBody::from),
+                JsBody::Stream(body) => Some(body),
+            };
+
+            let abort = signal.as_ref().map(abort_promise);
+            let result = until_aborted(abort.as_ref(), async {
+                Ok(client
+                    .request_streaming(method_parsed, &url, headers_map, body, timeout)
+                    .await)
+            })
+            .await?;
+
+            match result {
+                Ok(response) => {
+                    console_log!("Streaming response headers received");
+
+                    let js_response = JsStreamingResponse {
+                        status: response.status,
+                        headers: response.headers.clone(),
+                        url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
+                        body: body_to_readable_stream(response.into_body(), abort)?,
+                    };
+
+                    Ok(JsValue::from(js_response))
+                }
+                Err(e) => {
+                    console_error!(format!("Streaming request failed: {}", e));
+                    Err(tor_error_to_js(e))
+                }
+            }
+        })
+    }
+
+    /// `fetch()` through Tor, resolving to a standard `Response`
+    ///
+    /// Takes what `fetch` takes: a URL or `Request`, and a `RequestInit`
+    /// whose `signal` and `redirect` are honored. `init.timeout` (ms, not
+    /// part of the standard) bounds the whole transfer, 30s by default. Use
+    /// `client.torFetch.bind(client)` where a `fetch` function is expected.
+    #[wasm_bindgen(js_name = torFetch)]
+    pub fn tor_fetch(&self, input: JsValue, init: JsValue) -> js_sys::Promise {
+        let client = match &self.inner {
+            Some(client) => client.clone(),
+            None => {
+                return future_to_promise(async move {
+                    Err(JsTorError::not_initialized().into_js_value())
+                });
+            }
+        };
+
+        future_to_promise(async move {
+            let fetch = FetchRequest::new(&input, &init).await?;
+            console_log!(format!(
+                "Starting torFetch {} {}",
+                fetch.request.method, fetch.request.url
+            ));
+            let abort = Some(abort_promise(&fetch.signal));
+            let method = fetch.request.method.clone();
+            let redirect = fetch.redirect;
+
+            let response = until_aborted(abort.as_ref(), async {
+                let body = match fetch.body {
+                    JsBody::Bytes(bytes) => bytes.map(RequestBody::from),
+                    JsBody::Stream(body) => Some(body),
+                };
+                client
+                    .send_streaming(fetch.request, body)
+                    .await
+                    .map_err(fetch_error)
+            })
+            .await?;
+
+            if redirect == web_sys::RequestRedirect::Error
+                && is_redirect(response.status)
+                && response.headers.contains_key(header::LOCATION)
+            {
+                return Err(js_sys::TypeError::new(&format!(
+                    "Redirect from {} refused by redirect: \"error\"",
+                    response.url
+                ))
+                .into());
+            }
+            Ok(fetch_response(response, &method, abort)?.into())
+        })
+    }
+
     /// Make a one-time fetch request (static method)
     #[wasm_bindgen(js_name = fetchOneTime)]
     pub fn fetch_one_time(
@@ -414,9 +678,11 @@ impl TorClient {
 
                     let js_response = JsHttpResponse {
                         status: response.status,
-                        headers: headers_to_js(&response.headers),
+                        headers: response.headers,
                         body: response.body,
                         url: response.url.to_string(),
+                        redirects: response.redirects.iter().map(Url::to_string).collect(),
+                        decompressed: response.decompressed,
                     };
 
                     Ok(JsValue::from(js_response))
@@ -429,6 +695,90 @@ impl TorClient {
         })
     }
 
+    /// Register a client authorization key for an onion service
+    #[wasm_bindgen(js_name = addOnionClientAuth)]
+    pub fn add_onion_client_auth(
+        &self,
+        onion_address: String,
+        secret_key: String,
+    ) -> js_sys::Promise {
+        let client = match &self.inner {
+            Some(client) => client.clone(),
+            None => {
+                return future_to_promise(async move {
+                    Err(JsTorError::not_initialized().into_js_value())
+                });
+            }
+        };
+
+        future_to_promise(async move {
+            match client
+                .add_onion_client_auth(&onion_address, &secret_key)
+                .await
+            {
+                Ok(()) => Ok(JsValue::UNDEFINED),
+                Err(e) => {
+                    console_error!(format!("Failed to add onion client auth: {}", e));
+                    Err(tor_error_to_js(e))
+                }
+            }
+        })
+    }
+
+    /// Forget the client authorization key for an onion service
+    #[wasm_bindgen(js_name = removeOnionClientAuth)]
+    pub fn remove_onion_client_auth(&self, onion_address: String) -> js_sys::Promise {
+        let client = match &self.inner {
+            Some(client) => client.clone(),
+            None => {
+                return future_to_promise(async move {
+                    Err(JsTorError::not_initialized().into_js_value())
+                });
+            }
+        };
+
+        future_to_promise(async move {
+            match client.remove_onion_client_auth(&onion_address).await {
+                Ok(()) => Ok(JsValue::UNDEFINED),
+                Err(e) => {
+                    console_error!(format!("Failed to remove onion client auth: {}", e));
+                    Err(tor_error_to_js(e))
+                }
+            }
+        })
+    }
+
+    /// Host an onion service from this tab
+    ///
+    /// Resolves to an `OnionService` once its descriptor is published.
+    #[wasm_bindgen(js_name = launchOnionService)]
+    pub fn launch_onion_service(&self, options: &OnionServiceOptions) -> js_sys::Promise {
+        let client = match &self.inner {
+            Some(client) => client.clone(),
+            None => {
+                return future_to_promise(async move {
+                    Err(JsTorError::not_initialized().into_js_value())
+                });
+            }
+        };
+        let config = options.inner.clone();
+
+        future_to_promise(async move {
+            match client.launch_onion_service(config).await {
+                Ok(handle) => {
+                    console_log!(format!("Onion service {} is up", handle.onion_address()));
+                    Ok(JsValue::from(OnionService {
+                        inner: Rc::new(handle),
+                    }))
+                }
+                Err(e) => {
+                    console_error!(format!("Failed to launch onion service: {}", e));
+                    Err(tor_error_to_js(e))
+                }
+            }
+        })
+    }
+
     /// Update the circuit
     #[wasm_bindgen(js_name = updateCircuit)]
     pub fn update_circuit(&self, deadline_ms: u32) -> js_sys::Promise {
@@ -608,15 +958,53 @@ impl TorClient {
             true
         }
     }
+
+    /// Cookies stored for the isolation partition `url` belongs to
+    #[wasm_bindgen(js_name = exportCookies)]
+    pub fn export_cookies(&self, url: String) -> Result<JsValue, JsValue> {
+        let client = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| JsTorError::not_initialized().into_js_value())?;
+        let cookies = client.export_cookies(&url).map_err(tor_error_to_js)?;
+        serde_wasm_bindgen::to_value(&cookies)
+            .map_err(|e| JsValue::from_str(&format!("Cookie serialization failed: {}", e)))
+    }
+
+    /// Add cookies, as returned by `exportCookies`, to the partition for `url`
+    #[wasm_bindgen(js_name = importCookies)]
+    pub fn import_cookies(&self, url: String, cookies: JsValue) -> Result<(), JsValue> {
+        let client = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| JsTorError::not_initialized().into_js_value())?;
+        let cookies = serde_wasm_bindgen::from_value(cookies)
+            .map_err(|e| JsValue::from_str(&format!("Invalid cookies: {}", e)))?;
+        client
+            .import_cookies(&url, cookies)
+            .map_err(tor_error_to_js)
+    }
+
+    /// Drop the cookies in the partition for `url`
+    #[wasm_bindgen(js_name = clearCookies)]
+    pub fn clear_cookies(&self, url: String) -> Result<(), JsValue> {
+        let client = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| JsTorError::not_initialized().into_js_value())?;
+        client.clear_cookies(&url).map_err(tor_error_to_js)
+    }
 }
 
 /// JavaScript-friendly HTTP response
 #[wasm_bindgen]
 pub struct JsHttpResponse {
     status: u16,
-    headers: JsValue,
+    headers: HeaderMap,
     body: Vec<u8>,
     url: String,
+    redirects: Vec<String>,
+    decompressed: bool,
 }
 
 #[wasm_bindgen]
@@ -626,9 +1014,16 @@ impl JsHttpResponse {
         self.status
     }
 
+    /// Headers as `[name, value]` pairs in the order received
     #[wasm_bindgen(getter)]
     pub fn headers(&self) -> JsValue {
-        self.headers.clone()
+        headers_to_js(&self.headers)
+    }
+
+    /// Value of a header, case-insensitively; repeated headers are joined with ", "
+    #[wasm_bindgen(js_name = header)]
+    pub fn header(&self, name: &str) -> Option<String> {
+        header_to_js(&self.headers, name)
     }
 
     #[wasm_bindgen(getter)]
@@ -641,6 +1036,18 @@ impl JsHttpResponse {
         self.url.clone()
     }
 
+    /// URLs that answered with a redirect, in the order they were followed
+    #[wasm_bindgen(getter)]
+    pub fn redirects(&self) -> Vec<String> {
+        self.redirects.clone()
+    }
+
+    /// Whether the body was decoded from a gzip, deflate or brotli `Content-Encoding`
+    #[wasm_bindgen(getter)]
+    pub fn decompressed(&self) -> bool {
+        self.decompressed
+    }
+
     #[wasm_bindgen(js_name = text)]
     pub fn text(&self) -> Result<String, JsValue> {
         String::from_utf8(self.body.clone())
@@ -659,6 +1066,1054 @@ impl JsHttpResponse {
     }
 }
 
+/// JavaScript-friendly HTTP response with a streaming body
+#[wasm_bindgen]
+pub struct JsStreamingResponse {
+    status: u16,
+    headers: HeaderMap,
+    url: String,
+    redirects: Vec<String>,
+    decompressed: bool,
+    body: web_sys::ReadableStream,
+}
+
+#[wasm_bindgen]
+impl JsStreamingResponse {
+    #[wasm_bindgen(getter)]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Headers as `[name, value]` pairs in the order received
+    #[wasm_bindgen(getter)]
+    pub fn headers(&self) -> JsValue {
+        headers_to_js(&self.headers)
+    }
+
+    /// Value of a header, case-insensitively; repeated headers are joined with ", "
+    #[wasm_bindgen(js_name = header)]
+    pub fn header(&self, name: &str) -> Option<String> {
+        header_to_js(&self.headers, name)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// URLs that answered with a redirect, in the order they were followed
+    #[wasm_bindgen(getter)]
+    pub fn redirects(&self) -> Vec<String> {
+        self.redirects.clone()
+    }
+
+    /// Whether the body is decoded from a gzip, deflate or brotli `Content-Encoding`
+    #[wasm_bindgen(getter)]
+    pub fn decompressed(&self) -> bool {
+        self.decompressed
+    }
+
+    /// Body as a `ReadableStream` of `Uint8Array` chunks
+    #[wasm_bindgen(getter)]
+    pub fn body(&self) -> web_sys::ReadableStream {
+        self.body.clone()
+    }
+}
+
+/// Request body passed from JavaScript
+enum JsBody {
+    Bytes(Option<Vec<u8>>),
+    Stream(RequestBody),
+}
+
+/// Convert a JS request body: bytes stay in memory, `Blob`s and
+/// `ReadableStream`s are streamed
+fn js_body(body: JsValue) -> Result<JsBody, JsValue> {
+    if body.is_undefined() || body.is_null() {
+        return Ok(JsBody::Bytes(None));
+    }
+    if let Some(blob) = body.dyn_ref::<web_sys::Blob>() {
+        let length = blob.size() as u64;
+        return Ok(JsBody::Stream(readable_stream_to_body(
+            blob.stream(),
+            Some(length),
+        )));
+    }
+    if let Some(stream) = body.dyn_ref::<web_sys::ReadableStream>() {
+        return Ok(JsBody::Stream(readable_stream_to_body(
+            stream.clone(),
+            None,
+        )));
+    }
+    if let Some(text) = body.as_string() {
+        return Ok(JsBody::Bytes(Some(text.into_bytes())));
+    }
+    if let Some(bytes) = body.dyn_ref::<js_sys::Uint8Array>() {
+        return Ok(JsBody::Bytes(Some(bytes.to_vec())));
+    }
+    if body.is_instance_of::<js_sys::ArrayBuffer>() {
+        return Ok(JsBody::Bytes(Some(js_sys::Uint8Array::new(&body).to_vec())));
+    }
+    serde_wasm_bindgen::from_value(body)
+        .map(|bytes| JsBody::Bytes(Some(bytes)))
+        .map_err(|e| JsValue::from_str(&format!("Unsupported request body: {}", e)))
+}
+
+/// A JS `ReadableStream` of `Uint8Array` chunks, readable from Rust
+struct JsByteStream(Pin<Box<dyn Stream<Item = std::io::Result<Vec<u8>>>>>);
+
+// WASM is single-threaded
+unsafe impl Send for JsByteStream {}
+
+impl Stream for JsByteStream {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Upload a `ReadableStream` as a request body
+fn readable_stream_to_body(stream: web_sys::ReadableStream, length: Option<u64>) -> RequestBody {
+    let reader = stream
+        .get_reader()
+        .unchecked_into::<web_sys::ReadableStreamDefaultReader>();
+    let chunks = futures::stream::unfold(reader, |reader| async move {
+        let result = match JsFuture::from(reader.read()).await {
+            Ok(result) => result,
+            Err(e) => {
+                let message = e.as_string().unwrap_or_else(|| format!("{:?}", e));
+                return Some((Err(std::io::Error::other(message)), reader));
+            }
+        };
+        let done = js_sys::Reflect::get(&result, &"done".into())
+            .map(|done| done.is_truthy())
+            .unwrap_or(true);
+        if done {
+            return None;
+        }
+        let chunk = js_sys::Reflect::get(&result, &"value".into())
+            .ok()
+            .and_then(|value| value.dyn_into::<js_sys::Uint8Array>().ok())
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "ReadableStream chunks must be Uint8Arrays",
+                )
+            });
+        Some((chunk, reader))
+    });
+    RequestBody::from_stream(JsByteStream(Box::pin(chunks)), length)
+}
+
+/// Wrap a response body in a pull-based `ReadableStream`
+///
+/// Once `abort` settles, reads fail with its value and the body is dropped.
+fn body_to_readable_stream(
+    body: ResponseBody,
+    abort: Option<js_sys::Promise>,
+) -> Result<web_sys::ReadableStream, JsValue> {
+    let body = Rc::new(RefCell::new(Some(body)));
+    let abort_reason: Rc<RefCell<Option<JsValue>>> = Rc::new(RefCell::new(None));
+
+    // Close the connection on abort, even if nothing is reading; a read in
+    // progress holds the body and drops it itself
+    if let Some(abort) = &abort {
+        let aborted = JsFuture::from(abort.clone());
+        let body = body.clone();
+        let abort_reason = abort_reason.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let reason = aborted.await.unwrap_or_else(|e| e);
+            *abort_reason.borrow_mut() = Some(reason);
+            body.borrow_mut().take();
+        });
+    }
+
+    let pull_body = body.clone();
+    let pull = Closure::wrap(Box::new(
+        move |controller: web_sys::ReadableStreamDefaultController| {
+            let body = pull_body.clone();
+            let abort = abort.clone();
+            let abort_reason = abort_reason.clone();
+            future_to_promise(async move {
+                // The stream waits for each pull to settle before the next,
+                // so the body can be taken out across the await
+                let Some(mut inner) = body.borrow_mut().take() else {
+                    match abort_reason.borrow().as_ref() {
+                        Some(reason) => controller.error_with_e(reason),
+                        None => controller.close()?,
+                    }
+                    return Ok(JsValue::UNDEFINED);
+                };
+                let chunk = until_aborted(abort.as_ref(), async {
+                    inner.chunk().await.map_err(tor_error_to_js)
+                })
+                .await;
+                match chunk {
+                    Ok(Some(chunk)) => {
+                        controller.enqueue_with_chunk(&js_sys::Uint8Array::from(&chunk[..]))?;
+                        *body.borrow_mut() = Some(inner);
+                    }
+                    Ok(None) => controller.close()?,
+                    Err(e) => controller.error_with_e(&e),
+                }
+                Ok(JsValue::UNDEFINED)
+            })
+        },
+    )
+        as Box<dyn FnMut(web_sys::ReadableStreamDefaultController) -> js_sys::Promise>);
+
+    // Cancelling drops the body, closing its connection
+    let cancel = Closure::wrap(Box::new(move |_reason: JsValue| {
+        body.borrow_mut().take();
+    }) as Box<dyn FnMut(JsValue)>);
+
+    let source = js_sys::Object::new();
+    js_sys::Reflect::set(&source, &"pull".into(), &pull.into_js_value())?;
+    js_sys::Reflect::set(&source, &"cancel".into(), &cancel.into_js_value())?;
+
+    web_sys::ReadableStream::new_with_underlying_source(&source)
+}
+
+/// A `fetch()` call's arguments, read into a native request
+struct FetchRequest {
+    request: HttpRequest,
+    body: JsBody,
+    signal: web_sys::AbortSignal,
+    redirect: web_sys::RequestRedirect,
+}
+
+impl FetchRequest {
+    /// Let the platform's `Request` constructor merge `input` and `init`,
+    /// so headers and bodies (`FormData`, `URLSearchParams`, ...) get the
+    /// same treatment, `Content-Type` included, as with `fetch`
+    async fn new(input: &JsValue, init: &JsValue) -> Result<Self, JsValue> {
+        let has_init = !init.is_undefined() && !init.is_null();
+        let init_field = |name: &str| {
+            has_init
+                .then(|| js_sys::Reflect::get(init, &name.into()).ok())
+                .flatten()
+                .filter(|value| !value.is_undefined())
+        };
+
+        // A `ReadableStream` body is uploaded as it is read; the rest are
+        // small enough to buffer, as browsers do
+        let stream_body =
+            init_field("body").and_then(|body| body.dyn_into::<web_sys::ReadableStream>().ok());
+        let init = if stream_body.is_some() {
+            let copy = js_sys::Object::assign(&js_sys::Object::new(), init.unchecked_ref());
+            js_sys::Reflect::delete_property(&copy, &"body".into())?;
+            copy.into()
+        } else {
+            init.clone()
+        };
+        let init = init.unchecked_ref::<web_sys::RequestInit>();
+
+        let request = match input.dyn_ref::<web_sys::Request>() {
+            Some(request) if has_init => web_sys::Request::new_with_request_and_init(request, init),
+            Some(request) => web_sys::Request::new_with_request(request),
+            None => {
+                let url = input
+                    .as_string()
+                    .unwrap_or_else(|| input.unchecked_ref::<js_sys::Object>().to_string().into());
+                if has_init {
+                    web_sys::Request::new_with_str_and_init(&url, init)
+                } else {
+                    web_sys::Request::new_with_str(&url)
+                }
+            }
+        }?;
+
+        let url = Url::parse(&request.url())
+            .map_err(|e| js_sys::TypeError::new(&format!("Invalid URL: {}", e)))?;
+        let method: http::Method = request
+            .method()
+            .parse()
+            .map_err(|e| js_sys::TypeError::new(&format!("Invalid HTTP method: {}", e)))?;
+        let mut native = HttpRequest::new(url);
+        native.method = method;
+        native.headers = js_headers(request.headers().into())?;
+        if let Some(timeout) = init_field("timeout").and_then(|timeout| timeout.as_f64()) {
+            native.timeout = Duration::from_millis(timeout as u64);
+        }
+        let redirect = request.redirect();
+        if redirect != web_sys::RequestRedirect::Follow {
+            native.redirect = RedirectPolicy::none();
+        }
+
+        let body = match stream_body {
+            Some(stream) => JsBody::Stream(readable_stream_to_body(stream, None)),
+            None if request.body().is_some() => {
+                let buffer = JsFuture::from(request.array_buffer()?).await?;
+                JsBody::Bytes(Some(js_sys::Uint8Array::new(&buffer).to_vec()))
+            }
+            None => JsBody::Bytes(None),
+        };
+        Ok(Self {
+            request: native,
+            body,
+            signal: request.signal(),
+            redirect,
+        })
+    }
+}
+
+/// Statuses `fetch` treats as redirects
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Reject like `fetch` does on network errors: a `TypeError`, with the
+/// `JsTorError` as its `cause`
+fn fetch_error(e: TorError) -> JsValue {
+    let error = js_sys::TypeError::new(&format!("torFetch failed: {}", e));
+    let _ = js_sys::Reflect::set(&error, &"cause".into(), &tor_error_to_js(e));
+    error.into()
+}
+
+/// Build a standard `Response` around a streaming response
+fn fetch_response(
+    response: StreamingResponse,
+    method: &http::Method,
+    abort: Option<js_sys::Promise>,
+) -> Result<web_sys::Response, JsValue> {
+    // The Response constructor only takes final statuses
+    if !(200..=599).contains(&response.status) {
+        return Err(js_sys::TypeError::new(&format!(
+            "torFetch can't represent HTTP {}",
+            response.status
+        ))
+        .into());
+    }
+
+    let headers = web_sys::Headers::new()?;
+    for (name, value) in &response.headers {
+        headers.append(name.as_str(), &String::from_utf8_lossy(value.as_bytes()))?;
+    }
+    let init = web_sys::ResponseInit::new();
+    init.set_status(response.status);
+    init.set_status_text(
+        http::StatusCode::from_u16(response.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default(),
+    );
+    init.set_headers(&headers);
+
+    let url = response.url.to_string();
+    let redirected = !response.redirects.is_empty();
+    // These must not have a body, even an empty one
+    let null_body = *method == http::Method::HEAD || matches!(response.status, 204 | 205 | 304);
+    let body = if null_body {
+        None
+    } else {
+        Some(body_to_readable_stream(response.into_body(), abort)?)
+    };
+    let js_response =
+        web_sys::Response::new_with_opt_readable_stream_and_init(body.as_ref(), &init)?;
+
+    // Constructed responses have an empty `url` and `redirected: false`
+    for (name, value) in [
+        ("url", JsValue::from_str(&url)),
+        ("redirected", redirected.into()),
+    ] {
+        let descriptor = js_sys::Object::new();
+        js_sys::Reflect::set(&descriptor, &"value".into(), &value)?;
+        js_sys::Object::define_property(&js_response, &name.into(), &descriptor);
+    }
+    Ok(js_response)
+}
+
+/// Settles when `signal` aborts, resolving to its reason
+fn abort_promise(signal: &web_sys::AbortSignal) -> js_sys::Promise {
+    let signal = signal.clone();
+    js_sys::Promise::new(&mut |resolve, _reject| {
+        if signal.aborted() {
+            let _ = resolve.call1(&JsValue::NULL, &signal.reason());
+            return;
+        }
+        let aborted = signal.clone();
+        let on_abort = Closure::once_into_js(move || {
+            let _ = resolve.call1(&JsValue::NULL, &aborted.reason());
+        });
+        let _ = signal.add_event_listener_with_callback("abort", on_abort.unchecked_ref());
+    })
+}
+
+/// Run `future` unless `abort` settles first, failing with its value
+///
+/// Dropping the future closes the connection it was using.
+async fn until_aborted<T>(
+    abort: Option<&js_sys::Promise>,
+    future: impl std::future::Future<Output = Result<T, JsValue>>,
+) -> Result<T, JsValue> {
+    let Some(abort) = abort else {
+        return future.await;
+    };
+    let aborted = JsFuture::from(abort.clone());
+    futures::pin_mut!(future);
+    match futures::future::select(future, aborted).await {
+        futures::future::Either::Left((result, _)) => result,
+        futures::future::Either::Right((reason, _)) => Err(reason.unwrap_or_else(|e| e)),
+    }
+}
+
+/// `readyState` values, as on the browser `WebSocket`
+const WS_CONNECTING: u16 = 0;
+const WS_OPEN: u16 = 1;
+const WS_CLOSING: u16 = 2;
+const WS_CLOSED: u16 = 3;
+
+/// Work for the task that writes to the socket, in the order `send` and
+/// `close` were called
+enum Outgoing {
+    Message(WebSocketMessage),
+    /// A `Blob` is read only when its turn comes, like the browser does
+    Blob(web_sys::Blob),
+    Close(u16, String),
+}
+
+struct WebSocketState {
+    ready_state: u16,
+    protocol: String,
+    buffered_amount: u32,
+    binary_type: String,
+    outgoing: futures::channel::mpsc::UnboundedSender<Outgoing>,
+    onopen: Option<js_sys::Function>,
+    onmessage: Option<js_sys::Function>,
+    onerror: Option<js_sys::Function>,
+    onclose: Option<js_sys::Function>,
+}
+
+/// A WebSocket through Tor, used like the browser's `WebSocket`
+///
+/// `new TorWebSocket(client, url, protocols)` connects in the background and
+/// reports progress with `open`, `message`, `error` and `close` events, via
+/// `on*` handlers or `addEventListener`.
+#[wasm_bindgen]
+pub struct TorWebSocket {
+    url: String,
+    state: Rc<RefCell<WebSocketState>>,
+    target: web_sys::EventTarget,
+}
+
+#[wasm_bindgen]
+impl TorWebSocket {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        client: &TorClient,
+        url: String,
+        protocols: JsValue,
+    ) -> Result<TorWebSocket, JsValue> {
+        let client = client
+            .inner
+            .clone()
+            .ok_or_else(|| JsTorError::not_initialized().into_js_value())?;
+        let protocols: Vec<String> = if protocols.is_undefined() || protocols.is_null() {
+            Vec::new()
+        } else if let Some(protocol) = protocols.as_string() {
+            vec![protocol]
+        } else {
+            serde_wasm_bindgen::from_value(protocols)
+                .map_err(|e| dom_exception(&format!("Invalid protocols: {}", e), "SyntaxError"))?
+        };
+        let parsed = Url::parse(&url).map_err(|e| dom_exception(&e.to_string(), "SyntaxError"))?;
+        if !matches!(parsed.scheme(), "ws" | "wss") || parsed.fragment().is_some() {
+            return Err(dom_exception(
+                &format!("Invalid WebSocket URL {}", url),
+                "SyntaxError",
+            ));
+        }
+        console_log!(format!("Opening WebSocket to {}", url));
+
+        let (outgoing, queue) = futures::channel::mpsc::unbounded();
+        let state = Rc::new(RefCell::new(WebSocketState {
+            ready_state: WS_CONNECTING,
+            protocol: String::new(),
+            buffered_amount: 0,
+            binary_type: "blob".to_string(),
+            outgoing,
+            onopen: None,
+            onmessage: None,
+            onerror: None,
+            onclose: None,
+        }));
+        let target = web_sys::EventTarget::new()?;
+
+        let options = WebSocketOptions {
+            protocols,
+            ..Default::default()
+        };
+        let url = parsed.to_string();
+        wasm_bindgen_futures::spawn_local(run_websocket(
+            client,
+            parsed,
+            options,
+            queue,
+            state.clone(),
+            target.clone(),
+        ));
+
+        Ok(TorWebSocket { url, state, target })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// 0 (connecting), 1 (open), 2 (closing) or 3 (closed)
+    #[wasm_bindgen(getter, js_name = readyState)]
+    pub fn ready_state(&self) -> u16 {
+        self.state.borrow().ready_state
+    }
+
+    /// Subprotocol the server picked, or ""
+    #[wasm_bindgen(getter)]
+    pub fn protocol(&self) -> String {
+        self.state.borrow().protocol.clone()
+    }
+
+    /// Bytes passed to `send` that haven't been written yet
+    #[wasm_bindgen(getter, js_name = bufferedAmount)]
+    pub fn buffered_amount(&self) -> u32 {
+        self.state.borrow().buffered_amount
+    }
+
+    /// Binary messages arrive as "blob" (the default) or "arraybuffer"
+    #[wasm_bindgen(getter, js_name = binaryType)]
+    pub fn binary_type(&self) -> String {
+        self.state.borrow().binary_type.clone()
+    }
+
+    #[wasm_bindgen(setter, js_name = binaryType)]
+    pub fn set_binary_type(&self, binary_type: String) {
+        // Browsers ignore other values too
+        if binary_type == "blob" || binary_type == "arraybuffer" {
+            self.state.borrow_mut().binary_type = binary_type;
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn onopen(&self) -> Option<js_sys::Function> {
+        self.state.borrow().onopen.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onopen(&self, handler: Option<js_sys::Function>) {
+        self.state.borrow_mut().onopen = handler;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn onmessage(&self) -> Option<js_sys::Function> {
+        self.state.borrow().onmessage.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onmessage(&self, handler: Option<js_sys::Function>) {
+        self.state.borrow_mut().onmessage = handler;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn onerror(&self) -> Option<js_sys::Function> {
+        self.state.borrow().onerror.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onerror(&self, handler: Option<js_sys::Function>) {
+        self.state.borrow_mut().onerror = handler;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn onclose(&self) -> Option<js_sys::Function> {
+        self.state.borrow().onclose.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onclose(&self, handler: Option<js_sys::Function>) {
+        self.state.borrow_mut().onclose = handler;
+    }
+
+    #[wasm_bindgen(js_name = addEventListener)]
+    pub fn add_event_listener(
+        &self,
+        kind: &str,
+        listener: &js_sys::Function,
+    ) -> Result<(), JsValue> {
+        self.target.add_event_listener_with_callback(kind, listener)
+    }
+
+    #[wasm_bindgen(js_name = removeEventListener)]
+    pub fn remove_event_listener(
+        &self,
+        kind: &str,
+        listener: &js_sys::Function,
+    ) -> Result<(), JsValue> {
+        self.target
+            .remove_event_listener_with_callback(kind, listener)
+    }
+
+    /// Queue a string, `ArrayBuffer`, typed array, `DataView` or `Blob`
+    ///
+    /// Throws `InvalidStateError` while connecting; data sent after `close`
+    /// is dropped.
+    pub fn send(&self, data: JsValue) -> Result<(), JsValue> {
+        let mut state = self.state.borrow_mut();
+        if state.ready_state == WS_CONNECTING {
+            return Err(dom_exception(
+                "Still in CONNECTING state",
+                "InvalidStateError",
+            ));
+        }
+
+        let (outgoing, length) = if let Some(text) = data.as_string() {
+            let length = text.len();
+            (Outgoing::Message(WebSocketMessage::Text(text)), length)
+        } else if let Some(blob) = data.dyn_ref::<web_sys::Blob>() {
+            (Outgoing::Blob(blob.clone()), blob.size() as usize)
+        } else if data.is_instance_of::<js_sys::ArrayBuffer>() {
+            let bytes = js_sys::Uint8Array::new(&data).to_vec();
+            let length = bytes.len();
+            (Outgoing::Message(WebSocketMessage::Binary(bytes)), length)
+        } else if js_sys::ArrayBuffer::is_view(&data) {
+            let view = data.unchecked_ref::<js_sys::Uint8Array>();
+            let bytes = js_sys::Uint8Array::new_with_byte_offset_and_length(
+                &view.buffer(),
+                view.byte_offset(),
+                view.byte_length(),
+            )
+            .to_vec();
+            let length = bytes.len();
+            (Outgoing::Message(WebSocketMessage::Binary(bytes)), length)
+        } else {
+            return Err(dom_exception(
+                "Data must be a string, ArrayBuffer, ArrayBufferView or Blob",
+                "TypeError",
+            ));
+        };
+
+        if state.ready_state == WS_OPEN {
+            state.buffered_amount = state.buffered_amount.saturating_add(length as u32);
+            let _ = state.outgoing.unbounded_send(outgoing);
+        }
+        Ok(())
+    }
+
+    /// Start the closing handshake
+    ///
+    /// `code` must be 1000 or in 3000-4999 and `reason` at most 123 bytes.
+    /// Closing while connecting closes as soon as the socket opens.
+    pub fn close(&self, code: Option<u16>, reason: Option<String>) -> Result<(), JsValue> {
+        let code = code.unwrap_or(CLOSE_NORMAL);
+        let reason = reason.unwrap_or_default();
+        if code != CLOSE_NORMAL && !(3000..=4999).contains(&code) {
+            return Err(dom_exception(
+                &format!("Invalid close code {}", code),
+                "InvalidAccessError",
+            ));
+        }
+        if reason.len() > 123 {
+            return Err(dom_exception(
+                "Close reason is longer than 123 bytes",
+                "SyntaxError",
+            ));
+        }
+
+        let mut state = self.state.borrow_mut();
+        if state.ready_state >= WS_CLOSING {
+            return Ok(());
+        }
+        state.ready_state = WS_CLOSING;
+        let _ = state.outgoing.unbounded_send(Outgoing::Close(code, reason));
+        Ok(())
+    }
+}
+
+/// A `DOMException`, which is what `WebSocket` throws
+fn dom_exception(message: &str, name: &str) -> JsValue {
+    web_sys::DomException::new_with_message_and_name(message, name)
+        .map(JsValue::from)
+        .unwrap_or_else(|_| JsValue::from_str(message))
+}
+
+/// Call the `on*` handler for `event`, then the listeners
+fn dispatch(
+    state: &Rc<RefCell<WebSocketState>>,
+    target: &web_sys::EventTarget,
+    event: &web_sys::Event,
+) {
+    let handler = {
+        let state = state.borrow();
+        match event.type_().as_str() {
+            "open" => state.onopen.clone(),
+            "message" => state.onmessage.clone(),
+            "error" => state.onerror.clone(),
+            _ => state.onclose.clone(),
+        }
+    };
+    if let Some(handler) = handler {
+        if let Err(e) = handler.call1(&JsValue::NULL, event) {
+            console_error!(format!("WebSocket event handler threw: {:?}", e));
+        }
+    }
+    let _ = target.dispatch_event(event);
+}
+
+fn dispatch_simple(state: &Rc<RefCell<WebSocketState>>, target: &web_sys::EventTarget, kind: &str) {
+    if let Ok(event) = web_sys::Event::new(kind) {
+        dispatch(state, target, &event);
+    }
+}
+
+fn dispatch_close(
+    state: &Rc<RefCell<WebSocketState>>,
+    target: &web_sys::EventTarget,
+    code: u16,
+    reason: &str,
+    was_clean: bool,
+) {
+    let init = web_sys::CloseEventInit::new();
+    init.set_code(code);
+    init.set_reason(reason);
+    init.set_was_clean(was_clean);
+    if let Ok(event) = web_sys::CloseEvent::new_with_event_init_dict("close", &init) {
+        dispatch(state, target, &event);
+    }
+}
+
+/// Connect, then pump messages until the socket closes
+async fn run_websocket(
+    client: Arc<NativeTorClient>,
+    url: Url,
+    options: WebSocketOptions,
+    queue: futures::channel::mpsc::UnboundedReceiver<Outgoing>,
+    state: Rc<RefCell<WebSocketState>>,
+    target: web_sys::EventTarget,
+) {
+    let socket = match client.websocket(url.as_str(), options).await {
+        Ok(socket) => Rc::new(socket),
+        Err(e) => {
+            console_error!(format!("WebSocket to {} failed: {}", url, e));
+            state.borrow_mut().ready_state = WS_CLOSED;
+            dispatch_simple(&state, &target, "error");
+            dispatch_close(&state, &target, CLOSE_ABNORMAL, "", false);
+            return;
+        }
+    };
+
+    let opened = {
+        let mut state = state.borrow_mut();
+        state.protocol = socket.protocol().unwrap_or_default().to_string();
+        // Unless `close` was called while connecting
+        let opened = state.ready_state == WS_CONNECTING;
+        if opened {
+            state.ready_state = WS_OPEN;
+        }
+        opened
+    };
+    if opened {
+        dispatch_simple(&state, &target, "open");
+    }
+
+    wasm_bindgen_futures::spawn_local(write_websocket(socket.clone(), queue, state.clone()));
+
+    let mut clean = true;
+    loop {
+        match socket.recv().await {
+            Ok(Some(message)) => {
+                let data = match message {
+                    WebSocketMessage::Text(text) => JsValue::from_str(&text),
+                    WebSocketMessage::Binary(bytes) => {
+                        let bytes = js_sys::Uint8Array::from(&bytes[..]);
+                        if state.borrow().binary_type == "arraybuffer" {
+                            bytes.buffer().into()
+                        } else {
+                            match web_sys::Blob::new_with_u8_array_sequence(&js_sys::Array::of1(
+                                &bytes,
+                            )) {
+                                Ok(blob) => blob.into(),
+                                Err(_) => bytes.buffer().into(),
+                            }
+                        }
+                    }
+                };
+                let init = web_sys::MessageEventInit::new();
+                init.set_data(&data);
+                init.set_origin(&url.origin().ascii_serialization());
+                if let Ok(event) = web_sys::MessageEvent::new_with_event_init_dict("message", &init)
+                {
+                    dispatch(&state, &target, &event);
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                console_error!(format!("WebSocket to {} failed: {}", url, e));
+                clean = false;
+                dispatch_simple(&state, &target, "error");
+                break;
+            }
+        }
+    }
+
+    let close = socket.close_frame();
+    let (code, reason) = close
+        .map(|close| (close.code, close.reason))
+        .unwrap_or((CLOSE_ABNORMAL, String::new()));
+    {
+        let mut state = state.borrow_mut();
+        state.ready_state = WS_CLOSED;
+        // Ends the writer task
+        state.outgoing.close_channel();
+    }
+    dispatch_close(
+        &state,
+        &target,
+        code,
+        &reason,
+        clean && code != CLOSE_ABNORMAL,
+    );
+}
+
+/// Write queued messages in order
+async fn write_websocket(
+    socket: Rc<NativeTorWebSocket>,
+    mut queue: futures::channel::mpsc::UnboundedReceiver<Outgoing>,
+    state: Rc<RefCell<WebSocketState>>,
+) {
+    use futures::StreamExt;
+
+    while let Some(outgoing) = queue.next().await {
+        let (result, length) = match outgoing {
+            Outgoing::Message(message) => {
+                let length = match &message {
+                    WebSocketMessage::Text(text) => text.len(),
+                    WebSocketMessage::Binary(bytes) => bytes.len(),
+                };
+                (socket.send(message).await, length)
+            }
+            Outgoing::Blob(blob) => {
+                let length = blob.size() as usize;
+                match JsFuture::from(blob.array_buffer()).await {
+                    Ok(buffer) => {
+                        let bytes = js_sys::Uint8Array::new(&buffer).to_vec();
+                        (socket.send(WebSocketMessage::Binary(bytes)).await, length)
+                    }
+                    Err(e) => {
+                        console_warn!(format!("Skipping a Blob that can't be read: {:?}", e));
+                        (Ok(()), length)
+                    }
+                }
+            }
+            Outgoing::Close(code, reason) => (socket.close(code, &reason).await, 0),
+        };
+        {
+            let mut state = state.borrow_mut();
+            state.buffered_amount = state.buffered_amount.saturating_sub(length as u32);
+        }
+        if let Err(e) = result {
+            console_warn!(format!("WebSocket write failed: {}", e));
+        }
+    }
+}
+
+/// Options for `TorClient.launchOnionService()`
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct OnionServiceOptions {
+    inner: OnionServiceConfig,
+}
+
+#[wasm_bindgen]
+impl OnionServiceOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reuse the identity from an earlier `OnionService.identityKey`,
+    /// keeping the same address
+    #[wasm_bindgen(js_name = setIdentityKey)]
+    pub fn set_identity_key(&mut self, identity_key: String) {
+        self.inner.identity_key = Some(identity_key);
+    }
+
+    /// Number of introduction points to maintain (default: 3)
+    #[wasm_bindgen(js_name = setIntroPoints)]
+    pub fn set_intro_points(&mut self, count: usize) {
+        self.inner.num_intro_points = count;
+    }
+
+    /// Accept streams on `port`; with no ports added, every port is accepted
+    #[wasm_bindgen(js_name = addPort)]
+    pub fn add_port(&mut self, port: u16) {
+        self.inner.ports.push(port);
+    }
+}
+
+/// An onion service hosted from this tab
+///
+/// Stops when `shutdown()` is called or the client is closed, and when the
+/// object is freed with no `accept()` pending.
+#[wasm_bindgen]
+pub struct OnionService {
+    inner: Rc<OnionServiceHandle>,
+}
+
+#[wasm_bindgen]
+impl OnionService {
+    /// The service's `.onion` hostname
+    #[wasm_bindgen(getter, js_name = onionAddress)]
+    pub fn onion_address(&self) -> String {
+        self.inner.onion_address().to_string()
+    }
+
+    /// Hex-encoded identity secret key; anyone holding it can impersonate
+    /// the service
+    #[wasm_bindgen(getter, js_name = identityKey)]
+    pub fn identity_key(&self) -> String {
+        self.inner.identity_key().to_string()
+    }
+
+    #[wasm_bindgen(getter, js_name = isRunning)]
+    pub fn is_running(&self) -> bool {
+        self.inner.is_running()
+    }
+
+    /// Resolves to the next incoming `OnionStream`, or `undefined` once the
+    /// service has stopped
+    #[wasm_bindgen(js_name = accept)]
+    pub fn accept(&self) -> js_sys::Promise {
+        let handle = self.inner.clone();
+        future_to_promise(async move {
+            Ok(match handle.accept().await {
+                Some(incoming) => JsValue::from(OnionStream::new(incoming.port, incoming.stream)?),
+                None => JsValue::UNDEFINED,
+            })
+        })
+    }
+
+    /// Stop the service and close its introduction points
+    #[wasm_bindgen(js_name = shutdown)]
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
+    }
+}
+
+/// A stream opened by a client of an `OnionService`
+///
+/// Incoming bytes arrive on `readable`; `write()` sends and `close()` ends
+/// the stream.
+#[wasm_bindgen]
+pub struct OnionStream {
+    port: u16,
+    readable: web_sys::ReadableStream,
+    writer: Rc<futures::lock::Mutex<futures::io::WriteHalf<DataStream>>>,
+}
+
+impl OnionStream {
+    fn new(port: u16, stream: DataStream) -> Result<Self, JsValue> {
+        let (reader, writer) = futures::AsyncReadExt::split(stream);
+        Ok(Self {
+            port,
+            readable: reader_to_readable_stream(reader)?,
+            writer: Rc::new(futures::lock::Mutex::new(writer)),
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl OnionStream {
+    /// Virtual port the client connected to
+    #[wasm_bindgen(getter)]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `ReadableStream` of `Uint8Array` chunks from the client
+    #[wasm_bindgen(getter)]
+    pub fn readable(&self) -> web_sys::ReadableStream {
+        self.readable.clone()
+    }
+
+    /// Send `data` to the client
+    #[wasm_bindgen(js_name = write)]
+    pub fn write(&self, data: Vec<u8>) -> js_sys::Promise {
+        let writer = self.writer.clone();
+        future_to_promise(async move {
+            let mut writer = writer.lock().await;
+            futures::AsyncWriteExt::write_all(&mut *writer, &data)
+                .await
+                .map_err(|e| io_error_to_js("Failed to write to onion stream", e))?;
+            futures::AsyncWriteExt::flush(&mut *writer)
+                .await
+                .map_err(|e| io_error_to_js("Failed to write to onion stream", e))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Close our side of the stream
+    #[wasm_bindgen(js_name = close)]
+    pub fn close(&self) -> js_sys::Promise {
+        let writer = self.writer.clone();
+        future_to_promise(async move {
+            futures::AsyncWriteExt::close(&mut *writer.lock().await)
+                .await
+                .map_err(|e| io_error_to_js("Failed to close onion stream", e))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+}
+
+/// A stream I/O failure as a `JsTorError`
+fn io_error_to_js(context: &str, e: std::io::Error) -> JsValue {
+    tor_error_to_js(TorError::network(format!("{}: {}", context, e)))
+}
+
+/// Wrap the read half of a stream in a pull-based `ReadableStream`
+fn reader_to_readable_stream(
+    reader: futures::io::ReadHalf<DataStream>,
+) -> Result<web_sys::ReadableStream, JsValue> {
+    let reader = Rc::new(RefCell::new(Some(reader)));
+
+    let pull_reader = reader.clone();
+    let pull = Closure::wrap(Box::new(
+        move |controller: web_sys::ReadableStreamDefaultController| {
+            let reader = pull_reader.clone();
+            future_to_promise(async move {
+                // As with response bodies, pulls never overlap
+                let Some(mut inner) = reader.borrow_mut().take() else {
+                    controller.close()?;
+                    return Ok(JsValue::UNDEFINED);
+                };
+                let mut buf = vec![0; 16 * 1024];
+                match futures::AsyncReadExt::read(&mut inner, &mut buf).await {
+                    Ok(0) => controller.close()?,
+                    Ok(n) => {
+                        controller.enqueue_with_chunk(&js_sys::Uint8Array::from(&buf[..n]))?;
+                        *reader.borrow_mut() = Some(inner);
+                    }
+                    Err(e) => controller
+                        .error_with_e(&io_error_to_js("Failed to read from onion stream", e)),
+                }
+                Ok(JsValue::UNDEFINED)
+            })
+        },
+    )
+        as Box<dyn FnMut(web_sys::ReadableStreamDefaultController) -> js_sys::Promise>);
+
+    let cancel = Closure::wrap(Box::new(move |_reason: JsValue| {
+        reader.borrow_mut().take();
+    }) as Box<dyn FnMut(JsValue)>);
+
+    // The stream owns the closures, so they are freed along with it
+    let source = js_sys::Object::new();
+    js_sys::Reflect::set(&source, &"pull".into(), &pull.into_js_value())?;
+    js_sys::Reflect::set(&source, &"cancel".into(), &cancel.into_js_value())?;
+
+    web_sys::ReadableStream::new_with_underlying_source(&source)
+}
+
 /// JavaScript-friendly circuit status
 #[wasm_bindgen]
 pub struct JsCircuitStatus {
@@ -5,11 +5,13 @@ use crate::error::{Result, TorError};
 use crate::isolation::IsolationKey;
 use crate::relay::{Relay, RelayManager};
 use crate::time::Instant;
+use futures::channel::oneshot;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::sync::RwLock;
-use tor_linkspec::HasRelayIds;
+use tor_linkspec::{HasRelayIds, OwnedCircTarget};
 use tor_proto::ccparams::{
     Algorithm, CongestionControlParamsBuilder, CongestionWindowParamsBuilder,
     FixedWindowParamsBuilder, RoundTripEstimatorParamsBuilder,
@@ -20,7 +22,17 @@ use tor_proto::client::circuit::TimeoutEstimator;
 use tor_proto::client::stream::DataStream;
 use tor_proto::{CellCount, ClientTunnel, FlowCtrlParameters};
 use tor_units::Percentage;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Resolves when a tunnel's reactor exits, with the error if it failed
+type ReactorExit = oneshot::Receiver<Option<String>>;
+
+/// TTL given to answers whose TTL the tunnel API does not report
+///
+/// `ClientTunnel::resolve` drops the TTLs carried in RESOLVED. Exits clip
+/// TTLs to 5 minutes or 1 hour before relaying them, so the lower bound
+/// never outlives what the exit returned.
+const UNREPORTED_DNS_TTL: Duration = Duration::from_secs(5 * 60);
 
 /// Circuit status
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -94,8 +106,12 @@ impl Circuit {
         self.last_used = Instant::now();
     }
 
+    /// Whether streams can be opened on this circuit
+    ///
+    /// A tunnel that is shutting down isn't ready, even before its reactor
+    /// exit has been noticed.
     pub fn is_ready(&self) -> bool {
-        self.status == CircuitStatus::Ready
+        self.status == CircuitStatus::Ready && !self.tunnel_closed()
     }
 
     pub fn is_failed(&self) -> bool {
@@ -106,6 +122,35 @@ impl Circuit {
         self.status == CircuitStatus::Closed
     }
 
+    /// Whether the tunnel under this circuit is closing or gone
+    pub fn tunnel_closed(&self) -> bool {
+        self.internal_circuit
+            .as_ref()
+            .is_some_and(|tunnel| tunnel.is_closed())
+    }
+
+    /// Mark this circuit failed, unless it already closed
+    pub fn mark_failed(&mut self) {
+        if !self.is_closed() {
+            self.status = CircuitStatus::Failed;
+        }
+    }
+
+    /// The last hop of this circuit, if the path is known
+    pub fn exit_relay(&self) -> Option<&Relay> {
+        self.relays.last()
+    }
+
+    /// Whether this circuit's exit accepts connections to `port`
+    ///
+    /// `None` means any port will do (e.g. prebuilding, directory use).
+    pub fn allows_port(&self, port: Option<u16>) -> bool {
+        match port {
+            Some(port) => self.exit_relay().is_some_and(|exit| exit.allows_port(port)),
+            None => true,
+        }
+    }
+
     /// Begin a TCP stream to the given host and port through this circuit.
     ///
     /// The hostname resolution is performed by the exit relay, so you can
@@ -116,16 +161,120 @@ impl Circuit {
             .as_ref()
             .ok_or_else(|| TorError::Internal("No internal circuit available".to_string()))?;
 
+        // Exits can't reach onion services; those need a rendezvous circuit
+        if crate::onion::is_onion_host(host) {
+            return Err(TorError::configuration(format!(
+                "{} is an onion service; connect through OnionClient instead of an exit circuit",
+                host
+            )));
+        }
+
         debug!("Beginning stream to {}:{}", host, port);
 
-        let stream = tunnel
-            .begin_stream(host, port, None)
-            .await
-            .map_err(|e| TorError::Internal(format!("Failed to begin stream: {}", e)))?;
+        let stream = tunnel.begin_stream(host, port, None).await.map_err(|e| {
+            if tunnel.is_closed() || matches!(e, tor_proto::Error::CircuitClosed) {
+                // The circuit itself is gone; another one may do better
+                TorError::network(format!("Circuit closed while beginning stream: {}", e))
+            } else {
+                TorError::Internal(format!("Failed to begin stream: {}", e))
+            }
+        })?;
 
         info!("Stream established to {}:{}", host, port);
         Ok(stream)
     }
+
+    /// Resolve a hostname at the exit relay (RESOLVE cell)
+    ///
+    /// Each address comes with the TTL it may be cached for.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<(IpAddr, Duration)>> {
+        let tunnel = self
+            .internal_circuit
+            .as_ref()
+            .ok_or_else(|| TorError::Internal("No internal circuit available".to_string()))?;
+
+        if crate::onion::is_onion_host(host) {
+            return Err(TorError::configuration(format!(
+                "{} is an onion service and has no address to resolve",
+                host
+            )));
+        }
+
+        debug!("Resolving {}", host);
+        let addrs = tunnel.resolve(host).await.map_err(resolve_error)?;
+        Ok(with_ttl(addrs))
+    }
+
+    /// Look up the hostnames for an address at the exit relay (reverse RESOLVE)
+    ///
+    /// Each hostname comes with the TTL it may be cached for.
+    pub async fn resolve_ptr(&self, addr: IpAddr) -> Result<Vec<(String, Duration)>> {
+        let tunnel = self
+            .internal_circuit
+            .as_ref()
+            .ok_or_else(|| TorError::Internal("No internal circuit available".to_string()))?;
+
+        debug!("Reverse resolving {}", addr);
+        let names = tunnel.resolve_ptr(addr).await.map_err(resolve_error)?;
+        Ok(with_ttl(names))
+    }
+}
+
+/// Mark `circuit` closed or failed once its tunnel's reactor exits
+fn watch_tunnel(circuit: Weak<RwLock<Circuit>>, reactor_exit: ReactorExit) {
+    let watch = async move {
+        let error = reactor_exit
+            .await
+            .unwrap_or_else(|_| Some("circuit reactor dropped".to_string()));
+        // Weak, so a circuit removed from the list isn't kept alive
+        if let Some(circuit) = circuit.upgrade() {
+            tunnel_ended(&mut *circuit.write().await, error);
+        }
+    };
+
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_futures::spawn_local(watch);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::spawn(watch);
+}
+
+/// Record how a circuit's tunnel ended; `error` is `None` for a clean close
+fn tunnel_ended(circuit: &mut Circuit, error: Option<String>) {
+    if circuit.is_failed() || circuit.is_closed() {
+        return;
+    }
+    match error {
+        Some(e) => {
+            warn!("Circuit {} failed: {}", circuit.id, e);
+            circuit.status = CircuitStatus::Failed;
+        }
+        None => {
+            debug!("Circuit {} closed", circuit.id);
+            circuit.status = CircuitStatus::Closed;
+        }
+    }
+}
+
+/// Map a failed RESOLVE to a TorError
+///
+/// Transient failures at the exit are network errors worth retrying on
+/// another circuit; anything else is a definitive answer.
+fn resolve_error(e: tor_proto::Error) -> TorError {
+    match e {
+        tor_proto::Error::ResolveError(tor_proto::ResolveError::Transient) => {
+            TorError::network("Exit reported a transient resolution failure")
+        }
+        e => TorError::dns(e.to_string()),
+    }
+}
+
+/// Pair answers from the tunnel API with `UNREPORTED_DNS_TTL`
+fn with_ttl<T>(answers: Vec<T>) -> Vec<(T, Duration)> {
+    answers
+        .into_iter()
+        .map(|answer| (answer, UNREPORTED_DNS_TTL))
+        .collect()
 }
 
 // Helper to create default circuit parameters
@@ -180,6 +329,22 @@ pub fn make_circ_params() -> Result<CircParameters> {
     Ok(CircParameters::new(true, ccontrol, flow_ctrl))
 }
 
+/// Extend `tunnel` by one hop to `target`
+async fn extend_tunnel(tunnel: &ClientTunnel, target: &OwnedCircTarget, hop: &str) -> Result<()> {
+    let params = make_circ_params()?;
+    tunnel
+        .as_single_circ()
+        .map_err(|e| {
+            TorError::Internal(format!(
+                "Failed to get single circ for {} extend: {}",
+                hop, e
+            ))
+        })?
+        .extend(target, params)
+        .await
+        .map_err(|e| TorError::Internal(format!("Failed to extend to {}: {}", hop, e)))
+}
+
 pub(crate) struct SimpleTimeoutEstimator;
 impl TimeoutEstimator for SimpleTimeoutEstimator {
     fn circuit_build_timeout(&self, _length: usize) -> Duration {
@@ -209,86 +374,29 @@ impl CircuitManager {
         }
     }
 
+    /// Relay manager used for path selection
+    pub fn relay_manager(&self) -> Arc<RwLock<RelayManager>> {
+        self.relay_manager.clone()
+    }
+
     /// Create a new circuit, optionally binding it to an isolation key
     ///
     /// If an isolation key is provided, the circuit will be bound to it
     /// BEFORE being added to the circuit list, preventing races where
     /// another request could steal the unassigned circuit.
+    ///
+    /// If `exit_port` is given, only exits whose policy accepts that port
+    /// are considered.
     pub async fn create_circuit_with_isolation(
         &self,
         isolation_key: Option<IsolationKey>,
+        exit_port: Option<u16>,
     ) -> Result<Arc<RwLock<Circuit>>> {
         let circuit_id = format!("circuit_{}", uuid::Uuid::new_v4());
         info!("Creating new circuit: {}", circuit_id);
 
-        // Log relay manager state
-        let relay_manager = self.relay_manager.read().await;
-        let total_relays = relay_manager.relays.len();
-        drop(relay_manager);
-        info!("Relay manager has {} total relays", total_relays);
-
-        if total_relays == 0 {
-            error!("No relays available in relay manager - cannot create circuit");
-            return Err(TorError::Internal("No relays available".to_string()));
-        }
-
-        let channel_guard = self.channel.read().await;
-        let channel = channel_guard
-            .as_ref()
-            .ok_or_else(|| TorError::Internal("Channel not established".to_string()))?
-            .clone();
-        drop(channel_guard);
-
-        // Create pending tunnel
-        let (pending_tunnel, reactor) = channel
-            .new_tunnel(Arc::new(SimpleTimeoutEstimator) as Arc<dyn TimeoutEstimator>)
-            .await
-            .map_err(|e| TorError::Internal(format!("Failed to create pending tunnel: {}", e)))?;
-
-        #[cfg(target_arch = "wasm32")]
-        wasm_bindgen_futures::spawn_local(async move {
-            if let Err(e) = reactor.run().await {
-                error!("Circuit reactor finished with error: {}", e);
-            }
-        });
-
-        #[cfg(not(target_arch = "wasm32"))]
-        tokio::spawn(async move {
-            if let Err(e) = reactor.run().await {
-                error!("Circuit reactor finished with error: {}", e);
-            }
-        });
-
-        // First hop (Bridge) - FAST handshake
-        let params = make_circ_params()?;
-        let tunnel = pending_tunnel
-            .create_firsthop_fast(params)
-            .await
-            .map_err(|e| TorError::Internal(format!("Failed to create first hop: {}", e)))?;
-
-        info!("First hop created (FAST)");
-
-        // Construct Relay object for the bridge from the channel target
-        // Note: The channel target might not have all relay info (like ntor key for fast handshake),
-        // but we construct a best-effort representation for the circuit info.
-        let channel_target = channel.target();
-        let bridge_fingerprint = channel_target
-            .rsa_identity()
-            .map(|id| hex::encode(id.as_bytes()))
-            .unwrap_or_else(|| "0000000000000000000000000000000000000000".to_string());
-
-        // We don't have the real address easily accessible in string format from OwnedChanTarget without some work,
-        // or the ntor key if it wasn't known.
-        // For visual consistency in the circuit list, we create a placeholder if needed.
-        // For Snowflake, we use WebRTC so there's no traditional IP address.
-        let bridge_relay = Relay::new(
-            bridge_fingerprint.clone(),
-            "Snowflake".to_string(), // More meaningful name for the proxy
-            "0.0.0.0".to_string(),   // Placeholder - will be shown as "Snowflake (WebRTC)" in UI
-            0,
-            std::collections::HashSet::new(),
-            "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
-        );
+        let (tunnel, bridge_relay, reactor_exit) = self.open_first_hop().await?;
+        let bridge_fingerprint = bridge_relay.fingerprint.clone();
 
         // Select relays
         let relay_manager = self.relay_manager.read().await;
@@ -298,9 +406,10 @@ impl CircuitManager {
         );
 
         // Middle
-        // Ensure we don't select the bridge as middle
-        let middle_criteria =
-            crate::relay::selection::middle_relays().without_fingerprint(&bridge_fingerprint);
+        // Ensure we don't select the bridge (or a relay related to it) as middle
+        let middle_criteria = crate::relay::selection::middle_relays()
+            .without_fingerprint(&bridge_fingerprint)
+            .unrelated_to(&bridge_relay);
         debug!("Middle relay criteria: {:?}", middle_criteria);
 
         let middle = match relay_manager.select_relay(&middle_criteria) {
@@ -314,31 +423,24 @@ impl CircuitManager {
                 return Err(e);
             }
         };
-        let middle_target = middle.as_circ_target()?;
-
         info!(
             "Extending to middle: {} (fp={})",
             middle.nickname,
             &middle.fingerprint[..8.min(middle.fingerprint.len())]
         );
-        let params = make_circ_params()?;
-        tunnel
-            .as_single_circ()
-            .map_err(|e| {
-                TorError::Internal(format!(
-                    "Failed to get single circ for middle extend: {}",
-                    e
-                ))
-            })?
-            .extend(&middle_target, params)
-            .await
-            .map_err(|e| TorError::Internal(format!("Failed to extend to middle: {}", e)))?;
+        extend_tunnel(&tunnel, &middle.as_circ_target()?, "middle").await?;
 
         // Exit
-        // Ensure we don't select bridge or middle as exit
-        let exit_criteria = crate::relay::selection::exit_relays()
+        // Ensure we don't select bridge or middle as exit, nor anything in
+        // their family or subnet
+        let mut exit_criteria = crate::relay::selection::exit_relays()
             .without_fingerprint(&bridge_fingerprint)
-            .without_fingerprint(&middle.fingerprint);
+            .without_fingerprint(&middle.fingerprint)
+            .unrelated_to(&bridge_relay)
+            .unrelated_to(&middle);
+        if let Some(port) = exit_port {
+            exit_criteria = exit_criteria.allowing_port(port);
+        }
         debug!("Exit relay criteria: {:?}", exit_criteria);
 
         let exit = match relay_manager.select_relay(&exit_criteria) {
@@ -352,22 +454,12 @@ impl CircuitManager {
                 return Err(e);
             }
         };
-        let exit_target = exit.as_circ_target()?;
-
         info!(
             "Extending to exit: {} (fp={})",
             exit.nickname,
             &exit.fingerprint[..8.min(exit.fingerprint.len())]
         );
-        let params = make_circ_params()?;
-        tunnel
-            .as_single_circ()
-            .map_err(|e| {
-                TorError::Internal(format!("Failed to get single circ for exit extend: {}", e))
-            })?
-            .extend(&exit_target, params)
-            .await
-            .map_err(|e| TorError::Internal(format!("Failed to extend to exit: {}", e)))?;
+        extend_tunnel(&tunnel, &exit.as_circ_target()?, "exit").await?;
 
         info!("Circuit established successfully");
 
@@ -389,6 +481,7 @@ impl CircuitManager {
         );
 
         let circuit_arc = Arc::new(RwLock::new(circuit));
+        watch_tunnel(Arc::downgrade(&circuit_arc), reactor_exit);
 
         // Add to active circuits
         let mut circuits = self.circuits.write().await;
@@ -397,18 +490,139 @@ impl CircuitManager {
         Ok(circuit_arc)
     }
 
+    /// Build a standalone three-hop tunnel (bridge, middle, `last_hop`)
+    ///
+    /// Used for onion service traffic, where the last hop is an HSDir,
+    /// introduction point or rendezvous point rather than an exit. These
+    /// tunnels are not added to the circuit list, so they are never handed
+    /// out for clearnet streams.
+    pub(crate) async fn build_tunnel_to(
+        &self,
+        last_hop: &OwnedCircTarget,
+        last_hop_relay: Option<&Relay>,
+    ) -> Result<Arc<ClientTunnel>> {
+        let (tunnel, bridge_relay, _) = self.open_first_hop().await?;
+
+        let mut middle_criteria = crate::relay::selection::middle_relays()
+            .without_fingerprint(&bridge_relay.fingerprint)
+            .unrelated_to(&bridge_relay);
+        if let Some(rsa_id) = last_hop.rsa_identity() {
+            middle_criteria = middle_criteria.without_fingerprint(&hex::encode(rsa_id.as_bytes()));
+        }
+        if let Some(relay) = last_hop_relay {
+            middle_criteria = middle_criteria.unrelated_to(relay);
+        }
+
+        let middle = self
+            .relay_manager
+            .read()
+            .await
+            .select_relay(&middle_criteria)?;
+        extend_tunnel(&tunnel, &middle.as_circ_target()?, "middle").await?;
+        extend_tunnel(&tunnel, last_hop, "last hop").await?;
+
+        Ok(Arc::new(tunnel))
+    }
+
+    /// Open a new tunnel on the bridge channel and create its first hop
+    ///
+    /// Returns the tunnel along with a best-effort `Relay` describing the
+    /// bridge, for path constraints and the circuit list, and a receiver for
+    /// the tunnel's reactor exit.
+    async fn open_first_hop(&self) -> Result<(ClientTunnel, Relay, ReactorExit)> {
+        // Log relay manager state
+        let relay_manager = self.relay_manager.read().await;
+        let total_relays = relay_manager.relays.len();
+        drop(relay_manager);
+        info!("Relay manager has {} total relays", total_relays);
+
+        if total_relays == 0 {
+            error!("No relays available in relay manager - cannot create circuit");
+            return Err(TorError::Internal("No relays available".to_string()));
+        }
+
+        let channel_guard = self.channel.read().await;
+        let channel = channel_guard
+            .as_ref()
+            .ok_or_else(|| TorError::Internal("Channel not established".to_string()))?
+            .clone();
+        drop(channel_guard);
+
+        // Create pending tunnel
+        let (pending_tunnel, reactor) = channel
+            .new_tunnel(Arc::new(SimpleTimeoutEstimator) as Arc<dyn TimeoutEstimator>)
+            .await
+            .map_err(|e| TorError::Internal(format!("Failed to create pending tunnel: {}", e)))?;
+
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let run = async move {
+            let result = reactor.run().await;
+            if let Err(e) = &result {
+                error!("Circuit reactor finished with error: {}", e);
+            }
+            let _ = exit_tx.send(result.err().map(|e| e.to_string()));
+        };
+
+        #[cfg(target_arch = "wasm32")]
+        wasm_bindgen_futures::spawn_local(run);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        tokio::spawn(run);
+
+        // First hop (Bridge) - FAST handshake
+        let params = make_circ_params()?;
+        let tunnel = pending_tunnel
+            .create_firsthop_fast(params)
+            .await
+            .map_err(|e| TorError::Internal(format!("Failed to create first hop: {}", e)))?;
+
+        info!("First hop created (FAST)");
+
+        // Construct Relay object for the bridge from the channel target
+        // Note: The channel target might not have all relay info (like ntor key for fast handshake),
+        // but we construct a best-effort representation for the circuit info.
+        let channel_target = channel.target();
+        let bridge_fingerprint = channel_target
+            .rsa_identity()
+            .map(|id| hex::encode(id.as_bytes()))
+            .unwrap_or_else(|| "0000000000000000000000000000000000000000".to_string());
+
+        // We don't have the real address easily accessible in string format from OwnedChanTarget without some work,
+        // or the ntor key if it wasn't known.
+        // For visual consistency in the circuit list, we create a placeholder if needed.
+        // For Snowflake, we use WebRTC so there's no traditional IP address.
+        let bridge_relay = Relay::new(
+            bridge_fingerprint,
+            "Snowflake".to_string(), // More meaningful name for the proxy
+            "0.0.0.0".to_string(),   // Placeholder - will be shown as "Snowflake (WebRTC)" in UI
+            0,
+            std::collections::HashSet::new(),
+            "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+        );
+
+        Ok((tunnel, bridge_relay, exit_rx))
+    }
+
     /// Create a new circuit (unassigned, for prebuilding)
     pub async fn create_circuit(&self) -> Result<Arc<RwLock<Circuit>>> {
-        self.create_circuit_with_isolation(None).await
+        self.create_circuit_with_isolation(None, None).await
     }
 
     /// Get a ready circuit (create one if none exist)
     pub async fn get_ready_circuit(&self) -> Result<Arc<RwLock<Circuit>>> {
+        self.get_ready_circuit_for_port(None).await
+    }
+
+    /// Get a ready circuit whose exit accepts `port` (create one if none exist)
+    pub async fn get_ready_circuit_for_port(
+        &self,
+        port: Option<u16>,
+    ) -> Result<Arc<RwLock<Circuit>>> {
         // First, try to find an existing ready circuit
         let circuits = self.circuits.read().await;
         for circuit in circuits.iter() {
             let circuit_read = circuit.read().await;
-            if circuit_read.is_ready() {
+            if circuit_read.is_ready() && circuit_read.allows_port(port) {
                 debug!("Found existing ready circuit: {}", circuit_read.id);
                 return Ok(circuit.clone());
             }
@@ -416,12 +630,19 @@ impl CircuitManager {
         drop(circuits);
 
         // No ready circuit found, create a new one
-        self.create_circuit().await
+        self.create_circuit_with_isolation(None, port).await
     }
 
     /// Get a ready circuit and mark it as used (updates last_used timestamp)
     pub async fn get_ready_circuit_and_mark_used(&self) -> Result<Arc<RwLock<Circuit>>> {
-        let circuit = self.get_ready_circuit().await?;
+        self.get_ready_circuit_for_port_and_mark_used(None).await
+    }
+
+    async fn get_ready_circuit_for_port_and_mark_used(
+        &self,
+        port: Option<u16>,
+    ) -> Result<Arc<RwLock<Circuit>>> {
+        let circuit = self.get_ready_circuit_for_port(port).await?;
         {
             let mut circ = circuit.write().await;
             circ.update_last_used();
@@ -436,11 +657,24 @@ impl CircuitManager {
     pub async fn get_circuit_for_isolation_key(
         &self,
         key: Option<IsolationKey>,
+    ) -> Result<Arc<RwLock<Circuit>>> {
+        self.get_circuit_for_stream(key, None).await
+    }
+
+    /// Get or create a circuit bound to the given isolation key whose exit
+    /// accepts connections to `port`
+    ///
+    /// Circuits whose exit policy rejects the port are skipped, so a stream
+    /// to e.g. 8545 is never attempted on a web-only exit.
+    pub async fn get_circuit_for_stream(
+        &self,
+        key: Option<IsolationKey>,
+        port: Option<u16>,
     ) -> Result<Arc<RwLock<Circuit>>> {
         // If no isolation key, fall back to legacy behavior
         let key = match key {
             Some(k) => k,
-            None => return self.get_ready_circuit_and_mark_used().await,
+            None => return self.get_ready_circuit_for_port_and_mark_used(port).await,
         };
 
         // 1. Look for a ready circuit already bound to this key
@@ -448,7 +682,7 @@ impl CircuitManager {
             let circuits = self.circuits.read().await;
             for circuit in circuits.iter() {
                 let circuit_read = circuit.read().await;
-                if circuit_read.is_ready() {
+                if circuit_read.is_ready() && circuit_read.allows_port(port) {
                     if let Some(ref circuit_key) = circuit_read.isolation_key {
                         if circuit_key == &key {
                             debug!(
@@ -472,7 +706,10 @@ impl CircuitManager {
             let circuits = self.circuits.read().await;
             for circuit in circuits.iter() {
                 let mut circuit_write = circuit.write().await;
-                if circuit_write.is_ready() && circuit_write.isolation_key.is_none() {
+                if circuit_write.is_ready()
+                    && circuit_write.isolation_key.is_none()
+                    && circuit_write.allows_port(port)
+                {
                     debug!(
                         "Binding unassigned circuit {} to isolation key {}",
                         circuit_write.id, key
@@ -494,7 +731,8 @@ impl CircuitManager {
                         if let Some(ref circuit_key) = circuit_read.isolation_key {
                             return circuit_key == &key
                                 && !circuit_read.is_failed()
-                                && !circuit_read.is_closed();
+                                && !circuit_read.is_closed()
+                                && circuit_read.allows_port(port);
                         }
                     }
                     false
@@ -509,6 +747,7 @@ impl CircuitManager {
                         if circuit_key == &key
                             && !circuit_read.is_failed()
                             && !circuit_read.is_closed()
+                            && circuit_read.allows_port(port)
                         {
                             debug!(
                                 "At per-key limit, reusing circuit {} for {}",
@@ -528,7 +767,7 @@ impl CircuitManager {
         // We pass the key to create_circuit_with_isolation so it's bound
         // BEFORE the circuit is added to the list, preventing races
         info!("Creating new circuit for isolation key {}", key);
-        let circuit = self.create_circuit_with_isolation(Some(key)).await?;
+        let circuit = self.create_circuit_with_isolation(Some(key), port).await?;
         {
             let mut circuit_write = circuit.write().await;
             circuit_write.update_last_used();
@@ -701,6 +940,25 @@ impl CircuitManager {
         });
     }
 
+    /// Mark every open circuit failed, e.g. because the channel under them
+    /// died
+    ///
+    /// Streams can no longer be opened on them, and the next
+    /// `cleanup_circuits` removes them. Returns how many were marked.
+    pub async fn fail_all_circuits(&self) -> usize {
+        let circuits = self.circuits.read().await;
+        let mut failed = 0;
+        for circuit in circuits.iter() {
+            let mut circuit = circuit.write().await;
+            if !circuit.is_failed() && !circuit.is_closed() {
+                debug!("Marking circuit {} failed", circuit.id);
+                circuit.status = CircuitStatus::Failed;
+                failed += 1;
+            }
+        }
+        failed
+    }
+
     /// Clean up failed and old circuits
     pub async fn cleanup_circuits(&self) -> Result<()> {
         let mut circuits = self.circuits.write().await;
@@ -715,9 +973,12 @@ impl CircuitManager {
         for (idx, circuit) in circuits.iter().enumerate() {
             let circuit_read = circuit.read().await;
 
-            // Remove failed circuits
-            if circuit_read.is_failed() {
-                info!("Removing failed circuit: {}", circuit_read.id);
+            // Remove failed and closed circuits
+            if circuit_read.is_failed() || circuit_read.is_closed() {
+                info!(
+                    "Removing {:?} circuit: {}",
+                    circuit_read.status, circuit_read.id
+                );
                 to_remove.push(idx);
                 remaining -= 1;
                 continue;
@@ -820,6 +1081,57 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[portable_test_async]
+    async fn test_fail_all_circuits() {
+        let relay_manager = Arc::new(RwLock::new(RelayManager::new(Vec::new())));
+        let circuit_manager = CircuitManager::new(relay_manager, Arc::new(RwLock::new(None)));
+
+        for (id, status) in [
+            ("ready", CircuitStatus::Ready),
+            ("creating", CircuitStatus::Creating),
+            ("closed", CircuitStatus::Closed),
+        ] {
+            let mut circuit = Circuit::new(id.to_string(), None);
+            circuit.status = status;
+            circuit_manager
+                .circuits
+                .write()
+                .await
+                .push(Arc::new(RwLock::new(circuit)));
+        }
+
+        assert_eq!(circuit_manager.fail_all_circuits().await, 2);
+        let status = circuit_manager.get_circuit_status().await;
+        assert_eq!(status.failed_circuits, 2);
+        assert!(!status.has_ready_circuits());
+
+        circuit_manager.cleanup_circuits().await.unwrap();
+        assert_eq!(circuit_manager.get_circuit_status().await.total_circuits, 0);
+    }
+
+    #[portable_test]
+    fn test_tunnel_ended_sets_status_once() {
+        let mut circuit = Circuit::new("clean".to_string(), None);
+        circuit.status = CircuitStatus::Ready;
+        tunnel_ended(&mut circuit, None);
+        assert!(circuit.is_closed());
+        assert!(!circuit.is_ready());
+
+        let mut circuit = Circuit::new("broken".to_string(), None);
+        circuit.status = CircuitStatus::Ready;
+        tunnel_ended(&mut circuit, Some("DESTROY received".to_string()));
+        assert!(circuit.is_failed());
+
+        // Whichever came first sticks
+        tunnel_ended(&mut circuit, None);
+        assert!(circuit.is_failed());
+
+        let mut circuit = Circuit::new("closed".to_string(), None);
+        circuit.status = CircuitStatus::Closed;
+        circuit.mark_failed();
+        assert!(circuit.is_closed());
+    }
+
     #[portable_test]
     fn test_circuit_status() {
         let mut circuit = Circuit::new("test_circuit".to_string(), None);
@@ -859,4 +1171,23 @@ mod tests {
         let circuit = Circuit::new("test".to_string(), None);
         assert!(circuit.isolation_key.is_none());
     }
+
+    #[portable_test]
+    fn test_circuit_allows_port_uses_exit_policy() {
+        let mut circuit = Circuit::new("test".to_string(), None);
+        assert!(circuit.allows_port(None));
+        assert!(!circuit.allows_port(Some(443)));
+
+        let mut exit = create_test_relay("exit", vec![flags::EXIT]);
+        exit.ipv4_policy = Some("accept 80,443".parse().unwrap());
+        circuit.relays = vec![
+            create_test_relay("bridge", vec![]),
+            create_test_relay("middle", vec![flags::FAST]),
+            exit,
+        ];
+
+        assert!(circuit.allows_port(Some(443)));
+        assert!(!circuit.allows_port(Some(8545)));
+        assert!(circuit.allows_port(None));
+    }
 }
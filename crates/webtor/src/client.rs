@@ -1,29 +1,44 @@
 //! Main Tor client implementation
 
 use crate::circuit::{CircuitManager, CircuitStatusInfo};
-use crate::config::{BridgeType, LogType, TorClientOptions, SNOWFLAKE_FINGERPRINT_PRIMARY};
+use crate::config::{
+    BridgeType, ChannelEvent, LogType, OnionServiceConfig, TorClientOptions,
+    SNOWFLAKE_FINGERPRINT_PRIMARY,
+};
 use crate::directory::DirectoryManager;
+use crate::dns::DnsResolver;
 use crate::error::{Result, TorError};
 use crate::http::{HttpRequest, HttpResponse, TorHttpClient};
+use crate::http_cookie::{Cookie, CookieJar};
+use crate::http_stream::{RequestBody, StreamingResponse};
+use crate::isolation::IsolationKey;
+use crate::onion_service::OnionServiceHandle;
 use crate::relay::RelayManager;
-use crate::retry::{with_timeout_and_cancellation, CancellationToken};
+use crate::retry::{
+    retry_with_backoff, with_cancellation, with_timeout_and_cancellation, CancellationToken,
+    RetryPolicy,
+};
 #[cfg(target_arch = "wasm32")]
 use crate::snowflake::{SnowflakeBridge, SnowflakeConfig};
 #[cfg(target_arch = "wasm32")]
 use crate::snowflake_ws::{SnowflakeWsConfig, SnowflakeWsStream};
 use crate::time::system_time_now;
+use crate::tor_websocket::{TorWebSocket, WebSocketOptions};
 use crate::wasm_runtime::WasmRuntime;
 #[cfg(not(target_arch = "wasm32"))]
 use crate::webtunnel::{create_webtunnel_stream, WebTunnelConfig};
-use http::Method;
+use futures::channel::oneshot;
+use http::{HeaderMap, Method};
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tor_linkspec::OwnedChanTargetBuilder;
 use tor_llcrypto::pk::rsa::RsaIdentity;
 use tor_memquota::MemoryQuotaTracker;
-use tor_proto::channel::ChannelBuilder;
+use tor_proto::channel::{Channel, ChannelBuilder};
+use tor_proto::client::stream::DataStream;
 use tor_proto::memquota::{ChannelAccount, SpecificAccount};
 use tracing::{debug, error, info, warn};
 use url::Url;
@@ -34,9 +49,13 @@ pub struct TorClient {
     circuit_manager: Arc<RwLock<CircuitManager>>,
     directory_manager: Arc<DirectoryManager>,
     http_client: Arc<TorHttpClient>,
+    dns_resolver: Arc<DnsResolver>,
     is_initialized: Arc<RwLock<bool>>,
     // Store the channel to prevent it from being dropped
     channel: Arc<RwLock<Option<Arc<tor_proto::channel::Channel>>>>,
+    /// Held while establishing the channel, so a request and the reconnect
+    /// task don't open two
+    establish_lock: Arc<Mutex<()>>,
     update_task: Arc<RwLock<Option<tokio::task::JoinHandle<()>>>>,
     /// Shutdown token for cooperative cancellation of long-running operations
     shutdown_token: CancellationToken,
@@ -70,15 +89,28 @@ impl TorClient {
             relay_manager_arc.clone(),
             channel.clone(),
         )));
-        let http_client = TorHttpClient::new(circuit_manager.clone(), options.stream_isolation);
+        let mut http_client = TorHttpClient::new(circuit_manager.clone(), options.stream_isolation);
+        if options.cookies {
+            http_client = http_client.with_cookie_jar(Arc::new(CookieJar::new()));
+        }
+        for (onion_address, secret_key) in &options.onion_client_auth {
+            http_client
+                .onion_client()
+                .add_client_auth(onion_address, secret_key.expose())
+                .await?;
+        }
+
+        let dns_resolver = Arc::new(DnsResolver::new(circuit_manager.clone()));
 
         let client = Self {
             options: options.clone(),
             circuit_manager,
             directory_manager,
             http_client: Arc::new(http_client),
+            dns_resolver,
             is_initialized: Arc::new(RwLock::new(false)),
             channel,
+            establish_lock: Arc::new(Mutex::new(())),
             update_task: Arc::new(RwLock::new(None)),
             shutdown_token: CancellationToken::new(),
             close_initiated: Arc::new(AtomicBool::new(false)),
@@ -166,8 +198,10 @@ impl TorClient {
         self.log(&format!("Starting fetch request to {}", url), LogType::Info);
 
         let url = Url::parse(url)?;
-        let request = HttpRequest::new(url);
+        let mut request = HttpRequest::new(url);
+        self.track(&mut request);
 
+        self.ensure_ready().await?;
         self.http_client.request(request).await
     }
 
@@ -182,7 +216,9 @@ impl TorClient {
         let mut request = HttpRequest::new(url);
         request.method = Method::POST;
         request.body = Some(body);
+        self.track(&mut request);
 
+        self.ensure_ready().await?;
         self.http_client.request(request).await
     }
 
@@ -191,7 +227,7 @@ impl TorClient {
         &self,
         method: Method,
         url: &str,
-        headers: std::collections::HashMap<String, String>,
+        headers: HeaderMap,
         body: Option<Vec<u8>>,
         timeout: Option<Duration>,
     ) -> Result<HttpResponse> {
@@ -204,10 +240,260 @@ impl TorClient {
         if let Some(timeout) = timeout {
             request.timeout = timeout;
         }
+        self.track(&mut request);
 
+        self.ensure_ready().await?;
         self.http_client.request(request).await
     }
 
+    /// Make an HTTP request whose body is streamed instead of buffered
+    ///
+    /// The body is sent chunked unless its length is known; see
+    /// `RequestBody`.
+    pub async fn request_with_body(
+        &self,
+        method: Method,
+        url: &str,
+        headers: HeaderMap,
+        body: RequestBody,
+        timeout: Option<Duration>,
+    ) -> Result<HttpResponse> {
+        let url = Url::parse(url)?;
+        let mut request = HttpRequest::new(url);
+        request.method = method;
+        request.headers = headers;
+
+        if let Some(timeout) = timeout {
+            request.timeout = timeout;
+        }
+        self.track(&mut request);
+
+        self.ensure_ready().await?;
+        self.http_client.request_with_body(request, body).await
+    }
+
+    /// Make a generic HTTP request and stream its body
+    ///
+    /// Returns once the response headers arrive; see
+    /// `TorHttpClient::request_streaming`.
+    pub async fn request_streaming(
+        &self,
+        method: Method,
+        url: &str,
+        headers: HeaderMap,
+        body: Option<RequestBody>,
+        timeout: Option<Duration>,
+    ) -> Result<StreamingResponse> {
+        let url = Url::parse(url)?;
+        let mut request = HttpRequest::new(url);
+        request.method = method;
+        request.headers = headers;
+
+        if let Some(timeout) = timeout {
+            request.timeout = timeout;
+        }
+        self.track(&mut request);
+
+        self.ensure_ready().await?;
+        match body {
+            Some(body) => {
+                self.http_client
+                    .request_streaming_with_body(request, body)
+                    .await
+            }
+            None => self.http_client.request_streaming(request).await,
+        }
+    }
+
+    /// Send a prepared `HttpRequest`, streaming the response body
+    ///
+    /// For callers that need fields the methods above don't take, such as
+    /// `redirect` or a `cancellation` token from `child_token`. `body`
+    /// overrides `request.body`.
+    pub async fn send_streaming(
+        &self,
+        mut request: HttpRequest,
+        body: Option<RequestBody>,
+    ) -> Result<StreamingResponse> {
+        self.track(&mut request);
+        self.ensure_ready().await?;
+        match body {
+            Some(body) => {
+                self.http_client
+                    .request_streaming_with_body(request, body)
+                    .await
+            }
+            None => self.http_client.request_streaming(request).await,
+        }
+    }
+
+    /// Open a WebSocket to a `ws://` or `wss://` URL through Tor
+    ///
+    /// See `TorHttpClient::websocket`. `options.timeout` bounds the opening
+    /// handshake.
+    pub async fn websocket(&self, url: &str, options: WebSocketOptions) -> Result<TorWebSocket> {
+        let url = Url::parse(url)?;
+        self.ensure_ready().await?;
+        with_cancellation(
+            &self.shutdown_token,
+            self.http_client.websocket(url, options),
+        )
+        .await
+    }
+
+    /// Open a TCP stream to `host:port` through the Tor network
+    ///
+    /// The returned stream implements futures `AsyncRead`/`AsyncWrite`.
+    /// `isolation` pins the stream to an isolation group; `None` derives the
+    /// key from the configured `StreamIsolationPolicy`, the same way HTTP
+    /// requests do. `.onion` hosts are reached through rendezvous circuits.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: Option<IsolationKey>,
+    ) -> Result<DataStream> {
+        if host.is_empty() || port == 0 {
+            return Err(TorError::configuration(format!(
+                "Invalid stream target {}:{}",
+                host, port
+            )));
+        }
+        self.ensure_ready().await?;
+
+        let isolation_key = isolation
+            .or_else(|| IsolationKey::from_host(host, Some(port), self.options.stream_isolation));
+        let stream = with_timeout_and_cancellation(
+            self.options.circuit_timeout_duration(),
+            "connect",
+            &self.shutdown_token,
+            self.http_client.open_stream(host, port, isolation_key),
+        )
+        .await?;
+
+        self.http_client.maybe_prebuild_circuit().await;
+        Ok(stream)
+    }
+
+    /// Resolve a hostname to its addresses through the Tor network
+    ///
+    /// The lookup runs at an exit on a circuit chosen by the configured
+    /// `StreamIsolationPolicy`, and answers are cached per isolation key.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        self.resolve_with_isolation(host, None).await
+    }
+
+    /// Resolve a hostname in an explicit isolation group
+    ///
+    /// `None` derives the key from the configured `StreamIsolationPolicy`.
+    pub async fn resolve_with_isolation(
+        &self,
+        host: &str,
+        isolation: Option<IsolationKey>,
+    ) -> Result<Vec<IpAddr>> {
+        self.ensure_ready().await?;
+        let isolation_key = isolation
+            .or_else(|| IsolationKey::from_host(host, None, self.options.stream_isolation));
+        self.dns_resolver.resolve(host, isolation_key).await
+    }
+
+    /// Look up the hostnames for an address through the Tor network
+    pub async fn resolve_ptr(&self, addr: IpAddr) -> Result<Vec<String>> {
+        self.resolve_ptr_with_isolation(addr, None).await
+    }
+
+    /// Look up the hostnames for an address in an explicit isolation group
+    ///
+    /// `None` derives the key from the configured `StreamIsolationPolicy`.
+    pub async fn resolve_ptr_with_isolation(
+        &self,
+        addr: IpAddr,
+        isolation: Option<IsolationKey>,
+    ) -> Result<Vec<String>> {
+        self.ensure_ready().await?;
+        let isolation_key = isolation.or_else(|| {
+            IsolationKey::from_host(&addr.to_string(), None, self.options.stream_isolation)
+        });
+        self.dns_resolver.resolve_ptr(addr, isolation_key).await
+    }
+
+    /// Register a client authorization key for an onion service
+    ///
+    /// Needed for services that restrict discovery to authorized clients.
+    pub async fn add_onion_client_auth(&self, onion_address: &str, secret_key: &str) -> Result<()> {
+        self.http_client
+            .onion_client()
+            .add_client_auth(onion_address, secret_key)
+            .await
+    }
+
+    /// Forget the client authorization key for an onion service
+    pub async fn remove_onion_client_auth(&self, onion_address: &str) -> Result<()> {
+        self.http_client
+            .onion_client()
+            .remove_client_auth(onion_address)
+            .await
+    }
+
+    /// Host an onion service over this client's Tor connection
+    ///
+    /// Resolves once the descriptor is published. Streams opened by the
+    /// service's clients arrive through the returned handle; the service
+    /// runs until the handle is dropped or the client is closed.
+    pub async fn launch_onion_service(
+        &self,
+        config: OnionServiceConfig,
+    ) -> Result<OnionServiceHandle> {
+        self.ensure_ready().await?;
+        crate::onion_service::launch(
+            self.circuit_manager.clone(),
+            config,
+            self.shutdown_token.clone(),
+        )
+        .await
+    }
+
+    /// Cookies stored for the isolation key `url` maps to
+    ///
+    /// Fails if the cookie jar is disabled (`TorClientOptions.cookies`).
+    pub fn export_cookies(&self, url: &str) -> Result<Vec<Cookie>> {
+        let (jar, key) = self.cookie_partition(url)?;
+        Ok(jar.export(key.as_ref()))
+    }
+
+    /// Add cookies to the partition for `url`, e.g. from `export_cookies`
+    pub fn import_cookies(&self, url: &str, cookies: Vec<Cookie>) -> Result<()> {
+        let (jar, key) = self.cookie_partition(url)?;
+        jar.import(key.as_ref(), cookies);
+        Ok(())
+    }
+
+    /// Drop the cookies in the partition for `url`
+    pub fn clear_cookies(&self, url: &str) -> Result<()> {
+        let (jar, key) = self.cookie_partition(url)?;
+        jar.clear(key.as_ref());
+        Ok(())
+    }
+
+    /// Drop every stored cookie
+    pub fn clear_all_cookies(&self) {
+        if let Some(jar) = self.http_client.cookie_jar() {
+            jar.clear_all();
+        }
+    }
+
+    fn cookie_partition(&self, url: &str) -> Result<(&Arc<CookieJar>, Option<IsolationKey>)> {
+        let jar = self
+            .http_client
+            .cookie_jar()
+            .ok_or_else(|| TorError::configuration("Cookies are not enabled"))?;
+        let url = Url::parse(url)?;
+        Ok((
+            jar,
+            IsolationKey::from_url(&url, self.options.stream_isolation),
+        ))
+    }
+
     /// Update the circuit by creating a new one
     /// The deadline parameter specifies the maximum time to wait for circuit creation
     pub async fn update_circuit(&self, deadline: Duration) -> Result<()> {
@@ -218,6 +504,10 @@ impl TorClient {
             let circuit_manager = self.circuit_manager.read().await;
             match circuit_manager.create_circuit().await {
                 Ok(circuit) => {
+                    // Keep-alive connections would stay on the old circuits,
+                    // and cookies would tie the new identity to the old one
+                    self.http_client.clear_connection_pool();
+                    self.clear_all_cookies();
                     let circuit_info = circuit.read().await;
                     let relay_names: Vec<_> = circuit_info
                         .relays
@@ -363,6 +653,10 @@ impl TorClient {
             task.abort();
         }
 
+        self.dns_resolver.clear_cache().await;
+        self.http_client.clear_connection_pool();
+        self.clear_all_cookies();
+
         // Clean up circuits
         let circuit_manager = self.circuit_manager.write().await;
         if let Err(e) = circuit_manager.cleanup_circuits().await {
@@ -392,8 +686,31 @@ impl TorClient {
         &self.shutdown_token
     }
 
+    /// A token for cancelling one operation, such as a single request.
+    ///
+    /// `abort()` and `close()` cancel it too; cancelling it leaves the
+    /// client and other requests alone. A cancelled request's Tor stream is
+    /// closed, and on HTTP/2 only its stream is reset.
+    pub fn child_token(&self) -> CancellationToken {
+        self.shutdown_token.child_token()
+    }
+
+    /// Tie `request` to `abort()` and `close()` unless it has its own token
+    fn track(&self, request: &mut HttpRequest) {
+        request
+            .cancellation
+            .get_or_insert_with(|| self.shutdown_token.child_token());
+    }
+
     /// Establish the Tor channel (called during construction if requested)
+    ///
+    /// Does nothing if another caller finished establishing it meanwhile.
     async fn establish_channel(&self) -> Result<()> {
+        let _establishing = self.establish_lock.lock().await;
+        if *self.is_initialized.read().await {
+            return Ok(());
+        }
+
         let timeout = self.options.connection_timeout_duration();
         with_timeout_and_cancellation(
             timeout,
@@ -442,7 +759,7 @@ impl TorClient {
         };
 
         // 1. Connect to bridge based on type
-        let chan = match &self.options.bridge {
+        let (chan, reactor_exit) = match &self.options.bridge {
             BridgeType::Snowflake { url } => {
                 self.log("Connecting via Snowflake (WebSocket)", LogType::Info);
                 self.log(
@@ -526,7 +843,8 @@ impl TorClient {
         };
 
         // Store the channel to keep it alive
-        *self.channel.write().await = Some(chan);
+        *self.channel.write().await = Some(chan.clone());
+        self.watch_channel(chan, reactor_exit);
 
         self.log("Channel established", LogType::Success);
 
@@ -559,11 +877,14 @@ impl TorClient {
     }
 
     /// Create Tor channel from a connected stream and spawn the reactor
+    ///
+    /// The receiver resolves with the reason once the reactor exits, which
+    /// is when the channel is dead.
     async fn create_channel_from_stream<S>(
         &self,
         stream: S,
         rsa_id: RsaIdentity,
-    ) -> Result<Arc<tor_proto::channel::Channel>>
+    ) -> Result<(Arc<Channel>, oneshot::Receiver<String>)>
     where
         S: futures::AsyncRead
             + futures::AsyncWrite
@@ -620,17 +941,137 @@ impl TorClient {
             .map_err(|e| TorError::Network(format!("Handshake finish failed: {}", e)))?;
 
         // Spawn reactor
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let run = async move {
+            let reason = match reactor.run().await {
+                Ok(()) => "channel closed".to_string(),
+                Err(e) => e.to_string(),
+            };
+            let _ = exit_tx.send(reason);
+        };
+
         #[cfg(target_arch = "wasm32")]
-        wasm_bindgen_futures::spawn_local(async move {
-            let _ = reactor.run().await;
-        });
+        wasm_bindgen_futures::spawn_local(run);
 
         #[cfg(not(target_arch = "wasm32"))]
-        tokio::spawn(async move {
-            let _ = reactor.run().await;
-        });
+        tokio::spawn(run);
+
+        Ok((chan, exit_rx))
+    }
+
+    /// Handle `chan` dying once its reactor exits
+    fn watch_channel(&self, chan: Arc<Channel>, reactor_exit: oneshot::Receiver<String>) {
+        // Weak, so a channel replaced before it died still gets dropped
+        let chan = Arc::downgrade(&chan);
+        let client = self.background_handle();
+        let watch = async move {
+            let exited = with_cancellation(&client.shutdown_token, async {
+                Ok(reactor_exit
+                    .await
+                    .unwrap_or_else(|_| "channel reactor dropped".to_string()))
+            })
+            .await;
+            if let Ok(reason) = exited {
+                client.channel_lost(chan, reason).await;
+            }
+        };
+
+        #[cfg(target_arch = "wasm32")]
+        wasm_bindgen_futures::spawn_local(watch);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        tokio::spawn(watch);
+    }
+
+    /// Fail everything that ran over the dead `chan`, then reconnect
+    async fn channel_lost(&self, chan: Weak<Channel>, reason: String) {
+        // Under the lock, so no circuit on a new channel gets failed too
+        let failed = {
+            let _establishing = self.establish_lock.lock().await;
+            let mut current = self.channel.write().await;
+            let is_current = match (current.as_ref(), chan.upgrade()) {
+                (Some(current), Some(chan)) => Arc::ptr_eq(current, &chan),
+                _ => false,
+            };
+            if !is_current {
+                // Already replaced, e.g. by a retried establish_channel
+                return;
+            }
+            *current = None;
+            drop(current);
+            *self.is_initialized.write().await = false;
+
+            self.http_client.clear_connection_pool();
+            self.circuit_manager.read().await.fail_all_circuits().await
+        };
+        self.log(
+            &format!("Channel lost: {} ({} circuits failed)", reason, failed),
+            LogType::Error,
+        );
+        self.emit(ChannelEvent::Lost { reason });
+
+        if !self.options.auto_reconnect {
+            return;
+        }
+
+        let result = retry_with_backoff(
+            "reconnect_channel",
+            RetryPolicy::network(),
+            |e| e.is_retryable(),
+            |attempt| {
+                self.log(
+                    &format!("Reconnecting to bridge (attempt {})", attempt),
+                    LogType::Info,
+                );
+                self.emit(ChannelEvent::Reconnecting { attempt });
+                self.establish_channel()
+            },
+        )
+        .await;
+
+        match result {
+            Ok(()) => {
+                if let Err(e) = self.circuit_manager.read().await.cleanup_circuits().await {
+                    warn!("Error cleaning up failed circuits: {}", e);
+                }
+                self.log("Reconnected to bridge", LogType::Success);
+                self.emit(ChannelEvent::Reconnected);
+            }
+            Err(TorError::Cancelled) => {}
+            Err(e) => {
+                self.log(&format!("Reconnection failed: {}", e), LogType::Error);
+                self.emit(ChannelEvent::ReconnectFailed {
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    /// A clone for background tasks, which end with `shutdown_token`
+    ///
+    /// Dropping a `TorClient` closes it, so tasks must not hold a plain
+    /// clone.
+    fn background_handle(&self) -> Self {
+        Self {
+            options: self.options.clone(),
+            circuit_manager: self.circuit_manager.clone(),
+            directory_manager: self.directory_manager.clone(),
+            http_client: self.http_client.clone(),
+            dns_resolver: self.dns_resolver.clone(),
+            is_initialized: self.is_initialized.clone(),
+            channel: self.channel.clone(),
+            establish_lock: self.establish_lock.clone(),
+            update_task: self.update_task.clone(),
+            shutdown_token: self.shutdown_token.clone(),
+            close_initiated: Arc::new(AtomicBool::new(true)),
+        }
+    }
 
-        Ok(chan)
+    /// Report a channel event to the app, if it asked for them
+    fn emit(&self, event: ChannelEvent) {
+        if let Some(ref on_channel_event) = self.options.on_channel_event {
+            (on_channel_event.0)(event);
+        }
     }
 
     /// Log a message (uses callback if provided)
@@ -682,8 +1123,10 @@ impl Clone for TorClient {
             circuit_manager: self.circuit_manager.clone(),
             directory_manager: self.directory_manager.clone(),
             http_client: self.http_client.clone(),
+            dns_resolver: self.dns_resolver.clone(),
             is_initialized: self.is_initialized.clone(),
             channel: self.channel.clone(),
+            establish_lock: self.establish_lock.clone(),
             update_task: self.update_task.clone(),
             shutdown_token: self.shutdown_token.clone(),
             close_initiated: self.close_initiated.clone(),
@@ -707,6 +1150,33 @@ mod tests {
         assert!(client.is_ok());
     }
 
+    #[portable_test_async]
+    async fn test_background_handle_does_not_close_client() {
+        let options = TorClientOptions {
+            create_circuit_early: false,
+            ..TorClientOptions::new("wss://snowflake.torproject.net/".to_string())
+        };
+        let client = TorClient::new(options).await.unwrap();
+
+        drop(client.background_handle());
+        assert!(!client.close_initiated.load(Ordering::SeqCst));
+        assert!(!client.is_aborted());
+    }
+
+    #[portable_test_async]
+    async fn test_connect_rejects_invalid_targets() {
+        let options = TorClientOptions {
+            create_circuit_early: false,
+            ..TorClientOptions::new("wss://snowflake.torproject.net/".to_string())
+        };
+        let client = TorClient::new(options).await.unwrap();
+
+        let result = client.connect("example.com", 0, None).await;
+        assert!(matches!(result, Err(TorError::Configuration(_))));
+        let result = client.connect("", 443, None).await;
+        assert!(matches!(result, Err(TorError::Configuration(_))));
+    }
+
     #[portable_test_async]
     async fn test_one_time_fetch() {
         // This will fail because we don't have WASM WebSocket implementation
@@ -735,4 +1205,19 @@ mod tests {
         assert_eq!(status.ready_circuits, 0);
         assert!(!status.has_ready_circuits());
     }
+
+    #[portable_test_async]
+    async fn test_invalid_onion_client_auth_is_rejected() {
+        let options = TorClientOptions {
+            create_circuit_early: false,
+            ..TorClientOptions::new("wss://snowflake.torproject.net/".to_string())
+        }
+        .with_onion_client_auth(
+            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion",
+            "not-a-key",
+        );
+
+        let err = TorClient::new(options).await.err().unwrap();
+        assert_eq!(err.kind(), crate::error::TorErrorKind::Configuration);
+    }
 }
@@ -2,6 +2,7 @@
 
 use crate::isolation::StreamIsolationPolicy;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
 use std::time::Duration;
@@ -17,6 +18,42 @@ impl fmt::Debug for LogCallback {
     }
 }
 
+type InnerChannelEventFn = dyn Fn(ChannelEvent) + Send + Sync;
+
+#[derive(Clone)]
+pub struct ChannelEventCallback(pub Arc<InnerChannelEventFn>);
+
+impl fmt::Debug for ChannelEventCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChannelEventCallback")
+    }
+}
+
+/// Secret key for onion service client authorization
+///
+/// Base32 x25519 secret key, or a line in Tor's `.auth_private` format.
+/// Redacted from `Debug` output so logged options don't leak it.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct ClientAuthKey(String);
+
+impl ClientAuthKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    /// The key as configured
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientAuthKey(<redacted>)")
+    }
+}
+
 /// Known Snowflake bridge fingerprints (from Tor Browser defaults)
 pub const SNOWFLAKE_FINGERPRINT_PRIMARY: &str = "2B280B23E1107BB62ABFC40DDCC8824814F80A72";
 pub const SNOWFLAKE_FINGERPRINT_SECONDARY: &str = "8838024498816A039FCBBAB14E6F40A0843051FA";
@@ -93,9 +130,29 @@ pub struct TorClientOptions {
     #[serde(default)]
     pub stream_isolation: StreamIsolationPolicy,
 
+    /// Onion service client authorization keys, keyed by onion address
+    ///
+    /// Never serialized, so saved options don't carry the secrets.
+    #[serde(default, skip_serializing)]
+    pub onion_client_auth: HashMap<String, ClientAuthKey>,
+
+    /// Keep a cookie jar for HTTP requests, partitioned by isolation key
+    #[serde(default)]
+    pub cookies: bool,
+
+    /// Re-establish the channel to the bridge in the background when it dies
+    ///
+    /// When disabled, the next request reconnects instead.
+    #[serde(default = "default_auto_reconnect")]
+    pub auto_reconnect: bool,
+
     /// Optional logging callback function (for WASM bindings)
     #[serde(skip)]
     pub on_log: Option<LogCallback>,
+
+    /// Optional callback for channel loss and reconnection
+    #[serde(skip)]
+    pub on_channel_event: Option<ChannelEventCallback>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -115,6 +172,20 @@ impl std::fmt::Display for LogType {
     }
 }
 
+/// A change in the state of the channel to the bridge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ChannelEvent {
+    /// The channel died; its circuits were marked failed
+    Lost { reason: String },
+    /// Reconnection attempt `attempt` (from 1) is starting
+    Reconnecting { attempt: u32 },
+    /// The channel is back and a new circuit was built
+    Reconnected,
+    /// Every reconnection attempt failed; the next request tries again
+    ReconnectFailed { error: String },
+}
+
 impl Default for TorClientOptions {
     fn default() -> Self {
         Self {
@@ -127,7 +198,11 @@ impl Default for TorClientOptions {
             circuit_update_advance: default_circuit_update_advance(),
             bridge_fingerprint: None,
             stream_isolation: StreamIsolationPolicy::default(),
+            onion_client_auth: HashMap::new(),
+            cookies: false,
+            auto_reconnect: default_auto_reconnect(),
             on_log: None,
+            on_channel_event: None,
         }
     }
 }
@@ -152,6 +227,10 @@ fn default_circuit_update_advance() -> u64 {
     60_000 // 1 minute
 }
 
+fn default_auto_reconnect() -> bool {
+    true
+}
+
 /// Maximum number of circuits to maintain (for preemptive building)
 pub const MAX_CIRCUITS: usize = 5;
 
@@ -230,6 +309,38 @@ impl TorClientOptions {
         }
     }
 
+    /// Add a client authorization key for an onion service
+    pub fn with_onion_client_auth(
+        mut self,
+        onion_address: impl Into<String>,
+        secret_key: impl Into<String>,
+    ) -> Self {
+        self.onion_client_auth
+            .insert(onion_address.into(), ClientAuthKey::new(secret_key));
+        self
+    }
+
+    /// Enable or disable the HTTP cookie jar
+    pub fn with_cookies(mut self, enabled: bool) -> Self {
+        self.cookies = enabled;
+        self
+    }
+
+    /// Enable or disable background reconnection when the channel dies
+    pub fn with_auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// Call `callback` when the channel is lost and while it reconnects
+    pub fn with_channel_events(
+        mut self,
+        callback: impl Fn(ChannelEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_channel_event = Some(ChannelEventCallback(Arc::new(callback)));
+        self
+    }
+
     pub fn connection_timeout_duration(&self) -> Duration {
         Duration::from_millis(self.connection_timeout)
     }
@@ -246,3 +357,84 @@ impl TorClientOptions {
         Duration::from_millis(self.circuit_update_advance)
     }
 }
+
+/// Configuration for an onion service hosted by the TorClient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnionServiceConfig {
+    /// Hex-encoded 32-byte ed25519 secret key of the service identity.
+    /// A fresh identity is generated when unset; read it back from
+    /// `OnionServiceHandle::identity_key` to keep the same address.
+    #[serde(default)]
+    pub identity_key: Option<String>,
+
+    /// Number of introduction points to maintain
+    #[serde(default = "default_intro_points")]
+    pub num_intro_points: usize,
+
+    /// Virtual ports that accept streams; empty accepts every port
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+fn default_intro_points() -> usize {
+    3
+}
+
+impl Default for OnionServiceConfig {
+    fn default() -> Self {
+        Self {
+            identity_key: None,
+            num_intro_points: default_intro_points(),
+            ports: Vec::new(),
+        }
+    }
+}
+
+impl OnionServiceConfig {
+    /// Use an existing identity instead of generating one
+    pub fn with_identity_key(mut self, identity_key: impl Into<String>) -> Self {
+        self.identity_key = Some(identity_key.into());
+        self
+    }
+
+    /// Set the number of introduction points
+    pub fn with_intro_points(mut self, num_intro_points: usize) -> Self {
+        self.num_intro_points = num_intro_points;
+        self
+    }
+
+    /// Accept streams on `port`
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    /// Whether streams to `port` are accepted
+    pub fn accepts_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.contains(&port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    const SECRET: &str = "4EXZFDO6Y2HJTALUUZ6ORPDRQPB3ELPTFBV7DRSZBFMXN6NXUCAQ";
+
+    #[portable_test]
+    fn client_auth_keys_stay_out_of_debug_and_serialized_options() {
+        let options = TorClientOptions::new("wss://snowflake.torproject.net/".to_string())
+            .with_onion_client_auth("example.onion", SECRET);
+
+        assert!(!format!("{:?}", options).contains(SECRET));
+        let json = serde_json::to_string(&options).unwrap();
+        assert!(!json.contains(SECRET));
+        assert!(!json.contains("onion_client_auth"));
+
+        let mut value = serde_json::to_value(&options).unwrap();
+        value["onion_client_auth"] = serde_json::json!({ "example.onion": SECRET });
+        let parsed: TorClientOptions = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.onion_client_auth["example.onion"].expose(), SECRET);
+    }
+}
@@ -1,7 +1,8 @@
 //! Directory management and consensus fetching
 
 use crate::error::{Result, TorError};
-use crate::relay::{Relay, RelayManager};
+use crate::onion::HsDirParams;
+use crate::relay::{flags as relay_flags, BandwidthWeights, PortPolicy, Relay, RelayManager};
 use crate::time::system_time_now;
 use futures::{AsyncReadExt, AsyncWriteExt};
 use std::collections::HashMap;
@@ -10,8 +11,8 @@ use std::io::Read;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tor_checkable::Timebound;
-use tor_netdoc::doc::microdesc::MicrodescReader;
-use tor_netdoc::doc::netstatus::MdConsensus;
+use tor_netdoc::doc::microdesc::{Microdesc, MicrodescReader};
+use tor_netdoc::doc::netstatus::{MdConsensus, MdRouterStatus, RelayWeight, SharedRandStatus};
 use tor_netdoc::AllowAnnotations;
 use tor_proto::channel::Channel;
 use tor_proto::client::circuit::TimeoutEstimator;
@@ -110,81 +111,14 @@ impl DirectoryManager {
             inner_consensus.relays().len()
         );
 
-        let mut router_statuses = HashMap::new();
-        for router in inner_consensus.relays() {
-            router_statuses.insert(*router.md_digest(), router.clone());
-        }
-
-        let mut relays = Vec::new();
-        let reader =
-            MicrodescReader::new(microdescs_body, &AllowAnnotations::AnnotationsNotAllowed)?;
-        for microdesc in reader {
-            let microdesc = match microdesc {
-                Ok(md) => md.into_microdesc(),
-                Err(e) => {
-                    warn!("Failed to parse microdescriptor: {}", e);
-                    continue;
-                }
-            };
-
-            if let Some(router) = router_statuses.get(microdesc.digest()) {
-                let nickname = router.nickname().to_string();
-                let fingerprint = hex::encode(router.rsa_identity().as_bytes());
-
-                let address = if let Some(addr) = router.addrs().next() {
-                    addr.ip().to_string()
-                } else {
-                    continue;
-                };
-
-                let or_port = router.addrs().next().map(|a| a.port()).unwrap_or(0);
-
-                let mut flags = std::collections::HashSet::new();
-                if router.is_flagged_fast() {
-                    flags.insert("Fast".to_string());
-                }
-                if router.is_flagged_stable() {
-                    flags.insert("Stable".to_string());
-                }
-                if router.is_flagged_guard() {
-                    flags.insert("Guard".to_string());
-                }
-                if router.is_flagged_exit() {
-                    flags.insert("Exit".to_string());
-                }
-                if router.is_flagged_bad_exit() {
-                    flags.insert("BadExit".to_string());
-                }
-                if router.is_flagged_hsdir() {
-                    flags.insert("HSDir".to_string());
-                }
-                if router.is_flagged_v2dir() {
-                    flags.insert("V2Dir".to_string());
-                }
-
-                let ntor_onion_key = hex::encode(microdesc.ntor_key().as_bytes());
-
-                let mut relay = Relay::new(
-                    fingerprint,
-                    nickname,
-                    address,
-                    or_port,
-                    flags,
-                    ntor_onion_key,
-                );
-
-                relay.ed25519_identity = Some(hex::encode(microdesc.ed25519_id().as_bytes()));
-
-                relays.push(relay);
-            }
-        }
-
+        let relays = build_relays(inner_consensus, microdescs_body)?;
         let count = relays.len();
-
-        {
-            let mut manager = self.relay_manager.write().await;
-            manager.update_relays(relays);
-        }
+        self.install_relays(
+            relays,
+            bandwidth_weights(inner_consensus),
+            hsdir_params(inner_consensus),
+        )
+        .await;
 
         info!("Loaded {} relays from cached consensus", count);
 
@@ -219,87 +153,33 @@ impl DirectoryManager {
             microdescs_body.len()
         );
 
-        let mut router_statuses = HashMap::new();
-        for router in inner_consensus.relays() {
-            router_statuses.insert(*router.md_digest(), router.clone());
-        }
-
-        let mut relays = Vec::new();
-        let reader =
-            MicrodescReader::new(&microdescs_body, &AllowAnnotations::AnnotationsNotAllowed)?;
-        for microdesc in reader {
-            let microdesc = match microdesc {
-                Ok(md) => md.into_microdesc(),
-                Err(e) => {
-                    warn!("Failed to parse microdescriptor: {}", e);
-                    continue;
-                }
-            };
-
-            if let Some(router) = router_statuses.get(microdesc.digest()) {
-                let nickname = router.nickname().to_string();
-                let fingerprint = hex::encode(router.rsa_identity().as_bytes());
-
-                let address = if let Some(addr) = router.addrs().next() {
-                    addr.ip().to_string()
-                } else {
-                    continue;
-                };
-
-                let or_port = router.addrs().next().map(|a| a.port()).unwrap_or(0);
-
-                let mut flags = std::collections::HashSet::new();
-                if router.is_flagged_fast() {
-                    flags.insert("Fast".to_string());
-                }
-                if router.is_flagged_stable() {
-                    flags.insert("Stable".to_string());
-                }
-                if router.is_flagged_guard() {
-                    flags.insert("Guard".to_string());
-                }
-                if router.is_flagged_exit() {
-                    flags.insert("Exit".to_string());
-                }
-                if router.is_flagged_bad_exit() {
-                    flags.insert("BadExit".to_string());
-                }
-                if router.is_flagged_hsdir() {
-                    flags.insert("HSDir".to_string());
-                }
-                if router.is_flagged_v2dir() {
-                    flags.insert("V2Dir".to_string());
-                }
-
-                let ntor_onion_key = hex::encode(microdesc.ntor_key().as_bytes());
-
-                let mut relay = Relay::new(
-                    fingerprint,
-                    nickname,
-                    address,
-                    or_port,
-                    flags,
-                    ntor_onion_key,
-                );
-
-                relay.ed25519_identity = Some(hex::encode(microdesc.ed25519_id().as_bytes()));
-
-                relays.push(relay);
-            }
-        }
-
+        let relays = build_relays(inner_consensus, &microdescs_body)?;
         let count = relays.len();
-
-        {
-            let mut manager = self.relay_manager.write().await;
-            manager.update_relays(relays);
-        }
+        self.install_relays(
+            relays,
+            bandwidth_weights(inner_consensus),
+            hsdir_params(inner_consensus),
+        )
+        .await;
 
         info!("Updated RelayManager with {} relays", count);
 
         Ok(())
     }
 
+    /// Replace the relay list and consensus parameters used for path selection
+    async fn install_relays(
+        &self,
+        relays: Vec<Relay>,
+        weights: BandwidthWeights,
+        hsdir_params: HsDirParams,
+    ) {
+        let mut manager = self.relay_manager.write().await;
+        manager.update_relays(relays);
+        manager.set_bandwidth_weights(weights);
+        manager.set_hsdir_params(hsdir_params);
+    }
+
     async fn fetch_consensus_body(&self, channel: Arc<Channel>) -> Result<String> {
         info!("Fetching consensus from bridge...");
 
@@ -528,6 +408,146 @@ impl DirectoryManager {
     }
 }
 
+/// Match microdescriptors to their consensus entries and build the relay list
+fn build_relays(consensus: &MdConsensus, microdescs_body: &str) -> Result<Vec<Relay>> {
+    let mut router_statuses = HashMap::new();
+    for router in consensus.relays() {
+        router_statuses.insert(*router.md_digest(), router);
+    }
+
+    let mut relays = Vec::new();
+    let reader = MicrodescReader::new(microdescs_body, &AllowAnnotations::AnnotationsNotAllowed)?;
+    for microdesc in reader {
+        let microdesc = match microdesc {
+            Ok(md) => md.into_microdesc(),
+            Err(e) => {
+                warn!("Failed to parse microdescriptor: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(router) = router_statuses.get(microdesc.digest()) {
+            if let Some(relay) = relay_from_router(router, &microdesc) {
+                relays.push(relay);
+            }
+        }
+    }
+
+    Ok(relays)
+}
+
+/// Build a `Relay` from a consensus entry and its microdescriptor
+///
+/// Returns `None` for entries without a usable OR address.
+fn relay_from_router(router: &MdRouterStatus, microdesc: &Microdesc) -> Option<Relay> {
+    let nickname = router.nickname().to_string();
+    let fingerprint = hex::encode(router.rsa_identity().as_bytes());
+
+    let addr = router.addrs().next()?;
+    let address = addr.ip().to_string();
+    let or_port = addr.port();
+
+    let mut flags = std::collections::HashSet::new();
+    if router.is_flagged_fast() {
+        flags.insert(relay_flags::FAST.to_string());
+    }
+    if router.is_flagged_stable() {
+        flags.insert(relay_flags::STABLE.to_string());
+    }
+    if router.is_flagged_guard() {
+        flags.insert(relay_flags::GUARD.to_string());
+    }
+    if router.is_flagged_exit() {
+        flags.insert(relay_flags::EXIT.to_string());
+    }
+    if router.is_flagged_bad_exit() {
+        flags.insert(relay_flags::BAD_EXIT.to_string());
+    }
+    if router.is_flagged_hsdir() {
+        flags.insert(relay_flags::HSDIR.to_string());
+    }
+    if router.is_flagged_v2dir() {
+        flags.insert(relay_flags::V2DIR.to_string());
+    }
+
+    let ntor_onion_key = hex::encode(microdesc.ntor_key().as_bytes());
+
+    let mut relay = Relay::new(
+        fingerprint,
+        nickname,
+        address,
+        or_port,
+        flags,
+        ntor_onion_key,
+    );
+
+    relay.ed25519_identity = Some(hex::encode(microdesc.ed25519_id().as_bytes()));
+
+    // Exit port policy summaries (`p` / `p6` lines); arti renders them as
+    // "accept <ranges>" or "reject 1-65535"
+    relay.ipv4_policy = parse_port_policy(&microdesc.ipv4_policy().to_string());
+    relay.ipv6_policy = parse_port_policy(&microdesc.ipv6_policy().to_string());
+
+    // Declared family; only mutual declarations are honoured at path-building time
+    relay.family = microdesc
+        .family()
+        .members()
+        .map(|id| hex::encode(id.as_bytes()))
+        .collect();
+
+    // `w Bandwidth=` is in kilobytes per second
+    let weight = match router.weight() {
+        RelayWeight::Measured(w) | RelayWeight::Unmeasured(w) => *w,
+        _ => 0,
+    };
+    relay.consensus_weight = weight;
+    relay.bandwidth = u64::from(weight) * 1000;
+
+    Some(relay)
+}
+
+/// Parse a port policy summary, logging and dropping malformed ones
+fn parse_port_policy(summary: &str) -> Option<PortPolicy> {
+    match summary.parse() {
+        Ok(policy) => Some(policy),
+        Err(e) => {
+            debug!("Ignoring unparseable port policy {:?}: {}", summary, e);
+            None
+        }
+    }
+}
+
+/// Read the `bandwidth-weights` footer of a consensus
+fn bandwidth_weights(consensus: &MdConsensus) -> BandwidthWeights {
+    let params = consensus.bandwidth_weights();
+    BandwidthWeights::from_lookup(|name| params.get(name).copied())
+}
+
+/// Read the shared-random values and HSDir parameters of a consensus
+fn hsdir_params(consensus: &MdConsensus) -> HsDirParams {
+    let defaults = HsDirParams::default();
+    let params = consensus.params();
+    let param = |name: &str| params.get(name).copied().filter(|v| *v > 0);
+    let srv = |status: Option<&SharedRandStatus>| status.map(|s| <[u8; 32]>::from(*s.value()));
+
+    HsDirParams {
+        srv_current: srv(consensus.shared_rand_cur()),
+        srv_previous: srv(consensus.shared_rand_prev()),
+        time_period_minutes: param("hsdir_interval")
+            .and_then(|v| u32::try_from(v).ok())
+            .unwrap_or(defaults.time_period_minutes),
+        n_replicas: param("hsdir_n_replicas")
+            .and_then(|v| u8::try_from(v).ok())
+            .unwrap_or(defaults.n_replicas),
+        spread_fetch: param("hsdir_spread_fetch")
+            .and_then(|v| usize::try_from(v).ok())
+            .unwrap_or(defaults.spread_fetch),
+        spread_store: param("hsdir_spread_store")
+            .and_then(|v| usize::try_from(v).ok())
+            .unwrap_or(defaults.spread_store),
+    }
+}
+
 /// Decompress brotli-compressed data
 #[cfg(target_arch = "wasm32")]
 fn decompress_brotli(compressed: &[u8]) -> std::io::Result<String> {
@@ -0,0 +1,364 @@
+//! Hostname resolution through exit relays
+//!
+//! Lookups travel as RESOLVE cells on an exit circuit picked for the
+//! lookup's isolation key, so a name is never resolved on a circuit that
+//! belongs to another site. Answers are cached per isolation key as well:
+//! sharing them would let one site learn what another looked up.
+
+use crate::circuit::CircuitManager;
+use crate::error::{Result, TorError};
+use crate::isolation::IsolationKey;
+use crate::time::system_time_now;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+/// Longest time an answer is cached, whatever TTL the exit returned
+pub const DNS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Upper bound on cached answers across all isolation keys
+const MAX_DNS_CACHE_ENTRIES: usize = 1024;
+
+/// A cached lookup
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DnsQuery {
+    /// Hostname to addresses (lowercased, without trailing dot)
+    Forward(String),
+    /// Address to hostnames
+    Reverse(IpAddr),
+}
+
+impl DnsQuery {
+    pub fn forward(host: &str) -> Self {
+        DnsQuery::Forward(host.trim_end_matches('.').to_ascii_lowercase())
+    }
+}
+
+/// Answer to a `DnsQuery`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsAnswer {
+    Addresses(Vec<IpAddr>),
+    Names(Vec<String>),
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    answer: DnsAnswer,
+    expires_at: SystemTime,
+}
+
+/// TTL-bounded answers, scoped per isolation key
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    entries: HashMap<(Option<IsolationKey>, DnsQuery), CacheEntry>,
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The unexpired answer to `query` for `isolation_key`
+    pub fn get(
+        &self,
+        isolation_key: &Option<IsolationKey>,
+        query: &DnsQuery,
+        now: SystemTime,
+    ) -> Option<&DnsAnswer> {
+        self.entries
+            .get(&(isolation_key.clone(), query.clone()))
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| &entry.answer)
+    }
+
+    /// Remember `answer` for `ttl`
+    ///
+    /// When the cache is full, expired entries go first, then the entry
+    /// closest to expiry.
+    pub fn insert(
+        &mut self,
+        isolation_key: Option<IsolationKey>,
+        query: DnsQuery,
+        answer: DnsAnswer,
+        ttl: Duration,
+        now: SystemTime,
+    ) {
+        if self.entries.len() >= MAX_DNS_CACHE_ENTRIES {
+            self.entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if self.entries.len() >= MAX_DNS_CACHE_ENTRIES {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            (isolation_key, query),
+            CacheEntry {
+                answer,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    /// Forget everything cached for one isolation key
+    pub fn clear_isolation_key(&mut self, isolation_key: &Option<IsolationKey>) {
+        self.entries.retain(|(key, _), _| key != isolation_key);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Resolves names on isolation-appropriate exit circuits
+pub struct DnsResolver {
+    circuit_manager: Arc<RwLock<CircuitManager>>,
+    cache: RwLock<DnsCache>,
+}
+
+impl DnsResolver {
+    pub fn new(circuit_manager: Arc<RwLock<CircuitManager>>) -> Self {
+        Self {
+            circuit_manager,
+            cache: RwLock::new(DnsCache::new()),
+        }
+    }
+
+    /// Resolve `host` to its addresses
+    ///
+    /// IP literals are returned as-is without touching the network.
+    pub async fn resolve(
+        &self,
+        host: &str,
+        isolation_key: Option<IsolationKey>,
+    ) -> Result<Vec<IpAddr>> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(addr) = literal.parse::<IpAddr>() {
+            return Ok(vec![addr]);
+        }
+
+        let query = DnsQuery::forward(host);
+        if let Some(DnsAnswer::Addresses(addrs)) =
+            self.cached(&isolation_key, &query, system_time_now()).await
+        {
+            debug!("DNS cache hit for {}", host);
+            return Ok(addrs);
+        }
+
+        let circuit = self
+            .circuit_manager
+            .read()
+            .await
+            .get_circuit_for_stream(isolation_key.clone(), None)
+            .await?;
+        let answers = circuit.read().await.resolve(host).await?;
+        if answers.is_empty() {
+            return Err(TorError::dns(format!("No addresses for {}", host)));
+        }
+
+        let ttl = cache_ttl(&answers);
+        let addrs: Vec<IpAddr> = answers.into_iter().map(|(addr, _)| addr).collect();
+        self.remember(
+            isolation_key,
+            query,
+            DnsAnswer::Addresses(addrs.clone()),
+            ttl,
+        )
+        .await;
+        Ok(addrs)
+    }
+
+    /// Look up the hostnames for `addr`
+    pub async fn resolve_ptr(
+        &self,
+        addr: IpAddr,
+        isolation_key: Option<IsolationKey>,
+    ) -> Result<Vec<String>> {
+        let query = DnsQuery::Reverse(addr);
+        if let Some(DnsAnswer::Names(names)) =
+            self.cached(&isolation_key, &query, system_time_now()).await
+        {
+            debug!("DNS cache hit for {}", addr);
+            return Ok(names);
+        }
+
+        let circuit = self
+            .circuit_manager
+            .read()
+            .await
+            .get_circuit_for_stream(isolation_key.clone(), None)
+            .await?;
+        let answers = circuit.read().await.resolve_ptr(addr).await?;
+        if answers.is_empty() {
+            return Err(TorError::dns(format!("No hostnames for {}", addr)));
+        }
+
+        let ttl = cache_ttl(&answers);
+        let names: Vec<String> = answers.into_iter().map(|(name, _)| name).collect();
+        self.remember(isolation_key, query, DnsAnswer::Names(names.clone()), ttl)
+            .await;
+        Ok(names)
+    }
+
+    /// Drop all cached answers
+    pub async fn clear_cache(&self) {
+        self.cache.write().await.clear();
+    }
+
+    async fn cached(
+        &self,
+        isolation_key: &Option<IsolationKey>,
+        query: &DnsQuery,
+        now: SystemTime,
+    ) -> Option<DnsAnswer> {
+        self.cache
+            .read()
+            .await
+            .get(isolation_key, query, now)
+            .cloned()
+    }
+
+    async fn remember(
+        &self,
+        isolation_key: Option<IsolationKey>,
+        query: DnsQuery,
+        answer: DnsAnswer,
+        ttl: Duration,
+    ) {
+        self.cache
+            .write()
+            .await
+            .insert(isolation_key, query, answer, ttl, system_time_now());
+    }
+}
+
+/// How long a set of answers may be cached: the shortest TTL among them,
+/// capped at `DNS_CACHE_TTL`
+fn cache_ttl<T>(answers: &[(T, Duration)]) -> Duration {
+    answers
+        .iter()
+        .map(|(_, ttl)| *ttl)
+        .min()
+        .unwrap_or_default()
+        .min(DNS_CACHE_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+    use std::net::Ipv4Addr;
+    use std::time::UNIX_EPOCH;
+
+    fn key(name: &str) -> Option<IsolationKey> {
+        Some(IsolationKey::from_string(name))
+    }
+
+    fn addrs(last: u8) -> DnsAnswer {
+        DnsAnswer::Addresses(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))])
+    }
+
+    #[portable_test]
+    fn cached_answers_expire_after_ttl() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut cache = DnsCache::new();
+        let query = DnsQuery::forward("Example.COM.");
+        cache.insert(key("a"), query.clone(), addrs(1), DNS_CACHE_TTL, now);
+
+        assert_eq!(
+            cache.get(&key("a"), &DnsQuery::forward("example.com"), now),
+            Some(&addrs(1))
+        );
+        let later = now + DNS_CACHE_TTL - Duration::from_secs(1);
+        assert!(cache.get(&key("a"), &query, later).is_some());
+        assert!(cache.get(&key("a"), &query, now + DNS_CACHE_TTL).is_none());
+    }
+
+    #[portable_test]
+    fn cached_answers_are_scoped_to_isolation_key() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut cache = DnsCache::new();
+        let query = DnsQuery::forward("example.com");
+        cache.insert(key("a"), query.clone(), addrs(1), DNS_CACHE_TTL, now);
+        cache.insert(None, query.clone(), addrs(2), DNS_CACHE_TTL, now);
+
+        assert_eq!(cache.get(&key("a"), &query, now), Some(&addrs(1)));
+        assert_eq!(cache.get(&None, &query, now), Some(&addrs(2)));
+        assert!(cache.get(&key("b"), &query, now).is_none());
+
+        cache.clear_isolation_key(&key("a"));
+        assert!(cache.get(&key("a"), &query, now).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[portable_test]
+    fn full_cache_evicts_soonest_expiry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut cache = DnsCache::new();
+        for i in 0..MAX_DNS_CACHE_ENTRIES {
+            let ttl = DNS_CACHE_TTL + Duration::from_secs(i as u64);
+            let query = DnsQuery::forward(&format!("host{}.example", i));
+            cache.insert(None, query, addrs(1), ttl, now);
+        }
+
+        cache.insert(
+            None,
+            DnsQuery::forward("new.example"),
+            addrs(2),
+            DNS_CACHE_TTL,
+            now,
+        );
+        assert_eq!(cache.len(), MAX_DNS_CACHE_ENTRIES);
+        assert!(cache
+            .get(&None, &DnsQuery::forward("host0.example"), now)
+            .is_none());
+        assert!(cache
+            .get(&None, &DnsQuery::forward("host1.example"), now)
+            .is_some());
+        assert!(cache
+            .get(&None, &DnsQuery::forward("new.example"), now)
+            .is_some());
+    }
+
+    #[portable_test]
+    fn cache_ttl_is_shortest_answer_ttl_capped() {
+        let short = Duration::from_secs(30);
+        let answers = [("a", DNS_CACHE_TTL * 2), ("b", short), ("c", DNS_CACHE_TTL)];
+        assert_eq!(cache_ttl(&answers), short);
+
+        let long = [("a", DNS_CACHE_TTL * 12)];
+        assert_eq!(cache_ttl(&long), DNS_CACHE_TTL);
+    }
+
+    #[portable_test]
+    fn short_ttl_answers_expire_before_cap() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut cache = DnsCache::new();
+        let query = DnsQuery::forward("example.com");
+        let short = Duration::from_secs(30);
+        let ttl = cache_ttl(&[(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), short)]);
+        cache.insert(None, query.clone(), addrs(1), ttl, now);
+
+        assert!(cache
+            .get(&None, &query, now + short - Duration::from_secs(1))
+            .is_some());
+        assert!(cache.get(&None, &query, now + short).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 //! Error types for the webtor library
 
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, TorError>;
@@ -44,6 +45,44 @@ impl TorErrorKind {
     }
 }
 
+/// Phase of an HTTP request that ran out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// Getting a circuit (building one, or rendezvous for onion services)
+    Circuit,
+    /// Waiting for the exit to answer BEGIN
+    Stream,
+    /// TLS handshake with the origin
+    Tls,
+    /// Waiting for the first response byte after sending the request
+    FirstByte,
+    /// The request as a whole (`HttpRequest.timeout`)
+    Total,
+}
+
+impl TimeoutPhase {
+    /// Returns a stable string code for JS consumption
+    pub fn as_code(&self) -> &'static str {
+        match self {
+            TimeoutPhase::Circuit => "CIRCUIT_TIMEOUT",
+            TimeoutPhase::Stream => "STREAM_TIMEOUT",
+            TimeoutPhase::Tls => "TLS_TIMEOUT",
+            TimeoutPhase::FirstByte => "FIRST_BYTE_TIMEOUT",
+            TimeoutPhase::Total => "REQUEST_TIMEOUT",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            TimeoutPhase::Circuit => "circuit acquisition",
+            TimeoutPhase::Stream => "stream BEGIN",
+            TimeoutPhase::Tls => "TLS handshake",
+            TimeoutPhase::FirstByte => "time to first byte",
+            TimeoutPhase::Total => "request",
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum TorError {
     #[error("WebSocket connection failed: {0}")]
@@ -64,6 +103,12 @@ pub enum TorError {
     #[error("Relay selection failed: {0}")]
     RelaySelection(String),
 
+    #[error("Onion service error: {0}")]
+    OnionService(String),
+
+    #[error("DNS resolution failed: {0}")]
+    Dns(String),
+
     #[error("Consensus fetch failed: {0}")]
     ConsensusFetch(String),
 
@@ -76,6 +121,9 @@ pub enum TorError {
     #[error("Timeout: {0}")]
     Timeout(String),
 
+    #[error("Timeout: {} exceeded {:?}", .0.description(), .1)]
+    PhaseTimeout(TimeoutPhase, Duration),
+
     #[error("Configuration error: {0}")]
     Configuration(String),
 
@@ -135,6 +183,14 @@ impl TorError {
         TorError::RelaySelection(msg.into())
     }
 
+    pub fn onion_service(msg: impl Into<String>) -> Self {
+        TorError::OnionService(msg.into())
+    }
+
+    pub fn dns(msg: impl Into<String>) -> Self {
+        TorError::Dns(msg.into())
+    }
+
     pub fn consensus_fetch(msg: impl Into<String>) -> Self {
         TorError::ConsensusFetch(msg.into())
     }
@@ -155,6 +211,18 @@ impl TorError {
         TorError::Timeout(msg.into())
     }
 
+    pub fn phase_timeout(phase: TimeoutPhase, limit: Duration) -> Self {
+        TorError::PhaseTimeout(phase, limit)
+    }
+
+    /// The request phase that timed out, for phase-specific timeouts
+    pub fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        match self {
+            TorError::PhaseTimeout(phase, _) => Some(*phase),
+            _ => None,
+        }
+    }
+
     pub fn configuration(msg: impl Into<String>) -> Self {
         TorError::Configuration(msg.into())
     }
@@ -178,9 +246,12 @@ impl TorError {
             TorError::WebSocket(_) => TorErrorKind::Network,
             TorError::Network(_) => TorErrorKind::Network,
             TorError::Timeout(_) => TorErrorKind::Timeout,
+            TorError::PhaseTimeout(..) => TorErrorKind::Timeout,
             TorError::CircuitCreation(_) => TorErrorKind::Circuit,
             TorError::CircuitExtension(_) => TorErrorKind::Circuit,
             TorError::RelaySelection(_) => TorErrorKind::Circuit,
+            TorError::OnionService(_) => TorErrorKind::Circuit,
+            TorError::Dns(_) => TorErrorKind::Network,
             TorError::ConsensusFetch(_) => TorErrorKind::Bootstrap,
             TorError::TorProtocol(_) => TorErrorKind::Protocol,
             TorError::TlsSetup(_) => TorErrorKind::Protocol,
@@ -209,6 +280,7 @@ impl TorError {
 
             // Timeouts are retryable (might succeed with more time or less load)
             TorError::Timeout(_) => true,
+            TorError::PhaseTimeout(..) => true,
 
             // Circuit failures can be retried with different relays
             TorError::CircuitCreation(_) => true,
@@ -223,6 +295,12 @@ impl TorError {
             // Relay selection might work with different criteria or updated consensus
             TorError::RelaySelection(_) => true,
 
+            // Onion services can be reached via other HSDirs / intro points later
+            TorError::OnionService(_) => true,
+
+            // The exit answered; asking again gets the same answer
+            TorError::Dns(_) => false,
+
             // Protocol errors are usually not retryable (indicates a bug or incompatibility)
             TorError::TorProtocol(_) => false,
             TorError::TlsSetup(_) => false,
@@ -255,10 +333,13 @@ impl TorError {
             TorError::CircuitCreation(_) => "CIRCUIT_CREATION",
             TorError::CircuitExtension(_) => "CIRCUIT_EXTENSION",
             TorError::RelaySelection(_) => "RELAY_SELECTION",
+            TorError::OnionService(_) => "ONION_SERVICE",
+            TorError::Dns(_) => "DNS",
             TorError::ConsensusFetch(_) => "CONSENSUS_FETCH",
             TorError::HttpRequest(_) => "HTTP_REQUEST",
             TorError::TlsSetup(_) => "TLS_SETUP",
             TorError::Timeout(_) => "TIMEOUT",
+            TorError::PhaseTimeout(phase, _) => phase.as_code(),
             TorError::Configuration(_) => "CONFIGURATION",
             TorError::Network(_) => "NETWORK",
             TorError::Protocol(_) => "PROTOCOL",
@@ -306,6 +387,36 @@ mod tests {
                 "TIMEOUT",
                 true,
             ),
+            (
+                TorError::phase_timeout(TimeoutPhase::Circuit, Duration::from_secs(1)),
+                TorErrorKind::Timeout,
+                "CIRCUIT_TIMEOUT",
+                true,
+            ),
+            (
+                TorError::phase_timeout(TimeoutPhase::Stream, Duration::from_secs(1)),
+                TorErrorKind::Timeout,
+                "STREAM_TIMEOUT",
+                true,
+            ),
+            (
+                TorError::phase_timeout(TimeoutPhase::Tls, Duration::from_secs(1)),
+                TorErrorKind::Timeout,
+                "TLS_TIMEOUT",
+                true,
+            ),
+            (
+                TorError::phase_timeout(TimeoutPhase::FirstByte, Duration::from_secs(1)),
+                TorErrorKind::Timeout,
+                "FIRST_BYTE_TIMEOUT",
+                true,
+            ),
+            (
+                TorError::phase_timeout(TimeoutPhase::Total, Duration::from_secs(1)),
+                TorErrorKind::Timeout,
+                "REQUEST_TIMEOUT",
+                true,
+            ),
             (
                 TorError::circuit_creation("x"),
                 TorErrorKind::Circuit,
@@ -324,6 +435,13 @@ mod tests {
                 "RELAY_SELECTION",
                 true,
             ),
+            (
+                TorError::onion_service("x"),
+                TorErrorKind::Circuit,
+                "ONION_SERVICE",
+                true,
+            ),
+            (TorError::dns("x"), TorErrorKind::Network, "DNS", false),
             (
                 TorError::consensus_fetch("x"),
                 TorErrorKind::Bootstrap,
@@ -0,0 +1,745 @@
+//! HPACK header compression for HTTP/2 (RFC 7541)
+//!
+//! The decoder understands everything a server may send: indexed fields,
+//! literals with or without indexing, dynamic table size updates and
+//! Huffman-coded strings. The encoder only emits literals without indexing,
+//! so the server never has to track a table for us; request headers are
+//! small enough that this costs little.
+
+use crate::error::{Result, TorError};
+use std::collections::VecDeque;
+
+/// Dynamic table size we allow the server, the protocol default
+pub(crate) const MAX_TABLE_SIZE: usize = 4096;
+
+/// Largest string we decode, so a bogus length can't make us allocate
+const MAX_STRING_SIZE: usize = 64 * 1024;
+
+/// A decoded header: name and value as sent
+pub(crate) type HeaderField = (Vec<u8>, Vec<u8>);
+
+/// Static table (Appendix A); index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code and bit length for each octet, then EOS (Appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// Number of Huffman codes of each bit length
+const HUFFMAN_COUNTS: [u16; 31] = huffman_counts();
+
+/// Symbols in code order; the code is canonical, so ordering by length
+/// then symbol gives consecutive codes
+const HUFFMAN_SYMBOLS: [u16; 257] = huffman_symbols();
+
+const fn huffman_counts() -> [u16; 31] {
+    let mut counts = [0; 31];
+    let mut symbol = 0;
+    while symbol < HUFFMAN_CODES.len() {
+        counts[HUFFMAN_CODES[symbol].1 as usize] += 1;
+        symbol += 1;
+    }
+    counts
+}
+
+const fn huffman_symbols() -> [u16; 257] {
+    let mut symbols = [0; 257];
+    let mut next = 0;
+    let mut length = 1;
+    while length < HUFFMAN_COUNTS.len() {
+        let mut symbol = 0;
+        while symbol < HUFFMAN_CODES.len() {
+            if HUFFMAN_CODES[symbol].1 as usize == length {
+                symbols[next] = symbol as u16;
+                next += 1;
+            }
+            symbol += 1;
+        }
+        length += 1;
+    }
+    symbols
+}
+
+/// Header block decoder, one per connection
+///
+/// Blocks must be decoded in the order they arrive, since each one may
+/// change the dynamic table the next one refers to.
+pub(crate) struct Decoder {
+    /// Newest entry first
+    table: VecDeque<HeaderField>,
+    /// Sum of entry sizes as RFC 7541 section 4.1 counts them
+    size: usize,
+    /// Current limit, lowered or raised by size updates from the server
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: MAX_TABLE_SIZE,
+        }
+    }
+
+    /// Decode a complete header block
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<HeaderField>> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+
+        while pos < block.len() {
+            let first = block[pos];
+            if first & 0x80 != 0 {
+                // Indexed field
+                let index = decode_int(block, &mut pos, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal with incremental indexing
+                let field = self.literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update; only allowed before any field
+                if !fields.is_empty() {
+                    return Err(hpack_error("table size update after a header"));
+                }
+                let size = decode_int(block, &mut pos, 5)?;
+                if size > MAX_TABLE_SIZE {
+                    return Err(hpack_error("table size update above our limit"));
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // Literal without indexing (0000) or never indexed (0001)
+                fields.push(self.literal(block, &mut pos, 4)?);
+            }
+        }
+
+        Ok(fields)
+    }
+
+    /// Look up a field by its 1-based index across both tables
+    fn entry(&self, index: usize) -> Result<HeaderField> {
+        if index == 0 {
+            return Err(hpack_error("index 0"));
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.table
+            .get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or_else(|| hpack_error(format!("index {} out of range", index)))
+    }
+
+    /// Read a literal field whose name index has a `prefix`-bit integer
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<HeaderField> {
+        let name = match decode_int(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.entry(index)?.0,
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, field: HeaderField) {
+        let size = entry_size(&field);
+        // An entry larger than the table empties it and isn't added
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drop the oldest entries until `incoming` more bytes fit
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.max_size {
+            let Some(oldest) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&oldest);
+        }
+    }
+}
+
+/// Encode a header block from literals without indexing
+///
+/// Names found in the static table are sent by index; nothing is
+/// Huffman-coded.
+pub(crate) fn encode(fields: &[HeaderField]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        match STATIC_TABLE
+            .iter()
+            .position(|(static_name, _)| static_name.as_bytes() == name.as_slice())
+        {
+            Some(index) => encode_int(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// Size of a table entry: its name and value plus 32 bytes of overhead
+fn entry_size((name, value): &HeaderField) -> usize {
+    name.len() + value.len() + 32
+}
+
+fn hpack_error(msg: impl std::fmt::Display) -> TorError {
+    TorError::Protocol(format!("HPACK: {}", msg))
+}
+
+/// Decode an integer with a `prefix`-bit prefix (section 5.1)
+fn decode_int(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize> {
+    let mask = (1u8 << prefix) - 1;
+    let first = *block
+        .get(*pos)
+        .ok_or_else(|| hpack_error("truncated integer"))?;
+    *pos += 1;
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let byte = *block
+            .get(*pos)
+            .ok_or_else(|| hpack_error("truncated integer"))?;
+        *pos += 1;
+        if shift > 21 {
+            return Err(hpack_error("integer too large"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Encode `value` with a `prefix`-bit prefix, OR-ing `flags` into the first byte
+fn encode_int(out: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decode a string literal, Huffman-coded or raw (section 5.2)
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let huffman = block
+        .get(*pos)
+        .map(|byte| byte & 0x80 != 0)
+        .ok_or_else(|| hpack_error("truncated string"))?;
+    let len = decode_int(block, pos, 7)?;
+    if len > MAX_STRING_SIZE {
+        return Err(hpack_error("string too long"));
+    }
+    let bytes = block
+        .get(*pos..*pos + len)
+        .ok_or_else(|| hpack_error("truncated string"))?;
+    *pos += len;
+    if huffman {
+        huffman_decode(bytes)
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+fn encode_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    encode_int(out, 0x00, 7, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+/// Decode a Huffman-coded string, checking its padding
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 5);
+    // Canonical decoding: `code` is compared against the first code of
+    // each length in turn
+    let mut code = 0u32;
+    let mut first = 0u32;
+    let mut index = 0usize;
+    let mut length = 0usize;
+    // Whether every bit since the last symbol was 1, as padding must be
+    let mut all_ones = true;
+
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            let bit = ((byte >> shift) & 1) as u32;
+            code |= bit;
+            all_ones &= bit == 1;
+            length += 1;
+
+            let count = HUFFMAN_COUNTS[length] as u32;
+            if code < first + count {
+                let symbol = HUFFMAN_SYMBOLS[index + (code - first) as usize];
+                if symbol == 256 {
+                    return Err(hpack_error("EOS in Huffman string"));
+                }
+                out.push(symbol as u8);
+                code = 0;
+                first = 0;
+                index = 0;
+                length = 0;
+                all_ones = true;
+                continue;
+            }
+
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+            if length == HUFFMAN_COUNTS.len() - 1 {
+                return Err(hpack_error("invalid Huffman code"));
+            }
+        }
+    }
+
+    // Up to 7 bits of the EOS prefix may pad the last byte
+    if length > 7 || !all_ones {
+        return Err(hpack_error("invalid Huffman padding"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn field(name: &str, value: &str) -> HeaderField {
+        (name.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    #[portable_test]
+    fn test_decode_huffman_request() {
+        // RFC 7541 C.4.1
+        let fields = Decoder::new()
+            .decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"))
+            .unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                field(":method", "GET"),
+                field(":scheme", "http"),
+                field(":path", "/"),
+                field(":authority", "www.example.com"),
+            ]
+        );
+    }
+
+    #[portable_test]
+    fn test_decode_responses_with_eviction() {
+        // RFC 7541 C.6, after shrinking the table to 256 bytes
+        let mut decoder = Decoder::new();
+        let first = decoder
+            .decode(&hex(concat!(
+                "3fe101",
+                "488264025885aec3771a4b6196d07abe941054d444a8200595040b8166e082a6",
+                "2d1bff6e919d29ad171863c78f0b97c8e9ae82ae43d3"
+            )))
+            .unwrap();
+        assert_eq!(first[0], field(":status", "302"));
+        assert_eq!(first[3], field("location", "https://www.example.com"));
+        assert_eq!(decoder.size, 222);
+
+        let second = decoder.decode(&hex("4883640effc1c0bf")).unwrap();
+        assert_eq!(second[0], field(":status", "307"));
+        assert_eq!(second[1..], first[1..]);
+        assert_eq!(decoder.size, 222);
+
+        let third = decoder
+            .decode(&hex(concat!(
+                "88c16196d07abe941054d444a8200595040b8166e084a62d1bffc05a839bd9ab",
+                "77ad94e7821dd7f2e6c7b335dfdfcd5b3960d5af27087f3672c1ab270fb5291f",
+                "9587316065c003ed4ee5b1063d5007"
+            )))
+            .unwrap();
+        assert_eq!(third[0], field(":status", "200"));
+        assert_eq!(third[2], field("date", "Mon, 21 Oct 2013 20:13:22 GMT"));
+        assert_eq!(
+            third[5],
+            field(
+                "set-cookie",
+                "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"
+            )
+        );
+        assert_eq!(decoder.size, 215);
+        assert_eq!(decoder.table.len(), 3);
+    }
+
+    #[portable_test]
+    fn test_encode_round_trip() {
+        let fields = vec![
+            field(":method", "POST"),
+            field(
+                ":path",
+                "/a/very/long/path/that/needs/a/multi-byte/length/prefix",
+            ),
+            field("x-custom", "1"),
+            field("accept", "*/*"),
+        ];
+        let block = encode(&fields);
+        // Static names go by index: `:method` is entry 2
+        assert_eq!(&block[..2], &[0x02, 0x04]);
+        assert_eq!(Decoder::new().decode(&block).unwrap(), fields);
+    }
+
+    #[portable_test]
+    fn test_decode_rejects_bad_input() {
+        let mut decoder = Decoder::new();
+        // Index 0, an index past both tables, and a truncated literal
+        assert!(decoder.decode(&[0x80]).is_err());
+        assert!(decoder.decode(&[0xff, 0x00]).is_err());
+        assert!(decoder.decode(&[0x40, 0x05, b'a']).is_err());
+        // Table size above what we advertised
+        assert!(decoder.decode(&hex("3fe21f")).is_err());
+        // Huffman padding of zeros instead of ones
+        assert!(decoder.decode(&hex("0081f00000")).is_err());
+    }
+}
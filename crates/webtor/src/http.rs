@@ -1,28 +1,108 @@
 //! HTTP client for making requests through Tor circuits
 
-use crate::circuit::CircuitManager;
+use crate::circuit::{Circuit, CircuitManager};
 use crate::config::{CIRCUIT_PREBUILD_AGE_THRESHOLD_MS, MAX_CIRCUITS};
-use crate::error::{Result, TorError};
+use crate::error::{Result, TimeoutPhase, TorError, TorErrorKind};
+use crate::hpack::HeaderField;
+use crate::http2::Http2Connection;
+use crate::http_conn::{
+    has_token, joined_header, BodyReader, Exchange, HttpConnection, HttpStream, ResponseHead,
+};
+use crate::http_cookie::{merge_cookie_header, restore_cookie_header, CookieJar};
+use crate::http_pool::{ConnectionPool, PoolKey};
+use crate::http_redirect::{next_request, RedirectPolicy};
+use crate::http_stream::{ContentDecoder, RequestBody, StreamingResponse};
 use crate::isolation::{IsolationKey, StreamIsolationPolicy};
+use crate::onion::{is_onion_host, OnionClient};
+use crate::retry::{with_cancellation, with_timeout, CancellationToken};
+use crate::time::Instant;
 #[cfg(not(target_arch = "wasm32"))]
 use crate::tls::wrap_with_tls;
-use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::tor_websocket::{handshake_url, TorWebSocket, WebSocketOptions};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
 use http::Method;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::sync::RwLock;
+use tor_proto::client::stream::DataStream;
 use tracing::{debug, info, warn};
-use url::Url;
+use url::{Position, Url};
+
+/// Content codings offered to servers and decoded transparently
+const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Headers sent unless the request sets them
+const DEFAULT_HEADERS: [(HeaderName, &str); 3] = [
+    (header::USER_AGENT, "webtor-rs/0.1.0"),
+    (header::ACCEPT, "*/*"),
+    (header::ACCEPT_ENCODING, ACCEPT_ENCODING),
+];
+
+/// Protocols offered in the TLS handshake, most preferred first
+const ALPN_PROTOCOLS: &[&str] = &["h2", "http/1.1"];
+
+/// Headers that only mean something to an HTTP/1.1 connection
+const CONNECTION_HEADERS: [HeaderName; 5] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// How long to wait for 100 Continue before sending the body anyway
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Largest body a compressed response may expand to
+const MAX_DECODED_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Compressed bytes decoded at a time when a body is read into memory
+const DECODE_PIECE_SIZE: usize = 4096;
+
+/// Per-phase deadlines for an HTTP request
+///
+/// Every phase is also bounded by what is left of `HttpRequest.timeout`;
+/// when that runs out first the error reports `TimeoutPhase::Total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpTimeouts {
+    /// Getting a circuit, including rendezvous for onion services
+    pub circuit: Duration,
+    /// Exit answering BEGIN with CONNECTED
+    pub stream: Duration,
+    /// TLS handshake with the origin
+    pub tls: Duration,
+    /// First response byte after the request is sent
+    pub first_byte: Duration,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> Self {
+        Self {
+            circuit: Duration::from_secs(25),
+            stream: Duration::from_secs(10),
+            tls: Duration::from_secs(10),
+            first_byte: Duration::from_secs(15),
+        }
+    }
+}
 
 /// HTTP request configuration
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub url: Url,
     pub method: Method,
-    pub headers: HashMap<String, String>,
+    /// Sent in order after the defaults, names title-cased on the wire
+    pub headers: HeaderMap,
     pub body: Option<Vec<u8>>,
+    /// Deadline for the whole request
     pub timeout: Duration,
+    pub phase_timeouts: HttpTimeouts,
+    /// Which 3xx responses are followed
+    pub redirect: RedirectPolicy,
+    /// Cancels the request, and the body of a streaming response, when
+    /// fired; see `TorClient::child_token`
+    pub cancellation: Option<CancellationToken>,
 }
 
 impl HttpRequest {
@@ -30,77 +110,230 @@ impl HttpRequest {
         Self {
             url,
             method: Method::GET,
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             body: None,
             timeout: Duration::from_secs(30),
+            phase_timeouts: HttpTimeouts::default(),
+            redirect: RedirectPolicy::default(),
+            cancellation: None,
         }
     }
 
-    pub fn add_header(&mut self, key: &str, value: &str) {
-        self.headers.insert(key.to_string(), value.to_string());
+    /// Append a header, keeping earlier values with the same name
+    pub fn add_header(&mut self, key: &str, value: &str) -> Result<()> {
+        let name = HeaderName::from_bytes(key.as_bytes())
+            .map_err(|e| TorError::http_request(format!("Invalid header name {:?}: {}", key, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| TorError::http_request(format!("Invalid value for {}: {}", key, e)))?;
+        self.headers.append(name, value);
+        Ok(())
     }
 
     /// Build the HTTP request as raw bytes
     fn build_request(&self, host: &str) -> Vec<u8> {
-        let path = if self.url.path().is_empty() {
-            "/"
-        } else {
-            self.url.path()
-        };
+        let body_length = self.body.as_ref().map(|body| body.len() as u64);
+        let mut bytes = self.build_head(host, body_length.map(Some)).into_bytes();
 
-        let query = self
-            .url
-            .query()
-            .map(|q| format!("?{}", q))
-            .unwrap_or_default();
+        // Add body if present
+        if let Some(ref body) = self.body {
+            bytes.extend_from_slice(body);
+        }
 
+        bytes
+    }
+
+    /// Build the request line and headers
+    ///
+    /// `body` is `Some` when a body follows, holding its length if known;
+    /// without one the body is sent chunked.
+    fn build_head(&self, host: &str, body: Option<Option<u64>>) -> String {
         let mut request = format!(
-            "{} {}{} HTTP/1.1\r\nHost: {}\r\n",
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
             self.method.as_str(),
-            path,
-            query,
+            self.target(),
             host
         );
 
         // Add default headers if not present
-        if !self.headers.contains_key("User-Agent") && !self.headers.contains_key("user-agent") {
-            request.push_str("User-Agent: webtor-rs/0.1.0\r\n");
-        }
-        if !self.headers.contains_key("Accept") && !self.headers.contains_key("accept") {
-            request.push_str("Accept: */*\r\n");
-        }
-        if !self.headers.contains_key("Connection") && !self.headers.contains_key("connection") {
-            request.push_str("Connection: close\r\n");
+        for (name, value) in &DEFAULT_HEADERS {
+            if !self.headers.contains_key(name) {
+                request.push_str(&format!("{}: {}\r\n", title_case(name), value));
+            }
         }
 
         // Add custom headers
-        for (key, value) in &self.headers {
-            request.push_str(&format!("{}: {}\r\n", key, value));
+        for (name, value) in &self.headers {
+            request.push_str(&format!(
+                "{}: {}\r\n",
+                title_case(name),
+                String::from_utf8_lossy(value.as_bytes())
+            ));
         }
 
-        // Add content-length for POST/PUT requests with body
-        if let Some(ref body) = self.body {
-            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        // Frame the body for POST/PUT requests
+        match body {
+            Some(Some(length)) => request.push_str(&format!("Content-Length: {}\r\n", length)),
+            Some(None) => request.push_str("Transfer-Encoding: chunked\r\n"),
+            None => {}
         }
 
         // End headers
         request.push_str("\r\n");
+        request
+    }
 
-        let mut bytes = request.into_bytes();
+    /// Header fields for HTTP/2, the request line turned into pseudo-headers
+    ///
+    /// Connection-specific headers are left out, as HTTP/2 forbids them.
+    fn build_fields(&self, body_length: Option<u64>) -> Vec<HeaderField> {
+        let field = |name: &str, value: &[u8]| (name.as_bytes().to_vec(), value.to_vec());
+        let mut fields = vec![
+            field(":method", self.method.as_str().as_bytes()),
+            field(":scheme", self.url.scheme().as_bytes()),
+            field(
+                ":authority",
+                self.url[Position::BeforeHost..Position::AfterPort].as_bytes(),
+            ),
+            field(":path", self.target().as_bytes()),
+        ];
+        for (name, value) in &DEFAULT_HEADERS {
+            if !self.headers.contains_key(name) {
+                fields.push(field(name.as_str(), value.as_bytes()));
+            }
+        }
+        for (name, value) in &self.headers {
+            let forbidden = CONNECTION_HEADERS.contains(name)
+                || *name == header::HOST
+                || (*name == header::TE && value.as_bytes() != b"trailers");
+            if !forbidden {
+                fields.push(field(name.as_str(), value.as_bytes()));
+            }
+        }
+        if let Some(length) = body_length {
+            fields.push(field("content-length", length.to_string().as_bytes()));
+        }
+        fields
+    }
 
-        // Add body if present
-        if let Some(ref body) = self.body {
-            bytes.extend_from_slice(body);
+    /// Path and query, as sent in the request line
+    fn target(&self) -> String {
+        let path = if self.url.path().is_empty() {
+            "/"
+        } else {
+            self.url.path()
+        };
+        match self.url.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
         }
+    }
 
-        bytes
+    /// Whether the caller asked to wait for 100 Continue before the body
+    fn expects_continue(&self) -> bool {
+        has_token(&self.headers, header::EXPECT, "100-continue")
+    }
+}
+
+/// `content-type` as `Content-Type`, the casing servers are used to seeing
+pub(crate) fn title_case(name: &HeaderName) -> String {
+    name.as_str()
+        .split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Clock for one request: the overall deadline plus per-phase limits
+///
+/// Also carries the request's cancellation token, since every wait goes
+/// through `run`.
+pub(crate) struct RequestDeadline {
+    started: Instant,
+    total: Duration,
+    phases: HttpTimeouts,
+    cancellation: Option<CancellationToken>,
+}
+
+impl RequestDeadline {
+    fn new(request: &HttpRequest) -> Self {
+        let mut deadline = Self::with_timeouts(request.timeout, request.phase_timeouts);
+        deadline.cancellation = request.cancellation.clone();
+        deadline
+    }
+
+    pub(crate) fn with_timeouts(total: Duration, phases: HttpTimeouts) -> Self {
+        Self {
+            started: Instant::now(),
+            total,
+            phases,
+            cancellation: None,
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.total.saturating_sub(self.started.elapsed())
     }
+
+    /// Run `future` as `phase`, failing with that phase's timeout error
+    ///
+    /// Timeouts raised inside `future` pass through unchanged, so only the
+    /// deadline set here is reported as this phase.
+    pub(crate) async fn run<F, T>(&self, phase: TimeoutPhase, future: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let limit = match phase {
+            TimeoutPhase::Circuit => self.phases.circuit,
+            TimeoutPhase::Stream => self.phases.stream,
+            TimeoutPhase::Tls => self.phases.tls,
+            TimeoutPhase::FirstByte => self.phases.first_byte,
+            TimeoutPhase::Total => Duration::MAX,
+        };
+        let remaining = self.remaining();
+        let (phase, budget, limit) = if limit < remaining {
+            (phase, limit, limit)
+        } else {
+            (TimeoutPhase::Total, remaining, self.total)
+        };
+
+        // Boxed so the timeout and cancellation layers below don't each
+        // make room for the whole request state
+        let future = Box::pin(future);
+        let timed = async {
+            match with_timeout(budget, phase.as_code(), async { Ok(future.await) }).await {
+                Ok(result) => result,
+                Err(_) => Err(TorError::phase_timeout(phase, limit)),
+            }
+        };
+        match &self.cancellation {
+            Some(token) => with_cancellation(token, timed).await,
+            None => timed.await,
+        }
+    }
+}
+
+/// A sent request whose response head has arrived
+struct SentRequest {
+    connection: Exchange,
+    head: ResponseHead,
+    pool_key: PoolKey,
+    /// Whether the caller allows the connection to be reused
+    keep_alive: bool,
 }
 
 /// HTTP client that routes requests through Tor circuits
 pub struct TorHttpClient {
     circuit_manager: Arc<RwLock<CircuitManager>>,
+    onion_client: Arc<OnionClient>,
     isolation_policy: StreamIsolationPolicy,
+    pool: Arc<ConnectionPool>,
+    cookie_jar: Option<Arc<CookieJar>>,
 }
 
 impl TorHttpClient {
@@ -109,369 +342,743 @@ impl TorHttpClient {
         isolation_policy: StreamIsolationPolicy,
     ) -> Self {
         Self {
+            onion_client: Arc::new(OnionClient::new(circuit_manager.clone())),
             circuit_manager,
             isolation_policy,
+            pool: Arc::new(ConnectionPool::new()),
+            cookie_jar: None,
         }
     }
 
-    /// Make an HTTP request through Tor
-    pub async fn request(&self, request: HttpRequest) -> Result<HttpResponse> {
-        info!(
-            "Making {} request to {} through Tor",
-            request.method, request.url
-        );
+    /// Send and store cookies, partitioned by isolation key, through `jar`
+    pub fn with_cookie_jar(mut self, jar: Arc<CookieJar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
 
-        // Parse URL to get host and port
-        let url = request.url.clone();
-        let host = url
-            .host_str()
-            .ok_or_else(|| TorError::http_request("Invalid URL: no host"))?
-            .to_string();
+    /// The cookie jar, if cookies are enabled
+    pub fn cookie_jar(&self) -> Option<&Arc<CookieJar>> {
+        self.cookie_jar.as_ref()
+    }
 
-        let port = url
-            .port_or_known_default()
-            .ok_or_else(|| TorError::http_request("Invalid URL: no port"))?;
+    /// Client used for `.onion` hosts
+    pub fn onion_client(&self) -> &Arc<OnionClient> {
+        &self.onion_client
+    }
 
-        let is_https = url.scheme() == "https";
+    /// Open a raw stream to `host:port`
+    ///
+    /// Clearnet hosts go through an exit circuit bound to `isolation_key`
+    /// whose exit accepts `port`; `.onion` hosts go through a rendezvous
+    /// circuit to the onion service.
+    pub async fn open_stream(
+        &self,
+        host: &str,
+        port: u16,
+        isolation_key: Option<IsolationKey>,
+    ) -> Result<DataStream> {
+        if is_onion_host(host) {
+            return self.onion_client.connect(host, port, isolation_key).await;
+        }
 
-        debug!("Target: {}:{} (HTTPS: {})", host, port, is_https);
+        let circuit = self
+            .circuit_manager
+            .read()
+            .await
+            .get_circuit_for_stream(isolation_key, Some(port))
+            .await?;
+        let circuit_read = circuit.read().await;
+        circuit_read.begin_stream(host, port).await
+    }
 
-        // Compute isolation key based on policy
-        let isolation_key = IsolationKey::from_url(&url, self.isolation_policy);
-        if let Some(ref key) = isolation_key {
-            debug!(
-                "Using isolation key: {} (policy: {:?})",
-                key, self.isolation_policy
-            );
+    /// `open_stream` with the circuit and BEGIN phases under `deadline`
+    ///
+    /// Also returns the circuit carrying the stream, for clearnet hosts.
+    async fn open_stream_within(
+        &self,
+        host: &str,
+        port: u16,
+        isolation_key: Option<IsolationKey>,
+        deadline: &RequestDeadline,
+    ) -> Result<(DataStream, Option<Weak<RwLock<Circuit>>>)> {
+        if is_onion_host(host) {
+            // Rendezvous and BEGIN happen together inside the onion client
+            let stream = deadline
+                .run(
+                    TimeoutPhase::Circuit,
+                    self.onion_client.connect(host, port, isolation_key),
+                )
+                .await?;
+            return Ok((stream, None));
         }
 
-        // Get a circuit for this isolation key
-        let circuit_manager = self.circuit_manager.read().await;
-        let circuit = circuit_manager
-            .get_circuit_for_isolation_key(isolation_key)
+        let mut retried = false;
+        loop {
+            let circuit = deadline
+                .run(TimeoutPhase::Circuit, async {
+                    self.circuit_manager
+                        .read()
+                        .await
+                        .get_circuit_for_stream(isolation_key.clone(), Some(port))
+                        .await
+                })
+                .await?;
+            let begun = deadline
+                .run(TimeoutPhase::Stream, async {
+                    circuit.read().await.begin_stream(host, port).await
+                })
+                .await;
+            match begun {
+                Ok(stream) => return Ok((stream, Some(Arc::downgrade(&circuit)))),
+                // The circuit died under us; it is skipped from now on, so
+                // the next lookup builds or finds another for this key
+                Err(e)
+                    if !retried && retries_on_new_circuit(&e) && fail_if_dead(&circuit).await =>
+                {
+                    warn!("Circuit died while opening a stream to {}: {}", host, e);
+                    retried = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Open a new connection to `host:port`, with TLS for https
+    ///
+    /// `alpn_protocols` are offered in the TLS handshake.
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        is_https: bool,
+        alpn_protocols: &[&str],
+        isolation_key: Option<IsolationKey>,
+        deadline: &RequestDeadline,
+    ) -> Result<HttpConnection> {
+        // Open a stream on a circuit for this isolation key whose exit
+        // accepts the port (or a rendezvous circuit for onion services)
+        #[allow(unused_mut)]
+        let (stream, mut circuit) = self
+            .open_stream_within(host, port, isolation_key.clone(), deadline)
             .await?;
 
-        // Begin stream on the circuit
+        if !is_https {
+            return Ok(HttpConnection::new(
+                HttpStream::Plain(Box::new(stream)),
+                circuit,
+            ));
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
         let stream = {
-            let circuit_read = circuit.read().await;
-            circuit_read.begin_stream(&host, port).await?
+            // Wrap stream with TLS using rustls
+            let tls_stream = deadline
+                .run(
+                    TimeoutPhase::Tls,
+                    wrap_with_tls(stream, host, alpn_protocols),
+                )
+                .await?;
+            HttpStream::Tls(Box::new(tls_stream))
         };
 
-        // Build the HTTP request
-        let request_bytes = request.build_request(&host);
-        debug!("Sending {} bytes of HTTP request", request_bytes.len());
-
-        // Execute request with or without TLS
-        let response_bytes = if is_https {
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                // Wrap stream with TLS using rustls
-                let tls_stream = wrap_with_tls(stream, &host).await?;
-                execute_http_request(tls_stream, &request_bytes).await?
-            }
-            #[cfg(target_arch = "wasm32")]
-            {
-                // Use subtle-tls for WASM (SubtleCrypto-based TLS)
-                use subtle_tls::{TlsConfig, TlsConnector, TlsVersion};
-
-                let config = TlsConfig {
-                    skip_verification: false,
-                    alpn_protocols: vec!["http/1.1".to_string()],
-                    version: TlsVersion::Tls13,
-                };
-                let connector = TlsConnector::with_config(config);
-
-                // Try TLS 1.3 first
-                match connector.connect(stream, &host).await {
-                    Ok(mut tls_stream) => {
-                        info!(
-                            "TLS 1.3 connection established with {} (WASM/SubtleCrypto)",
-                            host
-                        );
-                        execute_http_request_wasm(&mut tls_stream, &request_bytes).await?
-                    }
-                    Err(tls13_err) => {
-                        warn!(
-                            "TLS 1.3 handshake failed with {}: {}, trying TLS 1.2...",
-                            host, tls13_err
-                        );
-
-                        // Get a new stream for TLS 1.2 retry
-                        let stream_tls12 = {
-                            let circuit_write = circuit.write().await;
-                            circuit_write.begin_stream(&host, port).await?
-                        };
+        #[cfg(target_arch = "wasm32")]
+        let stream = {
+            // Use subtle-tls for WASM (SubtleCrypto-based TLS)
+            use subtle_tls::{TlsConfig, TlsConnector, TlsVersion};
+
+            let config = TlsConfig {
+                skip_verification: false,
+                alpn_protocols: alpn_protocols.iter().map(|p| p.to_string()).collect(),
+                version: TlsVersion::Tls13,
+            };
+            let connector = TlsConnector::with_config(config);
+
+            // Try TLS 1.3 first
+            let tls13 = deadline
+                .run(TimeoutPhase::Tls, async {
+                    Ok(connector.connect(stream, host).await)
+                })
+                .await?;
+            match tls13 {
+                Ok(tls_stream) => {
+                    info!(
+                        "TLS 1.3 connection established with {} (WASM/SubtleCrypto)",
+                        host
+                    );
+                    HttpStream::Tls13(Box::new(tls_stream))
+                }
+                Err(tls13_err) => {
+                    warn!(
+                        "TLS 1.3 handshake failed with {}: {}, trying TLS 1.2...",
+                        host, tls13_err
+                    );
 
-                        // Try TLS 1.2
-                        let config_tls12 = TlsConfig {
-                            skip_verification: false,
-                            alpn_protocols: vec!["http/1.1".to_string()],
-                            version: TlsVersion::Tls12,
-                        };
-                        let connector_tls12 = TlsConnector::with_config(config_tls12);
-
-                        match connector_tls12.connect_tls12(stream_tls12, &host).await {
-                            Ok(mut tls_stream) => {
-                                info!(
-                                    "TLS 1.2 connection established with {} (WASM/SubtleCrypto)",
-                                    host
-                                );
-                                execute_http_request_wasm_tls12(&mut tls_stream, &request_bytes)
-                                    .await?
-                            }
-                            Err(tls12_err) => {
-                                warn!("TLS 1.2 handshake also failed with {}: {}", host, tls12_err);
-                                return Err(TorError::tls(format!(
-                                    "TLS handshake failed - TLS 1.3: {}, TLS 1.2: {}",
-                                    tls13_err, tls12_err
-                                )));
-                            }
+                    // Get a new stream for TLS 1.2 retry
+                    let (stream_tls12, circuit_tls12) = self
+                        .open_stream_within(host, port, isolation_key, deadline)
+                        .await?;
+                    circuit = circuit_tls12;
+
+                    // Try TLS 1.2, which only carries HTTP/1.1 here
+                    let config_tls12 = TlsConfig {
+                        skip_verification: false,
+                        alpn_protocols: vec!["http/1.1".to_string()],
+                        version: TlsVersion::Tls12,
+                    };
+                    let connector_tls12 = TlsConnector::with_config(config_tls12);
+
+                    let tls12 = deadline
+                        .run(TimeoutPhase::Tls, async {
+                            Ok(connector_tls12.connect_tls12(stream_tls12, host).await)
+                        })
+                        .await?;
+                    match tls12 {
+                        Ok(tls_stream) => {
+                            info!(
+                                "TLS 1.2 connection established with {} (WASM/SubtleCrypto)",
+                                host
+                            );
+                            HttpStream::Tls12(Box::new(tls_stream))
+                        }
+                        Err(tls12_err) => {
+                            warn!("TLS 1.2 handshake also failed with {}: {}", host, tls12_err);
+                            return Err(TorError::tls(format!(
+                                "TLS handshake failed - TLS 1.3: {}, TLS 1.2: {}",
+                                tls13_err, tls12_err
+                            )));
                         }
                     }
                 }
             }
-        } else {
-            execute_http_request(stream, &request_bytes).await?
         };
 
-        info!("Received {} bytes of HTTP response", response_bytes.len());
+        Ok(HttpConnection::new(stream, circuit))
+    }
 
-        // Trigger preemptive circuit building after successful request
+    /// Build a spare circuit in the background if the pool is getting old
+    pub async fn maybe_prebuild_circuit(&self) {
         let age_threshold = Duration::from_millis(CIRCUIT_PREBUILD_AGE_THRESHOLD_MS);
-        circuit_manager
+        self.circuit_manager
+            .read()
+            .await
             .maybe_prebuild_circuit(MAX_CIRCUITS, age_threshold)
             .await;
-
-        // Parse the HTTP response
-        parse_http_response(&response_bytes, request.url)
     }
 
-    /// Convenience method for GET requests
-    pub async fn get(&self, url: &str) -> Result<HttpResponse> {
-        let url = Url::parse(url)?;
-        let request = HttpRequest::new(url);
-        self.request(request).await
+    /// Number of idle keep-alive and shared HTTP/2 connections ready for reuse
+    pub fn idle_connections(&self) -> usize {
+        self.pool.len()
     }
 
-    /// Convenience method for POST requests
-    pub async fn post(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse> {
-        let url = Url::parse(url)?;
-        let mut request = HttpRequest::new(url);
-        request.method = Method::POST;
-        request.body = Some(body);
-        self.request(request).await
+    /// Close all idle keep-alive connections and stop sharing HTTP/2 ones
+    pub fn clear_connection_pool(&self) {
+        self.pool.clear();
     }
-}
 
-/// Trait for TLS streams that support async read/write operations
-#[cfg(target_arch = "wasm32")]
-#[async_trait::async_trait(?Send)]
-trait WasmTlsStream {
-    async fn tls_write(&mut self, buf: &[u8]) -> std::io::Result<usize>;
-    async fn tls_flush(&mut self) -> std::io::Result<()>;
-    async fn tls_read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
-}
+    /// Make an HTTP request through Tor
+    ///
+    /// `request.timeout` bounds the whole exchange and `request.phase_timeouts`
+    /// each step of it; expiry fails with `TorError::PhaseTimeout`.
+    ///
+    /// Connections are kept alive and reused by later requests with the same
+    /// isolation key and origin.
+    pub async fn request(&self, request: HttpRequest) -> Result<HttpResponse> {
+        self.request_inner(request, None).await
+    }
 
-#[cfg(target_arch = "wasm32")]
-#[async_trait::async_trait(?Send)]
-impl<S> WasmTlsStream for subtle_tls::TlsStream<S>
-where
-    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
-{
-    async fn tls_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.write(buf).await
+    /// Make an HTTP request with a streamed body, ignoring `request.body`
+    ///
+    /// Set an `Expect: 100-continue` header to hold the body back until the
+    /// server agrees to take it.
+    pub async fn request_with_body(
+        &self,
+        request: HttpRequest,
+        body: RequestBody,
+    ) -> Result<HttpResponse> {
+        self.request_inner(request, Some(body)).await
     }
-    async fn tls_flush(&mut self) -> std::io::Result<()> {
-        self.flush().await
+
+    async fn request_inner(
+        &self,
+        request: HttpRequest,
+        body: Option<RequestBody>,
+    ) -> Result<HttpResponse> {
+        let deadline = RequestDeadline::new(&request);
+        let (
+            request,
+            SentRequest {
+                mut connection,
+                head,
+                pool_key,
+                keep_alive,
+            },
+            redirects,
+        ) = self.send_following(request, body, &deadline).await?;
+
+        let (body, reusable) = connection
+            .read_body(&head, &request.method, &deadline)
+            .await?;
+        if reusable && keep_alive {
+            self.pool.put(pool_key, connection);
+        }
+
+        info!(
+            "Received HTTP {} with {} body bytes",
+            head.status,
+            body.len()
+        );
+
+        // Trigger preemptive circuit building after successful request
+        self.maybe_prebuild_circuit().await;
+
+        let mut response = finish_response(head, body, request.url)?;
+        response.redirects = redirects;
+        Ok(response)
     }
-    async fn tls_read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.read(buf).await
+
+    /// Make an HTTP request through Tor, returning once the headers arrive
+    ///
+    /// The body is read from the returned response as it comes in, without
+    /// the 1MB limit of `request`. `request.timeout` still covers the whole
+    /// transfer, so raise it for large downloads.
+    pub async fn request_streaming(&self, request: HttpRequest) -> Result<StreamingResponse> {
+        self.request_streaming_inner(request, None).await
     }
-}
 
-#[cfg(target_arch = "wasm32")]
-#[async_trait::async_trait(?Send)]
-impl<S> WasmTlsStream for subtle_tls::TlsStream12<S>
-where
-    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
-{
-    async fn tls_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.write(buf).await
+    /// `request_streaming` with a streamed body, ignoring `request.body`
+    pub async fn request_streaming_with_body(
+        &self,
+        request: HttpRequest,
+        body: RequestBody,
+    ) -> Result<StreamingResponse> {
+        self.request_streaming_inner(request, Some(body)).await
     }
-    async fn tls_flush(&mut self) -> std::io::Result<()> {
-        self.flush().await
+
+    async fn request_streaming_inner(
+        &self,
+        request: HttpRequest,
+        body: Option<RequestBody>,
+    ) -> Result<StreamingResponse> {
+        let deadline = RequestDeadline::new(&request);
+        let (
+            request,
+            SentRequest {
+                connection,
+                head,
+                pool_key,
+                keep_alive,
+            },
+            redirects,
+        ) = self.send_following(request, body, &deadline).await?;
+
+        info!("Received HTTP {} headers, streaming body", head.status);
+        self.maybe_prebuild_circuit().await;
+
+        let reader = BodyReader::new(head.framing(&request.method));
+        let pool = (keep_alive && head.keeps_alive()).then(|| (self.pool.clone(), pool_key));
+        Ok(StreamingResponse::new(
+            head,
+            request.url,
+            redirects,
+            connection,
+            reader,
+            deadline,
+            pool,
+        ))
     }
-    async fn tls_read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.read(buf).await
+
+    /// Open a WebSocket to a `ws://` or `wss://` URL through Tor
+    ///
+    /// The socket gets a connection of its own, on a circuit chosen by the
+    /// isolation policy like any request to the same origin. Redirects
+    /// aren't followed.
+    pub async fn websocket(&self, url: Url, mut options: WebSocketOptions) -> Result<TorWebSocket> {
+        info!("Opening WebSocket to {} through Tor", url);
+        let http_url = handshake_url(&url)?;
+        let host = http_url
+            .host_str()
+            .ok_or_else(|| TorError::http_request("Invalid URL: no host"))?
+            .to_string();
+        let port = http_url
+            .port_or_known_default()
+            .ok_or_else(|| TorError::http_request("Invalid URL: no port"))?;
+        let is_https = http_url.scheme() == "https";
+        let isolation_key = IsolationKey::from_url(&http_url, self.isolation_policy);
+        let deadline = RequestDeadline::with_timeouts(options.timeout, options.phase_timeouts);
+
+        // The upgrade has to happen on HTTP/1.1
+        let connection = self
+            .connect(
+                &host,
+                port,
+                is_https,
+                &["http/1.1"],
+                isolation_key.clone(),
+                &deadline,
+            )
+            .await?;
+
+        if let Some(cookies) = self
+            .cookie_jar
+            .as_ref()
+            .and_then(|jar| jar.header_for(isolation_key.as_ref(), &http_url))
+        {
+            merge_cookie_header(&mut options.headers, &cookies);
+        }
+        let socket = TorWebSocket::handshake(connection, url, options, &deadline).await?;
+        if let Some(jar) = &self.cookie_jar {
+            jar.store(isolation_key.as_ref(), &http_url, socket.headers());
+        }
+
+        self.maybe_prebuild_circuit().await;
+        Ok(socket)
     }
-}
 
-/// Execute an HTTP request over a TLS stream in WASM using async methods
-#[cfg(target_arch = "wasm32")]
-async fn execute_http_request_wasm<T: WasmTlsStream>(
-    tls_stream: &mut T,
-    request_bytes: &[u8],
-) -> Result<Vec<u8>> {
-    tls_stream
-        .tls_write(request_bytes)
-        .await
-        .map_err(|e| TorError::http_request(format!("Failed to write request: {}", e)))?;
-    tls_stream
-        .tls_flush()
-        .await
-        .map_err(|e| TorError::http_request(format!("Failed to flush request: {}", e)))?;
-
-    let mut response_bytes = Vec::new();
-    let mut buf = [0u8; 8192];
-
-    loop {
-        match tls_stream.tls_read(&mut buf).await {
-            Ok(0) => break,
-            Ok(n) => {
-                response_bytes.extend_from_slice(&buf[..n]);
-                debug!("Read {} bytes (total: {})", n, response_bytes.len());
-
-                if response_bytes.len() > 1024 * 1024 {
-                    warn!("Response exceeds 1MB limit, truncating");
-                    break;
-                }
+    /// `send`, following redirects as `request.redirect` allows
+    ///
+    /// Returns the request that got the final response, along with that
+    /// response and the URLs that redirected to it.
+    async fn send_following(
+        &self,
+        mut request: HttpRequest,
+        mut body: Option<RequestBody>,
+        deadline: &RequestDeadline,
+    ) -> Result<(HttpRequest, SentRequest, Vec<Url>)> {
+        let policy = request.redirect;
+        let first_key = IsolationKey::from_url(&request.url, self.isolation_policy);
+        let mut redirects = Vec::new();
+
+        loop {
+            let isolation_key = if policy.keep_circuit {
+                first_key.clone()
+            } else {
+                IsolationKey::from_url(&request.url, self.isolation_policy)
+            };
+
+            // Stored cookies go out with any the caller set, for this hop only
+            let own_cookies = self
+                .cookie_jar
+                .as_ref()
+                .and_then(|jar| jar.header_for(isolation_key.as_ref(), &request.url))
+                .map(|cookies| merge_cookie_header(&mut request.headers, &cookies));
+            let sent = self
+                .send(&request, &mut body, isolation_key.clone(), deadline)
+                .await;
+            if let Some(own_cookies) = own_cookies {
+                restore_cookie_header(&mut request.headers, own_cookies);
             }
-            Err(e) => {
-                if response_bytes.is_empty() {
-                    return Err(TorError::http_request(format!(
-                        "Failed to read response: {}",
-                        e
-                    )));
-                }
-                debug!("Read ended with error (may be normal close): {}", e);
-                break;
+            let mut sent = sent?;
+            if let Some(jar) = &self.cookie_jar {
+                jar.store(isolation_key.as_ref(), &request.url, &sent.head.headers);
+            }
+
+            let status = sent.head.status;
+            let location = sent
+                .head
+                .headers
+                .get(header::LOCATION)
+                .and_then(|value| value.to_str().ok());
+            let next = match location {
+                Some(location) if policy.max_hops > 0 => next_request(&request, status, location),
+                _ => None,
+            };
+            let Some(next) = next else {
+                return Ok((request, sent, redirects));
+            };
+            if !next.keeps_body {
+                body = None;
+            } else if !body.as_ref().is_none_or(RequestBody::is_replayable) {
+                debug!(
+                    "Not following {} redirect: the body can't be resent",
+                    status
+                );
+                return Ok((request, sent, redirects));
+            }
+            if redirects.len() >= policy.max_hops {
+                return Err(TorError::http_request(format!(
+                    "Too many redirects (limit {})",
+                    policy.max_hops
+                )));
             }
+
+            // Drain the redirect's body so its connection can be reused
+            let (_, reusable) = sent
+                .connection
+                .read_body(&sent.head, &request.method, deadline)
+                .await?;
+            if reusable && sent.keep_alive {
+                self.pool.put(sent.pool_key, sent.connection);
+            }
+
+            info!("Following {} redirect to {}", status, next.request.url);
+            redirects.push(std::mem::replace(&mut request, next.request).url);
         }
     }
 
-    Ok(response_bytes)
-}
+    /// Send `request` on a pooled or new connection and read the response head
+    ///
+    /// `body` overrides `request.body`; it is left in place so a redirect
+    /// can send it again.
+    async fn send(
+        &self,
+        request: &HttpRequest,
+        body: &mut Option<RequestBody>,
+        isolation_key: Option<IsolationKey>,
+        deadline: &RequestDeadline,
+    ) -> Result<SentRequest> {
+        info!(
+            "Making {} request to {} through Tor",
+            request.method, request.url
+        );
 
-/// Wrapper for TLS 1.2 streams to use with execute_http_request_wasm
-#[cfg(target_arch = "wasm32")]
-async fn execute_http_request_wasm_tls12<S>(
-    tls_stream: &mut subtle_tls::TlsStream12<S>,
-    request_bytes: &[u8],
-) -> Result<Vec<u8>>
-where
-    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
-{
-    execute_http_request_wasm(tls_stream, request_bytes).await
-}
+        // Parse URL to get host and port
+        let url = &request.url;
+        let host = url
+            .host_str()
+            .ok_or_else(|| TorError::http_request("Invalid URL: no host"))?
+            .to_string();
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| TorError::http_request("Invalid URL: no port"))?;
+
+        let is_https = url.scheme() == "https";
+
+        debug!("Target: {}:{} (HTTPS: {})", host, port, is_https);
 
-/// Execute an HTTP request over a stream and return the response bytes
-async fn execute_http_request<S>(mut stream: S, request_bytes: &[u8]) -> Result<Vec<u8>>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-{
-    // Write the request
-    stream
-        .write_all(request_bytes)
-        .await
-        .map_err(|e| TorError::http_request(format!("Failed to write request: {}", e)))?;
-    stream
-        .flush()
-        .await
-        .map_err(|e| TorError::http_request(format!("Failed to flush request: {}", e)))?;
-
-    // Read the response
-    let mut response_bytes = Vec::new();
-    let mut buf = [0u8; 8192];
-
-    loop {
-        match stream.read(&mut buf).await {
-            Ok(0) => break, // EOF
-            Ok(n) => {
-                response_bytes.extend_from_slice(&buf[..n]);
-                debug!("Read {} bytes (total: {})", n, response_bytes.len());
-
-                // Limit response size to 1MB for safety
-                if response_bytes.len() > 1024 * 1024 {
-                    warn!("Response exceeds 1MB limit, truncating");
-                    break;
+        if let Some(ref key) = isolation_key {
+            debug!(
+                "Using isolation key: {} (policy: {:?})",
+                key, self.isolation_policy
+            );
+        }
+        let pool_key = PoolKey::new(url, isolation_key.clone())
+            .ok_or_else(|| TorError::http_request("Invalid URL: no host"))?;
+
+        // An in-memory body goes out with the head unless it has to wait
+        // for 100 Continue
+        let expect_continue = request.expects_continue();
+        let mut inline_body = expect_continue
+            .then(|| request.body.clone().map(RequestBody::from))
+            .flatten();
+        let mut body = body.as_mut().or(inline_body.as_mut());
+
+        let wants_close = has_token(&request.headers, header::CONNECTION, "close");
+        let mut circuit_retried = false;
+
+        loop {
+            let (mut connection, reused) = self
+                .checkout(
+                    &pool_key,
+                    &host,
+                    port,
+                    is_https,
+                    isolation_key.clone(),
+                    deadline,
+                )
+                .await?;
+
+            let sent: Result<(ResponseHead, bool)> = async {
+                match &mut connection {
+                    Exchange::Http1(connection) => {
+                        let request_bytes = match &body {
+                            Some(body) => {
+                                request.build_head(&host, Some(body.length())).into_bytes()
+                            }
+                            None => request.build_request(&host),
+                        };
+                        debug!("Sending {} bytes of HTTP request", request_bytes.len());
+                        connection.start_request(&request_bytes, deadline).await?;
+                    }
+                    Exchange::Http2(stream) => {
+                        let (length, inline) = match &body {
+                            Some(body) => (body.length(), None),
+                            None => {
+                                let inline = request.body.as_deref();
+                                (inline.map(|body| body.len() as u64), inline)
+                            }
+                        };
+                        let fields = request.build_fields(length);
+                        stream
+                            .start_request(&fields, inline, body.is_some(), deadline)
+                            .await?;
+                    }
+                }
+                if let Some(body) = body.as_deref_mut() {
+                    if expect_continue {
+                        if let Some(head) = connection
+                            .wait_for_continue(EXPECT_CONTINUE_TIMEOUT, deadline)
+                            .await?
+                        {
+                            // Refused before the body; the connection is now
+                            // out of step, so it won't be reused
+                            debug!("Server answered {} before the body", head.status);
+                            return Ok((head, false));
+                        }
+                    }
+                    body.write_to(&mut connection, deadline).await?;
                 }
+                Ok((connection.read_response_head(deadline).await?, true))
             }
-            Err(e) => {
-                if response_bytes.is_empty() {
-                    return Err(TorError::http_request(format!(
-                        "Failed to read response: {}",
-                        e
-                    )));
+            .await;
+
+            match sent {
+                Ok((head, body_sent)) => {
+                    return Ok(SentRequest {
+                        connection,
+                        head,
+                        pool_key,
+                        keep_alive: !wants_close && body_sent,
+                    })
+                }
+                // The server closed the connection while it sat idle; the
+                // request never reached it, so send it again on a new one
+                Err(e)
+                    if reused
+                        && !connection.received_response()
+                        && request.method.is_idempotent()
+                        && body.as_deref().is_none_or(RequestBody::is_replayable)
+                        && !matches!(e.kind(), TorErrorKind::Timeout | TorErrorKind::Cancelled) =>
+                {
+                    debug!("Pooled connection to {} went stale: {}", host, e);
+                }
+                // The circuit died before the server answered; send the
+                // request again once, on a fresh circuit for the same key
+                Err(e)
+                    if !circuit_retried
+                        && !connection.received_response()
+                        && request.method.is_idempotent()
+                        && body.as_deref().is_none_or(RequestBody::is_replayable)
+                        && retries_on_new_circuit(&e)
+                        && !connection.circuit_alive() =>
+                {
+                    warn!("Circuit to {} died mid-request: {}", host, e);
+                    circuit_retried = true;
                 }
-                // We have some data, maybe connection was closed
-                debug!("Read ended with error (may be normal close): {}", e);
-                break;
+                Err(e) => return Err(e),
             }
         }
     }
 
-    Ok(response_bytes)
-}
-
-/// Parse raw HTTP response bytes into HttpResponse
-fn parse_http_response(data: &[u8], url: Url) -> Result<HttpResponse> {
-    // Find the header/body separator
-    let header_end = find_subsequence(data, b"\r\n\r\n")
-        .ok_or_else(|| TorError::http_request("Invalid HTTP response: no header separator"))?;
+    /// Take a pooled exchange for `pool_key`, or connect
+    ///
+    /// Until an https origin's protocol is known, connections to it are made
+    /// one at a time so a burst of requests can share one HTTP/2 connection.
+    /// The flag says whether the connection was reused.
+    async fn checkout(
+        &self,
+        pool_key: &PoolKey,
+        host: &str,
+        port: u16,
+        is_https: bool,
+        isolation_key: Option<IsolationKey>,
+        deadline: &RequestDeadline,
+    ) -> Result<(Exchange, bool)> {
+        if let Some(exchange) = self.pool.checkout(pool_key) {
+            return Ok((exchange, true));
+        }
 
-    let header_bytes = &data[..header_end];
-    let body = data[header_end + 4..].to_vec();
+        let gate = self.pool.connect_gate(pool_key);
+        let mut guard = None;
+        if let Some(gate) = &gate {
+            guard = Some(
+                deadline
+                    .run(TimeoutPhase::Total, async { Ok(gate.lock().await) })
+                    .await?,
+            );
+            // Whoever held the gate may have left a connection to share
+            if let Some(exchange) = self.pool.checkout(pool_key) {
+                return Ok((exchange, true));
+            }
+            if self.pool.is_http1_only(pool_key) {
+                guard = None;
+            }
+        }
 
-    let header_str = std::str::from_utf8(header_bytes)
-        .map_err(|e| TorError::http_request(format!("Invalid HTTP headers: {}", e)))?;
+        let connection = self
+            .connect(
+                host,
+                port,
+                is_https,
+                ALPN_PROTOCOLS,
+                isolation_key,
+                deadline,
+            )
+            .await?;
+        if !connection.is_http2() {
+            if is_https {
+                self.pool.learned_http1(pool_key);
+            }
+            return Ok((Exchange::Http1(connection), false));
+        }
 
-    let mut lines = header_str.lines();
+        info!("Negotiated HTTP/2 with {}", host);
+        let shared = Http2Connection::handshake(connection, deadline).await?;
+        self.pool.share(pool_key.clone(), shared.clone());
+        drop(guard);
+        Ok((Exchange::Http2(shared.stream()), false))
+    }
 
-    // Parse status line: "HTTP/1.1 200 OK"
-    let status_line = lines
-        .next()
-        .ok_or_else(|| TorError::http_request("Invalid HTTP response: no status line"))?;
+    /// Convenience method for GET requests
+    pub async fn get(&self, url: &str) -> Result<HttpResponse> {
+        let url = Url::parse(url)?;
+        let request = HttpRequest::new(url);
+        self.request(request).await
+    }
 
-    let parts: Vec<&str> = status_line.splitn(3, ' ').collect();
-    if parts.len() < 2 {
-        return Err(TorError::http_request("Invalid HTTP status line"));
+    /// Convenience method for POST requests
+    pub async fn post(&self, url: &str, body: Vec<u8>) -> Result<HttpResponse> {
+        let url = Url::parse(url)?;
+        let mut request = HttpRequest::new(url);
+        request.method = Method::POST;
+        request.body = Some(body);
+        self.request(request).await
     }
+}
 
-    let status: u16 = parts[1]
-        .parse()
-        .map_err(|e| TorError::http_request(format!("Invalid status code: {}", e)))?;
+/// Whether a failure is worth one more try on another circuit
+///
+/// Timeouts and cancellation aren't; the deadline or caller has spoken.
+fn retries_on_new_circuit(e: &TorError) -> bool {
+    !matches!(e.kind(), TorErrorKind::Timeout | TorErrorKind::Cancelled)
+}
 
-    // Parse headers
-    let mut headers = HashMap::new();
-    for line in lines {
-        if let Some((key, value)) = line.split_once(':') {
-            headers.insert(key.trim().to_lowercase(), value.trim().to_string());
-        }
+/// Mark a circuit failed if it is no longer ready, saying whether it was
+///
+/// Circuits join the list ready, so one that isn't has died since.
+async fn fail_if_dead(circuit: &RwLock<Circuit>) -> bool {
+    let mut circuit = circuit.write().await;
+    if circuit.is_ready() {
+        return false;
     }
+    circuit.mark_failed();
+    true
+}
 
-    // Decode body based on Transfer-Encoding or Content-Length
-    // Per HTTP/1.1 semantics: Transfer-Encoding takes precedence over Content-Length
+/// Build the response from a head and its transfer-decoded body
+fn finish_response(head: ResponseHead, body: Vec<u8>, url: Url) -> Result<HttpResponse> {
+    let ResponseHead {
+        status,
+        mut headers,
+        ..
+    } = head;
     let mut decoded_body = body;
 
-    let is_chunked = headers
-        .get("transfer-encoding")
-        .map(|te| te.to_ascii_lowercase().contains("chunked"))
-        .unwrap_or(false);
-
-    if is_chunked {
-        debug!("Decoding chunked transfer-encoding");
-        decoded_body = decode_chunked_body(&decoded_body)
-            .map_err(|e| TorError::http_request(format!("Failed to decode chunked body: {}", e)))?;
-    } else if let Some(cl) = headers.get("content-length") {
-        // Only enforce Content-Length for non-chunked responses
-        if let Ok(len) = cl.parse::<usize>() {
-            if decoded_body.len() > len {
+    // Undo Content-Encoding; the headers then describe the decoded body
+    let mut decompressed = false;
+    if let Some(encoding) = joined_header(&headers, header::CONTENT_ENCODING) {
+        if !decoded_body.is_empty() {
+            if let Some(body) = decode_content(&decoded_body, &encoding)? {
                 debug!(
-                    "Body longer than Content-Length ({} > {}), truncating",
+                    "Decoded {} body: {} -> {} bytes",
+                    encoding,
                     decoded_body.len(),
-                    len
+                    body.len()
                 );
-                decoded_body.truncate(len);
+                decoded_body = body;
+                decompressed = true;
+                headers.remove(header::CONTENT_ENCODING);
+                headers.remove(header::CONTENT_LENGTH);
             }
         }
     }
@@ -488,123 +1095,67 @@ fn parse_http_response(data: &[u8], url: Url) -> Result<HttpResponse> {
         headers,
         body: decoded_body,
         url,
+        redirects: Vec::new(),
+        decompressed,
     })
 }
 
-/// Find the position of a subsequence in a byte slice
-fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+/// The codings listed in a `Content-Encoding` header, in order of application
+///
+/// `None` if there is nothing to undo or a coding we can't decode is listed.
+pub(crate) fn content_codings(encoding: &str) -> Option<Vec<String>> {
+    let codings: Vec<String> = encoding
+        .split(',')
+        .map(|c| c.trim().to_ascii_lowercase())
+        .filter(|c| !c.is_empty() && c != "identity")
+        .collect();
+    let supported = codings
+        .iter()
+        .all(|c| matches!(c.as_str(), "gzip" | "x-gzip" | "deflate" | "br"));
+    (!codings.is_empty() && supported).then_some(codings)
 }
 
-/// Decode a chunked transfer-encoded body into plain bytes
-fn decode_chunked_body(body: &[u8]) -> std::result::Result<Vec<u8>, String> {
-    let mut result = Vec::new();
-    let mut i = 0;
-
-    loop {
-        // Skip any leading whitespace/CRLF (some servers send extra)
-        while i < body.len() && (body[i] == b'\r' || body[i] == b'\n' || body[i] == b' ') {
-            i += 1;
-        }
-
-        if i >= body.len() {
-            break;
-        }
-
-        // Find end of chunk-size line
-        let line_start = i;
-        let mut line_end_opt = None;
-        while i + 1 < body.len() {
-            if body[i] == b'\r' && body[i + 1] == b'\n' {
-                line_end_opt = Some(i);
-                break;
-            }
-            i += 1;
-        }
-        let line_end = match line_end_opt {
-            Some(end) => end,
-            None => {
-                // No CRLF found - might be incomplete or malformed
-                // If we have some data already, return what we have
-                if !result.is_empty() {
-                    debug!("Incomplete chunk size line, returning partial result");
-                    break;
-                }
-                return Err("Incomplete chunk size line".into());
-            }
-        };
-
-        let line = &body[line_start..line_end];
-
-        // Parse hex size, ignoring any ";extensions"
-        let size_str = match std::str::from_utf8(line) {
-            Ok(s) => s.split(';').next().unwrap_or("").trim(),
-            Err(_) => return Err("Chunk size line is not valid UTF-8".into()),
-        };
-
-        // Handle empty line (shouldn't happen but be defensive)
-        if size_str.is_empty() {
-            i = line_end + 2;
-            continue;
-        }
-
-        let size = match usize::from_str_radix(size_str, 16) {
-            Ok(s) => s,
-            Err(e) => {
-                // If we already have data and hit parse error, might be trailing garbage
-                if !result.is_empty() {
-                    debug!(
-                        "Failed to parse chunk size '{}', returning partial result: {}",
-                        size_str, e
-                    );
-                    break;
-                }
-                return Err(format!("Invalid chunk size '{}': {}", size_str, e));
-            }
-        };
-
-        // Move past "\r\n"
-        i = line_end + 2;
-
-        // Size 0 means end of chunks
-        if size == 0 {
-            break;
-        }
-
-        // Ensure enough bytes for this chunk
-        if i + size > body.len() {
-            // Partial chunk - take what we can
-            let available = body.len() - i;
-            debug!(
-                "Chunk extends beyond body length (need {}, have {}), taking available",
-                size, available
-            );
-            result.extend_from_slice(&body[i..]);
-            break;
-        }
-
-        // Copy chunk bytes
-        result.extend_from_slice(&body[i..i + size]);
-        i += size;
+/// Decode a body sent with the given `Content-Encoding`
+///
+/// Codings are undone in reverse order of application. Returns `None` when
+/// the header lists a coding we don't support, leaving the body as sent.
+fn decode_content(body: &[u8], encoding: &str) -> Result<Option<Vec<u8>>> {
+    let Some(codings) = content_codings(encoding) else {
+        return Ok(None);
+    };
+
+    let decoder = ContentDecoder::new(&codings).with_limit(MAX_DECODED_BODY_SIZE);
+    decode_all(decoder, body)
+        .map(Some)
+        .map_err(|e| TorError::http_request(format!("Failed to decode {} body: {}", encoding, e)))
+}
 
-        // Each chunk is followed by "\r\n"
-        if i + 1 < body.len() && body[i] == b'\r' && body[i + 1] == b'\n' {
-            i += 2;
-        }
+/// Feed a whole body through `decoder`
+///
+/// Small pieces let a size limit stop a decompression bomb well short of
+/// its full size.
+fn decode_all(mut decoder: ContentDecoder, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for piece in body.chunks(DECODE_PIECE_SIZE) {
+        data.extend(decoder.decode(piece)?);
     }
-
-    Ok(result)
+    data.extend(decoder.finish()?);
+    Ok(data)
 }
 
 /// HTTP response from Tor
 #[derive(Debug, Clone)]
 pub struct HttpResponse {
     pub status: u16,
-    pub headers: HashMap<String, String>,
+    /// Headers in the order received, repeated names such as `Set-Cookie` kept
+    pub headers: HeaderMap,
     pub body: Vec<u8>,
+    /// Final URL, after any redirects
     pub url: Url,
+    /// URLs that answered with a redirect, in the order they were followed
+    pub redirects: Vec<Url>,
+    /// Whether `body` was decoded from a `Content-Encoding`
+    pub decompressed: bool,
 }
 
 impl HttpResponse {
@@ -627,11 +1178,29 @@ impl HttpResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http_conn::{ChunkedDecoder, ScriptedStream};
+    use crate::http_stream::tests::compress;
     use crate::relay::{flags, Relay, RelayManager};
     use crate::test_util::{portable_test, portable_test_async};
     use std::sync::Arc;
     use tokio::sync::RwLock;
 
+    /// Decode a whole chunked body, keeping what arrived if it is cut short
+    fn decode_chunked_body(body: &[u8]) -> std::result::Result<Vec<u8>, String> {
+        let mut decoded = Vec::new();
+        ChunkedDecoder::new().decode(body, &mut decoded)?;
+        Ok(decoded)
+    }
+
+    /// Read one response from a connection that sends `data` and closes
+    async fn read_response(data: &[u8], url: Url) -> Result<HttpResponse> {
+        let mut connection = HttpConnection::new(ScriptedStream::replying([data]), None);
+        let deadline = deadline(Duration::from_secs(5), HttpTimeouts::default());
+        let head = connection.read_response_head(&deadline).await?;
+        let (body, _) = connection.read_body(&head, &Method::GET, &deadline).await?;
+        finish_response(head, body, url)
+    }
+
     fn create_test_relay(fingerprint: &str, flags: Vec<&str>) -> Relay {
         Relay::new(
             fingerprint.to_string(),
@@ -648,23 +1217,116 @@ mod tests {
         let url = Url::parse("https://httpbin.org/ip").unwrap();
         let mut request = HttpRequest::new(url.clone());
         request.method = Method::GET;
-        request.add_header("User-Agent", "Webtor/0.1.0");
+        request.add_header("User-Agent", "Webtor/0.1.0").unwrap();
         request.timeout = Duration::from_secs(30);
 
         assert_eq!(request.url, url);
         assert_eq!(request.method, Method::GET);
-        assert_eq!(
-            request.headers.get("User-Agent"),
-            Some(&"Webtor/0.1.0".to_string())
-        );
+        assert_eq!(request.headers.get("user-agent").unwrap(), "Webtor/0.1.0");
+        assert!(request.add_header("Bad Name", "x").is_err());
+        assert!(request.add_header("X-Bad", "a\r\nInjected: 1").is_err());
         assert_eq!(request.timeout, Duration::from_secs(30));
     }
 
+    #[portable_test_async]
+    async fn test_fail_if_dead_only_fails_dead_circuits() {
+        use crate::circuit::CircuitStatus;
+
+        let mut ready = Circuit::new("ready".to_string(), None);
+        ready.status = CircuitStatus::Ready;
+        let ready = RwLock::new(ready);
+        assert!(!fail_if_dead(&ready).await);
+        assert!(ready.read().await.is_ready());
+
+        let mut closed = Circuit::new("closed".to_string(), None);
+        closed.status = CircuitStatus::Closed;
+        let closed = RwLock::new(closed);
+        assert!(fail_if_dead(&closed).await);
+        assert!(closed.read().await.is_closed());
+
+        assert!(retries_on_new_circuit(&TorError::network("circuit closed")));
+        assert!(!retries_on_new_circuit(&TorError::Cancelled));
+    }
+
+    fn deadline(total: Duration, phases: HttpTimeouts) -> RequestDeadline {
+        let mut request = HttpRequest::new(Url::parse("http://example.com/").unwrap());
+        request.timeout = total;
+        request.phase_timeouts = phases;
+        RequestDeadline::new(&request)
+    }
+
+    #[portable_test_async]
+    async fn test_phase_deadline_reports_phase() {
+        let phases = HttpTimeouts {
+            first_byte: Duration::from_millis(10),
+            ..HttpTimeouts::default()
+        };
+        let deadline = deadline(Duration::from_secs(30), phases);
+
+        let err = deadline
+            .run(
+                TimeoutPhase::FirstByte,
+                futures::future::pending::<Result<()>>(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::FirstByte));
+        assert_eq!(err.code(), "FIRST_BYTE_TIMEOUT");
+    }
+
+    #[portable_test_async]
+    async fn test_total_deadline_caps_phases() {
+        let deadline = deadline(Duration::from_millis(10), HttpTimeouts::default());
+
+        let err = deadline
+            .run(
+                TimeoutPhase::Circuit,
+                futures::future::pending::<Result<()>>(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Total));
+        assert_eq!(err.code(), "REQUEST_TIMEOUT");
+    }
+
+    #[portable_test_async]
+    async fn test_cancelled_request_stops_waiting() {
+        let parent = CancellationToken::new();
+        let mut request = HttpRequest::new(Url::parse("http://example.com/").unwrap());
+        request.cancellation = Some(parent.child_token());
+        let deadline = RequestDeadline::new(&request);
+
+        let pending = deadline.run(
+            TimeoutPhase::FirstByte,
+            futures::future::pending::<Result<()>>(),
+        );
+        futures::pin_mut!(pending);
+        assert!(futures::poll!(pending.as_mut()).is_pending());
+        parent.cancel();
+        assert!(matches!(pending.await, Err(TorError::Cancelled)));
+    }
+
+    #[portable_test_async]
+    async fn test_inner_timeouts_pass_through() {
+        let deadline = deadline(Duration::from_secs(30), HttpTimeouts::default());
+
+        let err = deadline
+            .run(TimeoutPhase::Stream, async {
+                Err::<(), _>(TorError::timeout("begin"))
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TorError::Timeout(_)));
+
+        let ok = deadline.run(TimeoutPhase::Tls, async { Ok(7) }).await;
+        assert_eq!(ok.unwrap(), 7);
+    }
+
     #[portable_test]
     fn test_build_request() {
         let url = Url::parse("http://example.com/path?query=1").unwrap();
         let mut request = HttpRequest::new(url);
-        request.add_header("X-Custom", "value");
+        request.add_header("X-Custom", "value").unwrap();
 
         let bytes = request.build_request("example.com");
         let request_str = String::from_utf8(bytes).unwrap();
@@ -676,17 +1338,28 @@ mod tests {
     }
 
     #[portable_test]
-    fn test_parse_http_response() {
+    fn test_build_request_keeps_header_order_and_repeats() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut request = HttpRequest::new(url);
+        request.add_header("x-b", "1").unwrap();
+        request.add_header("X-A", "2").unwrap();
+        request.add_header("X-B", "3").unwrap();
+        request.add_header("user-agent", "custom").unwrap();
+
+        let request_str = String::from_utf8(request.build_request("example.com")).unwrap();
+        assert!(request_str.contains("X-B: 1\r\nX-B: 3\r\nX-A: 2\r\nUser-Agent: custom\r\n"));
+        assert!(!request_str.contains("webtor-rs"));
+    }
+
+    #[portable_test_async]
+    async fn test_parse_http_response() {
         let response_bytes = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nHello, World!";
         let url = Url::parse("http://example.com/").unwrap();
 
-        let response = parse_http_response(response_bytes, url).unwrap();
+        let response = read_response(response_bytes, url).await.unwrap();
 
         assert_eq!(response.status, 200);
-        assert_eq!(
-            response.headers.get("content-type"),
-            Some(&"text/plain".to_string())
-        );
+        assert_eq!(response.headers.get("content-type").unwrap(), "text/plain");
         assert_eq!(response.text().unwrap(), "Hello, World!");
     }
 
@@ -694,9 +1367,11 @@ mod tests {
     async fn test_http_response() {
         let response = HttpResponse {
             status: 200,
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             body: b"{\"ip\": \"127.0.0.1\"}".to_vec(),
             url: Url::parse("https://httpbin.org/ip").unwrap(),
+            redirects: Vec::new(),
+            decompressed: false,
         };
 
         assert!(response.is_success());
@@ -767,31 +1442,146 @@ mod tests {
         assert_eq!(String::from_utf8(decoded).unwrap(), json);
     }
 
-    #[portable_test]
-    fn test_parse_http_response_chunked() {
+    #[portable_test_async]
+    async fn test_parse_http_response_chunked() {
         // Full HTTP response with chunked encoding
         let response_bytes =
             b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n0\r\n\r\n";
         let url = Url::parse("http://example.com/").unwrap();
 
-        let response = parse_http_response(response_bytes, url).unwrap();
+        let response = read_response(response_bytes, url).await.unwrap();
 
         assert_eq!(response.status, 200);
         assert_eq!(response.text().unwrap(), "Hello");
     }
 
-    #[portable_test]
-    fn test_parse_http_response_content_length_truncation() {
+    #[portable_test_async]
+    async fn test_parse_http_response_content_length_truncation() {
         // Response with Content-Length but extra trailing data
         let response_bytes = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHelloExtra garbage";
         let url = Url::parse("http://example.com/").unwrap();
 
-        let response = parse_http_response(response_bytes, url).unwrap();
+        let response = read_response(response_bytes, url).await.unwrap();
 
         assert_eq!(response.status, 200);
         assert_eq!(response.text().unwrap(), "Hello");
     }
 
+    #[portable_test_async]
+    async fn test_parse_http_response_short_body_fails() {
+        // The connection closes five bytes before Content-Length is reached
+        let response_bytes = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHello";
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let err = read_response(response_bytes, url).await.unwrap_err();
+        assert_eq!(err.kind(), TorErrorKind::Network);
+    }
+
+    fn encoded_response(encoding: &str, body: &[u8]) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+            encoding,
+            body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[portable_test]
+    fn test_build_head_frames_streamed_bodies() {
+        let url = Url::parse("http://example.com/upload").unwrap();
+        let mut request = HttpRequest::new(url);
+        request.method = Method::PUT;
+
+        let head = request.build_head("example.com", Some(None));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let head = request.build_head("example.com", Some(Some(42)));
+        assert!(head.contains("Content-Length: 42\r\n"));
+        assert!(!head.contains("Transfer-Encoding"));
+
+        request.body = Some(b"inline".to_vec());
+        let bytes = request.build_request("example.com");
+        assert!(bytes.ends_with(b"Content-Length: 6\r\n\r\ninline"));
+
+        assert!(!request.expects_continue());
+        request.add_header("Expect", "100-Continue").unwrap();
+        assert!(request.expects_continue());
+    }
+
+    #[portable_test]
+    fn test_build_request_offers_compression() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut request = HttpRequest::new(url);
+        let request_str = String::from_utf8(request.build_request("example.com")).unwrap();
+        assert!(request_str.contains("Accept-Encoding: gzip, deflate, br\r\n"));
+
+        request.add_header("accept-encoding", "identity").unwrap();
+        let request_str = String::from_utf8(request.build_request("example.com")).unwrap();
+        assert!(request_str.contains("Accept-Encoding: identity\r\n"));
+        assert!(!request_str.contains("gzip"));
+    }
+
+    #[portable_test_async]
+    async fn test_parse_http_response_decodes_content_encoding() {
+        let text = b"Hello, compressed world! Hello, compressed world!";
+        let url = Url::parse("http://example.com/").unwrap();
+
+        for (header, coding) in [
+            ("gzip", "gzip"),
+            ("x-gzip", "gzip"),
+            ("deflate", "zlib"),
+            ("deflate", "raw-deflate"),
+            ("br", "br"),
+        ] {
+            let bytes = encoded_response(header, &compress(coding, text));
+            let response = read_response(&bytes, url.clone()).await.unwrap();
+            assert_eq!(response.body, text, "decoding {}", coding);
+            assert!(response.decompressed);
+            assert!(!response.headers.contains_key("content-encoding"));
+            assert!(!response.headers.contains_key("content-length"));
+        }
+
+        // Stacked codings are undone last-applied first
+        let stacked = compress("br", &compress("gzip", text));
+        let bytes = encoded_response("gzip, br", &stacked);
+        let response = read_response(&bytes, url).await.unwrap();
+        assert_eq!(response.body, text);
+    }
+
+    #[portable_test_async]
+    async fn test_parse_http_response_chunked_gzip() {
+        let compressed = compress("gzip", b"Hello");
+        let mut bytes =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n"
+                .to_vec();
+        bytes.extend_from_slice(format!("{:x}\r\n", compressed.len()).as_bytes());
+        bytes.extend_from_slice(&compressed);
+        bytes.extend_from_slice(b"\r\n0\r\n\r\n");
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let response = read_response(&bytes, url).await.unwrap();
+        assert_eq!(response.text().unwrap(), "Hello");
+        assert!(response.decompressed);
+    }
+
+    #[portable_test_async]
+    async fn test_parse_http_response_unsupported_or_broken_encoding() {
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let bytes = encoded_response("zstd", b"opaque");
+        let response = read_response(&bytes, url.clone()).await.unwrap();
+        assert_eq!(response.body, b"opaque");
+        assert!(!response.decompressed);
+        assert_eq!(response.headers.get("content-encoding").unwrap(), "zstd");
+
+        let bytes = encoded_response("gzip", b"not gzip at all");
+        assert!(read_response(&bytes, url).await.is_err());
+    }
+
     #[portable_test]
     fn test_decode_chunked_body_leading_crlf() {
         let chunked = b"\r\n\r\n5\r\nHello\r\n0\r\n\r\n";
@@ -821,17 +1611,17 @@ mod tests {
         assert_eq!(decoded, b"HelloWo");
     }
 
-    #[portable_test]
-    fn test_parse_http_response_chunked_mixed_case_header() {
+    #[portable_test_async]
+    async fn test_parse_http_response_chunked_mixed_case_header() {
         let response_bytes =
             b"HTTP/1.1 200 OK\r\nTransfer-Encoding: Chunked\r\n\r\n5\r\nHello\r\n0\r\n\r\n";
         let url = Url::parse("http://example.com/").unwrap();
-        let response = parse_http_response(response_bytes, url).unwrap();
+        let response = read_response(response_bytes, url).await.unwrap();
         assert_eq!(response.text().unwrap(), "Hello");
     }
 
-    #[portable_test]
-    fn test_parse_http_response_chunked_ignores_content_length() {
+    #[portable_test_async]
+    async fn test_parse_http_response_chunked_ignores_content_length() {
         // Content-Length is wrong on purpose - chunked should take precedence
         let response_bytes = b"HTTP/1.1 200 OK\r\n\
             Transfer-Encoding: chunked\r\n\
@@ -840,7 +1630,7 @@ mod tests {
             5\r\nHello\r\n6\r\n World\r\n0\r\n\r\n";
         let url = Url::parse("http://example.com/").unwrap();
 
-        let response = parse_http_response(response_bytes, url).unwrap();
+        let response = read_response(response_bytes, url).await.unwrap();
         assert_eq!(response.text().unwrap(), "Hello World");
     }
 }
@@ -0,0 +1,1454 @@
+//! HTTP/2 connections over Tor streams (RFC 9113)
+//!
+//! Origins that pick `h2` during the TLS handshake get one connection that
+//! carries every concurrent request to them, each on its own stream, which
+//! saves a stream BEGIN and a TLS handshake per request.
+//!
+//! A background task reads the connection for as long as it lives, filing
+//! each frame with the stream it belongs to and answering the server's
+//! PINGs and SETTINGS even while no request is open. Requests wait for their
+//! frames to be filed. Writes go through the other half of the transport,
+//! letting a request be sent while another waits for its response.
+//!
+//! This isn't built on the `h2` crate because that timestamps streams we
+//! reset with `std::time::Instant`, which panics on wasm32-unknown-unknown.
+
+use crate::circuit::Circuit;
+use crate::error::{Result, TimeoutPhase, TorError};
+use crate::hpack::{self, HeaderField};
+use crate::http::RequestDeadline;
+use crate::http_conn::{circuit_in_service, is_interim, touch_circuit, HttpConnection};
+use crate::http_conn::{ResponseHead, SplittableStream, MAX_BODY_SIZE};
+use crate::retry::with_timeout;
+use crate::time::Instant;
+use futures::channel::mpsc;
+use futures::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use futures::{FutureExt, StreamExt};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, Notify, RwLock};
+use tracing::{debug, trace, warn};
+
+/// Sent before anything else on a connection
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// Settings
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+
+/// Frame payload size every peer accepts, and the most we take
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+
+/// Flow-control window both sides start with
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Receive window we give each stream: a whole buffered body's worth
+const STREAM_WINDOW: u32 = MAX_BODY_SIZE as u32;
+
+/// Receive window for the connection, shared by all streams
+const CONNECTION_WINDOW: u32 = 4 * STREAM_WINDOW;
+
+/// Largest header block we assemble from HEADERS and CONTINUATION frames
+const MAX_HEADER_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Highest stream ID a client may use
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+
+/// One HTTP/2 frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    fn settings(settings: &[(u16, u32)]) -> Self {
+        let mut payload = Vec::with_capacity(settings.len() * 6);
+        for (id, value) in settings {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        Self::new(FRAME_SETTINGS, 0, 0, payload)
+    }
+
+    fn window_update(stream_id: u32, increment: u32) -> Self {
+        Self::new(
+            FRAME_WINDOW_UPDATE,
+            0,
+            stream_id,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+
+    fn rst_stream(stream_id: u32, code: u32) -> Self {
+        Self::new(FRAME_RST_STREAM, 0, stream_id, code.to_be_bytes().to_vec())
+    }
+
+    fn goaway(last_stream_id: u32, code: u32) -> Self {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        Self::new(FRAME_GOAWAY, 0, 0, payload)
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Encode as 9-byte header plus payload
+    fn encode(&self) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut bytes = Vec::with_capacity(9 + len);
+        bytes.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+        bytes.push(self.kind);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&(self.stream_id & MAX_STREAM_ID).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Decode a frame from the front of `buf`
+    ///
+    /// `None` until a whole frame has arrived; otherwise the frame and the
+    /// number of bytes it took.
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        if buf.len() < 9 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(TorError::Protocol(format!(
+                "HTTP/2 frame of {} bytes exceeds our limit",
+                len
+            )));
+        }
+        if buf.len() < 9 + len {
+            return Ok(None);
+        }
+        let stream_id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & MAX_STREAM_ID;
+        let frame = Self::new(buf[3], buf[4], stream_id, buf[9..9 + len].to_vec());
+        Ok(Some((frame, 9 + len)))
+    }
+
+    /// Payload without padding and, for HEADERS, priority fields
+    fn body(&self) -> Result<&[u8]> {
+        let mut body = &self.payload[..];
+        if self.has(FLAG_PADDED) {
+            let (&pad, rest) = body
+                .split_first()
+                .ok_or_else(|| protocol_error("padded frame without a pad length"))?;
+            body = rest
+                .get(..rest.len().wrapping_sub(pad as usize))
+                .filter(|_| (pad as usize) <= rest.len())
+                .ok_or_else(|| protocol_error("padding longer than the frame"))?;
+        }
+        if self.kind == FRAME_HEADERS && self.has(FLAG_PRIORITY) {
+            body = body
+                .get(5..)
+                .ok_or_else(|| protocol_error("HEADERS too short for its priority"))?;
+        }
+        Ok(body)
+    }
+
+    /// The 31-bit value at the start of the payload
+    fn leading_u32(&self) -> Result<u32> {
+        match self.payload.get(..4) {
+            Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Err(protocol_error("frame too short")),
+        }
+    }
+}
+
+fn protocol_error(msg: impl std::fmt::Display) -> TorError {
+    TorError::Protocol(format!("HTTP/2: {}", msg))
+}
+
+/// Settings the server sent
+#[derive(Debug, Clone, Copy)]
+struct PeerSettings {
+    max_concurrent_streams: usize,
+    initial_window: i64,
+    max_frame_size: usize,
+}
+
+impl Default for PeerSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: usize::MAX,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// Progress of one request's stream
+#[derive(Debug)]
+struct StreamState {
+    /// Response heads not yet taken: interim ones, then the final one
+    heads: VecDeque<ResponseHead>,
+    /// Whether the final response head arrived; later blocks are trailers
+    final_head: bool,
+    data: VecDeque<Vec<u8>>,
+    /// Whether any response frame arrived
+    received: bool,
+    /// Whether we sent END_STREAM
+    local_closed: bool,
+    /// Whether the server sent END_STREAM
+    remote_closed: bool,
+    /// Error code the stream was reset with
+    reset: Option<u32>,
+    /// How much more body we may send
+    send_window: i64,
+    /// Bytes taken from `data` and not yet returned to the server's window
+    unacked: u32,
+}
+
+impl StreamState {
+    fn new(send_window: i64) -> Self {
+        Self {
+            heads: VecDeque::new(),
+            final_head: false,
+            data: VecDeque::new(),
+            received: false,
+            local_closed: false,
+            remote_closed: false,
+            reset: None,
+            send_window,
+            unacked: 0,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.reset.is_some() || (self.local_closed && self.remote_closed)
+    }
+}
+
+/// A header block waiting for CONTINUATION frames
+struct PartialHeaders {
+    stream_id: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+/// Connection state shared by every stream
+struct State {
+    streams: HashMap<u32, StreamState>,
+    next_stream_id: u32,
+    peer: PeerSettings,
+    decoder: hpack::Decoder,
+    partial: Option<PartialHeaders>,
+    /// Encoded frames for the next writer: acks, window updates, resets
+    outbox: Vec<u8>,
+    /// How much more body the connection may carry to the server
+    send_window: i64,
+    /// Bytes consumed and not yet returned to the server's window
+    unacked: u32,
+    /// Last stream the server will process, once it sent GOAWAY
+    goaway: Option<u32>,
+    /// Why the connection is unusable
+    failure: Option<String>,
+    idle_since: Instant,
+}
+
+impl State {
+    fn queue(&mut self, frame: Frame) {
+        self.outbox.extend_from_slice(&frame.encode());
+    }
+
+    /// Mark the connection failed, telling the server why if it's our doing
+    fn fail(&mut self, error: &TorError, code: Option<u32>) {
+        if self.failure.is_some() {
+            return;
+        }
+        warn!("HTTP/2 connection failed: {}", error);
+        self.failure = Some(error.to_string());
+        if let Some(code) = code {
+            let last = self.streams.keys().copied().max().unwrap_or(0);
+            self.queue(Frame::goaway(last, code));
+        }
+    }
+
+    fn open_streams(&self) -> usize {
+        self.streams.values().filter(|s| !s.is_closed()).count()
+    }
+
+    /// Return consumed bytes to the server's windows once enough add up
+    fn credit(&mut self, stream_id: u32, len: usize) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.unacked += len as u32;
+            if stream.unacked >= STREAM_WINDOW / 2 && !stream.remote_closed {
+                let increment = std::mem::take(&mut stream.unacked);
+                self.queue(Frame::window_update(stream_id, increment));
+            }
+        }
+        self.unacked += len as u32;
+        if self.unacked >= CONNECTION_WINDOW / 2 {
+            let increment = std::mem::take(&mut self.unacked);
+            self.queue(Frame::window_update(0, increment));
+        }
+    }
+
+    /// File a frame read from the server
+    fn handle(&mut self, frame: Frame) -> Result<()> {
+        trace!(
+            "HTTP/2 frame type={} flags={:#x} stream={} len={}",
+            frame.kind,
+            frame.flags,
+            frame.stream_id,
+            frame.payload.len()
+        );
+
+        // A header block must be finished before anything else arrives
+        if let Some(partial) = &self.partial {
+            if frame.kind != FRAME_CONTINUATION || frame.stream_id != partial.stream_id {
+                return Err(protocol_error("header block interrupted"));
+            }
+        }
+
+        match frame.kind {
+            FRAME_DATA => {
+                if frame.stream_id == 0 {
+                    return Err(protocol_error("DATA on stream 0"));
+                }
+                let data = frame.body()?.to_vec();
+                let padding = frame.payload.len() - data.len();
+                match self.streams.get_mut(&frame.stream_id) {
+                    Some(stream) if !stream.remote_closed && stream.reset.is_none() => {
+                        stream.received = true;
+                        stream.remote_closed = frame.has(FLAG_END_STREAM);
+                        if !data.is_empty() {
+                            stream.data.push_back(data);
+                        }
+                        // Padding counts against the window but is never read
+                        self.credit(frame.stream_id, padding);
+                    }
+                    // A stream we gave up on; its bytes go straight back
+                    _ => self.credit(0, frame.payload.len()),
+                }
+            }
+            FRAME_HEADERS => {
+                if frame.stream_id == 0 {
+                    return Err(protocol_error("HEADERS on stream 0"));
+                }
+                let partial = PartialHeaders {
+                    stream_id: frame.stream_id,
+                    block: frame.body()?.to_vec(),
+                    end_stream: frame.has(FLAG_END_STREAM),
+                };
+                self.partial = Some(partial);
+                if frame.has(FLAG_END_HEADERS) {
+                    self.finish_headers()?;
+                }
+            }
+            FRAME_CONTINUATION => {
+                let Some(partial) = self.partial.as_mut() else {
+                    return Err(protocol_error("CONTINUATION without HEADERS"));
+                };
+                partial.block.extend_from_slice(&frame.payload);
+                if partial.block.len() > MAX_HEADER_BLOCK_SIZE {
+                    return Err(protocol_error("header block too large"));
+                }
+                if frame.has(FLAG_END_HEADERS) {
+                    self.finish_headers()?;
+                }
+            }
+            FRAME_RST_STREAM => {
+                let code = frame.leading_u32()?;
+                if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+                    debug!("HTTP/2 stream {} reset with {:#x}", frame.stream_id, code);
+                    stream.reset = Some(code);
+                }
+            }
+            FRAME_SETTINGS => {
+                if frame.stream_id != 0 || !frame.payload.len().is_multiple_of(6) {
+                    return Err(protocol_error("malformed SETTINGS"));
+                }
+                if frame.has(FLAG_ACK) {
+                    return Ok(());
+                }
+                for setting in frame.payload.chunks(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    self.apply_setting(id, value)?;
+                }
+                self.queue(Frame::new(FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()));
+            }
+            FRAME_PUSH_PROMISE => {
+                return Err(protocol_error("PUSH_PROMISE with push disabled"));
+            }
+            FRAME_PING => {
+                if frame.payload.len() != 8 {
+                    return Err(protocol_error("PING payload is not 8 bytes"));
+                }
+                if !frame.has(FLAG_ACK) {
+                    self.queue(Frame::new(FRAME_PING, FLAG_ACK, 0, frame.payload));
+                }
+            }
+            FRAME_GOAWAY => {
+                let last = frame.leading_u32()? & MAX_STREAM_ID;
+                let code = frame
+                    .payload
+                    .get(4..8)
+                    .map_or(NO_ERROR, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+                debug!("HTTP/2 GOAWAY with {:#x}, last stream {}", code, last);
+                self.goaway = Some(last);
+                // Streams past `last` were never processed and can be retried
+                for (&id, stream) in &mut self.streams {
+                    if id > last && !stream.is_closed() {
+                        stream.reset = Some(REFUSED_STREAM);
+                    }
+                }
+            }
+            FRAME_WINDOW_UPDATE => {
+                let increment = (frame.leading_u32()? & MAX_STREAM_ID) as i64;
+                if frame.stream_id == 0 {
+                    self.send_window += increment;
+                } else if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+                    stream.send_window += increment;
+                }
+            }
+            // PRIORITY and unknown frames are ignored
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn apply_setting(&mut self, id: u16, value: u32) -> Result<()> {
+        match id {
+            SETTINGS_MAX_CONCURRENT_STREAMS => {
+                self.peer.max_concurrent_streams = value as usize;
+            }
+            SETTINGS_INITIAL_WINDOW_SIZE => {
+                if value > MAX_STREAM_ID {
+                    return Err(protocol_error("initial window too large"));
+                }
+                // Open streams' windows move by the change
+                let delta = value as i64 - self.peer.initial_window;
+                self.peer.initial_window = value as i64;
+                for stream in self.streams.values_mut() {
+                    stream.send_window += delta;
+                }
+            }
+            SETTINGS_MAX_FRAME_SIZE => {
+                if !(DEFAULT_MAX_FRAME_SIZE..=(1 << 24) - 1).contains(&(value as usize)) {
+                    return Err(protocol_error("invalid max frame size"));
+                }
+                self.peer.max_frame_size = value as usize;
+            }
+            // Our encoder keeps no table, so the header table size doesn't
+            // matter; push is off from our side
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Decode a completed header block and hand it to its stream
+    fn finish_headers(&mut self) -> Result<()> {
+        let Some(partial) = self.partial.take() else {
+            return Ok(());
+        };
+        // Every block is decoded to keep the HPACK table in step
+        let fields = self.decoder.decode(&partial.block)?;
+        let Some(stream) = self.streams.get_mut(&partial.stream_id) else {
+            return Ok(());
+        };
+        stream.received = true;
+        if stream.final_head {
+            // Trailers aren't passed on, as with HTTP/1.1
+            trace!("Ignoring trailers on stream {}", partial.stream_id);
+        } else {
+            match response_head(fields) {
+                Some(head) => {
+                    stream.final_head = !is_interim(head.status);
+                    stream.heads.push_back(head);
+                }
+                None => stream.reset = Some(PROTOCOL_ERROR),
+            }
+        }
+        if partial.end_stream {
+            stream.remote_closed = true;
+        }
+        Ok(())
+    }
+}
+
+/// Build a response head from decoded fields; `None` without a `:status`
+fn response_head(fields: Vec<HeaderField>) -> Option<ResponseHead> {
+    let mut status = None;
+    let mut headers = HeaderMap::new();
+    for (name, value) in fields {
+        if name == b":status" {
+            status = std::str::from_utf8(&value).ok()?.parse::<u16>().ok();
+            continue;
+        }
+        if name.starts_with(b":") {
+            continue;
+        }
+        match (
+            HeaderName::from_bytes(&name),
+            HeaderValue::from_bytes(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            }
+            _ => debug!(
+                "Skipping malformed HTTP/2 header {:?}",
+                String::from_utf8_lossy(&name)
+            ),
+        }
+    }
+    Some(ResponseHead {
+        minor_version: 1,
+        status: status?,
+        headers,
+    })
+}
+
+/// Reading side of the transport
+struct Reader {
+    half: ReadHalf<SplittableStream>,
+    /// Bytes read past the last whole frame
+    buffer: Vec<u8>,
+    /// Scratch space for one read, kept off the stack of the read futures
+    chunk: Box<[u8]>,
+}
+
+impl Reader {
+    /// Read the next frame; `None` at EOF
+    ///
+    /// Bytes only move into the buffer once a read completes, so dropping
+    /// this future part way loses nothing.
+    async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            if let Some((frame, used)) = Frame::decode(&self.buffer)? {
+                self.buffer.drain(..used);
+                return Ok(Some(frame));
+            }
+            let n =
+                self.half.read(&mut self.chunk).await.map_err(|e| {
+                    TorError::http_request(format!("Failed to read response: {}", e))
+                })?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&self.chunk[..n]);
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Woken each time the reader task changes the state
+    changed: Notify,
+    /// Asks the reader task to write out the outbox
+    flush: mpsc::UnboundedSender<()>,
+    writer: AsyncMutex<WriteHalf<SplittableStream>>,
+    /// Circuit carrying the stream; `None` for onion services
+    circuit: Option<Weak<RwLock<Circuit>>>,
+}
+
+impl Shared {
+    /// Write out the outbox, failing the connection on error
+    async fn flush_outbox(&self) {
+        let mut writer = self.writer.lock().await;
+        let out = std::mem::take(&mut self.state.lock().unwrap().outbox);
+        if out.is_empty() {
+            return;
+        }
+        let written = async {
+            writer.write_all(&out).await?;
+            writer.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            let error = TorError::http_request(format!("Failed to write to server: {}", e));
+            self.state.lock().unwrap().fail(&error, None);
+            self.changed.notify_waiters();
+        }
+    }
+}
+
+/// Read frames for as long as the connection lives
+///
+/// Acks and window updates a frame calls for go out as soon as it's filed,
+/// as does whatever a request queued and asked to have flushed. Ends once
+/// the connection fails or its last handle is dropped.
+async fn run_reader(
+    shared: Weak<Shared>,
+    mut reader: Reader,
+    mut flushes: mpsc::UnboundedReceiver<()>,
+) {
+    loop {
+        let frame = {
+            let next = reader.next_frame().fuse();
+            futures::pin_mut!(next);
+            futures::select! {
+                frame = next => Some(frame),
+                flush = flushes.next() => match flush {
+                    Some(()) => None,
+                    // The sender lives in `Shared`, so every handle is gone
+                    None => return,
+                },
+            }
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if let Some(frame) = frame {
+            let mut state = shared.state.lock().unwrap();
+            let handled = match frame {
+                Ok(Some(frame)) => state.handle(frame).map_err(|e| (e, Some(PROTOCOL_ERROR))),
+                Ok(None) => Err((TorError::http_request("Connection closed by server"), None)),
+                Err(e) => Err((e, None)),
+            };
+            if let Err((e, code)) = handled {
+                state.fail(&e, code);
+            }
+            drop(state);
+            shared.changed.notify_waiters();
+        }
+        shared.flush_outbox().await;
+        if shared.state.lock().unwrap().failure.is_some() {
+            return;
+        }
+    }
+}
+
+/// An HTTP/2 connection to one origin, shared by its requests
+#[derive(Clone)]
+pub(crate) struct Http2Connection {
+    shared: Arc<Shared>,
+}
+
+impl Http2Connection {
+    /// Start HTTP/2 on a connection whose TLS handshake picked `h2`
+    ///
+    /// Requests may go out right away; the server's settings are applied
+    /// when they arrive.
+    pub async fn handshake(connection: HttpConnection, deadline: &RequestDeadline) -> Result<Self> {
+        let (stream, buffer, circuit) = connection.into_parts();
+        let transport = stream
+            .into_splittable()
+            .ok_or_else(|| TorError::http_request("HTTP/2 needs a TLS 1.3 stream"))?;
+        let (read_half, write_half) = transport.split();
+        let (flush, flushes) = mpsc::unbounded();
+
+        let connection = Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    streams: HashMap::new(),
+                    next_stream_id: 1,
+                    peer: PeerSettings::default(),
+                    decoder: hpack::Decoder::new(),
+                    partial: None,
+                    outbox: Vec::new(),
+                    send_window: DEFAULT_WINDOW,
+                    unacked: 0,
+                    goaway: None,
+                    failure: None,
+                    idle_since: Instant::now(),
+                }),
+                changed: Notify::new(),
+                flush,
+                writer: AsyncMutex::new(write_half),
+                circuit,
+            }),
+        };
+
+        let mut preface = PREFACE.to_vec();
+        preface.extend_from_slice(
+            &Frame::settings(&[
+                (SETTINGS_HEADER_TABLE_SIZE, hpack::MAX_TABLE_SIZE as u32),
+                (SETTINGS_ENABLE_PUSH, 0),
+                (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW),
+            ])
+            .encode(),
+        );
+        preface.extend_from_slice(
+            &Frame::window_update(0, CONNECTION_WINDOW - DEFAULT_WINDOW as u32).encode(),
+        );
+        connection.write(&preface, deadline).await?;
+
+        // Started after the preface, which has to go out before any ack
+        let reader = Reader {
+            half: read_half,
+            buffer,
+            chunk: vec![0; 8192].into_boxed_slice(),
+        };
+        let task = run_reader(Arc::downgrade(&connection.shared), reader, flushes);
+        #[cfg(target_arch = "wasm32")]
+        wasm_bindgen_futures::spawn_local(task);
+        #[cfg(not(target_arch = "wasm32"))]
+        tokio::spawn(task);
+
+        debug!("HTTP/2 connection started");
+        Ok(connection)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// Whether new requests can go out on this connection
+    pub fn is_usable(&self) -> bool {
+        let state = self.state();
+        state.failure.is_none()
+            && state.goaway.is_none()
+            && state.next_stream_id <= MAX_STREAM_ID
+            && circuit_in_service(self.shared.circuit.as_ref())
+    }
+
+    /// How long no request has been open, or zero while one is
+    pub fn idle_for(&self) -> Duration {
+        let state = self.state();
+        if state.streams.is_empty() {
+            state.idle_since.elapsed()
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Mark the circuit as used so idle cleanup leaves it alone
+    pub fn touch_circuit(&self) {
+        touch_circuit(self.shared.circuit.as_ref());
+    }
+
+    /// A stream for the next request, opened when its headers are sent
+    pub fn stream(&self) -> Http2Stream {
+        Http2Stream {
+            connection: self.clone(),
+            id: 0,
+            received: false,
+        }
+    }
+
+    /// Write `bytes` after anything queued, failing the connection on error
+    async fn write(&self, bytes: &[u8], deadline: &RequestDeadline) -> Result<()> {
+        let mut writer = deadline
+            .run(TimeoutPhase::Total, async {
+                Ok(self.shared.writer.lock().await)
+            })
+            .await?;
+        self.write_locked(&mut writer, bytes, deadline).await
+    }
+
+    async fn write_locked(
+        &self,
+        writer: &mut WriteHalf<SplittableStream>,
+        bytes: &[u8],
+        deadline: &RequestDeadline,
+    ) -> Result<()> {
+        let mut out = std::mem::take(&mut self.state().outbox);
+        out.extend_from_slice(bytes);
+        if out.is_empty() {
+            return Ok(());
+        }
+        let written = deadline
+            .run(TimeoutPhase::Total, async {
+                writer.write_all(&out).await?;
+                writer.flush().await?;
+                Ok(())
+            })
+            .await;
+        // A write cut off part way leaves the framing broken
+        if let Err(e) = &written {
+            self.state().fail(e, None);
+        }
+        written.map_err(|e| TorError::http_request(format!("Failed to write request: {}", e)))
+    }
+
+    /// Wait until `ready` has an answer
+    ///
+    /// `ready` runs again each time the reader task changes the state.
+    async fn drive<T>(
+        &self,
+        phase: TimeoutPhase,
+        deadline: &RequestDeadline,
+        mut ready: impl FnMut(&mut State) -> Option<Result<T>>,
+    ) -> Result<T> {
+        loop {
+            // Taken before looking, so a change in between still wakes us
+            let changed = self.shared.changed.notified();
+            let (result, queued) = {
+                let mut state = self.state();
+                let result = ready(&mut state).or_else(|| {
+                    state.failure.as_ref().map(|failure| {
+                        Err(TorError::http_request(format!(
+                            "HTTP/2 connection failed: {}",
+                            failure
+                        )))
+                    })
+                });
+                (result, !state.outbox.is_empty())
+            };
+            // Window updates `ready` queued go out without waiting for the
+            // next write
+            if queued {
+                self.request_flush();
+            }
+            if let Some(result) = result {
+                return result;
+            }
+            deadline
+                .run(phase, async {
+                    changed.await;
+                    Ok(())
+                })
+                .await?;
+        }
+    }
+
+    /// Have the reader task write out the outbox
+    fn request_flush(&self) {
+        // Only fails once the task has ended with the connection
+        let _ = self.shared.flush.unbounded_send(());
+    }
+
+    /// Send a request's headers on a new stream
+    async fn open(
+        &self,
+        fields: &[HeaderField],
+        end_stream: bool,
+        deadline: &RequestDeadline,
+    ) -> Result<u32> {
+        // Wait for the server to allow another stream
+        self.drive(TimeoutPhase::Total, deadline, |state| {
+            (state.open_streams() < state.peer.max_concurrent_streams).then_some(Ok(()))
+        })
+        .await?;
+
+        let block = hpack::encode(fields);
+        let mut writer = deadline
+            .run(TimeoutPhase::Total, async {
+                Ok(self.shared.writer.lock().await)
+            })
+            .await?;
+
+        // IDs are handed out under the writer lock so they go out in order
+        let (id, frames) = {
+            let mut state = self.state();
+            if let Some(failure) = &state.failure {
+                return Err(TorError::http_request(format!(
+                    "HTTP/2 connection failed: {}",
+                    failure
+                )));
+            }
+            if state.goaway.is_some() || state.next_stream_id > MAX_STREAM_ID {
+                return Err(TorError::http_request("HTTP/2 connection is closing"));
+            }
+            let id = state.next_stream_id;
+            state.next_stream_id += 2;
+            let mut stream = StreamState::new(state.peer.initial_window);
+            stream.local_closed = end_stream;
+            state.streams.insert(id, stream);
+
+            let mut frames = Vec::new();
+            let mut pieces = block.chunks(state.peer.max_frame_size).peekable();
+            let mut kind = FRAME_HEADERS;
+            let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+            // An empty block still needs its HEADERS frame
+            let first: &[u8] = pieces.next().unwrap_or_default();
+            let mut piece = Some(first);
+            while let Some(bytes) = piece {
+                piece = pieces.next();
+                if piece.is_none() {
+                    flags |= FLAG_END_HEADERS;
+                }
+                frames.extend_from_slice(&Frame::new(kind, flags, id, bytes.to_vec()).encode());
+                kind = FRAME_CONTINUATION;
+                flags = 0;
+            }
+            (id, frames)
+        };
+
+        debug!("Opening HTTP/2 stream {}", id);
+        self.write_locked(&mut writer, &frames, deadline).await?;
+        Ok(id)
+    }
+}
+
+/// One request on an `Http2Connection`
+///
+/// Dropping it before the exchange is over resets the stream.
+pub(crate) struct Http2Stream {
+    connection: Http2Connection,
+    /// Stream ID, 0 until the headers go out
+    id: u32,
+    /// Whether the final response head arrived
+    received: bool,
+}
+
+impl Http2Stream {
+    /// Send the request headers, with `body` as the whole body if given
+    ///
+    /// Without `body` and with `streamed` set, the body follows through
+    /// `write` and `end_body`.
+    pub async fn start_request(
+        &mut self,
+        fields: &[HeaderField],
+        body: Option<&[u8]>,
+        streamed: bool,
+        deadline: &RequestDeadline,
+    ) -> Result<()> {
+        let end_stream = body.is_none() && !streamed;
+        self.id = self.connection.open(fields, end_stream, deadline).await?;
+        if let Some(body) = body {
+            self.write(body, deadline).await?;
+            self.end_body(deadline).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether any response frame arrived for this request
+    ///
+    /// A request that failed before this on a shared connection never
+    /// reached the server, or was refused by it, and can be sent again.
+    pub fn received_response(&self) -> bool {
+        let state = self.connection.state();
+        match state.streams.get(&self.id) {
+            Some(stream) => stream.received && stream.reset != Some(REFUSED_STREAM),
+            None => false,
+        }
+    }
+
+    /// Whether the circuit under this stream's connection is still in service
+    pub fn circuit_alive(&self) -> bool {
+        circuit_in_service(self.connection.shared.circuit.as_ref())
+    }
+
+    /// Send body bytes within the server's flow-control windows
+    pub async fn write(&mut self, mut bytes: &[u8], deadline: &RequestDeadline) -> Result<()> {
+        let id = self.id;
+        while !bytes.is_empty() {
+            let allowed = self
+                .connection
+                .drive(TimeoutPhase::Total, deadline, |state| {
+                    let max_frame_size = state.peer.max_frame_size as i64;
+                    let connection_window = state.send_window;
+                    let stream = match state.streams.get_mut(&id) {
+                        Some(stream) => stream,
+                        None => return Some(Err(stream_error(None))),
+                    };
+                    if stream.reset.is_some() {
+                        return Some(Err(stream_error(stream.reset)));
+                    }
+                    let allowed = stream
+                        .send_window
+                        .min(connection_window)
+                        .min(max_frame_size)
+                        .min(bytes.len() as i64);
+                    if allowed <= 0 {
+                        return None;
+                    }
+                    stream.send_window -= allowed;
+                    state.send_window -= allowed;
+                    Some(Ok(allowed as usize))
+                })
+                .await?;
+
+            let (piece, rest) = bytes.split_at(allowed);
+            let frame = Frame::new(FRAME_DATA, 0, id, piece.to_vec());
+            self.connection.write(&frame.encode(), deadline).await?;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    /// Finish the request body
+    pub async fn end_body(&mut self, deadline: &RequestDeadline) -> Result<()> {
+        if let Some(stream) = self.connection.state().streams.get_mut(&self.id) {
+            stream.local_closed = true;
+        }
+        let frame = Frame::new(FRAME_DATA, FLAG_END_STREAM, self.id, Vec::new());
+        self.connection.write(&frame.encode(), deadline).await
+    }
+
+    /// Read the next response head, interim or final
+    async fn read_head(&mut self, deadline: &RequestDeadline) -> Result<ResponseHead> {
+        let id = self.id;
+        let phase = if self.received {
+            TimeoutPhase::Total
+        } else {
+            TimeoutPhase::FirstByte
+        };
+        self.connection
+            .drive(phase, deadline, |state| {
+                let Some(stream) = state.streams.get_mut(&id) else {
+                    return Some(Err(stream_error(None)));
+                };
+                if let Some(head) = stream.heads.pop_front() {
+                    return Some(Ok(head));
+                }
+                if stream.reset.is_some() {
+                    return Some(Err(stream_error(stream.reset)));
+                }
+                stream
+                    .remote_closed
+                    .then(|| Err(TorError::http_request("Stream ended before response")))
+            })
+            .await
+    }
+
+    /// Read the head of the response, skipping interim ones
+    pub async fn read_response_head(&mut self, deadline: &RequestDeadline) -> Result<ResponseHead> {
+        loop {
+            let head = self.read_head(deadline).await?;
+            if is_interim(head.status) {
+                debug!("Skipping interim {} response", head.status);
+                continue;
+            }
+            self.received = true;
+            return Ok(head);
+        }
+    }
+
+    /// Wait up to `limit` for the answer to `Expect: 100-continue`
+    ///
+    /// Same contract as `HttpConnection::wait_for_continue`.
+    pub async fn wait_for_continue(
+        &mut self,
+        limit: Duration,
+        deadline: &RequestDeadline,
+    ) -> Result<Option<ResponseHead>> {
+        let head = match with_timeout(limit, "expect_continue", async {
+            Ok(self.read_head(deadline).await)
+        })
+        .await
+        {
+            Ok(head) => head?,
+            Err(_) => {
+                debug!("No answer to Expect: 100-continue, sending body");
+                return Ok(None);
+            }
+        };
+        if is_interim(head.status) {
+            debug!("Got {} before the request body", head.status);
+            return Ok(None);
+        }
+        self.received = true;
+        Ok(Some(head))
+    }
+
+    /// Read the next piece of the body; `None` once it has ended
+    pub async fn read_chunk(&mut self, deadline: &RequestDeadline) -> Result<Option<Vec<u8>>> {
+        let id = self.id;
+        self.connection
+            .drive(TimeoutPhase::Total, deadline, |state| {
+                let Some(stream) = state.streams.get_mut(&id) else {
+                    return Some(Err(stream_error(None)));
+                };
+                if let Some(chunk) = stream.data.pop_front() {
+                    state.credit(id, chunk.len());
+                    return Some(Ok(Some(chunk)));
+                }
+                if stream.remote_closed {
+                    return Some(Ok(None));
+                }
+                stream.reset.map(|code| Err(stream_error(Some(code))))
+            })
+            .await
+    }
+
+    /// Read a whole body, up to `MAX_BODY_SIZE`
+    ///
+    /// The flag says whether the body arrived in full.
+    pub async fn read_body(&mut self, deadline: &RequestDeadline) -> Result<(Vec<u8>, bool)> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.read_chunk(deadline).await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_BODY_SIZE {
+                warn!("Response exceeds 1MB limit, truncating");
+                body.truncate(MAX_BODY_SIZE);
+                return Ok((body, false));
+            }
+        }
+        Ok((body, true))
+    }
+}
+
+impl Drop for Http2Stream {
+    fn drop(&mut self) {
+        if self.id == 0 {
+            return;
+        }
+        let mut state = self.connection.state();
+        let Some(stream) = state.streams.remove(&self.id) else {
+            return;
+        };
+        // Unread data goes back to the connection window
+        let unread: usize = stream.data.iter().map(Vec::len).sum();
+        state.credit(0, unread + stream.unacked as usize);
+        if !stream.is_closed() {
+            debug!("Cancelling HTTP/2 stream {}", self.id);
+            state.queue(Frame::rst_stream(self.id, CANCEL));
+        }
+        if state.streams.is_empty() {
+            state.idle_since = Instant::now();
+        }
+        // No request may write again soon, so the reset can't wait for one
+        if !state.outbox.is_empty() {
+            self.connection.request_flush();
+        }
+    }
+}
+
+fn stream_error(code: Option<u32>) -> TorError {
+    match code {
+        Some(REFUSED_STREAM) => TorError::http_request("HTTP/2 stream refused by server"),
+        Some(FLOW_CONTROL_ERROR) => TorError::http_request("HTTP/2 flow control error"),
+        Some(code) => TorError::http_request(format!("HTTP/2 stream reset ({:#x})", code)),
+        None => TorError::http_request("HTTP/2 stream closed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpTimeouts;
+    use crate::http_conn::ScriptedStream;
+    use crate::test_util::{portable_test, portable_test_async};
+
+    fn state() -> State {
+        State {
+            streams: HashMap::new(),
+            next_stream_id: 1,
+            peer: PeerSettings::default(),
+            decoder: hpack::Decoder::new(),
+            partial: None,
+            outbox: Vec::new(),
+            send_window: DEFAULT_WINDOW,
+            unacked: 0,
+            goaway: None,
+            failure: None,
+            idle_since: Instant::now(),
+        }
+    }
+
+    fn headers_block(status: &str) -> Vec<u8> {
+        hpack::encode(&[
+            (b":status".to_vec(), status.as_bytes().to_vec()),
+            (b"set-cookie".to_vec(), b"a=1".to_vec()),
+            (b"set-cookie".to_vec(), b"b=2".to_vec()),
+        ])
+    }
+
+    fn deadline() -> RequestDeadline {
+        RequestDeadline::with_timeouts(Duration::from_secs(5), HttpTimeouts::default())
+    }
+
+    /// The server's end of an in-memory connection
+    struct Server {
+        to_client: mpsc::UnboundedSender<Vec<u8>>,
+        from_client: mpsc::UnboundedReceiver<Vec<u8>>,
+        buffer: Vec<u8>,
+    }
+
+    impl Server {
+        fn send(&self, frames: &[Frame]) {
+            let bytes = frames.iter().flat_map(Frame::encode).collect();
+            self.to_client.unbounded_send(bytes).unwrap();
+        }
+
+        /// Skip the client's frames until one matches
+        async fn expect(&mut self, wanted: impl Fn(&Frame) -> bool) -> Frame {
+            loop {
+                while let Some((frame, used)) = Frame::decode(&self.buffer).unwrap() {
+                    self.buffer.drain(..used);
+                    if wanted(&frame) {
+                        return frame;
+                    }
+                }
+                let bytes = with_timeout(Duration::from_secs(5), "client frame", async {
+                    Ok(self.from_client.next().await)
+                })
+                .await
+                .unwrap()
+                .expect("client closed the connection");
+                self.buffer.extend_from_slice(&bytes);
+            }
+        }
+    }
+
+    /// Start a connection, past the client's preface, to a server the test plays
+    async fn connect() -> (Http2Connection, Server) {
+        let (stream, to_client, from_client) = ScriptedStream::talking();
+        let connection = HttpConnection::new(stream, None);
+        let connection = Http2Connection::handshake(connection, &deadline())
+            .await
+            .unwrap();
+        let mut server = Server {
+            to_client,
+            from_client,
+            buffer: Vec::new(),
+        };
+        while server.buffer.len() < PREFACE.len() {
+            let bytes = server.from_client.next().await.unwrap();
+            server.buffer.extend_from_slice(&bytes);
+        }
+        assert!(server.buffer.starts_with(PREFACE));
+        server.buffer.drain(..PREFACE.len());
+        (connection, server)
+    }
+
+    #[portable_test]
+    fn test_frame_round_trip() {
+        let frame = Frame::new(FRAME_DATA, FLAG_END_STREAM, 3, b"hello".to_vec());
+        let mut bytes = frame.encode();
+        assert_eq!(&bytes[..9], &[0, 0, 5, 0, 1, 0, 0, 0, 3]);
+
+        // Incomplete until every byte is in
+        assert!(Frame::decode(&bytes[..8]).unwrap().is_none());
+        assert!(Frame::decode(&bytes[..12]).unwrap().is_none());
+        bytes.extend_from_slice(b"next");
+        let (decoded, used) = Frame::decode(&bytes).unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(used, 14);
+
+        // Oversized frames are refused
+        assert!(Frame::decode(&[0, 0x40, 1, 0, 0, 0, 0, 0, 1]).is_err());
+
+        // Padding is stripped
+        let padded = Frame::new(FRAME_DATA, FLAG_PADDED, 1, b"\x02hi\0\0".to_vec());
+        assert_eq!(padded.body().unwrap(), b"hi");
+        let bad = Frame::new(FRAME_DATA, FLAG_PADDED, 1, b"\x09hi".to_vec());
+        assert!(bad.body().is_err());
+    }
+
+    #[portable_test]
+    fn test_streams_get_their_own_frames() {
+        let mut state = state();
+        state.streams.insert(1, StreamState::new(DEFAULT_WINDOW));
+        state.streams.insert(3, StreamState::new(DEFAULT_WINDOW));
+
+        // A header block split over CONTINUATION, for stream 3
+        let block = headers_block("200");
+        let (first, rest) = block.split_at(4);
+        state
+            .handle(Frame::new(FRAME_HEADERS, 0, 3, first.to_vec()))
+            .unwrap();
+        assert!(state
+            .handle(Frame::new(FRAME_DATA, 0, 1, b"x".to_vec()))
+            .is_err());
+        state
+            .handle(Frame::new(
+                FRAME_CONTINUATION,
+                FLAG_END_HEADERS,
+                3,
+                rest.to_vec(),
+            ))
+            .unwrap();
+        state
+            .handle(Frame::new(
+                FRAME_HEADERS,
+                FLAG_END_HEADERS,
+                1,
+                headers_block("404"),
+            ))
+            .unwrap();
+        state
+            .handle(Frame::new(
+                FRAME_DATA,
+                FLAG_END_STREAM,
+                3,
+                b"three".to_vec(),
+            ))
+            .unwrap();
+        state
+            .handle(Frame::new(FRAME_DATA, 0, 1, b"one".to_vec()))
+            .unwrap();
+
+        let three = &state.streams[&3];
+        assert_eq!(three.heads[0].status, 200);
+        assert_eq!(
+            three.heads[0].headers.get_all("set-cookie").iter().count(),
+            2
+        );
+        assert_eq!(three.data[0], b"three");
+        assert!(three.remote_closed);
+        let one = &state.streams[&1];
+        assert_eq!(one.heads[0].status, 404);
+        assert_eq!(one.data[0], b"one");
+        assert!(!one.remote_closed);
+    }
+
+    #[portable_test]
+    fn test_trailers_after_taken_head() {
+        let mut state = state();
+        state.streams.insert(1, StreamState::new(DEFAULT_WINDOW));
+
+        state
+            .handle(Frame::new(
+                FRAME_HEADERS,
+                FLAG_END_HEADERS,
+                1,
+                headers_block("200"),
+            ))
+            .unwrap();
+        state
+            .handle(Frame::new(FRAME_DATA, 0, 1, b"body".to_vec()))
+            .unwrap();
+        // The reader takes the head while the body is still queued
+        let stream = state.streams.get_mut(&1).unwrap();
+        assert_eq!(stream.heads.pop_front().unwrap().status, 200);
+
+        let trailers = hpack::encode(&[(b"grpc-status".to_vec(), b"0".to_vec())]);
+        state
+            .handle(Frame::new(
+                FRAME_HEADERS,
+                FLAG_END_HEADERS | FLAG_END_STREAM,
+                1,
+                trailers,
+            ))
+            .unwrap();
+
+        let stream = &state.streams[&1];
+        assert_eq!(stream.reset, None);
+        assert!(stream.heads.is_empty());
+        assert_eq!(stream.data[0], b"body");
+        assert!(stream.remote_closed);
+    }
+
+    #[portable_test]
+    fn test_control_frames() {
+        let mut state = state();
+        let mut stream = StreamState::new(DEFAULT_WINDOW);
+        stream.local_closed = true;
+        state.streams.insert(1, stream);
+        state.streams.insert(3, StreamState::new(DEFAULT_WINDOW));
+
+        // Settings are applied and acknowledged; windows follow the change
+        state
+            .handle(Frame::settings(&[
+                (SETTINGS_MAX_CONCURRENT_STREAMS, 1),
+                (SETTINGS_INITIAL_WINDOW_SIZE, 100),
+            ]))
+            .unwrap();
+        assert_eq!(state.peer.max_concurrent_streams, 1);
+        assert_eq!(state.streams[&3].send_window, 100);
+        state.handle(Frame::window_update(3, 50)).unwrap();
+        state.handle(Frame::window_update(0, 10)).unwrap();
+        assert_eq!(state.streams[&3].send_window, 150);
+        assert_eq!(state.send_window, DEFAULT_WINDOW + 10);
+
+        // Pings are answered with the same payload
+        state
+            .handle(Frame::new(FRAME_PING, 0, 0, b"12345678".to_vec()))
+            .unwrap();
+        let mut outbox = &state.outbox[..];
+        let mut sent = Vec::new();
+        while let Some((frame, used)) = Frame::decode(outbox).unwrap() {
+            outbox = &outbox[used..];
+            sent.push(frame);
+        }
+        assert_eq!(sent[0], Frame::new(FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()));
+        assert_eq!(
+            sent[1],
+            Frame::new(FRAME_PING, FLAG_ACK, 0, b"12345678".to_vec())
+        );
+
+        // GOAWAY refuses streams the server never got to
+        state.handle(Frame::goaway(1, NO_ERROR)).unwrap();
+        assert_eq!(state.goaway, Some(1));
+        assert_eq!(state.streams[&1].reset, None);
+        assert_eq!(state.streams[&3].reset, Some(REFUSED_STREAM));
+
+        // Push is off, so a promise is a connection error
+        assert!(state
+            .handle(Frame::new(
+                FRAME_PUSH_PROMISE,
+                FLAG_END_HEADERS,
+                1,
+                vec![0; 4]
+            ))
+            .is_err());
+    }
+
+    #[portable_test_async]
+    async fn test_idle_connection_answers_server() {
+        let (connection, mut server) = connect().await;
+
+        // No request is open; the reader task still answers
+        server.send(&[
+            Frame::settings(&[(SETTINGS_MAX_CONCURRENT_STREAMS, 10)]),
+            Frame::goaway(0, NO_ERROR),
+            Frame::new(FRAME_PING, 0, 0, b"12345678".to_vec()),
+        ]);
+        server
+            .expect(|f| f.kind == FRAME_SETTINGS && f.has(FLAG_ACK))
+            .await;
+        let pong = server.expect(|f| f.kind == FRAME_PING).await;
+        assert!(pong.has(FLAG_ACK));
+        assert_eq!(pong.payload, b"12345678");
+
+        // Frames are handled in order, so the GOAWAY already took effect
+        assert_eq!(connection.state().peer.max_concurrent_streams, 10);
+        assert!(!connection.is_usable());
+    }
+
+    #[portable_test_async]
+    async fn test_dropped_stream_is_reset() {
+        let (connection, mut server) = connect().await;
+        let deadline = deadline();
+
+        let mut stream = connection.stream();
+        let fields = [
+            (b":method".to_vec(), b"GET".to_vec()),
+            (b":scheme".to_vec(), b"https".to_vec()),
+            (b":authority".to_vec(), b"example.com".to_vec()),
+            (b":path".to_vec(), b"/".to_vec()),
+        ];
+        stream
+            .start_request(&fields, None, false, &deadline)
+            .await
+            .unwrap();
+        let headers = server.expect(|f| f.kind == FRAME_HEADERS).await;
+        assert_eq!(headers.stream_id, 1);
+
+        // Cancelled before the response; nothing else is written after
+        drop(stream);
+        let reset = server.expect(|f| f.kind == FRAME_RST_STREAM).await;
+        assert_eq!(reset, Frame::rst_stream(1, CANCEL));
+        assert!(connection.state().streams.is_empty());
+    }
+}
@@ -0,0 +1,1135 @@
+//! HTTP/1.1 connections over Tor streams
+//!
+//! A connection owns one (optionally TLS-wrapped) stream and reads responses
+//! by their framing - Content-Length, chunked or close-delimited - instead of
+//! waiting for EOF, so a connection can carry several requests in turn.
+//!
+//! An `Exchange` is one request on either such a connection or a stream of a
+//! shared HTTP/2 connection, so callers need not care which they got.
+
+use crate::circuit::Circuit;
+use crate::error::{Result, TimeoutPhase, TorError};
+use crate::http::RequestDeadline;
+use crate::http2::Http2Stream;
+use crate::retry::with_timeout;
+use crate::time::Instant;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use http::header::{self, AsHeaderName, HeaderMap, HeaderName, HeaderValue};
+use http::Method;
+use std::io;
+use std::pin::Pin;
+use std::sync::Weak;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tor_proto::client::stream::DataStream;
+use tracing::{debug, warn};
+
+/// Largest response head we buffer before giving up
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Largest body we buffer; longer bodies are truncated
+pub(crate) const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Transport under an HTTP connection
+pub(crate) enum HttpStream {
+    Plain(Box<DataStream>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Tls(Box<futures_rustls::client::TlsStream<DataStream>>),
+    #[cfg(target_arch = "wasm32")]
+    Tls13(Box<subtle_tls::TlsStream<DataStream>>),
+    #[cfg(target_arch = "wasm32")]
+    Tls12(Box<subtle_tls::TlsStream12<DataStream>>),
+    #[cfg(test)]
+    Scripted(Box<ScriptedStream>),
+}
+
+impl HttpStream {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(s) => s.read(buf).await,
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpStream::Tls(s) => s.read(buf).await,
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls13(s) => s.read(buf).await,
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls12(s) => s.read(buf).await,
+            #[cfg(test)]
+            HttpStream::Scripted(s) => s.read(buf).await,
+        }
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = match self {
+                HttpStream::Plain(s) => s.write(buf).await?,
+                #[cfg(not(target_arch = "wasm32"))]
+                HttpStream::Tls(s) => s.write(buf).await?,
+                #[cfg(target_arch = "wasm32")]
+                HttpStream::Tls13(s) => s.write(buf).await?,
+                #[cfg(target_arch = "wasm32")]
+                HttpStream::Tls12(s) => s.write(buf).await?,
+                #[cfg(test)]
+                HttpStream::Scripted(s) => s.write(buf).await?,
+            };
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HttpStream::Plain(s) => s.flush().await,
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpStream::Tls(s) => s.flush().await,
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls13(s) => s.flush().await,
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls12(s) => s.flush().await,
+            #[cfg(test)]
+            HttpStream::Scripted(s) => s.flush().await,
+        }
+    }
+
+    /// The stream as one that can be split into read and write halves
+    ///
+    /// `None` for TLS 1.2 streams from the wasm fallback, which can't be.
+    pub fn into_splittable(self) -> Option<SplittableStream> {
+        match self {
+            HttpStream::Plain(s) => Some(SplittableStream::Plain(s)),
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpStream::Tls(s) => Some(SplittableStream::Tls(s)),
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls13(s) => Some(SplittableStream::Tls13(s)),
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls12(_) => None,
+            #[cfg(test)]
+            HttpStream::Scripted(s) => Some(SplittableStream::Scripted(s)),
+        }
+    }
+
+    /// Whether ALPN picked `h2`
+    ///
+    /// Only offered on streams that can be split for HTTP/2, so TLS 1.2
+    /// streams never have it.
+    fn negotiated_http2(&self) -> bool {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpStream::Tls(s) => s.get_ref().1.alpn_protocol() == Some(b"h2"),
+            #[cfg(target_arch = "wasm32")]
+            HttpStream::Tls13(s) => s.alpn_protocol() == Some(b"h2"),
+            _ => false,
+        }
+    }
+}
+
+/// An `HttpStream` that implements futures `AsyncRead`/`AsyncWrite`
+///
+/// Protocols that read and write at the same time, HTTP/2 and WebSockets,
+/// split it in two.
+pub(crate) enum SplittableStream {
+    Plain(Box<DataStream>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Tls(Box<futures_rustls::client::TlsStream<DataStream>>),
+    #[cfg(target_arch = "wasm32")]
+    Tls13(Box<subtle_tls::TlsStream<DataStream>>),
+    #[cfg(test)]
+    Scripted(Box<ScriptedStream>),
+}
+
+impl AsyncRead for SplittableStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SplittableStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(not(target_arch = "wasm32"))]
+            SplittableStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            #[cfg(target_arch = "wasm32")]
+            SplittableStream::Tls13(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            #[cfg(test)]
+            SplittableStream::Scripted(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SplittableStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SplittableStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(not(target_arch = "wasm32"))]
+            SplittableStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            #[cfg(target_arch = "wasm32")]
+            SplittableStream::Tls13(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            #[cfg(test)]
+            SplittableStream::Scripted(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SplittableStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            SplittableStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+            #[cfg(target_arch = "wasm32")]
+            SplittableStream::Tls13(s) => Pin::new(s.as_mut()).poll_flush(cx),
+            #[cfg(test)]
+            SplittableStream::Scripted(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SplittableStream::Plain(s) => Pin::new(s).poll_close(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            SplittableStream::Tls(s) => Pin::new(s.as_mut()).poll_close(cx),
+            #[cfg(target_arch = "wasm32")]
+            SplittableStream::Tls13(s) => Pin::new(s.as_mut()).poll_close(cx),
+            #[cfg(test)]
+            SplittableStream::Scripted(s) => Pin::new(s.as_mut()).poll_close(cx),
+        }
+    }
+}
+
+/// In-memory peer that answers reads from a script
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct ScriptedStream {
+    /// Results of successive reads; EOF once they run out
+    pub reads: std::collections::VecDeque<io::Result<Vec<u8>>>,
+    pub written: Vec<u8>,
+    /// Reads after the script, pending until they arrive; EOF once the
+    /// sender is dropped
+    pub incoming: Option<futures::channel::mpsc::UnboundedReceiver<Vec<u8>>>,
+    /// Gets each write as it happens
+    pub outgoing: Option<futures::channel::mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl ScriptedStream {
+    /// A connection that sends `replies` in turn and then closes
+    pub fn replying<'a>(replies: impl IntoIterator<Item = &'a [u8]>) -> HttpStream {
+        HttpStream::Scripted(Box::new(Self {
+            reads: replies.into_iter().map(|r| Ok(r.to_vec())).collect(),
+            ..Self::default()
+        }))
+    }
+
+    /// A connection to a peer the test plays: what's sent on the first
+    /// channel is read, and writes come out of the second
+    pub fn talking() -> (
+        HttpStream,
+        futures::channel::mpsc::UnboundedSender<Vec<u8>>,
+        futures::channel::mpsc::UnboundedReceiver<Vec<u8>>,
+    ) {
+        let (to_client, incoming) = futures::channel::mpsc::unbounded();
+        let (outgoing, from_client) = futures::channel::mpsc::unbounded();
+        let stream = HttpStream::Scripted(Box::new(Self {
+            incoming: Some(incoming),
+            outgoing: Some(outgoing),
+            ..Self::default()
+        }));
+        (stream, to_client, from_client)
+    }
+}
+
+#[cfg(test)]
+impl AsyncRead for ScriptedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        use futures::StreamExt;
+
+        if self.reads.is_empty() {
+            if let Some(incoming) = &mut self.incoming {
+                match incoming.poll_next_unpin(cx) {
+                    Poll::Ready(Some(data)) => self.reads.push_back(Ok(data)),
+                    Poll::Ready(None) => {}
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+        let Some(read) = self.reads.pop_front() else {
+            return Poll::Ready(Ok(0));
+        };
+        let mut data = read?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        if n < data.len() {
+            self.reads.push_front(Ok(data.split_off(n)));
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+impl AsyncWrite for ScriptedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written.extend_from_slice(buf);
+        if let Some(outgoing) = &self.outgoing {
+            // The test may have stopped listening
+            let _ = outgoing.unbounded_send(buf.to_vec());
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Status line and headers of a response
+#[derive(Debug, Clone)]
+pub(crate) struct ResponseHead {
+    /// Minor HTTP version: 1 for HTTP/1.1, 0 for HTTP/1.0
+    pub minor_version: u8,
+    pub status: u16,
+    /// Headers in the order received, repeated names kept
+    pub headers: HeaderMap,
+}
+
+impl ResponseHead {
+    /// Parse a head up to (not including) the blank line
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let header_str = std::str::from_utf8(bytes)
+            .map_err(|e| TorError::http_request(format!("Invalid HTTP headers: {}", e)))?;
+        let mut lines = header_str.lines();
+
+        // Parse status line: "HTTP/1.1 200 OK"
+        let status_line = lines
+            .next()
+            .ok_or_else(|| TorError::http_request("Invalid HTTP response: no status line"))?;
+        let parts: Vec<&str> = status_line.splitn(3, ' ').collect();
+        if parts.len() < 2 {
+            return Err(TorError::http_request("Invalid HTTP status line"));
+        }
+        let minor_version = if parts[0].eq_ignore_ascii_case("HTTP/1.0") {
+            0
+        } else {
+            1
+        };
+        let status: u16 = parts[1]
+            .parse()
+            .map_err(|e| TorError::http_request(format!("Invalid status code: {}", e)))?;
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match (
+                HeaderName::from_bytes(key.trim().as_bytes()),
+                HeaderValue::from_str(value.trim()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.append(name, value);
+                }
+                _ => debug!("Skipping malformed header line: {:?}", line),
+            }
+        }
+
+        Ok(Self {
+            minor_version,
+            status,
+            headers,
+        })
+    }
+
+    /// How the body of this response is delimited
+    pub fn framing(&self, method: &Method) -> BodyFraming {
+        if method == Method::HEAD
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return BodyFraming::Empty;
+        }
+        // Transfer-Encoding takes precedence over Content-Length
+        if has_token(&self.headers, header::TRANSFER_ENCODING, "chunked") {
+            return BodyFraming::Chunked;
+        }
+        match self
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|cl| cl.to_str().ok()?.trim().parse::<usize>().ok())
+        {
+            Some(0) => BodyFraming::Empty,
+            Some(len) => BodyFraming::Length(len),
+            None => BodyFraming::UntilClose,
+        }
+    }
+
+    /// Whether the server lets the connection carry another request
+    pub fn keeps_alive(&self) -> bool {
+        if has_token(&self.headers, header::CONNECTION, "close") {
+            return false;
+        }
+        self.minor_version >= 1 || has_token(&self.headers, header::CONNECTION, "keep-alive")
+    }
+}
+
+/// Whether any `name` header lists `token` in its comma-separated values
+pub(crate) fn has_token(headers: &HeaderMap, name: impl AsHeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// All `name` headers joined with ", ", as if sent on one line
+pub(crate) fn joined_header(headers: &HeaderMap, name: impl AsHeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// How a response body is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    /// Reading a chunk-size line
+    Size,
+    /// Inside chunk data with this many bytes left
+    Data(usize),
+    /// Expecting the CRLF after chunk data
+    DataEnd,
+    /// Reading trailer lines after the last chunk
+    Trailers,
+    Done,
+}
+
+/// Incremental `Transfer-Encoding: chunked` decoder
+///
+/// Finds the exact end of the body so the bytes after it can start the next
+/// response on the same connection.
+#[derive(Debug)]
+pub(crate) struct ChunkedDecoder {
+    state: ChunkState,
+    line: Vec<u8>,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: ChunkState::Size,
+            line: Vec::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    /// Decode as much of `input` as possible into `out`
+    ///
+    /// Returns how many bytes were consumed; input past the end of the body
+    /// is left alone.
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        out: &mut Vec<u8>,
+    ) -> std::result::Result<usize, String> {
+        let mut i = 0;
+        while i < input.len() {
+            match self.state {
+                ChunkState::Size | ChunkState::DataEnd | ChunkState::Trailers => {
+                    let byte = input[i];
+                    i += 1;
+                    if byte != b'\n' {
+                        self.line.push(byte);
+                        if self.line.len() > 4096 {
+                            return Err("Chunk line too long".into());
+                        }
+                        continue;
+                    }
+                    let line = std::mem::take(&mut self.line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(&line);
+                    self.state = match self.state {
+                        // Some servers send stray CRLFs before a chunk size
+                        ChunkState::Size if line.trim_ascii().is_empty() => ChunkState::Size,
+                        ChunkState::Size => {
+                            let size = std::str::from_utf8(line)
+                                .ok()
+                                .and_then(|l| l.split(';').next())
+                                .map(str::trim)
+                                .and_then(|l| usize::from_str_radix(l, 16).ok())
+                                .ok_or_else(|| {
+                                    format!(
+                                        "Invalid chunk size '{}'",
+                                        String::from_utf8_lossy(line)
+                                    )
+                                })?;
+                            if size == 0 {
+                                ChunkState::Trailers
+                            } else {
+                                ChunkState::Data(size)
+                            }
+                        }
+                        ChunkState::DataEnd if line.is_empty() => ChunkState::Size,
+                        ChunkState::DataEnd => return Err("Missing CRLF after chunk".into()),
+                        ChunkState::Trailers if line.is_empty() => ChunkState::Done,
+                        _ => ChunkState::Trailers,
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    let take = remaining.min(input.len() - i);
+                    out.extend_from_slice(&input[i..i + take]);
+                    i += take;
+                    self.state = if take == remaining {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - take)
+                    };
+                }
+                ChunkState::Done => break,
+            }
+        }
+        Ok(i)
+    }
+}
+
+/// Progress through one response body
+#[derive(Debug)]
+pub(crate) struct BodyReader {
+    state: BodyState,
+}
+
+#[derive(Debug)]
+enum BodyState {
+    /// This many bytes left before the body ends
+    Length(usize),
+    Chunked(ChunkedDecoder),
+    UntilClose,
+    /// `complete` says whether the body ended where its framing said
+    Done {
+        complete: bool,
+    },
+}
+
+impl BodyReader {
+    pub fn new(framing: BodyFraming) -> Self {
+        let state = match framing {
+            BodyFraming::Empty => BodyState::Done { complete: true },
+            BodyFraming::Length(len) => BodyState::Length(len),
+            BodyFraming::Chunked => BodyState::Chunked(ChunkedDecoder::new()),
+            BodyFraming::UntilClose => BodyState::UntilClose,
+        };
+        Self { state }
+    }
+
+    /// Whether the whole body arrived, leaving the connection reusable
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, BodyState::Done { complete: true })
+    }
+}
+
+/// One HTTP/1.1 connection to an origin
+pub(crate) struct HttpConnection {
+    stream: HttpStream,
+    /// Bytes read past the end of the previous response
+    buffer: Vec<u8>,
+    /// Circuit carrying the stream; `None` for onion services
+    circuit: Option<Weak<RwLock<Circuit>>>,
+    idle_since: Instant,
+    /// Whether the current exchange has received any bytes
+    received: bool,
+}
+
+impl HttpConnection {
+    pub fn new(stream: HttpStream, circuit: Option<Weak<RwLock<Circuit>>>) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            circuit,
+            idle_since: Instant::now(),
+            received: false,
+        }
+    }
+
+    /// Whether the current exchange got any response bytes
+    ///
+    /// A reused connection that fails before this is most likely one the
+    /// server closed while it sat idle.
+    pub fn received_response(&self) -> bool {
+        self.received
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.idle_since.elapsed()
+    }
+
+    /// Whether the circuit under this connection is still in service
+    pub fn circuit_alive(&self) -> bool {
+        circuit_in_service(self.circuit.as_ref())
+    }
+
+    /// Mark the circuit as used so idle cleanup leaves it alone
+    pub fn touch_circuit(&self) {
+        touch_circuit(self.circuit.as_ref());
+    }
+
+    /// Whether the TLS handshake picked HTTP/2
+    pub fn is_http2(&self) -> bool {
+        self.stream.negotiated_http2()
+    }
+
+    /// Give up the stream, for HTTP/2 or a WebSocket
+    ///
+    /// Also returns bytes already read past the last response head, and the
+    /// circuit.
+    pub fn into_parts(self) -> (HttpStream, Vec<u8>, Option<Weak<RwLock<Circuit>>>) {
+        (self.stream, self.buffer, self.circuit)
+    }
+
+    /// Start a request by writing its head and any in-memory body
+    pub async fn start_request(
+        &mut self,
+        request_bytes: &[u8],
+        deadline: &RequestDeadline,
+    ) -> Result<()> {
+        self.received = !self.buffer.is_empty();
+        self.write(request_bytes, deadline).await
+    }
+
+    /// Write more of the current request
+    pub async fn write(&mut self, bytes: &[u8], deadline: &RequestDeadline) -> Result<()> {
+        let stream = &mut self.stream;
+        deadline
+            .run(TimeoutPhase::Total, async {
+                stream.write_all(bytes).await.map_err(|e| {
+                    TorError::http_request(format!("Failed to write request: {}", e))
+                })?;
+                stream
+                    .flush()
+                    .await
+                    .map_err(|e| TorError::http_request(format!("Failed to flush request: {}", e)))
+            })
+            .await
+    }
+
+    /// Read the head of the response
+    ///
+    /// Interim responses (100 Continue, 103 Early Hints) are skipped.
+    pub async fn read_response_head(&mut self, deadline: &RequestDeadline) -> Result<ResponseHead> {
+        loop {
+            let head = self.read_head(deadline).await?;
+            if is_interim(head.status) {
+                debug!("Skipping interim {} response", head.status);
+                continue;
+            }
+            return Ok(head);
+        }
+    }
+
+    /// Wait up to `limit` for the answer to `Expect: 100-continue`
+    ///
+    /// `None` means the body should be sent: the server sent an interim
+    /// response or stayed silent. A final response means it refused the body.
+    pub async fn wait_for_continue(
+        &mut self,
+        limit: Duration,
+        deadline: &RequestDeadline,
+    ) -> Result<Option<ResponseHead>> {
+        let head = match with_timeout(limit, "expect_continue", async {
+            Ok(self.read_head(deadline).await)
+        })
+        .await
+        {
+            Ok(head) => head?,
+            Err(_) => {
+                debug!("No answer to Expect: 100-continue, sending body");
+                return Ok(None);
+            }
+        };
+        if is_interim(head.status) {
+            debug!("Got {} before the request body", head.status);
+            return Ok(None);
+        }
+        Ok(Some(head))
+    }
+
+    /// Read the next piece of a body; `None` once it has ended
+    pub async fn read_chunk(
+        &mut self,
+        reader: &mut BodyReader,
+        deadline: &RequestDeadline,
+    ) -> Result<Option<Vec<u8>>> {
+        loop {
+            match &mut reader.state {
+                BodyState::Done { .. } => {
+                    self.idle_since = Instant::now();
+                    return Ok(None);
+                }
+                BodyState::Length(remaining) => {
+                    if self.buffer.is_empty() && self.fill(deadline).await? == 0 {
+                        let remaining = *remaining;
+                        reader.state = BodyState::Done { complete: false };
+                        return Err(TorError::network(format!(
+                            "Connection closed {} bytes before the end of the body",
+                            remaining
+                        )));
+                    }
+                    let take = (*remaining).min(self.buffer.len());
+                    let chunk: Vec<u8> = self.buffer.drain(..take).collect();
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        reader.state = BodyState::Done { complete: true };
+                    }
+                    return Ok(Some(chunk));
+                }
+                BodyState::Chunked(decoder) => {
+                    let mut chunk = Vec::new();
+                    let consumed = decoder.decode(&self.buffer, &mut chunk).map_err(|e| {
+                        TorError::http_request(format!("Failed to decode chunked body: {}", e))
+                    })?;
+                    self.buffer.drain(..consumed);
+                    if decoder.is_done() {
+                        reader.state = BodyState::Done { complete: true };
+                    } else if chunk.is_empty() && self.fill(deadline).await? == 0 {
+                        reader.state = BodyState::Done { complete: false };
+                        return Err(TorError::network("Connection closed inside chunked body"));
+                    }
+                    if !chunk.is_empty() {
+                        return Ok(Some(chunk));
+                    }
+                }
+                BodyState::UntilClose => {
+                    if self.buffer.is_empty() {
+                        match self.read_more(deadline).await? {
+                            Ok(0) => {
+                                reader.state = BodyState::Done { complete: false };
+                                continue;
+                            }
+                            Ok(_) => {}
+                            // Only the close marks the end of this body, and
+                            // some servers reset instead of closing cleanly
+                            Err(e) => {
+                                debug!("Close-delimited body ended with error: {}", e);
+                                reader.state = BodyState::Done { complete: false };
+                                continue;
+                            }
+                        }
+                    }
+                    return Ok(Some(std::mem::take(&mut self.buffer)));
+                }
+            }
+        }
+    }
+
+    /// Read a whole body, up to `MAX_BODY_SIZE`
+    ///
+    /// The flag says whether the connection can carry another request.
+    pub async fn read_body(
+        &mut self,
+        head: &ResponseHead,
+        method: &Method,
+        deadline: &RequestDeadline,
+    ) -> Result<(Vec<u8>, bool)> {
+        let mut reader = BodyReader::new(head.framing(method));
+        let mut body = Vec::new();
+        while let Some(chunk) = self.read_chunk(&mut reader, deadline).await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_BODY_SIZE {
+                warn!("Response exceeds 1MB limit, truncating");
+                body.truncate(MAX_BODY_SIZE);
+                return Ok((body, false));
+            }
+        }
+        Ok((body, reader.is_complete() && head.keeps_alive()))
+    }
+
+    /// Read more bytes into the buffer; `Ok(0)` at EOF
+    async fn fill(&mut self, deadline: &RequestDeadline) -> Result<usize> {
+        self.read_more(deadline)
+            .await?
+            .map_err(|e| TorError::network(format!("Failed to read response: {}", e)))
+    }
+
+    /// Like `fill`, but hands back read errors for the caller to judge
+    ///
+    /// The outer error is the deadline running out.
+    async fn read_more(&mut self, deadline: &RequestDeadline) -> Result<io::Result<usize>> {
+        let phase = if self.received {
+            TimeoutPhase::Total
+        } else {
+            TimeoutPhase::FirstByte
+        };
+        let mut buf = [0u8; 8192];
+        let stream = &mut self.stream;
+        let read = deadline
+            .run(phase, async { Ok(stream.read(&mut buf).await) })
+            .await?;
+        if let Ok(n) = read {
+            if n > 0 {
+                self.received = true;
+                self.buffer.extend_from_slice(&buf[..n]);
+            }
+        }
+        Ok(read)
+    }
+
+    async fn read_head(&mut self, deadline: &RequestDeadline) -> Result<ResponseHead> {
+        loop {
+            if let Some(end) = find_subsequence(&self.buffer, b"\r\n\r\n") {
+                let head = ResponseHead::parse(&self.buffer[..end])?;
+                self.buffer.drain(..end + 4);
+                return Ok(head);
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(TorError::http_request("Response head too large"));
+            }
+            if self.fill(deadline).await? == 0 {
+                return Err(TorError::http_request(if self.buffer.is_empty() {
+                    "Connection closed before response"
+                } else {
+                    "Invalid HTTP response: no header separator"
+                }));
+            }
+        }
+    }
+}
+
+/// One request and its response, over HTTP/1.1 or HTTP/2
+pub(crate) enum Exchange {
+    Http1(HttpConnection),
+    Http2(Http2Stream),
+}
+
+impl Exchange {
+    pub fn is_http2(&self) -> bool {
+        matches!(self, Exchange::Http2(_))
+    }
+
+    /// Whether any of the response arrived; see `HttpConnection`
+    pub fn received_response(&self) -> bool {
+        match self {
+            Exchange::Http1(c) => c.received_response(),
+            Exchange::Http2(s) => s.received_response(),
+        }
+    }
+
+    /// Whether the circuit under the exchange is still in service
+    pub fn circuit_alive(&self) -> bool {
+        match self {
+            Exchange::Http1(c) => c.circuit_alive(),
+            Exchange::Http2(s) => s.circuit_alive(),
+        }
+    }
+
+    /// Write more of the request body, already framed for HTTP/1.1
+    pub async fn write(&mut self, bytes: &[u8], deadline: &RequestDeadline) -> Result<()> {
+        match self {
+            Exchange::Http1(c) => c.write(bytes, deadline).await,
+            Exchange::Http2(s) => s.write(bytes, deadline).await,
+        }
+    }
+
+    /// Mark the end of a streamed body; HTTP/1.1 framing already did
+    pub async fn end_body(&mut self, deadline: &RequestDeadline) -> Result<()> {
+        match self {
+            Exchange::Http1(_) => Ok(()),
+            Exchange::Http2(s) => s.end_body(deadline).await,
+        }
+    }
+
+    pub async fn read_response_head(&mut self, deadline: &RequestDeadline) -> Result<ResponseHead> {
+        match self {
+            Exchange::Http1(c) => c.read_response_head(deadline).await,
+            Exchange::Http2(s) => s.read_response_head(deadline).await,
+        }
+    }
+
+    pub async fn wait_for_continue(
+        &mut self,
+        limit: Duration,
+        deadline: &RequestDeadline,
+    ) -> Result<Option<ResponseHead>> {
+        match self {
+            Exchange::Http1(c) => c.wait_for_continue(limit, deadline).await,
+            Exchange::Http2(s) => s.wait_for_continue(limit, deadline).await,
+        }
+    }
+
+    /// Next piece of the body; HTTP/2 frames it itself, ignoring `reader`
+    pub async fn read_chunk(
+        &mut self,
+        reader: &mut BodyReader,
+        deadline: &RequestDeadline,
+    ) -> Result<Option<Vec<u8>>> {
+        match self {
+            Exchange::Http1(c) => c.read_chunk(reader, deadline).await,
+            Exchange::Http2(s) => {
+                let chunk = s.read_chunk(deadline).await?;
+                if chunk.is_none() {
+                    reader.state = BodyState::Done { complete: true };
+                }
+                Ok(chunk)
+            }
+        }
+    }
+
+    /// Read a whole body, up to `MAX_BODY_SIZE`
+    ///
+    /// The flag says whether the exchange finished cleanly, leaving an
+    /// HTTP/1.1 connection reusable.
+    pub async fn read_body(
+        &mut self,
+        head: &ResponseHead,
+        method: &Method,
+        deadline: &RequestDeadline,
+    ) -> Result<(Vec<u8>, bool)> {
+        match self {
+            Exchange::Http1(c) => c.read_body(head, method, deadline).await,
+            Exchange::Http2(s) => s.read_body(deadline).await,
+        }
+    }
+}
+
+/// Whether a connection's circuit is still in service; `None` means no circuit
+pub(crate) fn circuit_in_service(circuit: Option<&Weak<RwLock<Circuit>>>) -> bool {
+    match circuit {
+        Some(weak) => match weak.upgrade() {
+            // Busy means someone is using it, so it is still around
+            Some(circuit) => circuit.try_read().map_or(true, |c| c.is_ready()),
+            None => false,
+        },
+        None => true,
+    }
+}
+
+/// Mark a connection's circuit as used so idle cleanup leaves it alone
+pub(crate) fn touch_circuit(circuit: Option<&Weak<RwLock<Circuit>>>) {
+    if let Some(circuit) = circuit.and_then(Weak::upgrade) {
+        if let Ok(mut circuit) = circuit.try_write() {
+            circuit.update_last_used();
+        }
+    }
+}
+
+/// 1xx responses other than 101 Switching Protocols precede the final one
+pub(crate) fn is_interim(status: u16) -> bool {
+    (100..200).contains(&status) && status != 101
+}
+
+/// Find the position of a subsequence in a byte slice
+pub(crate) fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpTimeouts;
+    use crate::test_util::{portable_test, portable_test_async};
+
+    fn head(raw: &str) -> ResponseHead {
+        ResponseHead::parse(raw.as_bytes()).unwrap()
+    }
+
+    fn deadline() -> RequestDeadline {
+        RequestDeadline::with_timeouts(Duration::from_secs(5), HttpTimeouts::default())
+    }
+
+    /// Send a GET and read its whole response
+    async fn get(connection: &mut HttpConnection) -> Result<(ResponseHead, Vec<u8>, bool)> {
+        let deadline = deadline();
+        connection
+            .start_request(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n", &deadline)
+            .await?;
+        let head = connection.read_response_head(&deadline).await?;
+        let (body, reusable) = connection.read_body(&head, &Method::GET, &deadline).await?;
+        Ok((head, body, reusable))
+    }
+
+    #[portable_test_async]
+    async fn test_keep_alive_connection_carries_two_responses() {
+        // The second response arrives in the same read as the end of the
+        // first, split mid-chunk from the rest
+        let mut connection = HttpConnection::new(
+            ScriptedStream::replying([
+                &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHel"[..],
+                b"loHTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab",
+                b"c\r\n0\r\n\r\n",
+            ]),
+            None,
+        );
+
+        let (head, body, reusable) = get(&mut connection).await.unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(body, b"Hello");
+        assert!(reusable);
+
+        let (head, body, reusable) = get(&mut connection).await.unwrap();
+        assert_eq!(head.status, 201);
+        assert_eq!(body, b"abc");
+        assert!(reusable);
+
+        let HttpStream::Scripted(peer) = connection.into_parts().0 else {
+            unreachable!()
+        };
+        let written = String::from_utf8(peer.written).unwrap();
+        assert_eq!(written.matches("GET / HTTP/1.1").count(), 2);
+    }
+
+    #[portable_test_async]
+    async fn test_truncated_body_is_a_network_error() {
+        for reply in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHello"[..],
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n",
+        ] {
+            let mut connection = HttpConnection::new(ScriptedStream::replying([reply]), None);
+            let err = get(&mut connection).await.unwrap_err();
+            assert!(matches!(err, TorError::Network(_)), "{:?}", err);
+        }
+    }
+
+    #[portable_test_async]
+    async fn test_read_error_mid_body_is_a_network_error() {
+        let reads = [
+            Ok(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHello".to_vec()),
+            Err(io::ErrorKind::ConnectionReset.into()),
+        ];
+        let stream = HttpStream::Scripted(Box::new(ScriptedStream {
+            reads: reads.into_iter().collect(),
+            ..ScriptedStream::default()
+        }));
+        let mut connection = HttpConnection::new(stream, None);
+        let err = get(&mut connection).await.unwrap_err();
+        assert!(matches!(err, TorError::Network(_)), "{:?}", err);
+    }
+
+    #[portable_test_async]
+    async fn test_close_delimited_body_ends_at_close() {
+        let reads = [
+            Ok(b"HTTP/1.1 200 OK\r\n\r\nHello".to_vec()),
+            Err(io::ErrorKind::ConnectionReset.into()),
+        ];
+        let stream = HttpStream::Scripted(Box::new(ScriptedStream {
+            reads: reads.into_iter().collect(),
+            ..ScriptedStream::default()
+        }));
+        let mut connection = HttpConnection::new(stream, None);
+        let (_, body, reusable) = get(&mut connection).await.unwrap();
+        assert_eq!(body, b"Hello");
+        assert!(!reusable);
+    }
+
+    #[portable_test]
+    fn test_chunked_decoder_stops_at_end_of_body() {
+        let input =
+            b"5\r\nHello\r\n6;ext=1\r\n World\r\n0\r\nX-Trailer: yes\r\n\r\nHTTP/1.1 200 OK";
+        let mut decoder = ChunkedDecoder::new();
+        let mut out = Vec::new();
+
+        // Feed one byte at a time to exercise every state boundary
+        let mut consumed = 0;
+        while !decoder.is_done() {
+            consumed += decoder
+                .decode(&input[consumed..consumed + 1], &mut out)
+                .unwrap();
+        }
+        assert_eq!(out, b"Hello World");
+        assert_eq!(&input[consumed..], b"HTTP/1.1 200 OK");
+        assert_eq!(decoder.decode(b"more", &mut out).unwrap(), 0);
+    }
+
+    #[portable_test]
+    fn test_chunked_decoder_rejects_garbage() {
+        let mut out = Vec::new();
+        assert!(ChunkedDecoder::new().decode(b"ZZ\r\n", &mut out).is_err());
+        assert!(ChunkedDecoder::new()
+            .decode(b"2\r\nabXY\r\n", &mut out)
+            .is_err());
+    }
+
+    #[portable_test]
+    fn test_response_framing() {
+        let get = Method::GET;
+        assert_eq!(
+            head("HTTP/1.1 200 OK\r\nContent-Length: 12").framing(&get),
+            BodyFraming::Length(12)
+        );
+        assert_eq!(
+            head("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, Chunked\r\nContent-Length: 3")
+                .framing(&get),
+            BodyFraming::Chunked
+        );
+        assert_eq!(
+            head("HTTP/1.1 200 OK").framing(&get),
+            BodyFraming::UntilClose
+        );
+        assert_eq!(
+            head("HTTP/1.1 200 OK\r\nContent-Length: 12").framing(&Method::HEAD),
+            BodyFraming::Empty
+        );
+        assert_eq!(
+            head("HTTP/1.1 304 Not Modified").framing(&get),
+            BodyFraming::Empty
+        );
+    }
+
+    #[portable_test]
+    fn test_keep_alive_rules() {
+        assert!(head("HTTP/1.1 200 OK").keeps_alive());
+        assert!(!head("HTTP/1.1 200 OK\r\nConnection: close").keeps_alive());
+        assert!(!head("HTTP/1.0 200 OK").keeps_alive());
+        assert!(head("HTTP/1.0 200 OK\r\nConnection: Keep-Alive").keeps_alive());
+    }
+
+    #[portable_test]
+    fn test_response_head_keeps_repeated_headers() {
+        let parsed = head(
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nX-Order: first\r\nset-cookie: b=2\r\n\
+             Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\nBad Header: x",
+        );
+        let cookies: Vec<_> = parsed.headers.get_all("Set-Cookie").iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(parsed.headers.get("x-order").unwrap(), "first");
+        assert_eq!(
+            joined_header(&parsed.headers, header::TRANSFER_ENCODING).as_deref(),
+            Some("gzip, chunked")
+        );
+        assert_eq!(parsed.framing(&Method::GET), BodyFraming::Chunked);
+        assert_eq!(parsed.headers.len(), 5);
+        assert!(joined_header(&parsed.headers, "content-type").is_none());
+    }
+}
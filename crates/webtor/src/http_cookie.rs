@@ -0,0 +1,629 @@
+//! Cookie jar for HTTP requests
+//!
+//! Cookies are handled per RFC 6265 and stored in one partition per
+//! isolation key. A cookie set on a response is only ever sent with requests
+//! under the same key, so cookies can't link first-party domains that
+//! stream isolation keeps on separate circuits.
+
+use crate::isolation::IsolationKey;
+use crate::onion::is_onion_host;
+use crate::time::system_time_now;
+use http::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tracing::debug;
+use url::Url;
+
+/// Largest name plus value accepted from `Set-Cookie`
+pub const MAX_COOKIE_SIZE: usize = 4096;
+
+/// Cookies kept per partition; the oldest are evicted first
+pub const MAX_COOKIES_PER_PARTITION: usize = 500;
+
+/// A stored cookie
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lowercased, without a leading dot
+    pub domain: String,
+    /// Only sent to `domain` itself, not to its subdomains
+    pub host_only: bool,
+    pub path: String,
+    /// Unix time in seconds; `None` for a session cookie
+    pub expires: Option<u64>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn same_slot(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+
+    /// Whether the cookie goes with a request to `url`
+    fn matches(&self, url: &Url, host: &str) -> bool {
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(host, &self.domain)
+        };
+        domain_ok && path_matches(url.path(), &self.path) && (!self.secure || is_secure(url))
+    }
+}
+
+/// Cookies partitioned by isolation key
+///
+/// `None` is the partition for requests made without isolation.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    partitions: Mutex<HashMap<Option<IsolationKey>, Vec<Cookie>>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store the cookies a response to `url` set
+    pub(crate) fn store(&self, key: Option<&IsolationKey>, url: &Url, headers: &HeaderMap) {
+        let now = now_secs();
+        let cookies: Vec<Cookie> = headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| parse_set_cookie(value, url, now))
+            .collect();
+        if cookies.is_empty() {
+            return;
+        }
+
+        let mut partitions = self.partitions.lock().unwrap();
+        let partition = partitions.entry(key.cloned()).or_default();
+        for cookie in cookies {
+            debug!("Storing cookie {} for {}", cookie.name, cookie.domain);
+            insert(partition, cookie, now);
+        }
+    }
+
+    /// `Cookie` header value for a request to `url`, if any cookies apply
+    ///
+    /// Longer paths come first, then older cookies, as RFC 6265 suggests.
+    pub(crate) fn header_for(&self, key: Option<&IsolationKey>, url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let now = now_secs();
+        let mut partitions = self.partitions.lock().unwrap();
+        let partition = partitions.get_mut(&key.cloned())?;
+        partition.retain(|cookie| !cookie.is_expired(now));
+
+        let mut cookies: Vec<&Cookie> = partition
+            .iter()
+            .filter(|cookie| cookie.matches(url, &host))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        // Stable, so older cookies stay ahead among equal path lengths
+        cookies.sort_by_key(|cookie| Reverse(cookie.path.len()));
+        let pairs: Vec<String> = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Unexpired cookies in a partition, oldest first
+    pub fn export(&self, key: Option<&IsolationKey>) -> Vec<Cookie> {
+        let now = now_secs();
+        self.partitions
+            .lock()
+            .unwrap()
+            .get(&key.cloned())
+            .map(|partition| {
+                partition
+                    .iter()
+                    .filter(|cookie| !cookie.is_expired(now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Add cookies to a partition, replacing any with the same name, domain
+    /// and path
+    pub fn import(&self, key: Option<&IsolationKey>, cookies: impl IntoIterator<Item = Cookie>) {
+        let now = now_secs();
+        let mut partitions = self.partitions.lock().unwrap();
+        let partition = partitions.entry(key.cloned()).or_default();
+        for mut cookie in cookies {
+            cookie.domain = cookie.domain.trim_start_matches('.').to_ascii_lowercase();
+            if cookie.name.is_empty() || cookie.domain.is_empty() || !cookie.path.starts_with('/') {
+                continue;
+            }
+            insert(partition, cookie, now);
+        }
+    }
+
+    /// Drop every cookie in a partition
+    pub fn clear(&self, key: Option<&IsolationKey>) {
+        self.partitions.lock().unwrap().remove(&key.cloned());
+    }
+
+    /// Drop every cookie in every partition
+    pub fn clear_all(&self) {
+        self.partitions.lock().unwrap().clear();
+    }
+}
+
+/// Append stored `cookies` to the caller's `Cookie` header, returning the
+/// values it replaced so they can be put back
+pub(crate) fn merge_cookie_header(headers: &mut HeaderMap, cookies: &str) -> Vec<HeaderValue> {
+    let previous: Vec<HeaderValue> = headers.get_all(header::COOKIE).iter().cloned().collect();
+    let merged = previous
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain([cookies])
+        .collect::<Vec<_>>()
+        .join("; ");
+    match HeaderValue::from_str(&merged) {
+        Ok(value) => {
+            headers.insert(header::COOKIE, value);
+        }
+        Err(e) => debug!("Not sending stored cookies: {}", e),
+    }
+    previous
+}
+
+/// Put back `Cookie` values saved by `merge_cookie_header`
+pub(crate) fn restore_cookie_header(headers: &mut HeaderMap, previous: Vec<HeaderValue>) {
+    headers.remove(header::COOKIE);
+    for value in previous {
+        headers.append(header::COOKIE, value);
+    }
+}
+
+/// Store `cookie`, replacing one with the same name, domain and path
+///
+/// The replacement keeps the old cookie's place, which stands in for its
+/// creation time. An already expired cookie just deletes the old one.
+fn insert(partition: &mut Vec<Cookie>, cookie: Cookie, now: u64) {
+    let existing = partition.iter().position(|c| c.same_slot(&cookie));
+    match (existing, cookie.is_expired(now)) {
+        (Some(i), true) => {
+            partition.remove(i);
+        }
+        (Some(i), false) => partition[i] = cookie,
+        (None, true) => {}
+        (None, false) => {
+            partition.retain(|c| !c.is_expired(now));
+            if partition.len() >= MAX_COOKIES_PER_PARTITION {
+                partition.remove(0);
+            }
+            partition.push(cookie);
+        }
+    }
+}
+
+/// Parse one `Set-Cookie` value received from `url` (RFC 6265 section 5.2)
+///
+/// `None` if the cookie is malformed or the response may not set it.
+fn parse_set_cookie(value: &str, url: &Url, now: u64) -> Option<Cookie> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut parts = value.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    if name.is_empty() || name.len() + value.len() > MAX_COOKIE_SIZE {
+        return None;
+    }
+
+    let mut expires = None;
+    let mut max_age = None;
+    let mut domain = None;
+    let mut path = None;
+    let mut secure = false;
+    let mut http_only = false;
+    for attribute in parts {
+        let (key, val) = match attribute.split_once('=') {
+            Some((key, val)) => (key.trim(), val.trim()),
+            None => (attribute.trim(), ""),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "expires" => expires = parse_cookie_date(val).or(expires),
+            "max-age" => max_age = parse_max_age(val, now).or(max_age),
+            "domain" if !val.is_empty() => {
+                domain = Some(val.trim_start_matches('.').to_ascii_lowercase())
+            }
+            "path" => path = val.starts_with('/').then(|| val.to_string()),
+            "secure" => secure = true,
+            "httponly" => http_only = true,
+            _ => {}
+        }
+    }
+
+    // Max-Age wins over Expires
+    let expires = max_age.or(expires);
+
+    let (domain, host_only) = match domain {
+        Some(domain) if is_public_suffix(&domain) => {
+            // A public suffix can only be set by that host itself
+            if domain != host {
+                return None;
+            }
+            (domain, true)
+        }
+        Some(domain) => {
+            if !domain_matches(&host, &domain) {
+                return None;
+            }
+            (domain, false)
+        }
+        None => (host, true),
+    };
+
+    if secure && !is_secure(url) {
+        return None;
+    }
+
+    Some(Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain,
+        host_only,
+        path: path.unwrap_or_else(|| default_path(url.path())),
+        expires,
+        secure,
+        http_only,
+    })
+}
+
+/// Whether `host` falls under `domain` (RFC 6265 section 5.1.3)
+fn domain_matches(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    let is_ip = host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[');
+    !is_ip
+        && host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Whether `path` falls under `cookie_path` (RFC 6265 section 5.1.4)
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    let path = if path.is_empty() { "/" } else { path };
+    match path.strip_prefix(cookie_path) {
+        Some(rest) => rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Directory of the request path, used when `Set-Cookie` has no `Path`
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+/// Whether a suffix like `com` or `co.uk` is being set as a cookie domain
+fn is_public_suffix(domain: &str) -> bool {
+    if domain.parse::<std::net::IpAddr>().is_ok() {
+        return false;
+    }
+    public_suffix::EffectiveTLDProvider::effective_tld_plus_one(
+        &public_suffix::DEFAULT_PROVIDER,
+        domain,
+    )
+    .is_err()
+}
+
+/// Secure cookies need https, or an onion service, which is end-to-end
+/// encrypted by Tor
+fn is_secure(url: &Url) -> bool {
+    url.scheme() == "https" || url.host_str().is_some_and(is_onion_host)
+}
+
+fn parse_max_age(value: &str, now: u64) -> Option<u64> {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if value.starts_with('-') {
+        return Some(0);
+    }
+    // Too many digits to parse means far in the future
+    let seconds = digits.parse::<u64>().unwrap_or(u64::MAX);
+    Some(if seconds == 0 {
+        0
+    } else {
+        now.saturating_add(seconds)
+    })
+}
+
+/// Parse a cookie date (RFC 6265 section 5.1.1) to Unix seconds
+fn parse_cookie_date(value: &str) -> Option<u64> {
+    let is_delimiter = |c: char| matches!(c, '\t' | ' '..='/' | ';'..='@' | '['..='`' | '{'..='~');
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+
+    for token in value.split(is_delimiter).filter(|t| !t.is_empty()) {
+        if time.is_none() {
+            if let Some(parsed) = parse_time(token) {
+                time = Some(parsed);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some(parsed) = leading_number(token, 1, 2) {
+                day = Some(parsed);
+                continue;
+            }
+        }
+        if month.is_none() {
+            if let Some(parsed) = parse_month(token) {
+                month = Some(parsed);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some(parsed) = leading_number(token, 2, 4) {
+                year = Some(parsed);
+            }
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let (day, month, mut year) = (day?, month?, year?);
+    if (70..=99).contains(&year) {
+        year += 1900;
+    } else if year <= 69 {
+        year += 2000;
+    }
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month, day as u32);
+    let seconds = days * 86_400 + (hour * 3600 + minute * 60 + second) as i64;
+    Some(seconds.max(0) as u64)
+}
+
+/// `hh:mm:ss` with one or two digits per field
+fn parse_time(token: &str) -> Option<(u64, u64, u64)> {
+    let mut fields = token.splitn(3, ':');
+    let hour = leading_number(fields.next()?, 1, 2)?;
+    let minute = leading_number(fields.next()?, 1, 2)?;
+    let second = leading_number(fields.next()?, 1, 2)?;
+    Some((hour, minute, second))
+}
+
+/// A run of `min..=max` digits at the start of `token`, possibly followed
+/// by non-digits
+fn leading_number(token: &str, min: usize, max: usize) -> Option<u64> {
+    let digits = token.bytes().take_while(u8::is_ascii_digit).count();
+    if digits < min || digits > max {
+        return None;
+    }
+    token[..digits].parse().ok()
+}
+
+fn parse_month(token: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let prefix = token.get(..3)?.to_ascii_lowercase();
+    MONTHS
+        .iter()
+        .position(|month| *month == prefix)
+        .map(|i| i as u32 + 1)
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn now_secs() -> u64 {
+    system_time_now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn set_cookie(value: &str, from: &str) -> Option<Cookie> {
+        parse_set_cookie(value, &url(from), NOW)
+    }
+
+    fn response(cookies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(header::SET_COOKIE, cookie.parse().unwrap());
+        }
+        headers
+    }
+
+    #[portable_test]
+    fn test_parse_set_cookie_attributes() {
+        let cookie = set_cookie(
+            "sid=abc; Domain=.Example.com; Path=/api; Secure; HttpOnly; Max-Age=60",
+            "https://www.example.com/login",
+        )
+        .unwrap();
+        assert_eq!(cookie.name, "sid");
+        assert_eq!(cookie.value, "abc");
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert_eq!(cookie.path, "/api");
+        assert_eq!(cookie.expires, Some(NOW + 60));
+        assert!(cookie.secure && cookie.http_only);
+
+        let cookie = set_cookie("a=1", "http://example.com/docs/page").unwrap();
+        assert!(cookie.host_only);
+        assert_eq!(cookie.domain, "example.com");
+        assert_eq!(cookie.path, "/docs");
+        assert_eq!(cookie.expires, None);
+
+        assert_eq!(
+            set_cookie(
+                "a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+                "http://example.com/"
+            )
+            .unwrap()
+            .expires,
+            Some(1_445_412_480)
+        );
+        assert_eq!(
+            set_cookie(
+                "a=1; Max-Age=-1; Expires=Wed, 21 Oct 2099 07:28:00 GMT",
+                "http://a.com/"
+            )
+            .unwrap()
+            .expires,
+            Some(0)
+        );
+    }
+
+    #[portable_test]
+    fn test_parse_set_cookie_rejections() {
+        // Foreign domain, public suffix, missing '=', insecure Secure
+        assert!(set_cookie("a=1; Domain=other.com", "https://example.com/").is_none());
+        assert!(set_cookie("a=1; Domain=com", "https://example.com/").is_none());
+        assert!(set_cookie("a=1; Domain=co.uk", "https://example.co.uk/").is_none());
+        assert!(set_cookie("novalue", "https://example.com/").is_none());
+        assert!(set_cookie("=1", "https://example.com/").is_none());
+        assert!(set_cookie("a=1; Secure", "http://example.com/").is_none());
+        assert!(set_cookie("a=1; Domain=1.2.3.4", "http://1.2.3.4/").is_some());
+        assert!(set_cookie("a=1; Domain=2.3.4", "http://1.2.3.4/").is_none());
+    }
+
+    #[portable_test]
+    fn test_cookie_dates() {
+        assert_eq!(parse_cookie_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_cookie_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            parse_cookie_date("Sun Nov  6 08:49:37 1994"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            parse_cookie_date("29 Feb 2024 12:00:00"),
+            Some(1_709_208_000)
+        );
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015"), None);
+        assert_eq!(parse_cookie_date("Wed, 32 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[portable_test]
+    fn test_domain_and_path_matching() {
+        assert!(domain_matches("www.example.com", "example.com"));
+        assert!(!domain_matches("badexample.com", "example.com"));
+        assert!(path_matches("/api/users", "/api"));
+        assert!(path_matches("/api", "/api"));
+        assert!(path_matches("/api/users", "/api/"));
+        assert!(!path_matches("/apiary", "/api"));
+        assert_eq!(default_path(""), "/");
+        assert_eq!(default_path("/file"), "/");
+        assert_eq!(default_path("/a/b/c"), "/a/b");
+    }
+
+    #[portable_test]
+    fn test_jar_partitions_and_header_order() {
+        let jar = CookieJar::new();
+        let a = IsolationKey::from_string("example.com");
+        let b = IsolationKey::from_string("other.org");
+        let login = url("https://www.example.com/api/login");
+
+        jar.store(
+            Some(&a),
+            &login,
+            &response(&[
+                "root=1; Path=/; Domain=example.com",
+                "api=2; Path=/api",
+                "gone=3; Max-Age=0",
+            ]),
+        );
+
+        let api = url("https://www.example.com/api/items");
+        assert_eq!(
+            jar.header_for(Some(&a), &api).as_deref(),
+            Some("api=2; root=1")
+        );
+        assert_eq!(
+            jar.header_for(Some(&a), &url("https://cdn.example.com/"))
+                .as_deref(),
+            Some("root=1")
+        );
+        // Same URL under another key sees nothing
+        assert_eq!(jar.header_for(Some(&b), &api), None);
+        assert_eq!(jar.header_for(None, &api), None);
+
+        // Replacing keeps the cookie's place; an expired one deletes it
+        jar.store(
+            Some(&a),
+            &login,
+            &response(&["root=new; Path=/; Domain=example.com"]),
+        );
+        assert_eq!(jar.export(Some(&a))[0].value, "new");
+        jar.store(
+            Some(&a),
+            &login,
+            &response(&["api=x; Path=/api; Max-Age=0"]),
+        );
+        assert_eq!(jar.export(Some(&a)).len(), 1);
+
+        let exported = jar.export(Some(&a));
+        jar.import(Some(&b), exported.clone());
+        assert_eq!(jar.export(Some(&b)), exported);
+
+        jar.clear(Some(&a));
+        assert!(jar.export(Some(&a)).is_empty());
+        assert!(!jar.export(Some(&b)).is_empty());
+        jar.clear_all();
+        assert!(jar.export(Some(&b)).is_empty());
+    }
+
+    #[portable_test]
+    fn test_merge_and_restore_cookie_header() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, HeaderValue::from_static("own=1"));
+        let previous = merge_cookie_header(&mut headers, "jar=2");
+        assert_eq!(headers.get(header::COOKIE).unwrap(), "own=1; jar=2");
+        restore_cookie_header(&mut headers, previous);
+        assert_eq!(headers.get(header::COOKIE).unwrap(), "own=1");
+
+        let mut headers = HeaderMap::new();
+        let previous = merge_cookie_header(&mut headers, "jar=2");
+        assert_eq!(headers.get(header::COOKIE).unwrap(), "jar=2");
+        restore_cookie_header(&mut headers, previous);
+        assert!(headers.get(header::COOKIE).is_none());
+    }
+}
@@ -0,0 +1,217 @@
+//! Idle HTTP connections kept for reuse
+//!
+//! Opening a connection costs a stream BEGIN and usually a TLS handshake,
+//! which takes seconds over Snowflake. Finished keep-alive connections are
+//! parked here, keyed by isolation key as well as origin so a connection
+//! never carries requests for two isolation groups.
+//!
+//! HTTP/2 connections are shared rather than parked: every request to the
+//! origin opens a stream on the same connection for as long as it lasts.
+
+use crate::http2::Http2Connection;
+use crate::http_conn::{Exchange, HttpConnection};
+use crate::isolation::IsolationKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::debug;
+use url::Url;
+
+/// How long an idle connection is kept
+///
+/// Servers commonly drop idle connections after 5-60 seconds; requests on a
+/// connection the server already closed are retried on a fresh one.
+pub const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Idle connections kept per key
+pub const MAX_IDLE_PER_KEY: usize = 4;
+
+/// Which requests may share a connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub isolation_key: Option<IsolationKey>,
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl PoolKey {
+    pub fn new(url: &Url, isolation_key: Option<IsolationKey>) -> Option<Self> {
+        Some(Self {
+            isolation_key,
+            scheme: url.scheme().to_ascii_lowercase(),
+            host: url.host_str()?.to_ascii_lowercase(),
+            port: url.port_or_known_default()?,
+        })
+    }
+}
+
+/// Idle keep-alive connections and shared HTTP/2 connections
+#[derive(Default)]
+pub(crate) struct ConnectionPool {
+    idle: Mutex<HashMap<PoolKey, Vec<HttpConnection>>>,
+    shared: Mutex<HashMap<PoolKey, Http2Connection>>,
+    /// Held while connecting to an origin whose protocol isn't known yet;
+    /// `None` once it turned out to be HTTP/1.1 only
+    gates: Mutex<HashMap<PoolKey, Option<Arc<AsyncMutex<()>>>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A stream on the shared HTTP/2 connection for `key`, or else an idle
+    /// HTTP/1.1 connection
+    pub fn checkout(&self, key: &PoolKey) -> Option<Exchange> {
+        if let Some(connection) = self.shared(key) {
+            debug!("Sharing HTTP/2 connection to {}:{}", key.host, key.port);
+            connection.touch_circuit();
+            return Some(Exchange::Http2(connection.stream()));
+        }
+        self.take(key).map(Exchange::Http1)
+    }
+
+    fn shared(&self, key: &PoolKey) -> Option<Http2Connection> {
+        let mut shared = self.shared.lock().unwrap();
+        Self::prune_shared(&mut shared);
+        shared.get(key).cloned()
+    }
+
+    /// Offer an HTTP/2 connection to every request for `key`
+    pub fn share(&self, key: PoolKey, connection: Http2Connection) {
+        self.shared.lock().unwrap().insert(key, connection);
+    }
+
+    /// Lock to hold while connecting to an https origin that may speak
+    /// HTTP/2, so a burst of requests shares one connection instead of
+    /// opening one each
+    ///
+    /// `None` once the origin is known to speak only HTTP/1.1.
+    pub fn connect_gate(&self, key: &PoolKey) -> Option<Arc<AsyncMutex<()>>> {
+        if key.scheme != "https" {
+            return None;
+        }
+        self.gates
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| Some(Arc::default()))
+            .clone()
+    }
+
+    /// Note that `key` picked HTTP/1.1, so connections need not wait on
+    /// each other
+    pub fn learned_http1(&self, key: &PoolKey) {
+        self.gates.lock().unwrap().insert(key.clone(), None);
+    }
+
+    /// Whether `key` is known to speak only HTTP/1.1
+    pub fn is_http1_only(&self, key: &PoolKey) -> bool {
+        matches!(self.gates.lock().unwrap().get(key), Some(None))
+    }
+
+    /// Take the most recently used live connection for `key`
+    fn take(&self, key: &PoolKey) -> Option<HttpConnection> {
+        let mut idle = self.idle.lock().unwrap();
+        Self::prune(&mut idle);
+        let connections = idle.get_mut(key)?;
+        let connection = connections.pop();
+        if connections.is_empty() {
+            idle.remove(key);
+        }
+        if let Some(ref connection) = connection {
+            debug!("Reusing pooled connection to {}:{}", key.host, key.port);
+            connection.touch_circuit();
+        }
+        connection
+    }
+
+    /// Park a connection that finished its response cleanly
+    ///
+    /// HTTP/2 streams just close; their connection stays shared.
+    pub fn put(&self, key: PoolKey, exchange: Exchange) {
+        let Exchange::Http1(connection) = exchange else {
+            return;
+        };
+        let mut idle = self.idle.lock().unwrap();
+        Self::prune(&mut idle);
+        let connections = idle.entry(key).or_default();
+        if connections.len() >= MAX_IDLE_PER_KEY {
+            connections.remove(0);
+        }
+        connections.push(connection);
+    }
+
+    /// Drop connections whose circuit was retired or that sat idle too long
+    fn prune(idle: &mut HashMap<PoolKey, Vec<HttpConnection>>) {
+        idle.retain(|key, connections| {
+            connections.retain(|c| {
+                let keep = c.circuit_alive() && c.idle_for() < POOL_IDLE_TIMEOUT;
+                if !keep {
+                    debug!("Evicting pooled connection to {}:{}", key.host, key.port);
+                }
+                keep
+            });
+            !connections.is_empty()
+        });
+    }
+
+    /// Drop HTTP/2 connections that are closing or sat idle too long
+    fn prune_shared(shared: &mut HashMap<PoolKey, Http2Connection>) {
+        shared.retain(|key, c| {
+            let keep = c.is_usable() && c.idle_for() < POOL_IDLE_TIMEOUT;
+            if !keep {
+                debug!("Dropping HTTP/2 connection to {}:{}", key.host, key.port);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+        self.shared.lock().unwrap().clear();
+        self.gates.lock().unwrap().clear();
+    }
+
+    /// Number of idle and shared connections, after dropping dead ones
+    pub fn len(&self) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        Self::prune(&mut idle);
+        let mut shared = self.shared.lock().unwrap();
+        Self::prune_shared(&mut shared);
+        idle.values().map(Vec::len).sum::<usize>() + shared.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    #[portable_test]
+    fn test_pool_key_separates_isolation_and_origin() {
+        let url = Url::parse("https://Example.com/a").unwrap();
+        let a = PoolKey::new(&url, Some(IsolationKey::from_string("a"))).unwrap();
+        let b = PoolKey::new(&url, Some(IsolationKey::from_string("b"))).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a.host, "example.com");
+        assert_eq!(a.port, 443);
+
+        let http = PoolKey::new(
+            &Url::parse("http://example.com:443/").unwrap(),
+            Some(IsolationKey::from_string("a")),
+        )
+        .unwrap();
+        assert_ne!(a, http);
+        assert_eq!(
+            a,
+            PoolKey::new(
+                &Url::parse("https://example.com:443/b").unwrap(),
+                Some(IsolationKey::from_string("a"))
+            )
+            .unwrap()
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Redirect following for HTTP requests
+//!
+//! Each hop is a new request built from the previous one per RFC 9110
+//! section 15.4: 303 turns into GET, 301/302 turn a POST into GET as
+//! browsers do, and 307/308 resend the same method and body. Credentials
+//! never follow a redirect to another origin.
+
+use crate::http::HttpRequest;
+use http::header::{self, HeaderName};
+use http::Method;
+
+/// Headers dropped when a redirect leaves the original origin
+const SENSITIVE_HEADERS: &[HeaderName] = &[
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+];
+
+/// Headers describing a body, dropped when a redirect discards it
+const BODY_HEADERS: &[HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::CONTENT_LOCATION,
+    header::TRANSFER_ENCODING,
+    header::EXPECT,
+];
+
+/// How `TorHttpClient` handles 3xx responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectPolicy {
+    /// Redirects followed before giving up; 0 returns 3xx responses as-is
+    pub max_hops: usize,
+    /// Keep the first hop's isolation key for every hop, so the chain stays
+    /// on the original circuit where its exit allows, instead of picking
+    /// the key for each new URL
+    pub keep_circuit: bool,
+}
+
+impl RedirectPolicy {
+    /// Return 3xx responses to the caller untouched
+    pub fn none() -> Self {
+        Self {
+            max_hops: 0,
+            keep_circuit: false,
+        }
+    }
+
+    /// Follow up to `max_hops` redirects
+    pub fn limited(max_hops: usize) -> Self {
+        Self {
+            max_hops,
+            ..Self::default()
+        }
+    }
+
+    /// Keep every hop on the first hop's isolation key
+    pub fn with_keep_circuit(mut self, keep_circuit: bool) -> Self {
+        self.keep_circuit = keep_circuit;
+        self
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self {
+            max_hops: 10,
+            keep_circuit: false,
+        }
+    }
+}
+
+/// The request to send after a redirect
+#[derive(Debug)]
+pub(crate) struct Redirect {
+    pub request: HttpRequest,
+    /// Whether the body goes along; otherwise it was dropped with the method
+    pub keeps_body: bool,
+}
+
+/// Whether `status` is a redirect we know how to follow
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Build the next hop for a `status` response pointing at `location`
+///
+/// `None` when the response isn't a followable redirect or the location
+/// isn't a usable http(s) URL, in which case it goes back to the caller.
+pub(crate) fn next_request(request: &HttpRequest, status: u16, location: &str) -> Option<Redirect> {
+    if !is_redirect(status) {
+        return None;
+    }
+
+    let mut url = request.url.join(location.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    // A location without a fragment inherits the original one
+    if url.fragment().is_none() {
+        url.set_fragment(request.url.fragment());
+    }
+
+    let mut next = request.clone();
+    let keeps_body = match status {
+        303 => request.method == Method::HEAD,
+        301 | 302 => request.method != Method::POST,
+        _ => true,
+    };
+    if !keeps_body {
+        next.method = Method::GET;
+        next.body = None;
+        for name in BODY_HEADERS {
+            next.headers.remove(name);
+        }
+    }
+    if request.url.origin() != url.origin() {
+        for name in SENSITIVE_HEADERS {
+            next.headers.remove(name);
+        }
+    }
+    next.url = url;
+
+    Some(Redirect {
+        request: next,
+        keeps_body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+    use url::Url;
+
+    fn post(url: &str) -> HttpRequest {
+        let mut request = HttpRequest::new(Url::parse(url).unwrap());
+        request.method = Method::POST;
+        request.body = Some(b"a=1".to_vec());
+        request
+            .add_header("Content-Type", "application/x-www-form-urlencoded")
+            .unwrap();
+        request
+            .add_header("Authorization", "Bearer secret")
+            .unwrap();
+        request.add_header("Cookie", "session=1").unwrap();
+        request.add_header("X-Custom", "kept").unwrap();
+        request
+    }
+
+    #[portable_test]
+    fn test_redirect_method_rewriting() {
+        let request = post("https://example.com/form");
+
+        for status in [301, 302, 303] {
+            let redirect = next_request(&request, status, "/done").unwrap();
+            assert!(!redirect.keeps_body);
+            assert_eq!(redirect.request.method, Method::GET);
+            assert!(redirect.request.body.is_none());
+            assert!(!redirect.request.headers.contains_key("Content-Type"));
+            assert_eq!(redirect.request.url.as_str(), "https://example.com/done");
+        }
+
+        for status in [307, 308] {
+            let redirect = next_request(&request, status, "/retry").unwrap();
+            assert!(redirect.keeps_body);
+            assert_eq!(redirect.request.method, Method::POST);
+            assert_eq!(redirect.request.body.as_deref(), Some(&b"a=1"[..]));
+            assert!(redirect.request.headers.contains_key("Content-Type"));
+        }
+
+        // 303 keeps HEAD; 301/302 keep methods other than POST
+        let mut head = HttpRequest::new(Url::parse("http://example.com/").unwrap());
+        head.method = Method::HEAD;
+        assert_eq!(
+            next_request(&head, 303, "/x").unwrap().request.method,
+            Method::HEAD
+        );
+        let mut put = post("http://example.com/");
+        put.method = Method::PUT;
+        let redirect = next_request(&put, 301, "/x").unwrap();
+        assert!(redirect.keeps_body);
+        assert_eq!(redirect.request.method, Method::PUT);
+    }
+
+    #[portable_test]
+    fn test_redirect_strips_credentials_across_origins() {
+        let request = post("https://example.com/a#top");
+
+        let same = next_request(&request, 307, "https://example.com/b").unwrap();
+        assert!(same.request.headers.contains_key("Authorization"));
+        assert!(same.request.headers.contains_key("Cookie"));
+        assert_eq!(same.request.url.fragment(), Some("top"));
+
+        for location in [
+            "https://other.example/b",
+            "http://example.com/b",
+            "https://example.com:8443/b",
+        ] {
+            let cross = next_request(&request, 307, location).unwrap();
+            assert!(!cross.request.headers.contains_key("Authorization"));
+            assert!(!cross.request.headers.contains_key("Cookie"));
+            assert_eq!(cross.request.headers.get("X-Custom").unwrap(), "kept");
+        }
+    }
+
+    #[portable_test]
+    fn test_redirect_rejects_unusable_responses() {
+        let request = post("https://example.com/");
+        assert!(next_request(&request, 200, "/x").is_none());
+        assert!(next_request(&request, 304, "/x").is_none());
+        assert!(next_request(&request, 302, "ftp://example.com/x").is_none());
+        assert!(next_request(&request, 302, "http://[bad").is_none());
+        assert_eq!(RedirectPolicy::none().max_hops, 0);
+        assert!(
+            RedirectPolicy::limited(3)
+                .with_keep_circuit(true)
+                .keep_circuit
+        );
+    }
+}
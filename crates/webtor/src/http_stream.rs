@@ -0,0 +1,622 @@
+//! Streaming HTTP bodies
+//!
+//! `TorHttpClient::request_streaming` returns as soon as the response head is
+//! parsed. The body is then pulled off the connection piece by piece, with
+//! chunked transfer coding and Content-Encoding undone incrementally, so a
+//! download never has to fit in memory.
+//!
+//! Uploads go the other way: a `RequestBody` is written from a `Stream` or
+//! `AsyncRead` as it is produced, chunked when its length isn't known.
+
+use crate::error::{Result, TimeoutPhase, TorError};
+use crate::http::{content_codings, RequestDeadline};
+use crate::http_conn::{joined_header, BodyReader, Exchange, ResponseHead};
+use crate::http_pool::{ConnectionPool, PoolKey};
+use futures::io::{AsyncRead, AsyncReadExt};
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use http::header::{self, HeaderMap};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::debug;
+use url::Url;
+
+/// HTTP response whose body is still arriving
+pub struct StreamingResponse {
+    pub status: u16,
+    /// Headers in the order received, repeated names such as `Set-Cookie` kept
+    pub headers: HeaderMap,
+    /// Final URL, after any redirects
+    pub url: Url,
+    /// URLs that answered with a redirect, in the order they were followed
+    pub redirects: Vec<Url>,
+    /// Whether the body is decoded from a `Content-Encoding` as it streams
+    pub decompressed: bool,
+    body: ResponseBody,
+}
+
+impl StreamingResponse {
+    pub(crate) fn new(
+        head: ResponseHead,
+        url: Url,
+        redirects: Vec<Url>,
+        connection: Exchange,
+        reader: BodyReader,
+        deadline: RequestDeadline,
+        pool: Option<(Arc<ConnectionPool>, PoolKey)>,
+    ) -> Self {
+        let ResponseHead {
+            status,
+            mut headers,
+            ..
+        } = head;
+
+        // The headers describe the decoded body, as for buffered responses
+        let decoder = match joined_header(&headers, header::CONTENT_ENCODING)
+            .as_deref()
+            .and_then(content_codings)
+        {
+            Some(codings) => {
+                headers.remove(header::CONTENT_ENCODING);
+                headers.remove(header::CONTENT_LENGTH);
+                Some(ContentDecoder::new(&codings))
+            }
+            None => None,
+        };
+
+        Self {
+            status,
+            headers,
+            url,
+            redirects,
+            decompressed: decoder.is_some(),
+            body: ResponseBody {
+                connection: Some(connection),
+                reader,
+                deadline,
+                decoder,
+                pool,
+            },
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// The body, for reading piece by piece
+    pub fn body(&mut self) -> &mut ResponseBody {
+        &mut self.body
+    }
+
+    pub fn into_body(self) -> ResponseBody {
+        self.body
+    }
+}
+
+/// Body of a `StreamingResponse`
+///
+/// Once the body has been read to its end the connection goes back to the
+/// keep-alive pool; dropping it earlier closes the connection.
+pub struct ResponseBody {
+    connection: Option<Exchange>,
+    reader: BodyReader,
+    deadline: RequestDeadline,
+    decoder: Option<ContentDecoder>,
+    pool: Option<(Arc<ConnectionPool>, PoolKey)>,
+}
+
+impl ResponseBody {
+    /// Next piece of the body, or `None` at the end
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some(connection) = self.connection.as_mut() else {
+                return Ok(None);
+            };
+            let read = match connection
+                .read_chunk(&mut self.reader, &self.deadline)
+                .await
+            {
+                Ok(read) => read,
+                Err(e) => {
+                    self.connection = None;
+                    return Err(e);
+                }
+            };
+
+            match read {
+                Some(data) => {
+                    let data = match self.decoder.as_mut() {
+                        Some(decoder) => decoder.decode(&data).map_err(|e| self.fail(e))?,
+                        None => data,
+                    };
+                    // A decoder may hold input back until it has a full block
+                    if !data.is_empty() {
+                        return Ok(Some(data));
+                    }
+                }
+                None => {
+                    let tail = match self.decoder.take() {
+                        Some(decoder) => decoder.finish().map_err(|e| self.fail(e))?,
+                        None => Vec::new(),
+                    };
+                    self.release();
+                    return Ok((!tail.is_empty()).then_some(tail));
+                }
+            }
+        }
+    }
+
+    /// Read the rest of the body into memory
+    pub async fn bytes(mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// The body as a `Stream` of chunks
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<u8>>> {
+        futures::stream::unfold(self, |mut body| async move {
+            match body.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), body)),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), body)),
+            }
+        })
+    }
+
+    /// The body as a futures `AsyncRead`
+    pub fn into_async_read(self) -> impl AsyncRead + Unpin {
+        Box::pin(self.into_stream().map_err(io::Error::other)).into_async_read()
+    }
+
+    fn fail(&mut self, e: io::Error) -> TorError {
+        self.connection = None;
+        TorError::http_request(format!("Failed to decode body: {}", e))
+    }
+
+    /// Hand the connection back to the pool if the body ended cleanly
+    fn release(&mut self) {
+        let Some(connection) = self.connection.take() else {
+            return;
+        };
+        if !self.reader.is_complete() {
+            return;
+        }
+        if let Some((pool, key)) = self.pool.take() {
+            pool.put(key, connection);
+        }
+    }
+}
+
+/// Size of the reads `RequestBody::from_async_read` makes
+const UPLOAD_READ_SIZE: usize = 16 * 1024;
+
+/// Body of an outgoing request
+///
+/// In-memory bodies are sent with a Content-Length. Streamed bodies use the
+/// length they were given, or `Transfer-Encoding: chunked` without one.
+pub struct RequestBody {
+    source: BodySource,
+}
+
+enum BodySource {
+    Bytes(Vec<u8>),
+    Stream {
+        stream: Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>,
+        length: Option<u64>,
+        /// Whether anything was pulled from the stream, so it can't be resent
+        started: bool,
+    },
+}
+
+impl RequestBody {
+    /// A body produced by `stream`
+    ///
+    /// With a `length` the stream must yield exactly that many bytes.
+    pub fn from_stream<S>(stream: S, length: Option<u64>) -> Self
+    where
+        S: Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        Self {
+            source: BodySource::Stream {
+                stream: Box::pin(stream),
+                length,
+                started: false,
+            },
+        }
+    }
+
+    /// A body read from `reader` until EOF
+    pub fn from_async_read<R>(reader: R, length: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let stream = futures::stream::unfold(Box::pin(reader), |mut reader| async move {
+            let mut buf = vec![0u8; UPLOAD_READ_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(buf), reader))
+                }
+                Err(e) => Some((Err(e), reader)),
+            }
+        });
+        Self::from_stream(stream, length)
+    }
+
+    /// Length sent as Content-Length; `None` means the body goes chunked
+    pub fn length(&self) -> Option<u64> {
+        match &self.source {
+            BodySource::Bytes(bytes) => Some(bytes.len() as u64),
+            BodySource::Stream { length, .. } => *length,
+        }
+    }
+
+    /// Whether the body can still be sent on another connection
+    pub(crate) fn is_replayable(&self) -> bool {
+        match &self.source {
+            BodySource::Bytes(_) => true,
+            BodySource::Stream { started, .. } => !started,
+        }
+    }
+
+    /// Write the whole body to `connection`, framed to match `length()`
+    ///
+    /// HTTP/2 frames the body itself, so it is never chunked there.
+    pub(crate) async fn write_to(
+        &mut self,
+        connection: &mut Exchange,
+        deadline: &RequestDeadline,
+    ) -> Result<()> {
+        let chunked = self.length().is_none() && !connection.is_http2();
+        let (stream, length, started) = match &mut self.source {
+            BodySource::Bytes(bytes) => {
+                connection.write(bytes, deadline).await?;
+                return connection.end_body(deadline).await;
+            }
+            BodySource::Stream {
+                stream,
+                length,
+                started,
+            } => (stream, *length, started),
+        };
+        *started = true;
+
+        let mut sent = 0u64;
+        while let Some(chunk) = deadline
+            .run(TimeoutPhase::Total, async { Ok(stream.next().await) })
+            .await?
+        {
+            let chunk = chunk.map_err(|e| {
+                TorError::http_request(format!("Failed to read request body: {}", e))
+            })?;
+            if chunk.is_empty() {
+                continue;
+            }
+            sent += chunk.len() as u64;
+            match length {
+                Some(length) if sent > length => {
+                    return Err(TorError::http_request(format!(
+                        "Request body is longer than its length of {} bytes",
+                        length
+                    )));
+                }
+                _ if chunked => connection.write(&frame_chunk(&chunk), deadline).await?,
+                _ => connection.write(&chunk, deadline).await?,
+            }
+        }
+
+        match length {
+            Some(length) if sent < length => Err(TorError::http_request(format!(
+                "Request body ended after {} of {} bytes",
+                sent, length
+            ))),
+            _ if chunked => connection.write(b"0\r\n\r\n", deadline).await,
+            _ => connection.end_body(deadline).await,
+        }
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            source: BodySource::Bytes(bytes),
+        }
+    }
+}
+
+/// Wrap one piece of a body in chunked transfer coding
+fn frame_chunk(chunk: &[u8]) -> Vec<u8> {
+    let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+    framed.extend_from_slice(chunk);
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+/// One stage of incremental Content-Encoding decoding
+enum Coding {
+    Gzip(flate2::write::MultiGzDecoder<Vec<u8>>),
+    Zlib(flate2::write::ZlibDecoder<Vec<u8>>),
+    RawDeflate(flate2::write::DeflateDecoder<Vec<u8>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    /// "deflate" before we have seen enough to tell zlib from raw
+    Deflate(Vec<u8>),
+}
+
+impl Coding {
+    fn new(coding: &str) -> Self {
+        match coding {
+            "gzip" | "x-gzip" => Coding::Gzip(flate2::write::MultiGzDecoder::new(Vec::new())),
+            "deflate" => Coding::Deflate(Vec::new()),
+            _ => Coding::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096))),
+        }
+    }
+
+    /// Feed `input`, returning whatever output it produced
+    fn decode(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Coding::Gzip(d) => {
+                d.write_all(input)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            Coding::Zlib(d) => {
+                d.write_all(input)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            Coding::RawDeflate(d) => {
+                d.write_all(input)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            Coding::Brotli(d) => {
+                d.write_all(input)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            Coding::Deflate(pending) => {
+                pending.extend_from_slice(input);
+                if pending.len() < 2 {
+                    return Ok(Vec::new());
+                }
+                let pending = std::mem::take(pending);
+                *self = Self::deflate_for(&pending);
+                self.decode(&pending)
+            }
+        }
+    }
+
+    /// Flush out the end of the stream
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Coding::Gzip(d) => d.finish(),
+            Coding::Zlib(d) => d.finish(),
+            Coding::RawDeflate(d) => d.finish(),
+            Coding::Brotli(d) => (*d).into_inner().map_err(|_| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream")
+            }),
+            Coding::Deflate(pending) if pending.is_empty() => Ok(Vec::new()),
+            Coding::Deflate(pending) => {
+                let mut coding = Self::deflate_for(&pending);
+                let mut out = coding.decode(&pending)?;
+                out.extend(coding.finish()?);
+                Ok(out)
+            }
+        }
+    }
+
+    /// "deflate" is zlib-wrapped per RFC 9110, but some servers send raw
+    /// deflate; a zlib header is a CMF/FLG pair that is a multiple of 31
+    fn deflate_for(start: &[u8]) -> Self {
+        let is_zlib = start.len() >= 2
+            && start[0] & 0x0f == 8
+            && (u16::from(start[0]) << 8 | u16::from(start[1])) % 31 == 0;
+        if is_zlib {
+            Coding::Zlib(flate2::write::ZlibDecoder::new(Vec::new()))
+        } else {
+            debug!("Deflate body has no zlib header, decoding as raw deflate");
+            Coding::RawDeflate(flate2::write::DeflateDecoder::new(Vec::new()))
+        }
+    }
+}
+
+/// Incremental decoder for a `Content-Encoding` chain
+///
+/// Used for streamed bodies and, with a limit, for bodies read into memory.
+pub(crate) struct ContentDecoder {
+    /// Stages in the order they are undone
+    stages: Vec<Coding>,
+    /// Whether any input arrived; empty bodies (HEAD, 204) decode to nothing
+    started: bool,
+    /// Most decoded bytes to produce, if capped
+    limit: Option<usize>,
+    produced: usize,
+}
+
+impl ContentDecoder {
+    /// `codings` in order of application, as from `content_codings`
+    pub fn new(codings: &[String]) -> Self {
+        Self {
+            stages: codings
+                .iter()
+                .rev()
+                .map(|c| Coding::new(c.as_str()))
+                .collect(),
+            started: false,
+            limit: None,
+            produced: 0,
+        }
+    }
+
+    /// Fail once the decoded body grows past `limit` bytes
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn decode(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        self.started |= !input.is_empty();
+        let mut data = input.to_vec();
+        for stage in &mut self.stages {
+            if data.is_empty() {
+                break;
+            }
+            data = stage.decode(&data)?;
+        }
+        self.count(data)
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        if !self.started {
+            return Ok(Vec::new());
+        }
+        // Each stage's tail is the last input of the stage after it
+        let mut data = Vec::new();
+        for mut stage in std::mem::take(&mut self.stages) {
+            let mut out = if data.is_empty() {
+                Vec::new()
+            } else {
+                stage.decode(&data)?
+            };
+            out.extend(stage.finish()?);
+            data = out;
+        }
+        self.count(data)
+    }
+
+    fn count(&mut self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        self.produced += data.len();
+        match self.limit {
+            Some(limit) if self.produced > limit => {
+                Err(io::Error::other("decoded body exceeds size limit"))
+            }
+            _ => Ok(data),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_util::{portable_test, portable_test_async};
+
+    const TEXT: &[u8] =
+        b"Streaming bodies arrive a piece at a time. Streaming bodies arrive a piece at a time.";
+
+    pub(crate) fn compress(coding: &str, data: &[u8]) -> Vec<u8> {
+        let level = flate2::Compression::default();
+        match coding {
+            "gzip" => {
+                let mut enc = flate2::write::GzEncoder::new(Vec::new(), level);
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            "zlib" => {
+                let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            "raw-deflate" => {
+                let mut enc = flate2::write::DeflateEncoder::new(Vec::new(), level);
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            "br" => {
+                let mut out = Vec::new();
+                {
+                    let mut enc = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    enc.write_all(data).unwrap();
+                }
+                out
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Decode `encoded` fed in pieces of `step` bytes
+    fn decode_in_steps(encoding: &str, encoded: &[u8], step: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = ContentDecoder::new(&content_codings(encoding).unwrap());
+        let mut out = Vec::new();
+        for piece in encoded.chunks(step) {
+            out.extend(decoder.decode(piece)?);
+        }
+        out.extend(decoder.finish()?);
+        Ok(out)
+    }
+
+    #[portable_test]
+    fn test_content_decoder_handles_split_input() {
+        for (encoding, coding) in [
+            ("gzip", "gzip"),
+            ("deflate", "zlib"),
+            ("deflate", "raw-deflate"),
+            ("br", "br"),
+        ] {
+            let encoded = compress(coding, TEXT);
+            for step in [1, 7, encoded.len()] {
+                assert_eq!(
+                    decode_in_steps(encoding, &encoded, step).unwrap(),
+                    TEXT,
+                    "{} in {}-byte pieces",
+                    coding,
+                    step
+                );
+            }
+        }
+    }
+
+    #[portable_test]
+    fn test_content_decoder_stacked_and_empty() {
+        let stacked = compress("br", &compress("gzip", TEXT));
+        assert_eq!(decode_in_steps("gzip, br", &stacked, 5).unwrap(), TEXT);
+
+        // HEAD and 204 responses keep the header but have no body
+        let decoder = ContentDecoder::new(&content_codings("gzip").unwrap());
+        assert!(decoder.finish().unwrap().is_empty());
+    }
+
+    #[portable_test]
+    fn test_frame_chunk() {
+        assert_eq!(frame_chunk(b"Hello, world!"), b"d\r\nHello, world!\r\n");
+    }
+
+    #[portable_test_async]
+    async fn test_request_body_from_async_read() {
+        let data = vec![7u8; UPLOAD_READ_SIZE + 10];
+        let mut body = RequestBody::from_async_read(futures::io::Cursor::new(data.clone()), None);
+        assert_eq!(body.length(), None);
+        assert!(body.is_replayable());
+
+        let BodySource::Stream { stream, .. } = &mut body.source else {
+            panic!("expected a streamed body");
+        };
+        let chunks: Vec<Vec<u8>> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.concat(), data);
+
+        let body = RequestBody::from(b"abc".to_vec());
+        assert_eq!(body.length(), Some(3));
+        assert!(body.is_replayable());
+    }
+
+    #[portable_test]
+    fn test_content_decoder_limit() {
+        let encoded = compress("gzip", TEXT);
+        let codings = content_codings("gzip").unwrap();
+
+        let mut decoder = ContentDecoder::new(&codings).with_limit(TEXT.len());
+        let mut out = decoder.decode(&encoded).unwrap();
+        out.extend(decoder.finish().unwrap());
+        assert_eq!(out, TEXT);
+
+        let mut decoder = ContentDecoder::new(&codings).with_limit(TEXT.len() - 1);
+        let result = decoder.decode(&encoded).and_then(|_| decoder.finish());
+        assert!(result.is_err());
+    }
+
+    #[portable_test]
+    fn test_content_decoder_rejects_garbage() {
+        assert!(decode_in_steps("gzip", b"definitely not gzip", 4).is_err());
+    }
+}
@@ -77,6 +77,23 @@ impl IsolationKey {
         }
     }
 
+    /// Create an isolation key for a connection or lookup that has no URL
+    ///
+    /// Raw streams have no scheme, so under `PerOrigin` they are keyed as
+    /// `tcp://host:port`. Without a port (DNS lookups) the full hostname is
+    /// used instead.
+    pub fn from_host(host: &str, port: Option<u16>, policy: StreamIsolationPolicy) -> Option<Self> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let key = match (policy, port) {
+            (StreamIsolationPolicy::None, _) => return None,
+            (StreamIsolationPolicy::PerOrigin, Some(port)) => format!("tcp://{}:{}", host, port),
+            (StreamIsolationPolicy::PerOrigin, None) => host,
+            (StreamIsolationPolicy::PerSubdomain, _) => host,
+            (StreamIsolationPolicy::PerDomain, _) => extract_domain(&host),
+        };
+        Some(IsolationKey(key))
+    }
+
     /// Create an isolation key from a raw string (for testing)
     pub fn from_string(s: impl Into<String>) -> Self {
         IsolationKey(s.into())
@@ -188,6 +205,30 @@ mod tests {
         assert_eq!(key.0, "example.co.uk");
     }
 
+    #[portable_test]
+    fn test_from_host_follows_policy() {
+        let key = |port, policy| IsolationKey::from_host("WWW.Example.com.", port, policy);
+        assert_eq!(
+            key(Some(993), StreamIsolationPolicy::PerDomain).unwrap().0,
+            "example.com"
+        );
+        assert_eq!(
+            key(Some(993), StreamIsolationPolicy::PerSubdomain)
+                .unwrap()
+                .0,
+            "www.example.com"
+        );
+        assert_eq!(
+            key(Some(993), StreamIsolationPolicy::PerOrigin).unwrap().0,
+            "tcp://www.example.com:993"
+        );
+        assert_eq!(
+            key(None, StreamIsolationPolicy::PerOrigin).unwrap().0,
+            "www.example.com"
+        );
+        assert!(key(Some(993), StreamIsolationPolicy::None).is_none());
+    }
+
     #[portable_test]
     fn test_no_isolation_returns_none() {
         let url = Url::parse("https://example.com/").unwrap();
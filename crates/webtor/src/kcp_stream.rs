@@ -10,7 +10,7 @@
 //! - transport -> input() -> rcv_buf -> recv() -> application
 
 use crate::error::{Result, TorError};
-use crate::time::Instant;
+use crate::time::{sleep, Instant, Sleep};
 use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use kcp::Kcp;
 use std::io::{self, Write};
@@ -18,7 +18,7 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 use std::time::Duration;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 /// Output buffer that collects data from KCP for sending
 #[derive(Clone)]
@@ -72,6 +72,8 @@ pub struct KcpConfig {
     pub snd_wnd: u16,
     /// Receive window size
     pub rcv_wnd: u16,
+    /// Retransmissions of one segment before the link counts as dead
+    pub dead_link: u32,
 }
 
 impl Default for KcpConfig {
@@ -87,6 +89,9 @@ impl Default for KcpConfig {
             nc: true,      // Disable congestion control (nc=1 in Go)
             snd_wnd: 128,
             rcv_wnd: 128,
+            // Each resend doubles the timeout, so KCP's default of 20 would
+            // take days to give up; 8 gives up after a minute or two
+            dead_link: 8,
         }
     }
 }
@@ -106,6 +111,9 @@ pub struct KcpStream<S> {
     waker: Option<Waker>,
     /// Pending output data that needs to be written to transport
     pending_write: Vec<u8>,
+    /// How often to run KCP's retransmission timer while reads wait
+    update_interval: Duration,
+    update_timer: Option<Sleep>,
 }
 
 impl<S> KcpStream<S> {
@@ -117,6 +125,7 @@ impl<S> KcpStream<S> {
         // Configure KCP to match Snowflake Go client settings
         kcp.set_nodelay(config.nodelay, config.interval, config.resend, config.nc);
         kcp.set_wndsize(config.snd_wnd, config.rcv_wnd);
+        kcp.set_maximum_resend_times(config.dead_link);
 
         Self {
             kcp,
@@ -128,12 +137,77 @@ impl<S> KcpStream<S> {
             last_update: 0,
             waker: None,
             pending_write: Vec::new(),
+            update_interval: Duration::from_millis(config.interval.max(10) as u64),
+            update_timer: None,
         }
     }
 
     fn current_ms(&self) -> u32 {
         self.start_time.elapsed().as_millis() as u32
     }
+
+    /// Whether KCP gave up retransmitting to the peer
+    pub fn is_dead_link(&self) -> bool {
+        self.kcp.is_dead_link()
+    }
+}
+
+/// The error reads and writes fail with once the link is dead
+fn dead_link_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "KCP dead link: peer stopped acknowledging data",
+    )
+}
+
+impl<S: AsyncWrite + Unpin> KcpStream<S> {
+    /// Keep retransmitting unacknowledged data while a read waits
+    ///
+    /// Without this, lost segments would only be resent when the peer
+    /// happened to send something, and a peer that vanished would never be
+    /// noticed. Registers the timer with `cx`.
+    fn poll_retransmit(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        loop {
+            if self.kcp.wait_snd() == 0 {
+                self.update_timer = None;
+                return Ok(());
+            }
+
+            let interval = self.update_interval;
+            let timer = self.update_timer.get_or_insert_with(|| sleep(interval));
+            if timer.as_mut().poll(cx).is_pending() {
+                return Ok(());
+            }
+            self.update_timer = None;
+
+            let current = self.current_ms();
+            self.kcp
+                .update(current)
+                .map_err(|e| io::Error::other(format!("KCP update error: {:?}", e)))?;
+            if self.kcp.is_dead_link() {
+                warn!(
+                    "KCP dead link: {} segments unacknowledged",
+                    self.kcp.wait_snd()
+                );
+                return Err(dead_link_error());
+            }
+
+            let output_data = self.output.take();
+            if !output_data.is_empty() {
+                trace!("KCP retransmit: {} bytes", output_data.len());
+                self.pending_write.extend_from_slice(&output_data);
+            }
+            if !self.pending_write.is_empty() {
+                let data = std::mem::take(&mut self.pending_write);
+                match Pin::new(&mut self.transport).poll_write(cx, &data) {
+                    Poll::Ready(Ok(n)) => self.pending_write = data[n..].to_vec(),
+                    Poll::Ready(Err(e)) => return Err(e),
+                    Poll::Pending => self.pending_write = data,
+                }
+                let _ = Pin::new(&mut self.transport).poll_flush(cx);
+            }
+        }
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> KcpStream<S> {
@@ -344,7 +418,10 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for KcpStream<S> {
                 debug!("KCP read: transport error: {}", e);
                 Poll::Ready(Err(e))
             }
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => match self.poll_retransmit(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
         }
     }
 }
@@ -357,6 +434,10 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for KcpStream<S> {
     ) -> Poll<io::Result<usize>> {
         debug!("KCP write: sending {} bytes", buf.len());
 
+        if self.kcp.is_dead_link() {
+            return Poll::Ready(Err(dead_link_error()));
+        }
+
         // Queue data in KCP
         match self.kcp.send(buf) {
             Ok(n) => {
@@ -461,7 +542,8 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for KcpStream<S> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_util::portable_test;
+    use crate::test_util::{portable_test, portable_test_async};
+    use futures::AsyncReadExt;
 
     #[portable_test]
     fn test_kcp_config_default() {
@@ -482,4 +564,53 @@ mod tests {
         assert_eq!(data, b"hello world");
         assert!(buf.is_empty());
     }
+
+    /// Takes every write and never answers
+    struct SilentPeer;
+
+    impl AsyncRead for SilentPeer {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for SilentPeer {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[portable_test_async]
+    async fn test_dead_link_fails_reads() {
+        let config = KcpConfig {
+            dead_link: 2,
+            ..Default::default()
+        };
+        let mut stream = KcpStream::new(SilentPeer, config);
+        stream.write_all(b"hello").await.unwrap();
+        assert!(!stream.is_dead_link());
+
+        // The first resend is unacknowledged too, which is one too many
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(stream.is_dead_link());
+        assert!(stream.write_all(b"again").await.is_err());
+    }
 }
@@ -11,10 +11,21 @@ pub mod circuit;
 pub mod client;
 pub mod config;
 pub mod directory;
+pub mod dns;
 pub mod error;
+pub mod hpack;
 pub mod http;
+pub mod http2;
+pub mod http_conn;
+pub mod http_cookie;
+pub mod http_pool;
+pub mod http_redirect;
+pub mod http_stream;
 pub mod isolation;
 pub mod kcp_stream;
+pub mod onion;
+pub mod onion_service;
+pub mod pow;
 pub mod relay;
 pub mod retry;
 pub mod smux;
@@ -23,6 +34,7 @@ pub mod snowflake_broker;
 pub mod snowflake_ws;
 pub mod time;
 pub mod tls;
+pub mod tor_websocket;
 pub mod turbo;
 pub mod wasm_runtime;
 pub mod websocket;
@@ -34,8 +46,8 @@ pub mod webtunnel;
 pub mod webrtc_stream;
 
 pub use client::TorClient;
-pub use config::TorClientOptions;
-pub use error::{Result, TorError, TorErrorKind};
+pub use config::{ChannelEvent, TorClientOptions};
+pub use error::{Result, TimeoutPhase, TorError, TorErrorKind};
 pub use isolation::{IsolationKey, StreamIsolationPolicy};
 pub use retry::{
     retry_with_backoff, with_cancellation, with_timeout, with_timeout_and_cancellation,
@@ -43,7 +55,11 @@ pub use retry::{
 };
 
 // Re-export commonly used types
-pub use http::HttpResponse;
+pub use http::{HttpRequest, HttpResponse, HttpTimeouts};
+pub use http_cookie::{Cookie, CookieJar};
+pub use http_redirect::RedirectPolicy;
+pub use http_stream::{RequestBody, ResponseBody, StreamingResponse};
+pub use tor_websocket::{CloseFrame, TorWebSocket, WebSocketMessage, WebSocketOptions};
 pub use url::Url;
 
 // Re-export Tor stream types for advanced usage
@@ -0,0 +1,1239 @@
+//! Onion service (v3) client
+//!
+//! Connecting to `xyz.onion` follows rend-spec-v3:
+//! 1. Blind the service's identity key for the current time period and find
+//!    the responsible HSDirs on the hash ring derived from the consensus
+//!    shared-random values.
+//! 2. Fetch the descriptor from one of them and decrypt both layers.
+//! 3. Establish a rendezvous point, then send INTRODUCE1 to one of the
+//!    service's introduction points.
+//! 4. Complete the hs-ntor handshake from RENDEZVOUS2 and add the service as
+//!    a virtual hop on the rendezvous circuit. Streams are then opened on that
+//!    circuit with an empty hostname.
+
+use crate::circuit::{make_circ_params, CircuitManager};
+use crate::error::{Result, TorError};
+use crate::isolation::IsolationKey;
+use crate::pow;
+use crate::relay::{flags, Relay};
+use crate::retry::with_timeout;
+use crate::time::system_time_now;
+use base64::Engine;
+use futures::channel::mpsc;
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use rand::seq::SliceRandom;
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tor_bytes::Writeable;
+use tor_cell::relaycell::hs::intro_payload::{IntroduceHandshakePayload, OnionKey};
+use tor_cell::relaycell::hs::pow::ProofOfWork;
+use tor_cell::relaycell::hs::{AuthKeyType, EstablishRendezvous, IntroduceAckStatus};
+use tor_cell::relaycell::msg::{AnyRelayMsg, Introduce1};
+use tor_cell::relaycell::{RelayCmd, RelayMsg};
+use tor_checkable::Timebound;
+use tor_hscrypto::pk::{
+    HsBlindId, HsClientDescEncKey, HsClientDescEncKeypair, HsClientDescEncSecretKey, HsId, HsIdKey,
+};
+use tor_hscrypto::time::TimePeriod;
+use tor_hscrypto::{RendCookie, Subcredential};
+use tor_linkspec::decode::Strictness;
+use tor_linkspec::{CircTarget, EncodedLinkSpec, OwnedChanTargetBuilder, OwnedCircTarget};
+use tor_llcrypto::pk::curve25519;
+use tor_netdoc::doc::hsdesc::{HsDesc, IntroPointDesc};
+use tor_proto::client::circuit::handshake::{hs_ntor, HandshakeRole, RelayProtocol};
+use tor_proto::client::stream::DataStream;
+use tor_proto::{ClientTunnel, MetaCellDisposition, MsgHandler, TargetHop};
+use tor_protover::Protocols;
+use tracing::{debug, info, warn};
+
+/// Hostname suffix identifying onion services
+pub const ONION_SUFFIX: &str = ".onion";
+
+/// Offset of time period boundaries from the Unix epoch (rend-spec-v3 2.2.1)
+const TIME_PERIOD_ROTATION_OFFSET: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// A shared-random value becomes current at midnight UTC and lasts a day
+const SHARED_RANDOM_PERIOD_SECS: u64 = 24 * 60 * 60;
+
+/// Upper bound on a descriptor document (consensus `hs_desc_max_size` default)
+const MAX_DESCRIPTOR_SIZE: usize = 50_000;
+
+const DESCRIPTOR_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const INTRODUCTION_TIMEOUT: Duration = Duration::from_secs(30);
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether `host` names an onion service (`*.onion`)
+pub fn is_onion_host(host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    host.len() > ONION_SUFFIX.len()
+        && host[host.len() - ONION_SUFFIX.len()..].eq_ignore_ascii_case(ONION_SUFFIX)
+}
+
+/// Parse an onion hostname into the service identity
+///
+/// Subdomains are allowed (`www.xyz.onion`); only the label directly in
+/// front of `.onion` identifies the service.
+pub fn parse_onion_host(host: &str) -> Result<HsId> {
+    let lowered = host.trim_end_matches('.').to_ascii_lowercase();
+    let label = lowered
+        .strip_suffix(ONION_SUFFIX)
+        .and_then(|rest| rest.rsplit('.').next())
+        .filter(|label| !label.is_empty())
+        .ok_or_else(|| TorError::configuration(format!("Not an onion address: {}", host)))?;
+
+    HsId::from_str(&format!("{}{}", label, ONION_SUFFIX))
+        .map_err(|e| TorError::configuration(format!("Invalid onion address {}: {}", host, e)))
+}
+
+/// Decode unpadded RFC 4648 base32, as used for onion addresses and keys
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut out = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Encode unpadded lowercase RFC 4648 base32
+fn encode_base32(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut out = String::with_capacity(input.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in input {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// The `.onion` hostname for a service's ed25519 identity key
+/// (rend-spec-v3 6: `base32(PUBKEY | CHECKSUM | VERSION)`)
+pub fn onion_address(public_key: &[u8; 32]) -> String {
+    const VERSION: u8 = 3;
+    let checksum = sha3_256(&[b".onion checksum", public_key, &[VERSION]]);
+
+    let mut bytes = public_key.to_vec();
+    bytes.extend_from_slice(&checksum[..2]);
+    bytes.push(VERSION);
+    format!("{}{}", encode_base32(&bytes), ONION_SUFFIX)
+}
+
+/// Parse an x25519 client authorization secret key
+///
+/// Accepts the bare base32 key or Tor's `.auth_private` file format,
+/// `<onion-address>:descriptor:x25519:<base32-key>`.
+pub fn parse_client_auth_key(key: &str) -> Result<HsClientDescEncKeypair> {
+    let encoded = key.trim().rsplit(':').next().unwrap_or_default();
+    let bytes: [u8; 32] = decode_base32(encoded)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| {
+            TorError::configuration("Client auth key must be a base32-encoded x25519 secret key")
+        })?;
+
+    let secret = curve25519::StaticSecret::from(bytes);
+    let public = curve25519::PublicKey::from(&secret);
+    Ok(HsClientDescEncKeypair::new(
+        HsClientDescEncKey::from(public),
+        HsClientDescEncSecretKey::from(secret),
+    ))
+}
+
+/// Consensus parameters for locating onion service descriptors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDirParams {
+    /// `shared-rand-current-value` from the consensus
+    pub srv_current: Option<[u8; 32]>,
+    /// `shared-rand-previous-value` from the consensus
+    pub srv_previous: Option<[u8; 32]>,
+    /// Time period length in minutes (`hsdir_interval`)
+    pub time_period_minutes: u32,
+    /// Number of descriptor replicas (`hsdir_n_replicas`)
+    pub n_replicas: u8,
+    /// HSDirs to consider per replica when fetching (`hsdir_spread_fetch`)
+    pub spread_fetch: usize,
+    /// HSDirs to upload to per replica when publishing (`hsdir_spread_store`)
+    pub spread_store: usize,
+}
+
+impl Default for HsDirParams {
+    fn default() -> Self {
+        Self {
+            srv_current: None,
+            srv_previous: None,
+            time_period_minutes: 1440,
+            n_replicas: 2,
+            spread_fetch: 3,
+            spread_store: 4,
+        }
+    }
+}
+
+impl HsDirParams {
+    /// The time period containing `now`
+    pub fn time_period(&self, now: SystemTime) -> Result<TimePeriod> {
+        let length = Duration::from_secs(u64::from(self.time_period_minutes) * 60);
+        TimePeriod::new(length, now, TIME_PERIOD_ROTATION_OFFSET)
+            .map_err(|e| TorError::onion_service(format!("Invalid time period: {}", e)))
+    }
+
+    /// Shared-random value that seeds the hash ring for `period_num`
+    ///
+    /// The ring for a time period uses the value that was current when the
+    /// period began. Periods start at 12:00 UTC and shared-random values
+    /// rotate at midnight, so in the morning the period still belongs to
+    /// yesterday's value. If the consensus lacks the value we need, fall back
+    /// to the "disaster" value from rend-spec-v3 2.2.3.
+    pub(crate) fn shared_random_value(&self, period_num: u64, now: SystemTime) -> [u8; 32] {
+        let now_secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let last_rotation = now_secs - now_secs % SHARED_RANDOM_PERIOD_SECS;
+        let period_start = period_start_secs(period_num, self.time_period_minutes);
+
+        let srv = if period_start >= last_rotation {
+            self.srv_current
+        } else {
+            self.srv_previous
+        };
+        srv.unwrap_or_else(|| disaster_srv(period_num, self.time_period_minutes))
+    }
+
+    /// Time periods a service publishes descriptors for at `now`
+    ///
+    /// A service keeps a descriptor up for each period whose shared-random
+    /// value is in the consensus: from a period's start until the value
+    /// rotates at midnight those are the previous and current periods, and
+    /// after the rotation the current and next ones (rend-spec-v3 2.2.4.1).
+    pub(crate) fn publish_periods(&self, now: SystemTime) -> Result<Vec<TimePeriod>> {
+        let current = self.time_period(now)?;
+        let now_secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let last_rotation = now_secs - now_secs % SHARED_RANDOM_PERIOD_SECS;
+        let period_start = period_start_secs(current.interval_num(), self.time_period_minutes);
+
+        let periods = if period_start >= last_rotation {
+            [current.prev(), Some(current)]
+        } else {
+            [Some(current), current.next()]
+        };
+        Ok(periods.into_iter().flatten().collect())
+    }
+}
+
+/// Seconds since the epoch at which time period `period_num` begins
+fn period_start_secs(period_num: u64, period_minutes: u32) -> u64 {
+    (period_num * u64::from(period_minutes)) * 60 + TIME_PERIOD_ROTATION_OFFSET.as_secs()
+}
+
+fn sha3_256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Fallback shared-random value used when the consensus carries none
+fn disaster_srv(period_num: u64, period_minutes: u32) -> [u8; 32] {
+    sha3_256(&[
+        b"shared-random-disaster",
+        &u64::from(period_minutes).to_be_bytes(),
+        &period_num.to_be_bytes(),
+    ])
+}
+
+/// Position of an HSDir on the hash ring
+fn hsdir_index(
+    ed25519_id: &[u8],
+    srv: &[u8; 32],
+    period_num: u64,
+    period_minutes: u32,
+) -> [u8; 32] {
+    sha3_256(&[
+        b"node-idx",
+        ed25519_id,
+        srv,
+        &period_num.to_be_bytes(),
+        &u64::from(period_minutes).to_be_bytes(),
+    ])
+}
+
+/// Position of a descriptor replica on the hash ring
+fn descriptor_index(
+    blinded_id: &[u8; 32],
+    replica: u8,
+    period_num: u64,
+    period_minutes: u32,
+) -> [u8; 32] {
+    sha3_256(&[
+        b"store-at-idx",
+        blinded_id,
+        &u64::from(replica).to_be_bytes(),
+        &u64::from(period_minutes).to_be_bytes(),
+        &period_num.to_be_bytes(),
+    ])
+}
+
+/// HSDirs responsible for a blinded identity, in ring order per replica
+///
+/// For each replica this walks clockwise from the replica's index and takes
+/// the next `spread` HSDirs that were not already chosen. Clients pass
+/// `spread_fetch`, services `spread_store`.
+pub(crate) fn responsible_hsdirs<'a>(
+    relays: &'a [Relay],
+    blinded_id: &[u8; 32],
+    srv: &[u8; 32],
+    period_num: u64,
+    params: &HsDirParams,
+    spread: usize,
+) -> Vec<&'a Relay> {
+    let mut ring: Vec<([u8; 32], &Relay)> = relays
+        .iter()
+        .filter(|relay| relay.flags.contains(flags::HSDIR))
+        .filter_map(|relay| {
+            let ed_id = hex::decode(relay.ed25519_identity.as_deref()?).ok()?;
+            (ed_id.len() == 32).then(|| {
+                (
+                    hsdir_index(&ed_id, srv, period_num, params.time_period_minutes),
+                    relay,
+                )
+            })
+        })
+        .collect();
+    ring.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut chosen: Vec<&Relay> = Vec::new();
+    for replica in 1..=params.n_replicas {
+        let index = descriptor_index(blinded_id, replica, period_num, params.time_period_minutes);
+        let start = ring.partition_point(|(position, _)| *position <= index);
+
+        let mut taken = 0;
+        for (_, relay) in ring.iter().cycle().skip(start).take(ring.len()) {
+            if taken == spread {
+                break;
+            }
+            if chosen.iter().any(|c| c.fingerprint == relay.fingerprint) {
+                continue;
+            }
+            chosen.push(relay);
+            taken += 1;
+        }
+    }
+    chosen
+}
+
+/// Build a circuit target for an introduction point from its descriptor entry
+fn intro_point_target(ipt: &IntroPointDesc) -> Result<OwnedCircTarget> {
+    circ_target_from_link_specs(ipt.link_specifiers(), *ipt.ipt_ntor_key())
+}
+
+/// Build a circuit target from encoded link specifiers and an ntor key, as
+/// carried in descriptors and INTRODUCE2 payloads
+pub(crate) fn circ_target_from_link_specs(
+    link_specs: &[EncodedLinkSpec],
+    ntor_key: curve25519::PublicKey,
+) -> Result<OwnedCircTarget> {
+    let link_specs = link_specs
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| TorError::onion_service(format!("Bad link specifier: {}", e)))?;
+    let chan_target = OwnedChanTargetBuilder::from_linkspecs(Strictness::Standard, &link_specs)
+        .map_err(|e| TorError::onion_service(format!("Bad relay address: {}", e)))?;
+
+    let mut builder = OwnedCircTarget::builder();
+    *builder.chan_target() = chan_target;
+    builder
+        .ntor_onion_key(ntor_key)
+        .protocols(Protocols::default())
+        .build()
+        .map_err(|e| TorError::Internal(format!("Failed to build circuit target: {}", e)))
+}
+
+/// Send one HTTP/1.0 request to an HSDir over a directory stream
+///
+/// Returns the status code and body of the response.
+pub(crate) async fn hsdir_request(
+    circuit_manager: &RwLock<CircuitManager>,
+    hsdir: &Relay,
+    request: &[u8],
+) -> Result<(u16, Vec<u8>)> {
+    let tunnel = {
+        let circuit_manager = circuit_manager.read().await;
+        circuit_manager
+            .build_tunnel_to(&hsdir.as_circ_target()?, Some(hsdir))
+            .await?
+    };
+
+    let mut stream = tunnel
+        .begin_dir_stream()
+        .await
+        .map_err(|e| TorError::Internal(format!("Failed to begin dir stream: {}", e)))?;
+
+    stream
+        .write_all(request)
+        .await
+        .map_err(|e| TorError::Network(format!("Failed to write dir request: {}", e)))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| TorError::Network(format!("Failed to flush dir request: {}", e)))?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_DESCRIPTOR_SIZE as u64 + 4096)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| TorError::Network(format!("Failed to read dir response: {}", e)))?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let body_start = match parsed.parse(&response) {
+        Ok(httparse::Status::Complete(len)) => len,
+        _ => return Err(TorError::onion_service("Malformed HSDir response")),
+    };
+    let status = parsed
+        .code
+        .ok_or_else(|| TorError::onion_service("Malformed HSDir response"))?;
+
+    Ok((status, response[body_start..].to_vec()))
+}
+
+/// Forwards replies on a circuit conversation to a channel
+pub(crate) struct ReplyForwarder {
+    pub tx: mpsc::UnboundedSender<AnyRelayMsg>,
+    /// Command that ends the conversation once forwarded; `None` keeps the
+    /// conversation open for the life of the circuit
+    pub last: Option<RelayCmd>,
+}
+
+impl MsgHandler for ReplyForwarder {
+    fn handle_msg(&mut self, msg: AnyRelayMsg) -> tor_proto::Result<MetaCellDisposition> {
+        let finished = Some(msg.cmd()) == self.last;
+        // The receiver may have given up (timeout); nothing else to do then
+        let _ = self.tx.unbounded_send(msg);
+        Ok(if finished {
+            MetaCellDisposition::ConversationFinished
+        } else {
+            MetaCellDisposition::Consumed
+        })
+    }
+}
+
+pub(crate) async fn next_reply(
+    rx: &mut mpsc::UnboundedReceiver<AnyRelayMsg>,
+) -> Result<AnyRelayMsg> {
+    rx.next()
+        .await
+        .ok_or_else(|| TorError::onion_service("Circuit closed while waiting for a reply"))
+}
+
+/// Rendezvous circuits are kept per isolation key and service
+type RendezvousKey = (Option<IsolationKey>, HsId);
+
+/// What the service needs to meet us at our rendezvous point
+struct RendezvousPoint {
+    cookie: [u8; 20],
+    target: OwnedCircTarget,
+    link_specs: Vec<EncodedLinkSpec>,
+}
+
+/// A decrypted descriptor for the current time period
+#[derive(Clone)]
+struct CachedDescriptor {
+    desc: Arc<HsDesc>,
+    blinded_id: HsBlindId,
+    subcredential: Subcredential,
+    period: TimePeriod,
+    /// When the descriptor's `descriptor-lifetime` runs out
+    expires: SystemTime,
+    revision: u64,
+}
+
+impl CachedDescriptor {
+    fn is_fresh(&self, period: TimePeriod, now: SystemTime) -> bool {
+        self.period == period && now < self.expires
+    }
+}
+
+/// The `descriptor-lifetime` and `revision-counter` of a descriptor's
+/// outer document
+///
+/// `HsDesc` keeps both to itself, so they are read from the plaintext.
+fn outer_lifetime_and_revision(body: &str) -> Result<(Duration, u64)> {
+    let mut lifetime = None;
+    let mut revision = None;
+    for line in body.lines() {
+        let mut words = line.split_ascii_whitespace();
+        let field = match words.next() {
+            Some("descriptor-lifetime") => &mut lifetime,
+            Some("revision-counter") => &mut revision,
+            // The outer document's fields all precede its encrypted body
+            Some("superencrypted") => break,
+            _ => continue,
+        };
+        *field = words.next().and_then(|value| value.parse::<u64>().ok());
+    }
+    match (lifetime, revision) {
+        (Some(minutes), Some(revision)) => Ok((Duration::from_secs(minutes * 60), revision)),
+        _ => Err(TorError::onion_service(
+            "Descriptor lacks a lifetime or revision counter",
+        )),
+    }
+}
+
+/// Client for connecting to v3 onion services
+pub struct OnionClient {
+    circuit_manager: Arc<RwLock<CircuitManager>>,
+    descriptors: RwLock<HashMap<HsId, CachedDescriptor>>,
+    /// Client authorization keys for services using restricted discovery
+    client_auth: RwLock<HashMap<HsId, Arc<HsClientDescEncKeypair>>>,
+    /// Rendezvous circuits, one per isolation key and service
+    rendezvous: RwLock<HashMap<RendezvousKey, Arc<ClientTunnel>>>,
+    /// Consecutive failed introduction rounds per service, used to raise
+    /// the proof-of-work effort
+    intro_failures: RwLock<HashMap<HsId, u32>>,
+}
+
+impl OnionClient {
+    pub fn new(circuit_manager: Arc<RwLock<CircuitManager>>) -> Self {
+        Self {
+            circuit_manager,
+            descriptors: RwLock::new(HashMap::new()),
+            client_auth: RwLock::new(HashMap::new()),
+            rendezvous: RwLock::new(HashMap::new()),
+            intro_failures: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Register the client authorization key for an onion service
+    ///
+    /// The key decrypts the second descriptor layer of services that only
+    /// publish to authorized clients. See `parse_client_auth_key` for the
+    /// accepted formats.
+    pub async fn add_client_auth(&self, onion: &str, key: &str) -> Result<()> {
+        let hs_id = parse_onion_host(onion)?;
+        let keypair = parse_client_auth_key(key)?;
+        self.client_auth
+            .write()
+            .await
+            .insert(hs_id, Arc::new(keypair));
+        // A descriptor we already hold can't have needed this key
+        self.descriptors.write().await.remove(&hs_id);
+        Ok(())
+    }
+
+    /// Forget the client authorization key for an onion service
+    ///
+    /// Cached descriptors and rendezvous circuits for the service are
+    /// dropped, so later connections must succeed without the key.
+    pub async fn remove_client_auth(&self, onion: &str) -> Result<()> {
+        let hs_id = parse_onion_host(onion)?;
+        self.client_auth.write().await.remove(&hs_id);
+        self.descriptors.write().await.remove(&hs_id);
+        self.rendezvous
+            .write()
+            .await
+            .retain(|(_, service), _| *service != hs_id);
+        Ok(())
+    }
+
+    /// Open a stream to `port` on the onion service named by `host`
+    ///
+    /// Rendezvous circuits are reused per isolation key, mirroring how exit
+    /// circuits are bound to isolation keys.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation_key: Option<IsolationKey>,
+    ) -> Result<DataStream> {
+        let hs_id = parse_onion_host(host)?;
+        let tunnel = self.rendezvous_tunnel(hs_id, isolation_key).await?;
+
+        debug!("Beginning onion stream to {}:{}", host, port);
+        let stream = tunnel
+            .begin_stream("", port, None)
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to begin onion stream: {}", e)))?;
+
+        info!("Stream established to {}:{}", host, port);
+        Ok(stream)
+    }
+
+    /// Drop all cached descriptors and rendezvous circuits
+    pub async fn clear(&self) {
+        self.descriptors.write().await.clear();
+        self.rendezvous.write().await.clear();
+        self.intro_failures.write().await.clear();
+    }
+
+    async fn rendezvous_tunnel(
+        &self,
+        hs_id: HsId,
+        isolation_key: Option<IsolationKey>,
+    ) -> Result<Arc<ClientTunnel>> {
+        let cache_key = (isolation_key, hs_id);
+        if let Some(tunnel) = self.rendezvous.read().await.get(&cache_key) {
+            if !tunnel.is_closed() {
+                return Ok(tunnel.clone());
+            }
+        }
+
+        let descriptor = self.descriptor(hs_id).await?;
+        let failed_attempts = self
+            .intro_failures
+            .read()
+            .await
+            .get(&hs_id)
+            .copied()
+            .unwrap_or(0);
+        let tunnel = match self
+            .rendezvous_with(hs_id, &descriptor, failed_attempts)
+            .await
+        {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                *self.intro_failures.write().await.entry(hs_id).or_insert(0) += 1;
+                return Err(e);
+            }
+        };
+        self.intro_failures.write().await.remove(&hs_id);
+
+        self.rendezvous
+            .write()
+            .await
+            .insert(cache_key, tunnel.clone());
+        Ok(tunnel)
+    }
+
+    /// Get the service's descriptor for the current time period
+    async fn descriptor(&self, hs_id: HsId) -> Result<CachedDescriptor> {
+        let now = system_time_now();
+        let relay_manager = self.circuit_manager.read().await.relay_manager();
+        let (params, relays) = {
+            let manager = relay_manager.read().await;
+            (manager.hsdir_params.clone(), manager.relays.clone())
+        };
+        let period = params.time_period(now)?;
+
+        // An expired entry is kept until replaced so that a refetch can't
+        // roll the service back to an older revision
+        let previous = self.descriptors.read().await.get(&hs_id).cloned();
+        if let Some(cached) = &previous {
+            if cached.is_fresh(period, now) {
+                return Ok(cached.clone());
+            }
+        }
+        let min_revision = previous
+            .filter(|cached| cached.period == period)
+            .map_or(0, |cached| cached.revision);
+
+        let id_key = HsIdKey::try_from(hs_id)
+            .map_err(|e| TorError::configuration(format!("Invalid onion service key: {}", e)))?;
+        let (blinded_key, subcredential) = id_key
+            .compute_blinded_key(period)
+            .map_err(|e| TorError::onion_service(format!("Failed to blind identity: {}", e)))?;
+        let blinded_id = HsBlindId::from(blinded_key);
+        let blinded_bytes: &[u8; 32] = blinded_id.as_ref();
+
+        let period_num = period.interval_num();
+        let srv = params.shared_random_value(period_num, now);
+        let mut hsdirs = responsible_hsdirs(
+            &relays,
+            blinded_bytes,
+            &srv,
+            period_num,
+            &params,
+            params.spread_fetch,
+        );
+        if hsdirs.is_empty() {
+            return Err(TorError::relay_selection("No HSDirs in consensus"));
+        }
+        hsdirs.shuffle(&mut rand::thread_rng());
+
+        let path = format!(
+            "/tor/hs/3/{}",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(blinded_bytes)
+        );
+
+        let client_auth = self.client_auth.read().await.get(&hs_id).cloned();
+
+        let mut last_error = None;
+        for hsdir in hsdirs {
+            debug!("Fetching onion descriptor from HSDir {}", hsdir.nickname);
+            let body = match with_timeout(
+                DESCRIPTOR_FETCH_TIMEOUT,
+                "onion descriptor fetch",
+                self.fetch_descriptor(hsdir, &path),
+            )
+            .await
+            {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("HSDir {} failed: {}", hsdir.nickname, e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            let (lifetime, revision) = match outer_lifetime_and_revision(&body) {
+                Ok(outer) => outer,
+                Err(e) => {
+                    warn!("Descriptor from HSDir {} rejected: {}", hsdir.nickname, e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            if revision < min_revision {
+                warn!(
+                    "HSDir {} served descriptor revision {}, older than {}",
+                    hsdir.nickname, revision, min_revision
+                );
+                last_error = Some(TorError::onion_service(
+                    "HSDir served an outdated descriptor",
+                ));
+                continue;
+            }
+
+            match HsDesc::parse_decrypt_validate(
+                &body,
+                &blinded_id,
+                now,
+                &subcredential,
+                client_auth.as_deref(),
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|desc| desc.check_valid_at(&now).map_err(|e| e.to_string()))
+            {
+                Ok(desc) => {
+                    let cached = CachedDescriptor {
+                        desc: Arc::new(desc),
+                        blinded_id,
+                        subcredential,
+                        period,
+                        expires: now + lifetime,
+                        revision,
+                    };
+                    self.descriptors.write().await.insert(hs_id, cached.clone());
+                    return Ok(cached);
+                }
+                Err(e) => {
+                    warn!("Descriptor from HSDir {} rejected: {}", hsdir.nickname, e);
+                    last_error = Some(if client_auth.is_none() {
+                        TorError::onion_service(format!(
+                            "Invalid descriptor (the service may require client authorization): {}",
+                            e
+                        ))
+                    } else {
+                        TorError::onion_service(format!("Invalid descriptor: {}", e))
+                    });
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| TorError::onion_service("Descriptor not found")))
+    }
+
+    /// Download a descriptor document from one HSDir
+    async fn fetch_descriptor(&self, hsdir: &Relay, path: &str) -> Result<String> {
+        let request = format!(
+            "GET {} HTTP/1.0\r\n\
+             Host: directory\r\n\
+             Connection: close\r\n\
+             \r\n",
+            path
+        );
+        let (status, body) =
+            hsdir_request(&self.circuit_manager, hsdir, request.as_bytes()).await?;
+        match status {
+            200 => {}
+            404 => return Err(TorError::onion_service("Descriptor not found on HSDir")),
+            code => {
+                return Err(TorError::onion_service(format!(
+                    "HSDir returned status {}",
+                    code
+                )))
+            }
+        }
+
+        String::from_utf8(body)
+            .map_err(|_| TorError::onion_service("Descriptor is not valid UTF-8"))
+    }
+
+    /// Build a rendezvous circuit to the service described by `descriptor`
+    ///
+    /// `failed_attempts` counts earlier rounds that failed to reach the
+    /// service; it raises the proof-of-work effort for services that ask
+    /// for one.
+    async fn rendezvous_with(
+        &self,
+        hs_id: HsId,
+        descriptor: &CachedDescriptor,
+        failed_attempts: u32,
+    ) -> Result<Arc<ClientTunnel>> {
+        let desc = &descriptor.desc;
+        let pow_params = pow::descriptor_params(desc, system_time_now());
+        let effort = pow_params
+            .as_ref()
+            .map(|params| pow::effort_for_attempt(params.suggested_effort, failed_attempts))
+            .unwrap_or(0);
+
+        let circuit_manager = self.circuit_manager.read().await;
+
+        // 1. Rendezvous point
+        let rend_relay = circuit_manager
+            .relay_manager()
+            .read()
+            .await
+            .select_relay(&crate::relay::selection::middle_relays())?;
+        let rend_target = rend_relay.as_circ_target()?;
+        let rend_tunnel = circuit_manager
+            .build_tunnel_to(&rend_target, Some(&rend_relay))
+            .await?;
+        info!("Rendezvous circuit built to {}", rend_relay.nickname);
+
+        let cookie: [u8; 20] = rand::random();
+        let (tx, mut rend_replies) = mpsc::unbounded();
+        let _conversation = rend_tunnel
+            .start_conversation(
+                Some(EstablishRendezvous::new(RendCookie::from(cookie)).into()),
+                ReplyForwarder {
+                    tx,
+                    last: Some(RelayCmd::RENDEZVOUS2),
+                },
+                TargetHop::LastHop,
+            )
+            .await
+            .map_err(|e| {
+                TorError::onion_service(format!("Failed to send ESTABLISH_RENDEZVOUS: {}", e))
+            })?;
+
+        let reply = with_timeout(
+            RENDEZVOUS_TIMEOUT,
+            "rendezvous establishment",
+            next_reply(&mut rend_replies),
+        )
+        .await?;
+        if reply.cmd() != RelayCmd::RENDEZVOUS_ESTABLISHED {
+            return Err(TorError::onion_service(format!(
+                "Unexpected reply to ESTABLISH_RENDEZVOUS: {}",
+                reply.cmd()
+            )));
+        }
+
+        // 2. Introduction, trying intro points in random order
+        let rend_point = RendezvousPoint {
+            cookie,
+            link_specs: rend_target.linkspecs().map_err(|e| {
+                TorError::Internal(format!("Failed to encode link specifiers: {}", e))
+            })?,
+            target: rend_target,
+        };
+        let mut intro_points: Vec<&IntroPointDesc> = desc.intro_points().iter().collect();
+        intro_points.shuffle(&mut rand::thread_rng());
+
+        let mut handshake = None;
+        let mut last_error = None;
+        for ipt in intro_points {
+            // Each INTRODUCE1 needs its own solution: services reject
+            // replayed nonces
+            let proof_of_work = match &pow_params {
+                Some(params) => pow::solve(descriptor.blinded_id, params, effort).await?,
+                None => None,
+            };
+            match self
+                .introduce(
+                    &circuit_manager,
+                    ipt,
+                    &descriptor.subcredential,
+                    proof_of_work,
+                    &rend_point,
+                )
+                .await
+            {
+                Ok(state) => {
+                    handshake = Some(state);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Introduction failed: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        let Some(handshake) = handshake else {
+            // The service may have rotated its intro points; refetch next time
+            self.descriptors.write().await.remove(&hs_id);
+            return Err(last_error.unwrap_or_else(|| {
+                TorError::onion_service("Descriptor lists no introduction points")
+            }));
+        };
+
+        // 3. Wait for the service to meet us at the rendezvous point
+        let reply = with_timeout(
+            RENDEZVOUS_TIMEOUT,
+            "onion service rendezvous",
+            next_reply(&mut rend_replies),
+        )
+        .await?;
+        let rendezvous2 = match reply {
+            AnyRelayMsg::Rendezvous2(rendezvous2) => rendezvous2,
+            other => {
+                return Err(TorError::onion_service(format!(
+                    "Unexpected message on rendezvous circuit: {}",
+                    other.cmd()
+                )))
+            }
+        };
+
+        let keygen = handshake
+            .client_receive_rend(rendezvous2.handshake_info())
+            .map_err(|e| TorError::onion_service(format!("Rendezvous handshake failed: {}", e)))?;
+
+        rend_tunnel
+            .as_single_circ()
+            .map_err(|e| TorError::Internal(format!("Rendezvous circuit is not single: {}", e)))?
+            .extend_virtual(
+                RelayProtocol::HsV3,
+                HandshakeRole::Initiator,
+                keygen,
+                &make_circ_params()?,
+                &Protocols::default(),
+            )
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to add service hop: {}", e)))?;
+
+        info!("Onion service rendezvous complete");
+        Ok(rend_tunnel)
+    }
+
+    /// Send INTRODUCE1 through one introduction point
+    ///
+    /// Returns the client side of the hs-ntor handshake, to be completed
+    /// with the RENDEZVOUS2 message.
+    async fn introduce(
+        &self,
+        circuit_manager: &CircuitManager,
+        ipt: &IntroPointDesc,
+        subcredential: &Subcredential,
+        proof_of_work: Option<ProofOfWork>,
+        rend_point: &RendezvousPoint,
+    ) -> Result<hs_ntor::HsNtorClientState> {
+        let intro_tunnel = circuit_manager
+            .build_tunnel_to(&intro_point_target(ipt)?, None)
+            .await?;
+
+        // The handshake covers the encoded header, which is all an
+        // INTRODUCE1 with nothing encrypted yet encodes to
+        let auth_key = ipt.ipt_sid_key().as_bytes().to_vec();
+        let mut header_bytes = Vec::new();
+        let header = Introduce1::new(AuthKeyType::ED25519_SHA3_256, auth_key.clone(), Vec::new());
+        RelayMsg::encode_onto(header, &mut header_bytes)
+            .map_err(|e| TorError::Internal(format!("Failed to encode INTRODUCE1: {}", e)))?;
+
+        let payload = IntroduceHandshakePayload::new(
+            RendCookie::from(rend_point.cookie),
+            OnionKey::NtorOnionKey(*rend_point.target.ntor_onion_key()),
+            rend_point.link_specs.clone(),
+            proof_of_work,
+        );
+        let mut plaintext = Vec::new();
+        payload
+            .write_onto(&mut plaintext)
+            .map_err(|e| TorError::Internal(format!("Failed to encode INTRODUCE1: {}", e)))?;
+
+        let state = hs_ntor::HsNtorClientState::new(
+            &mut tor_llcrypto::rng::CautiousRng,
+            hs_ntor::HsNtorServiceInfo::new(
+                ipt.svc_ntor_key().clone(),
+                ipt.ipt_sid_key().clone(),
+                *subcredential,
+            ),
+        );
+        let encrypted = state
+            .client_send_intro(&header_bytes, &plaintext)
+            .map_err(|e| TorError::onion_service(format!("Failed to encrypt INTRODUCE1: {}", e)))?;
+
+        let (tx, mut replies) = mpsc::unbounded();
+        let _conversation = intro_tunnel
+            .start_conversation(
+                Some(Introduce1::new(AuthKeyType::ED25519_SHA3_256, auth_key, encrypted).into()),
+                ReplyForwarder {
+                    tx,
+                    last: Some(RelayCmd::INTRODUCE_ACK),
+                },
+                TargetHop::LastHop,
+            )
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to send INTRODUCE1: {}", e)))?;
+
+        match with_timeout(
+            INTRODUCTION_TIMEOUT,
+            "onion service introduction",
+            next_reply(&mut replies),
+        )
+        .await?
+        {
+            AnyRelayMsg::IntroduceAck(ack) if ack.status() == IntroduceAckStatus::SUCCESS => {
+                Ok(state)
+            }
+            AnyRelayMsg::IntroduceAck(ack) => Err(TorError::onion_service(format!(
+                "Introduction point rejected INTRODUCE1: {}",
+                ack.status()
+            ))),
+            other => Err(TorError::onion_service(format!(
+                "Unexpected reply to INTRODUCE1: {}",
+                other.cmd()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    // The Tor Project website's onion address
+    const ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    fn hsdir(fingerprint: &str, ed_byte: u8) -> Relay {
+        let mut relay = Relay::new(
+            fingerprint.to_string(),
+            fingerprint.to_string(),
+            "127.0.0.1".to_string(),
+            9001,
+            [flags::HSDIR.to_string()].into_iter().collect(),
+            "00".repeat(32),
+        );
+        relay.ed25519_identity = Some(hex::encode([ed_byte; 32]));
+        relay
+    }
+
+    #[portable_test]
+    fn onion_hosts_are_recognised() {
+        assert!(is_onion_host(ONION));
+        assert!(is_onion_host("www.EXAMPLE.ONION"));
+        assert!(is_onion_host("example.onion."));
+        assert!(!is_onion_host(".onion"));
+        assert!(!is_onion_host("onion"));
+        assert!(!is_onion_host("example.com"));
+        assert!(!is_onion_host("onion.example.com"));
+    }
+
+    #[portable_test]
+    fn onion_host_parsing_accepts_subdomains_and_rejects_garbage() {
+        let id = parse_onion_host(ONION).unwrap();
+        assert_eq!(parse_onion_host(&format!("www.{}", ONION)).unwrap(), id);
+        assert_eq!(parse_onion_host(&ONION.to_uppercase()).unwrap(), id);
+
+        assert!(parse_onion_host("example.com").is_err());
+        assert!(parse_onion_host("tooshort.onion").is_err());
+        // Bad checksum
+        assert!(parse_onion_host(&ONION.replacen('2', "3", 1)).is_err());
+    }
+
+    #[portable_test]
+    fn shared_random_value_follows_period_start() {
+        let params = HsDirParams {
+            srv_current: Some([1; 32]),
+            srv_previous: Some([2; 32]),
+            ..HsDirParams::default()
+        };
+        let day = SHARED_RANDOM_PERIOD_SECS;
+
+        // Period 100 starts at 12:00 on day 100
+        let afternoon = UNIX_EPOCH + Duration::from_secs(100 * day + 13 * 3600);
+        assert_eq!(params.shared_random_value(100, afternoon), [1; 32]);
+
+        // By 06:00 on day 101 the current value has rotated past the period start
+        let morning = UNIX_EPOCH + Duration::from_secs(101 * day + 6 * 3600);
+        assert_eq!(params.shared_random_value(100, morning), [2; 32]);
+
+        let empty = HsDirParams::default();
+        assert_eq!(
+            empty.shared_random_value(100, afternoon),
+            disaster_srv(100, empty.time_period_minutes)
+        );
+    }
+
+    #[portable_test]
+    fn services_publish_for_previous_period_until_the_value_rotates() {
+        let params = HsDirParams::default();
+        let day = SHARED_RANDOM_PERIOD_SECS;
+        let periods = |secs: u64| -> Vec<u64> {
+            params
+                .publish_periods(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap()
+                .iter()
+                .map(|p| p.interval_num())
+                .collect()
+        };
+
+        // Period 100 runs from 12:00 on day 100 to 12:00 on day 101. Before
+        // midnight the consensus value is the one period 100 began under,
+        // and clients not yet in period 100 still look in period 99.
+        assert_eq!(periods(100 * day + 13 * 3600), [99, 100]);
+        assert_eq!(periods(100 * day + 23 * 3600), [99, 100]);
+
+        // After midnight the new value already seeds period 101's ring
+        assert_eq!(periods(101 * day + 3600), [100, 101]);
+        assert_eq!(periods(101 * day + 11 * 3600), [100, 101]);
+    }
+
+    #[portable_test]
+    fn responsible_hsdirs_are_distinct_and_bounded() {
+        let mut relays: Vec<Relay> = (0..20u8)
+            .map(|i| hsdir(&format!("hsdir{}", i), i))
+            .collect();
+        // Relays without the flag or an ed25519 identity never make the ring
+        relays.push(Relay::new(
+            "noflag".to_string(),
+            "noflag".to_string(),
+            "127.0.0.1".to_string(),
+            9001,
+            Default::default(),
+            "00".repeat(32),
+        ));
+        let mut no_id = hsdir("noid", 0);
+        no_id.ed25519_identity = None;
+        relays.push(no_id);
+
+        let params = HsDirParams::default();
+        let chosen = responsible_hsdirs(
+            &relays,
+            &[7; 32],
+            &[9; 32],
+            19000,
+            &params,
+            params.spread_fetch,
+        );
+
+        assert_eq!(
+            chosen.len(),
+            params.spread_fetch * params.n_replicas as usize
+        );
+        let mut fingerprints: Vec<_> = chosen.iter().map(|r| r.fingerprint.clone()).collect();
+        fingerprints.sort();
+        fingerprints.dedup();
+        assert_eq!(fingerprints.len(), chosen.len());
+        assert!(fingerprints.iter().all(|fp| fp.starts_with("hsdir")));
+
+        // Deterministic for the same inputs
+        let again = responsible_hsdirs(
+            &relays,
+            &[7; 32],
+            &[9; 32],
+            19000,
+            &params,
+            params.spread_fetch,
+        );
+        assert!(chosen
+            .iter()
+            .zip(again.iter())
+            .all(|(a, b)| a.fingerprint == b.fingerprint));
+
+        // Services upload to a wider spread than clients fetch from
+        let stored = responsible_hsdirs(
+            &relays,
+            &[7; 32],
+            &[9; 32],
+            19000,
+            &params,
+            params.spread_store,
+        );
+        assert_eq!(
+            stored.len(),
+            params.spread_store * params.n_replicas as usize
+        );
+    }
+
+    #[portable_test]
+    fn responsible_hsdirs_handle_small_rings() {
+        let relays = vec![hsdir("a", 1), hsdir("b", 2)];
+        let params = HsDirParams::default();
+        let chosen = responsible_hsdirs(&relays, &[7; 32], &[9; 32], 1, &params, 3);
+        assert_eq!(chosen.len(), 2);
+        assert!(responsible_hsdirs(&[], &[7; 32], &[9; 32], 1, &params, 3).is_empty());
+    }
+
+    #[portable_test]
+    fn base32_decoding_matches_rfc4648() {
+        assert_eq!(decode_base32("").unwrap(), b"");
+        assert_eq!(decode_base32("MY").unwrap(), b"f");
+        assert_eq!(decode_base32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(decode_base32("mzxw6ytboi======").unwrap(), b"foobar");
+        assert!(decode_base32("MZXW6YTBO1").is_none());
+    }
+
+    #[portable_test]
+    fn onion_address_round_trips() {
+        assert_eq!(encode_base32(b"foobar"), "mzxw6ytboi");
+        assert_eq!(
+            decode_base32(&encode_base32(&[0xff; 7])).unwrap(),
+            [0xff; 7]
+        );
+
+        let public_key: [u8; 32] = decode_base32(&ONION[..56]).unwrap()[..32]
+            .try_into()
+            .unwrap();
+        assert_eq!(onion_address(&public_key), ONION);
+    }
+
+    #[portable_test]
+    fn client_auth_keys_accept_bare_and_auth_private_formats() {
+        let encoded = "A".repeat(52);
+        let bare = parse_client_auth_key(&encoded).unwrap();
+        let line = format!("{}:descriptor:x25519:{}", &ONION[..56], encoded);
+        let from_file = parse_client_auth_key(&line).unwrap();
+        assert_eq!(bare.public(), from_file.public());
+
+        assert!(parse_client_auth_key("").is_err());
+        assert!(parse_client_auth_key("descriptor:x25519:AAAA").is_err());
+        assert!(parse_client_auth_key(&"!".repeat(52)).is_err());
+    }
+
+    #[portable_test]
+    fn outer_document_gives_lifetime_and_revision() {
+        let body = "hs-descriptor 3\n\
+                    descriptor-lifetime 180\n\
+                    descriptor-signing-key-cert\n\
+                    -----BEGIN ED25519 CERT-----\n\
+                    AQgABl5/\n\
+                    -----END ED25519 CERT-----\n\
+                    revision-counter 42\n\
+                    superencrypted\n\
+                    -----BEGIN MESSAGE-----\n\
+                    revision-counter 99\n\
+                    -----END MESSAGE-----\n";
+        let (lifetime, revision) = outer_lifetime_and_revision(body).unwrap();
+        assert_eq!(lifetime, Duration::from_secs(3 * 60 * 60));
+        assert_eq!(revision, 42);
+
+        assert!(outer_lifetime_and_revision("hs-descriptor 3\nrevision-counter 1\n").is_err());
+        assert!(
+            outer_lifetime_and_revision("descriptor-lifetime x\nrevision-counter 1\n").is_err()
+        );
+    }
+}
@@ -0,0 +1,805 @@
+//! Onion service (v3) hosting
+//!
+//! The service side of rend-spec-v3, built on the client's own circuits:
+//! 1. Establish introduction points by sending ESTABLISH_INTRO, signed with a
+//!    fresh per-point key, on circuits to ordinary relays.
+//! 2. Publish a descriptor listing them to the HSDirs responsible for the
+//!    current and next time period, and republish it periodically.
+//! 3. For every INTRODUCE2, complete the hs-ntor handshake, build a circuit to
+//!    the client's rendezvous point, send RENDEZVOUS1 and accept BEGIN cells
+//!    on that circuit. Accepted streams are handed to the application.
+
+use crate::circuit::{make_circ_params, CircuitManager};
+use crate::config::OnionServiceConfig;
+use crate::error::{Result, TorError};
+use crate::onion::{
+    circ_target_from_link_specs, hsdir_request, next_reply, onion_address, responsible_hsdirs,
+    ReplyForwarder,
+};
+use crate::relay::{selection, Relay};
+use crate::retry::{sleep, with_cancellation, with_timeout, CancellationToken};
+use crate::time::system_time_now;
+use futures::channel::mpsc;
+use futures::{Future, StreamExt};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex as AsyncMutex, RwLock};
+use tor_bytes::Writeable;
+use tor_cell::chancell::msg::HandshakeType;
+use tor_cell::relaycell::hs::est_intro::EstablishIntroDetails;
+use tor_cell::relaycell::hs::intro_payload::{IntroduceHandshakePayload, OnionKey};
+use tor_cell::relaycell::msg::{
+    AnyRelayMsg, Connected, End, EndReason, Introduce2, Rendezvous1, Unrecognized,
+};
+use tor_cell::relaycell::{RelayCmd, RelayMsg};
+use tor_hscrypto::pk::{
+    HsBlindId, HsIdKeypair, HsIntroPtSessionIdKey, HsIntroPtSessionIdKeypair, HsSvcNtorKey,
+    HsSvcNtorKeypair,
+};
+use tor_hscrypto::time::TimePeriod;
+use tor_hscrypto::Subcredential;
+use tor_linkspec::{CircTarget, EncodedLinkSpec};
+use tor_llcrypto::pk::{curve25519, ed25519};
+use tor_llcrypto::rng::CautiousRng;
+use tor_netdoc::doc::hsdesc::{
+    create_desc_sign_key_cert, HsDescBuilder, IntroPointDesc, IntroPointDescBuilder,
+};
+use tor_netdoc::NetdocBuilder;
+use tor_proto::circuit::CircSyncView;
+use tor_proto::client::circuit::handshake::{hs_ntor, HandshakeRole, RelayProtocol};
+use tor_proto::client::stream::{
+    DataStream, IncomingStreamRequest, IncomingStreamRequestContext,
+    IncomingStreamRequestDisposition, IncomingStreamRequestFilter,
+};
+use tor_proto::{ClientTunnel, TargetHop};
+use tor_protover::Protocols;
+use tor_units::IntegerMinutes;
+use tracing::{debug, info, warn};
+
+/// Upper bound on introduction points (`hs_intro_num_intro_points` max)
+const MAX_INTRO_POINTS: usize = 20;
+
+const INTRO_ESTABLISH_TIMEOUT: Duration = Duration::from_secs(30);
+const DESCRIPTOR_UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const RENDEZVOUS_CIRCUIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often introduction points are checked
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Republish unchanged descriptors well within their lifetime
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Descriptor lifetime advertised to HSDirs
+const DESCRIPTOR_LIFETIME_MINUTES: u16 = 180;
+
+/// Validity of the certificates inside a descriptor
+const DESCRIPTOR_CERT_LIFETIME: Duration = Duration::from_secs(54 * 60 * 60);
+
+/// INTRODUCE2 cells remembered per introduction point to drop replays
+const REPLAY_CACHE_SIZE: usize = 4096;
+
+/// A stream opened by a client of a hosted onion service
+pub struct IncomingOnionStream {
+    /// Virtual port the client connected to
+    pub port: u16,
+    pub stream: DataStream,
+}
+
+/// Handle to a running onion service
+///
+/// Incoming streams arrive through `accept`. The service stops when the
+/// handle is dropped, `shutdown` is called, or the TorClient is closed.
+pub struct OnionServiceHandle {
+    onion_address: String,
+    identity_key: String,
+    incoming: AsyncMutex<mpsc::UnboundedReceiver<IncomingOnionStream>>,
+    shutdown: CancellationToken,
+}
+
+impl OnionServiceHandle {
+    /// The service's `.onion` hostname
+    pub fn onion_address(&self) -> &str {
+        &self.onion_address
+    }
+
+    /// Hex-encoded identity secret key
+    ///
+    /// Pass it to `OnionServiceConfig::with_identity_key` to relaunch the
+    /// service under the same address. Anyone holding it can impersonate
+    /// the service.
+    pub fn identity_key(&self) -> &str {
+        &self.identity_key
+    }
+
+    /// Wait for the next incoming stream
+    ///
+    /// Returns `None` once the service has stopped. Concurrent callers are
+    /// served in turn.
+    pub async fn accept(&self) -> Option<IncomingOnionStream> {
+        self.incoming.lock().await.next().await
+    }
+
+    /// Stop the service and close its introduction points
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Whether the service is still running
+    pub fn is_running(&self) -> bool {
+        !self.shutdown.is_cancelled()
+    }
+}
+
+impl Drop for OnionServiceHandle {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Launch an onion service on top of `circuit_manager`
+///
+/// Returns once the introduction points are up and at least one HSDir
+/// accepted the descriptor.
+pub(crate) async fn launch(
+    circuit_manager: Arc<RwLock<CircuitManager>>,
+    config: OnionServiceConfig,
+    client_shutdown: CancellationToken,
+) -> Result<OnionServiceHandle> {
+    if config.num_intro_points == 0 || config.num_intro_points > MAX_INTRO_POINTS {
+        return Err(TorError::configuration(format!(
+            "Onion services need between 1 and {} introduction points",
+            MAX_INTRO_POINTS
+        )));
+    }
+
+    let seed = match &config.identity_key {
+        Some(key) => parse_identity_key(key)?,
+        None => rand::random(),
+    };
+    let keypair = ed25519::Keypair::from_bytes(&seed);
+    let address = onion_address(&keypair.verifying_key().to_bytes());
+    let identity = HsIdKeypair::from(ed25519::ExpandedKeypair::from(&keypair));
+
+    let (streams, incoming) = mpsc::unbounded();
+    let service = Arc::new(OnionService {
+        circuit_manager,
+        config,
+        identity,
+        streams,
+        shutdown: CancellationToken::new(),
+        client_shutdown,
+        intro_points: RwLock::new(Vec::new()),
+    });
+
+    info!("Launching onion service {}", address);
+    let published = async {
+        service.establish_intro_points().await?;
+        service.publish().await
+    };
+    if let Err(e) = published.await {
+        service.stop().await;
+        return Err(e);
+    }
+    spawn(service.clone().maintain());
+
+    info!("Onion service {} is reachable", address);
+    Ok(OnionServiceHandle {
+        onion_address: address,
+        identity_key: hex::encode(seed),
+        incoming: AsyncMutex::new(incoming),
+        shutdown: service.shutdown.clone(),
+    })
+}
+
+/// Parse a hex-encoded 32-byte ed25519 secret key
+fn parse_identity_key(key: &str) -> Result<[u8; 32]> {
+    hex::decode(key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| {
+            TorError::configuration("Onion service identity key must be 64 hex characters")
+        })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    wasm_bindgen_futures::spawn_local(future);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+/// An established introduction point
+struct IntroPoint {
+    relay: Relay,
+    tunnel: Arc<ClientTunnel>,
+    link_specs: Vec<EncodedLinkSpec>,
+    ntor_key: curve25519::PublicKey,
+    /// Key that signed ESTABLISH_INTRO; clients name it in INTRODUCE1
+    auth_key: HsIntroPtSessionIdKey,
+    /// Service half of the hs-ntor handshake for this point
+    enc_key: HsSvcNtorKeypair,
+    enc_public: HsSvcNtorKey,
+}
+
+impl IntroPoint {
+    /// Build a circuit to `relay` and make it an introduction point
+    ///
+    /// Returns the point with the channel its INTRODUCE2 cells arrive on.
+    async fn establish(
+        circuit_manager: &CircuitManager,
+        relay: Relay,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<AnyRelayMsg>)> {
+        let target = relay.as_circ_target()?;
+        let link_specs = target
+            .linkspecs()
+            .map_err(|e| TorError::Internal(format!("Failed to encode link specifiers: {}", e)))?;
+        let ntor_key = *target.ntor_onion_key();
+        let tunnel = circuit_manager
+            .build_tunnel_to(&target, Some(&relay))
+            .await?;
+
+        let auth = ed25519::Keypair::generate(&mut CautiousRng);
+        let auth_id = ed25519::Ed25519Identity::from(auth.verifying_key());
+        let auth_key = HsIntroPtSessionIdKey::from(auth.verifying_key());
+        let auth_keypair = HsIntroPtSessionIdKeypair::from(auth);
+
+        let secret = curve25519::StaticSecret::random_from_rng(CautiousRng);
+        let public = curve25519::PublicKey::from(&secret);
+        let enc_key = HsSvcNtorKeypair::from(curve25519::StaticKeypair { secret, public });
+
+        let binding = tunnel
+            .as_single_circ()
+            .map_err(|e| TorError::Internal(format!("Introduction circuit is not single: {}", e)))?
+            .binding_key(TargetHop::LastHop)
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to get binding key: {}", e)))?
+            .ok_or_else(|| TorError::Internal("Introduction circuit has no binding key".into()))?;
+        let establish_intro = EstablishIntroDetails::new(auth_id)
+            .sign_and_encode(auth_keypair.as_ref(), binding.hs_mac())
+            .map_err(|e| TorError::Internal(format!("Failed to encode ESTABLISH_INTRO: {}", e)))?;
+
+        // ESTABLISH_INTRO only comes signed and encoded, so it goes out as
+        // a raw body. The handler stays installed after the conversation is
+        // dropped, so INTRODUCE2 cells keep arriving on `replies`
+        let establish_intro = Unrecognized::new(RelayCmd::ESTABLISH_INTRO, establish_intro);
+        let (tx, mut replies) = mpsc::unbounded();
+        tunnel
+            .start_conversation(
+                Some(AnyRelayMsg::Unrecognized(establish_intro)),
+                ReplyForwarder { tx, last: None },
+                TargetHop::LastHop,
+            )
+            .await
+            .map_err(|e| {
+                TorError::onion_service(format!("Failed to send ESTABLISH_INTRO: {}", e))
+            })?;
+
+        let reply = with_timeout(
+            INTRO_ESTABLISH_TIMEOUT,
+            "introduction point establishment",
+            next_reply(&mut replies),
+        )
+        .await?;
+        if reply.cmd() != RelayCmd::INTRO_ESTABLISHED {
+            return Err(TorError::onion_service(format!(
+                "Unexpected reply to ESTABLISH_INTRO: {}",
+                reply.cmd()
+            )));
+        }
+
+        info!("Introduction point established at {}", relay.nickname);
+        Ok((
+            Self {
+                relay,
+                tunnel,
+                link_specs,
+                ntor_key,
+                auth_key,
+                enc_public: HsSvcNtorKey::from(public),
+                enc_key,
+            },
+            replies,
+        ))
+    }
+
+    /// This point's entry in the service descriptor
+    fn descriptor_entry(&self) -> Result<IntroPointDesc> {
+        IntroPointDescBuilder::default()
+            .link_specifiers(self.link_specs.clone())
+            .ipt_kp_ntor(self.ntor_key)
+            .kp_hs_ipt_sid(self.auth_key.clone())
+            .kp_hss_ntor(self.enc_public.clone())
+            .build()
+            .map_err(|e| TorError::Internal(format!("Failed to describe intro point: {}", e)))
+    }
+}
+
+/// Accepts BEGIN cells for the service's configured ports
+struct PortFilter {
+    config: OnionServiceConfig,
+}
+
+impl IncomingStreamRequestFilter for PortFilter {
+    fn disposition(
+        &mut self,
+        ctx: &IncomingStreamRequestContext<'_>,
+        _circ: &CircSyncView<'_>,
+    ) -> tor_proto::Result<IncomingStreamRequestDisposition> {
+        Ok(match ctx.request() {
+            IncomingStreamRequest::Begin(begin) if self.config.accepts_port(begin.port()) => {
+                IncomingStreamRequestDisposition::Accept
+            }
+            _ => IncomingStreamRequestDisposition::RejectRequest(End::new_with_reason(
+                EndReason::CONNECTREFUSED,
+            )),
+        })
+    }
+}
+
+/// State shared by a running service's tasks
+struct OnionService {
+    circuit_manager: Arc<RwLock<CircuitManager>>,
+    config: OnionServiceConfig,
+    identity: HsIdKeypair,
+    streams: mpsc::UnboundedSender<IncomingOnionStream>,
+    /// Cancelled by the handle
+    shutdown: CancellationToken,
+    /// Cancelled when the TorClient closes
+    client_shutdown: CancellationToken,
+    intro_points: RwLock<Vec<Arc<IntroPoint>>>,
+}
+
+impl OnionService {
+    fn is_stopped(&self) -> bool {
+        self.shutdown.is_cancelled()
+            || self.client_shutdown.is_cancelled()
+            || self.streams.is_closed()
+    }
+
+    /// Run `future` unless the service or the client shuts down first
+    async fn unless_stopped<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        let future = with_cancellation(&self.client_shutdown, async { Ok(future.await) });
+        with_cancellation(&self.shutdown, future).await.ok()
+    }
+
+    /// Bring the number of live introduction points up to the configured count
+    ///
+    /// Returns how many points were added.
+    async fn establish_intro_points(self: &Arc<Self>) -> Result<usize> {
+        let mut current: Vec<Arc<IntroPoint>> = self.intro_points.read().await.clone();
+        current.retain(|ipt| !ipt.tunnel.is_closed());
+
+        let relay_manager = self.circuit_manager.read().await.relay_manager();
+        let mut added = 0;
+        let mut last_error = None;
+        // Allow a few failures per missing point before giving up
+        let mut attempts = self.config.num_intro_points.saturating_sub(current.len()) * 3;
+        while current.len() < self.config.num_intro_points && attempts > 0 {
+            attempts -= 1;
+            let criteria = current
+                .iter()
+                .fold(selection::middle_relays(), |criteria, ipt| {
+                    criteria.unrelated_to(&ipt.relay)
+                });
+            let relay = relay_manager.read().await.select_relay(&criteria)?;
+
+            let established = {
+                let circuit_manager = self.circuit_manager.read().await;
+                IntroPoint::establish(&circuit_manager, relay).await
+            };
+            match established {
+                Ok((ipt, replies)) => {
+                    let ipt = Arc::new(ipt);
+                    spawn(self.clone().serve_intro_point(ipt.clone(), replies));
+                    current.push(ipt);
+                    added += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to establish introduction point: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        *self.intro_points.write().await = current.clone();
+        if current.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                TorError::onion_service("No introduction points could be established")
+            }));
+        }
+        Ok(added)
+    }
+
+    /// Publish the descriptor for the time periods clients may look in
+    async fn publish(&self) -> Result<()> {
+        let now = system_time_now();
+        let relay_manager = self.circuit_manager.read().await.relay_manager();
+        let (params, relays) = {
+            let manager = relay_manager.read().await;
+            (manager.hsdir_params.clone(), manager.relays.clone())
+        };
+
+        let intro_points = self
+            .intro_points
+            .read()
+            .await
+            .iter()
+            .map(|ipt| ipt.descriptor_entry())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut uploads = Vec::new();
+        for period in params.publish_periods(now)? {
+            let (descriptor, blinded_id) = self.build_descriptor(period, &intro_points, now)?;
+            let descriptor = Arc::new(descriptor);
+            let blinded_bytes: &[u8; 32] = blinded_id.as_ref();
+            let period_num = period.interval_num();
+            let srv = params.shared_random_value(period_num, now);
+            for hsdir in responsible_hsdirs(
+                &relays,
+                blinded_bytes,
+                &srv,
+                period_num,
+                &params,
+                params.spread_store,
+            ) {
+                let descriptor = descriptor.clone();
+                uploads.push(async move {
+                    let result = with_timeout(
+                        DESCRIPTOR_UPLOAD_TIMEOUT,
+                        "onion descriptor upload",
+                        self.upload_descriptor(hsdir, &descriptor),
+                    )
+                    .await;
+                    if let Err(e) = &result {
+                        warn!("Descriptor upload to {} failed: {}", hsdir.nickname, e);
+                    }
+                    result
+                });
+            }
+        }
+
+        let results = futures::future::join_all(uploads).await;
+        let accepted = results.iter().filter(|r| r.is_ok()).count();
+        info!(
+            "Onion service descriptor accepted by {}/{} HSDirs",
+            accepted,
+            results.len()
+        );
+        if accepted == 0 {
+            return Err(results
+                .into_iter()
+                .find_map(|r| r.err())
+                .unwrap_or_else(|| TorError::relay_selection("No HSDirs in consensus")));
+        }
+        Ok(())
+    }
+
+    /// Build and sign the descriptor for `period`
+    fn build_descriptor(
+        &self,
+        period: TimePeriod,
+        intro_points: &[IntroPointDesc],
+        now: SystemTime,
+    ) -> Result<(String, HsBlindId)> {
+        let (blinded_key, blinded_keypair, subcredential) = self
+            .identity
+            .compute_blinded_key(period)
+            .map_err(|e| TorError::onion_service(format!("Failed to blind identity: {}", e)))?;
+        let signing_key = ed25519::Keypair::generate(&mut CautiousRng);
+        let cert_expiry = now + DESCRIPTOR_CERT_LIFETIME;
+        let signing_cert =
+            create_desc_sign_key_cert(&signing_key.verifying_key(), &blinded_keypair, cert_expiry)
+                .map_err(|e| TorError::Internal(format!("Failed to certify signing key: {}", e)))?;
+        // Seconds since the epoch only ever grow, as HSDirs require
+        let revision = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let descriptor = HsDescBuilder::default()
+            .blinded_id(&blinded_key)
+            .hs_desc_sign(&signing_key)
+            .hs_desc_sign_cert(signing_cert)
+            .create2_formats(&[HandshakeType::NTOR])
+            .auth_required(None)
+            .is_single_onion_service(false)
+            .intro_points(intro_points)
+            .intro_auth_key_cert_expiry(cert_expiry)
+            .intro_enc_key_cert_expiry(cert_expiry)
+            .lifetime(IntegerMinutes::new(DESCRIPTOR_LIFETIME_MINUTES))
+            .revision_counter(revision.into())
+            .subcredential(subcredential)
+            .auth_clients(None)
+            .build_sign(&mut CautiousRng)
+            .map_err(|e| TorError::onion_service(format!("Failed to build descriptor: {}", e)))?;
+
+        Ok((descriptor, HsBlindId::from(blinded_key)))
+    }
+
+    /// Upload a descriptor document to one HSDir
+    async fn upload_descriptor(&self, hsdir: &Relay, descriptor: &str) -> Result<()> {
+        debug!("Uploading onion descriptor to HSDir {}", hsdir.nickname);
+        let request = format!(
+            "POST /tor/hs/3/publish HTTP/1.0\r\n\
+             Host: directory\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}",
+            descriptor.len(),
+            descriptor
+        );
+        match hsdir_request(&self.circuit_manager, hsdir, request.as_bytes()).await? {
+            (200, _) => Ok(()),
+            (status, _) => Err(TorError::onion_service(format!(
+                "HSDir rejected descriptor with status {}",
+                status
+            ))),
+        }
+    }
+
+    /// Subcredentials clients may have used to encrypt an introduction
+    ///
+    /// Descriptors are published for the current period and its neighbour
+    /// on one side, and clients with skewed clocks may be on the other.
+    fn subcredentials(&self, period: TimePeriod) -> Vec<Subcredential> {
+        [period.prev(), Some(period), period.next()]
+            .into_iter()
+            .flatten()
+            .filter_map(|period| self.identity.compute_blinded_key(period).ok())
+            .map(|(_, _, subcredential)| subcredential)
+            .collect()
+    }
+
+    /// Handle INTRODUCE2 cells arriving at one introduction point
+    async fn serve_intro_point(
+        self: Arc<Self>,
+        ipt: Arc<IntroPoint>,
+        mut replies: mpsc::UnboundedReceiver<AnyRelayMsg>,
+    ) {
+        let mut seen = HashSet::new();
+        while let Some(Some(msg)) = self.unless_stopped(replies.next()).await {
+            let introduce2 = match msg {
+                AnyRelayMsg::Introduce2(introduce2) => introduce2,
+                other => {
+                    debug!("Ignoring {} on introduction circuit", other.cmd());
+                    continue;
+                }
+            };
+
+            let digest: [u8; 32] = Sha3_256::digest(introduce2.encrypted_body()).into();
+            if seen.len() >= REPLAY_CACHE_SIZE {
+                seen.clear();
+            }
+            if !seen.insert(digest) {
+                warn!("Dropping replayed INTRODUCE2");
+                continue;
+            }
+
+            let service = self.clone();
+            let ipt = ipt.clone();
+            spawn(async move {
+                if let Err(e) = service.rendezvous(&ipt, introduce2).await {
+                    warn!("Failed to answer introduction: {}", e);
+                }
+            });
+        }
+        debug!("Introduction point {} stopped serving", ipt.relay.nickname);
+    }
+
+    /// Meet the client at its rendezvous point and accept its streams
+    async fn rendezvous(&self, ipt: &IntroPoint, introduce2: Introduce2) -> Result<()> {
+        let mut header = Vec::new();
+        introduce2
+            .header()
+            .write_onto(&mut header)
+            .map_err(|e| TorError::Internal(format!("Failed to encode INTRODUCE2: {}", e)))?;
+
+        let relay_manager = self.circuit_manager.read().await.relay_manager();
+        let period = relay_manager
+            .read()
+            .await
+            .hsdir_params
+            .time_period(system_time_now())?;
+        let (keygen, handshake_info, plaintext) = hs_ntor::server_receive_intro(
+            &mut CautiousRng,
+            &ipt.enc_key,
+            &ipt.auth_key,
+            &self.subcredentials(period),
+            &header,
+            introduce2.encrypted_body(),
+        )
+        .map_err(|e| TorError::onion_service(format!("Failed to decrypt INTRODUCE2: {}", e)))?;
+
+        let payload: IntroduceHandshakePayload = tor_bytes::Reader::from_slice(&plaintext)
+            .extract()
+            .map_err(|e| TorError::onion_service(format!("Malformed INTRODUCE2: {}", e)))?;
+        let ntor_key = match payload.onion_key() {
+            OnionKey::NtorOnionKey(key) => *key,
+            _ => {
+                return Err(TorError::onion_service(
+                    "Unsupported rendezvous point onion key",
+                ))
+            }
+        };
+        let rend_target = circ_target_from_link_specs(payload.link_specifiers(), ntor_key)?;
+
+        let tunnel = with_timeout(RENDEZVOUS_CIRCUIT_TIMEOUT, "rendezvous circuit", async {
+            let circuit_manager = self.circuit_manager.read().await;
+            circuit_manager.build_tunnel_to(&rend_target, None).await
+        })
+        .await?;
+
+        tunnel
+            .send_raw_msg(
+                Rendezvous1::new(*payload.cookie(), handshake_info).into(),
+                TargetHop::LastHop,
+            )
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to send RENDEZVOUS1: {}", e)))?;
+        tunnel
+            .as_single_circ()
+            .map_err(|e| TorError::Internal(format!("Rendezvous circuit is not single: {}", e)))?
+            .extend_virtual(
+                RelayProtocol::HsV3,
+                HandshakeRole::Responder,
+                keygen,
+                &make_circ_params()?,
+                &Protocols::default(),
+            )
+            .await
+            .map_err(|e| TorError::onion_service(format!("Failed to add client hop: {}", e)))?;
+        debug!("Rendezvous complete via {}", ipt.relay.nickname);
+
+        let mut requests = Box::pin(
+            tunnel
+                .allow_stream_requests(
+                    &[RelayCmd::BEGIN],
+                    TargetHop::LastHop,
+                    PortFilter {
+                        config: self.config.clone(),
+                    },
+                )
+                .await
+                .map_err(|e| TorError::onion_service(format!("Failed to accept streams: {}", e)))?,
+        );
+
+        while let Some(Some(request)) = self.unless_stopped(requests.next()).await {
+            let port = match request.request() {
+                IncomingStreamRequest::Begin(begin) => begin.port(),
+                _ => continue,
+            };
+            let stream = match request.accept_data(Connected::new_empty()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept onion stream: {}", e);
+                    continue;
+                }
+            };
+            debug!("Accepted onion stream on port {}", port);
+            if self
+                .streams
+                .unbounded_send(IncomingOnionStream { port, stream })
+                .is_err()
+            {
+                // The handle is gone
+                break;
+            }
+        }
+
+        tunnel.terminate();
+        Ok(())
+    }
+
+    /// Keep introduction points alive and the descriptor fresh
+    async fn maintain(self: Arc<Self>) {
+        let mut last_publish = system_time_now();
+        loop {
+            if self
+                .unless_stopped(sleep(MAINTENANCE_INTERVAL))
+                .await
+                .is_none()
+                || self.is_stopped()
+            {
+                break;
+            }
+
+            let replaced = match self.establish_intro_points().await {
+                Ok(added) => added,
+                Err(e) => {
+                    warn!("Onion service has no introduction points: {}", e);
+                    continue;
+                }
+            };
+
+            let since_publish = system_time_now()
+                .duration_since(last_publish)
+                .unwrap_or_default();
+            if replaced == 0 && since_publish < REPUBLISH_INTERVAL {
+                continue;
+            }
+            match self.publish().await {
+                Ok(()) => last_publish = system_time_now(),
+                Err(e) => warn!("Failed to republish onion descriptor: {}", e),
+            }
+        }
+
+        self.stop().await;
+        info!("Onion service stopped");
+    }
+
+    /// Tear down the introduction points
+    async fn stop(&self) {
+        self.shutdown.cancel();
+        for ipt in self.intro_points.write().await.drain(..) {
+            ipt.tunnel.terminate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayManager;
+    use crate::test_util::{portable_test, portable_test_async};
+
+    #[portable_test]
+    fn identity_keys_must_be_32_bytes_of_hex() {
+        let key = "11".repeat(32);
+        assert_eq!(parse_identity_key(&key).unwrap(), [0x11; 32]);
+        assert_eq!(
+            parse_identity_key(&format!(" {}\n", key)).unwrap(),
+            [0x11; 32]
+        );
+        assert!(parse_identity_key("11").is_err());
+        assert!(parse_identity_key(&"zz".repeat(32)).is_err());
+    }
+
+    #[portable_test]
+    fn config_filters_ports() {
+        let any = OnionServiceConfig::default();
+        assert!(any.accepts_port(80));
+        assert!(any.accepts_port(1));
+
+        let web = OnionServiceConfig::default().with_port(80).with_port(443);
+        assert!(web.accepts_port(80));
+        assert!(web.accepts_port(443));
+        assert!(!web.accepts_port(22));
+    }
+
+    #[portable_test_async]
+    async fn closing_the_client_stops_service_loops() {
+        let relay_manager = Arc::new(RwLock::new(RelayManager::new(Vec::new())));
+        let circuit_manager = CircuitManager::new(relay_manager, Arc::new(RwLock::new(None)));
+        let keypair = ed25519::Keypair::from_bytes(&[7; 32]);
+        let (streams, _incoming) = mpsc::unbounded();
+        let client_shutdown = CancellationToken::new();
+        let service = OnionService {
+            circuit_manager: Arc::new(RwLock::new(circuit_manager)),
+            config: OnionServiceConfig::default(),
+            identity: HsIdKeypair::from(ed25519::ExpandedKeypair::from(&keypair)),
+            streams,
+            shutdown: CancellationToken::new(),
+            client_shutdown: client_shutdown.clone(),
+            intro_points: RwLock::new(Vec::new()),
+        };
+
+        assert_eq!(service.unless_stopped(async { 1 }).await, Some(1));
+        client_shutdown.cancel();
+        assert!(service.is_stopped());
+        assert_eq!(
+            service
+                .unless_stopped(futures::future::pending::<()>())
+                .await,
+            None
+        );
+    }
+}
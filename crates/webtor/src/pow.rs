@@ -0,0 +1,162 @@
+//! Client puzzles for onion service introductions
+//!
+//! Services under DoS defense publish `pow-params v1` in their descriptor and
+//! prioritize INTRODUCE1 cells carrying an Equi-X proof of sufficient effort
+//! (proposal 327). The solver runs one Equi-X attempt per step and yields to
+//! the event loop in between, so it never blocks the single wasm32 thread.
+
+use crate::error::{Result, TorError};
+use crate::retry::yield_now;
+use std::time::SystemTime;
+use tor_cell::relaycell::hs::pow::v1::ProofOfWorkV1;
+use tor_cell::relaycell::hs::pow::ProofOfWork;
+use tor_checkable::Timebound;
+use tor_hscrypto::pk::HsBlindId;
+use tor_hscrypto::pow::v1::{Effort, Instance, Seed, SolverInput};
+use tor_netdoc::doc::hsdesc::pow::PowParams;
+use tor_netdoc::doc::hsdesc::HsDesc;
+use tracing::{debug, info};
+
+/// Highest effort the client will spend on one introduction
+pub const MAX_EFFORT: u32 = 10_000;
+
+/// `v1` puzzle parameters from a descriptor
+#[derive(Debug, Clone)]
+pub(crate) struct PowParamsV1 {
+    pub seed: Seed,
+    pub suggested_effort: u32,
+}
+
+/// The descriptor's currently valid `v1` puzzle parameters, if any
+pub(crate) fn descriptor_params(desc: &HsDesc, now: SystemTime) -> Option<PowParamsV1> {
+    desc.pow_params().iter().find_map(|params| match params {
+        PowParams::V1(v1) => v1
+            .seed()
+            .clone()
+            .check_valid_at(&now)
+            .ok()
+            .map(|seed| PowParamsV1 {
+                seed,
+                suggested_effort: v1.suggested_effort().into(),
+            }),
+        _ => None,
+    })
+}
+
+/// Effort to spend on the next introduction to a service
+///
+/// Starts at the descriptor's suggestion and doubles for every introduction
+/// round that already failed, since the suggestion lags behind the actual
+/// load on the service.
+pub fn effort_for_attempt(suggested: u32, failed_attempts: u32) -> u32 {
+    let mut effort = suggested.min(MAX_EFFORT);
+    for _ in 0..failed_attempts {
+        effort = effort.saturating_mul(2).max(1);
+    }
+    effort.min(MAX_EFFORT)
+}
+
+/// Solve the puzzle for `service` at `effort`
+///
+/// Returns `None` for zero effort: the extension is only worth sending
+/// when it carries actual work.
+pub(crate) async fn solve(
+    service: HsBlindId,
+    params: &PowParamsV1,
+    effort: u32,
+) -> Result<Option<ProofOfWork>> {
+    if effort == 0 {
+        return Ok(None);
+    }
+
+    debug!("Solving Equi-X puzzle at effort {}", effort);
+    let instance = Instance::new(service, params.seed.clone());
+    let mut solver =
+        SolverInput::new(instance, Effort::new(effort)).solve(&mut tor_llcrypto::rng::CautiousRng);
+
+    let mut steps: u64 = 0;
+    loop {
+        steps += 1;
+        let step = solver
+            .run_step()
+            .map_err(|e| TorError::onion_service(format!("Proof-of-work solver failed: {}", e)))?;
+        if let Some(solution) = step {
+            info!(
+                "Solved proof-of-work at effort {} after {} attempts",
+                effort, steps
+            );
+            return Ok(Some(ProofOfWork::V1(ProofOfWorkV1::new(
+                solution.nonce().clone(),
+                solution.effort(),
+                solution.seed_head(),
+                solution.proof_to_bytes(),
+            ))));
+        }
+        yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{portable_test, portable_test_async};
+    use tor_hscrypto::pow::v1::{Solution, Verifier};
+
+    fn params() -> PowParamsV1 {
+        PowParamsV1 {
+            seed: Seed::from([0x5e; 32]),
+            suggested_effort: 0,
+        }
+    }
+
+    #[portable_test_async]
+    async fn solved_proof_passes_equix_verification_at_its_effort() {
+        let service = HsBlindId::from([7; 32]);
+        let params = params();
+        let proof = match solve(service, &params, 2).await.unwrap() {
+            Some(ProofOfWork::V1(proof)) => proof,
+            other => panic!("Expected a v1 proof, got {:?}", other),
+        };
+        assert_eq!(proof.effort(), Effort::new(2));
+        assert_eq!(proof.seed_head(), params.seed.head());
+
+        let solution = Solution::try_from_bytes(
+            proof.nonce().clone(),
+            proof.effort(),
+            proof.seed_head(),
+            proof.solution(),
+        )
+        .unwrap();
+        Verifier::new(Instance::new(service, params.seed.clone()))
+            .check(&solution)
+            .unwrap();
+
+        // The proof is bound to the service it was solved for
+        let other = HsBlindId::from([8; 32]);
+        assert!(Verifier::new(Instance::new(other, params.seed))
+            .check(&solution)
+            .is_err());
+    }
+
+    #[portable_test_async]
+    async fn zero_effort_sends_no_proof() {
+        let proof = solve(HsBlindId::from([7; 32]), &params(), 0).await.unwrap();
+        assert!(proof.is_none());
+    }
+
+    #[portable_test]
+    fn effort_starts_at_suggestion_and_doubles_per_failure() {
+        assert_eq!(effort_for_attempt(0, 0), 0);
+        assert_eq!(effort_for_attempt(0, 1), 1);
+        assert_eq!(effort_for_attempt(0, 3), 4);
+        assert_eq!(effort_for_attempt(50, 0), 50);
+        assert_eq!(effort_for_attempt(50, 2), 200);
+    }
+
+    #[portable_test]
+    fn effort_is_capped() {
+        assert_eq!(effort_for_attempt(u32::MAX, 0), MAX_EFFORT);
+        assert_eq!(effort_for_attempt(6_000, 1), MAX_EFFORT);
+        assert_eq!(effort_for_attempt(1, 40), MAX_EFFORT);
+    }
+}
@@ -1,6 +1,7 @@
 //! Tor relay management and selection
 
 use crate::error::{Result, TorError};
+use crate::onion::HsDirParams;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::str::FromStr;
@@ -20,7 +21,9 @@ pub struct Relay {
     pub or_port: u16,
     pub dir_port: Option<u16>,
     pub flags: HashSet<String>,
+    /// Advertised bandwidth in bytes per second (derived from the consensus weight)
     pub bandwidth: u64,
+    /// Consensus `w Bandwidth=` value (kilobytes per second, as voted by the authorities)
     pub consensus_weight: u32,
     pub version: String,
     pub microdescriptor_hash: String,
@@ -30,6 +33,16 @@ pub struct Relay {
     pub ed25519_identity: Option<String>, // Hex encoded
     #[serde(default)]
     pub ntor_onion_key: Option<String>, // Hex encoded
+
+    /// IPv4 exit port policy summary (microdescriptor `p` line), if known
+    #[serde(default)]
+    pub ipv4_policy: Option<PortPolicy>,
+    /// IPv6 exit port policy summary (microdescriptor `p6` line), if known
+    #[serde(default)]
+    pub ipv6_policy: Option<PortPolicy>,
+    /// Hex fingerprints this relay declares as family (microdescriptor `family` line)
+    #[serde(default)]
+    pub family: Vec<String>,
 }
 
 impl Relay {
@@ -55,7 +68,71 @@ impl Relay {
             microdescriptor_hash: String::new(),
             ed25519_identity: None,
             ntor_onion_key: Some(ntor_onion_key),
+            ipv4_policy: None,
+            ipv6_policy: None,
+            family: Vec::new(),
+        }
+    }
+
+    /// Whether this relay and `other` declare each other as family
+    ///
+    /// Family membership only counts when it is mutual, as in path-spec.
+    pub fn in_same_family(&self, other: &Relay) -> bool {
+        let declares = |relay: &Relay, fingerprint: &str| {
+            relay
+                .family
+                .iter()
+                .any(|member| member.eq_ignore_ascii_case(fingerprint))
+        };
+        declares(self, &other.fingerprint) && declares(other, &self.fingerprint)
+    }
+
+    /// Whether this relay and `other` share an IPv4 /16 or IPv6 /32
+    ///
+    /// Unparseable or unspecified addresses (e.g. the Snowflake placeholder)
+    /// never match.
+    pub fn in_same_subnet(&self, other: &Relay) -> bool {
+        let (Ok(a), Ok(b)) = (
+            std::net::IpAddr::from_str(&self.address),
+            std::net::IpAddr::from_str(&other.address),
+        ) else {
+            return false;
+        };
+        if a.is_unspecified() || b.is_unspecified() {
+            return false;
         }
+        match (a, b) {
+            (std::net::IpAddr::V4(a), std::net::IpAddr::V4(b)) => {
+                a.octets()[..2] == b.octets()[..2]
+            }
+            (std::net::IpAddr::V6(a), std::net::IpAddr::V6(b)) => {
+                a.octets()[..4] == b.octets()[..4]
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `other` must not share a circuit with this relay
+    pub fn is_related_to(&self, other: &Relay) -> bool {
+        self.fingerprint.eq_ignore_ascii_case(&other.fingerprint)
+            || self.in_same_family(other)
+            || self.in_same_subnet(other)
+    }
+
+    /// Whether this relay's IPv4 exit policy accepts connections to `port`
+    ///
+    /// Relays without a known policy are treated as rejecting everything.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.ipv4_policy
+            .as_ref()
+            .is_some_and(|policy| policy.allows_port(port))
+    }
+
+    /// Whether this relay's IPv6 exit policy accepts connections to `port`
+    pub fn allows_ipv6_port(&self, port: u16) -> bool {
+        self.ipv6_policy
+            .as_ref()
+            .is_some_and(|policy| policy.allows_port(port))
     }
 
     /// Convert to OwnedCircTarget for circuit creation
@@ -134,6 +211,187 @@ impl Relay {
     }
 }
 
+/// Exit port policy summary, as carried in microdescriptor `p` / `p6` lines
+///
+/// Summaries are either `accept <ranges>` or `reject <ranges>`, where ranges
+/// are comma-separated ports or inclusive `low-high` spans (dir-spec 3.1.2.1).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortPolicy {
+    /// Whether `ranges` lists accepted ports (true) or rejected ports (false)
+    pub accept: bool,
+    /// Inclusive port ranges
+    pub ranges: Vec<(u16, u16)>,
+}
+
+impl PortPolicy {
+    /// A policy that rejects every port
+    pub fn reject_all() -> Self {
+        Self {
+            accept: true,
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Whether a connection to `port` is allowed by this policy
+    pub fn allows_port(&self, port: u16) -> bool {
+        if port == 0 {
+            return false;
+        }
+        let listed = self
+            .ranges
+            .iter()
+            .any(|&(low, high)| low <= port && port <= high);
+        listed == self.accept
+    }
+}
+
+impl FromStr for PortPolicy {
+    type Err = TorError;
+
+    /// Parse `accept 80,443,8000-8999` / `reject 1-65535`
+    ///
+    /// A bare range list is treated as an accept list.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (accept, list) = if let Some(rest) = s.strip_prefix("accept") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("reject") {
+            (false, rest)
+        } else {
+            (true, s)
+        };
+
+        let mut ranges = Vec::new();
+        for item in list.trim().split(',').filter(|item| !item.is_empty()) {
+            let parse_port = |p: &str| {
+                p.trim().parse::<u16>().map_err(|_| {
+                    TorError::serialization(format!("Invalid port in policy: {:?}", item))
+                })
+            };
+            let (low, high) = match item.split_once('-') {
+                Some((low, high)) => (parse_port(low)?, parse_port(high)?),
+                None => {
+                    let port = parse_port(item)?;
+                    (port, port)
+                }
+            };
+            if low == 0 || low > high {
+                return Err(TorError::serialization(format!(
+                    "Invalid port range in policy: {:?}",
+                    item
+                )));
+            }
+            ranges.push((low, high));
+        }
+
+        Ok(Self { accept, ranges })
+    }
+}
+
+/// Position of a relay within a circuit
+///
+/// Used to pick the consensus bandwidth-weight that applies to each hop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Position {
+    Guard,
+    #[default]
+    Middle,
+    Exit,
+}
+
+/// Denominator for consensus bandwidth-weights (`bwweightscale`)
+pub const WEIGHT_SCALE: u32 = 10_000;
+
+/// Bandwidth-weights from the consensus footer (dir-spec 3.4.3)
+///
+/// Each value is a fraction of `WEIGHT_SCALE` describing how much of a relay's
+/// bandwidth should be used when picking it for a given position. The first
+/// letter is the position (g=guard, m=middle, e=exit), the second letter is the
+/// relay's role (g=Guard only, m=neither flag, e=Exit only, d=Guard+Exit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthWeights {
+    pub wgg: u32,
+    pub wgm: u32,
+    pub wgd: u32,
+    pub wmg: u32,
+    pub wmm: u32,
+    pub wme: u32,
+    pub wmd: u32,
+    pub weg: u32,
+    pub wem: u32,
+    pub wee: u32,
+    pub wed: u32,
+}
+
+impl Default for BandwidthWeights {
+    /// Without a consensus footer every position uses the full bandwidth
+    fn default() -> Self {
+        Self {
+            wgg: WEIGHT_SCALE,
+            wgm: WEIGHT_SCALE,
+            wgd: WEIGHT_SCALE,
+            wmg: WEIGHT_SCALE,
+            wmm: WEIGHT_SCALE,
+            wme: WEIGHT_SCALE,
+            wmd: WEIGHT_SCALE,
+            weg: WEIGHT_SCALE,
+            wem: WEIGHT_SCALE,
+            wee: WEIGHT_SCALE,
+            wed: WEIGHT_SCALE,
+        }
+    }
+}
+
+impl BandwidthWeights {
+    /// Build weights from a `bandwidth-weights` lookup (e.g. `NetParams::get`)
+    ///
+    /// Missing values fall back to `WEIGHT_SCALE`; out-of-range values are
+    /// clamped to `0..=WEIGHT_SCALE`, matching how Tor sanitizes the footer.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<i32>) -> Self {
+        let get = |name: &str| {
+            lookup(name)
+                .map(|v| v.clamp(0, WEIGHT_SCALE as i32) as u32)
+                .unwrap_or(WEIGHT_SCALE)
+        };
+
+        Self {
+            wgg: get("Wgg"),
+            wgm: get("Wgm"),
+            wgd: get("Wgd"),
+            wmg: get("Wmg"),
+            wmm: get("Wmm"),
+            wme: get("Wme"),
+            wmd: get("Wmd"),
+            weg: get("Weg"),
+            wem: get("Wem"),
+            wee: get("Wee"),
+            wed: get("Wed"),
+        }
+    }
+
+    /// Weight that applies to a relay with the given flags at `position`
+    ///
+    /// BadExit relays are treated as non-exits, as in Tor's path selection.
+    pub fn for_relay(&self, relay: &Relay, position: Position) -> u32 {
+        let is_guard = relay.flags.contains(flags::GUARD);
+        let is_exit = relay.flags.contains(flags::EXIT) && !relay.flags.contains(flags::BAD_EXIT);
+
+        let (wg, wm, we, wd) = match position {
+            // Exit-only relays are never weighted into the guard position
+            Position::Guard => (self.wgg, self.wgm, 0, self.wgd),
+            Position::Middle => (self.wmg, self.wmm, self.wme, self.wmd),
+            Position::Exit => (self.weg, self.wem, self.wee, self.wed),
+        };
+
+        match (is_guard, is_exit) {
+            (true, true) => wd,
+            (true, false) => wg,
+            (false, true) => we,
+            (false, false) => wm,
+        }
+    }
+}
+
 /// Relay selection criteria
 #[derive(Debug, Clone)]
 pub struct RelayCriteria {
@@ -142,6 +400,12 @@ pub struct RelayCriteria {
     pub exclude_fingerprints: HashSet<String>,
     pub min_bandwidth: u64,
     pub max_selection: usize,
+    /// Circuit position the relay is being chosen for (selects the bandwidth-weight)
+    pub position: Position,
+    /// Port the relay's exit policy must accept, if any
+    pub required_port: Option<u16>,
+    /// Relays already on the path; candidates related to any of them are skipped
+    pub exclude_related: Vec<Relay>,
 }
 
 impl Default for RelayCriteria {
@@ -152,6 +416,9 @@ impl Default for RelayCriteria {
             exclude_fingerprints: HashSet::new(),
             min_bandwidth: 0,
             max_selection: 10,
+            position: Position::default(),
+            required_port: None,
+            exclude_related: Vec::new(),
         }
     }
 }
@@ -192,22 +459,44 @@ impl RelayCriteria {
         self.max_selection = max;
         self
     }
+
+    pub fn for_position(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub fn allowing_port(mut self, port: u16) -> Self {
+        self.required_port = Some(port);
+        self
+    }
+
+    /// Exclude relays that share a fingerprint, family or subnet with `relay`
+    pub fn unrelated_to(mut self, relay: &Relay) -> Self {
+        self.exclude_related.push(relay.clone());
+        self
+    }
 }
 
 /// Relay manager for selecting appropriate relays
 pub struct RelayManager {
     pub relays: Vec<Relay>,
+    pub bandwidth_weights: BandwidthWeights,
+    /// Consensus parameters for locating onion service descriptors
+    pub hsdir_params: HsDirParams,
 }
 
 impl RelayManager {
     pub fn new(relays: Vec<Relay>) -> Self {
-        Self { relays }
+        Self {
+            relays,
+            bandwidth_weights: BandwidthWeights::default(),
+            hsdir_params: HsDirParams::default(),
+        }
     }
 
-    /// Select relays matching the given criteria
-    pub fn select_relays(&self, criteria: &RelayCriteria) -> Result<Vec<Relay>> {
-        let mut candidates: Vec<&Relay> = self
-            .relays
+    /// All relays matching the given criteria, in consensus order
+    fn candidates(&self, criteria: &RelayCriteria) -> Vec<&Relay> {
+        self.relays
             .iter()
             .filter(|relay| {
                 // Check excluded fingerprints
@@ -229,10 +518,34 @@ impl RelayManager {
                     }
                 }
 
+                // Check exit policy
+                if let Some(port) = criteria.required_port {
+                    if !relay.allows_port(port) {
+                        return false;
+                    }
+                }
+
+                // Check family / subnet against hops already on the path
+                if criteria
+                    .exclude_related
+                    .iter()
+                    .any(|other| relay.is_related_to(other))
+                {
+                    return false;
+                }
+
                 // Check bandwidth
                 relay.bandwidth >= criteria.min_bandwidth
             })
-            .collect();
+            .collect()
+    }
+
+    /// Select relays matching the given criteria
+    ///
+    /// Returns up to `max_selection` candidates ordered by weighted bandwidth for
+    /// the criteria's position. Use `select_relay` to pick a hop for a circuit.
+    pub fn select_relays(&self, criteria: &RelayCriteria) -> Result<Vec<Relay>> {
+        let mut candidates = self.candidates(criteria);
 
         if candidates.is_empty() {
             return Err(TorError::relay_selection(
@@ -240,8 +553,10 @@ impl RelayManager {
             ));
         }
 
-        // Sort by consensus weight (higher is better)
-        candidates.sort_by_key(|relay| std::cmp::Reverse(relay.consensus_weight));
+        // Sort by weighted bandwidth (higher is better)
+        candidates.sort_by_key(|relay| {
+            std::cmp::Reverse(self.weighted_bandwidth(relay, criteria.position))
+        });
 
         // Take top candidates
         let selected: Vec<Relay> = candidates
@@ -260,23 +575,49 @@ impl RelayManager {
         Ok(selected)
     }
 
-    /// Select a single relay randomly from candidates
+    /// Select a single relay, weighted by bandwidth for the criteria's position
+    ///
+    /// Every matching relay is a candidate, chosen with probability proportional
+    /// to its consensus weight times the position's bandwidth-weight, as in Tor's
+    /// path selection (path-spec 2.2). If every candidate has zero weight the
+    /// choice falls back to uniform.
     pub fn select_relay(&self, criteria: &RelayCriteria) -> Result<Relay> {
+        use rand::distributions::{Distribution, WeightedIndex};
         use rand::seq::SliceRandom;
 
-        let candidates = self.select_relays(criteria)?;
+        let candidates = self.candidates(criteria);
 
         if candidates.is_empty() {
             return Err(TorError::relay_selection("No suitable relays found"));
         }
 
-        // Select a random relay from the top candidates to avoid deterministic paths
-        // and ensure load balancing
+        let weights: Vec<u64> = candidates
+            .iter()
+            .map(|relay| self.weighted_bandwidth(relay, criteria.position))
+            .collect();
+
         let mut rng = rand::thread_rng();
-        candidates
-            .choose(&mut rng)
-            .cloned()
-            .ok_or_else(|| TorError::relay_selection("Failed to choose random relay"))
+        let chosen = match WeightedIndex::new(&weights) {
+            Ok(dist) => candidates[dist.sample(&mut rng)],
+            Err(_) => {
+                debug!(
+                    "No usable weights among {} candidates, choosing uniformly",
+                    candidates.len()
+                );
+                candidates
+                    .choose(&mut rng)
+                    .copied()
+                    .ok_or_else(|| TorError::relay_selection("Failed to choose random relay"))?
+            }
+        };
+
+        Ok(chosen.clone())
+    }
+
+    /// Consensus weight of `relay` scaled by the bandwidth-weight for `position`
+    pub fn weighted_bandwidth(&self, relay: &Relay, position: Position) -> u64 {
+        u64::from(relay.consensus_weight)
+            * u64::from(self.bandwidth_weights.for_relay(relay, position))
     }
 
     /// Get relay by fingerprint
@@ -295,6 +636,18 @@ impl RelayManager {
         );
         self.relays = new_relays;
     }
+
+    /// Update the consensus bandwidth-weights used for path selection
+    pub fn set_bandwidth_weights(&mut self, weights: BandwidthWeights) {
+        debug!("Updating bandwidth weights: {:?}", weights);
+        self.bandwidth_weights = weights;
+    }
+
+    /// Update the shared-random values and HSDir parameters from the consensus
+    pub fn set_hsdir_params(&mut self, params: HsDirParams) {
+        debug!("Updating HSDir parameters: {:?}", params);
+        self.hsdir_params = params;
+    }
 }
 
 /// Common relay flag constants
@@ -322,6 +675,7 @@ pub mod selection {
             .with_flag(flags::FAST)
             .with_flag(flags::STABLE)
             .with_flag(flags::V2DIR)
+            .for_position(Position::Middle)
     }
 
     /// Select exit relays (Fast, Stable, Exit, not BadExit)
@@ -331,6 +685,12 @@ pub mod selection {
             .with_flag(flags::STABLE)
             .with_flag(flags::EXIT)
             .without_flag(flags::BAD_EXIT)
+            .for_position(Position::Exit)
+    }
+
+    /// Select exit relays whose exit policy accepts `port`
+    pub fn exit_relays_for_port(port: u16) -> RelayCriteria {
+        exit_relays().allowing_port(port)
     }
 
     /// Select guard relays (Fast, Stable, Guard)
@@ -339,6 +699,7 @@ pub mod selection {
             .with_flag(flags::FAST)
             .with_flag(flags::STABLE)
             .with_flag(flags::GUARD)
+            .for_position(Position::Guard)
     }
 }
 
@@ -447,6 +808,9 @@ mod tests {
             exclude_fingerprints,
             min_bandwidth: rng.gen_range(0..500_000),
             max_selection: rng.gen_range(1..=10),
+            position: [Position::Guard, Position::Middle, Position::Exit][rng.gen_range(0..3)],
+            required_port: None,
+            exclude_related: Vec::new(),
         }
     }
 
@@ -507,11 +871,12 @@ mod tests {
                         );
                     }
 
-                    // Ordering: sorted by consensus_weight descending
+                    // Ordering: sorted by weighted bandwidth descending
                     for win in selected.windows(2) {
                         assert!(
-                            win[0].consensus_weight >= win[1].consensus_weight,
-                            "Relays not sorted by consensus_weight"
+                            mgr.weighted_bandwidth(&win[0], criteria.position)
+                                >= mgr.weighted_bandwidth(&win[1], criteria.position),
+                            "Relays not sorted by weighted bandwidth"
                         );
                     }
                 }
@@ -551,7 +916,9 @@ mod tests {
 
             let mgr = RelayManager::new(relays);
 
-            let list_result = mgr.select_relays(&criteria);
+            // select_relay draws from every candidate, not just the top N
+            let all_criteria = criteria.clone().with_max_selection(usize::MAX);
+            let list_result = mgr.select_relays(&all_criteria);
             let single_result = mgr.select_relay(&criteria);
 
             match (list_result, single_result) {
@@ -581,4 +948,213 @@ mod tests {
             }
         }
     }
+
+    #[portable_test]
+    fn bandwidth_weights_from_lookup_clamps_and_defaults() {
+        let weights = BandwidthWeights::from_lookup(|name| match name {
+            "Wgg" => Some(5_917),
+            "Wee" => Some(20_000),
+            "Wmd" => Some(-1),
+            _ => None,
+        });
+
+        assert_eq!(weights.wgg, 5_917);
+        assert_eq!(weights.wee, WEIGHT_SCALE);
+        assert_eq!(weights.wmd, 0);
+        assert_eq!(weights.wmm, WEIGHT_SCALE);
+    }
+
+    #[portable_test]
+    fn bandwidth_weights_pick_entry_by_position_and_flags() {
+        let weights = BandwidthWeights {
+            wgg: 1,
+            wgm: 2,
+            wgd: 3,
+            wmg: 4,
+            wmm: 5,
+            wme: 6,
+            wmd: 7,
+            weg: 8,
+            wem: 9,
+            wee: 10,
+            wed: 11,
+        };
+
+        let guard = create_test_relay("g", vec![flags::GUARD]);
+        let exit = create_test_relay("e", vec![flags::EXIT]);
+        let both = create_test_relay("d", vec![flags::GUARD, flags::EXIT]);
+        let bad_exit = create_test_relay("b", vec![flags::EXIT, flags::BAD_EXIT]);
+        let plain = create_test_relay("m", vec![flags::FAST]);
+
+        assert_eq!(weights.for_relay(&guard, Position::Guard), 1);
+        assert_eq!(weights.for_relay(&exit, Position::Guard), 0);
+        assert_eq!(weights.for_relay(&both, Position::Guard), 3);
+        assert_eq!(weights.for_relay(&guard, Position::Middle), 4);
+        assert_eq!(weights.for_relay(&plain, Position::Middle), 5);
+        assert_eq!(weights.for_relay(&exit, Position::Middle), 6);
+        assert_eq!(weights.for_relay(&both, Position::Middle), 7);
+        assert_eq!(weights.for_relay(&bad_exit, Position::Middle), 5);
+        assert_eq!(weights.for_relay(&exit, Position::Exit), 10);
+        assert_eq!(weights.for_relay(&both, Position::Exit), 11);
+    }
+
+    #[portable_test]
+    fn select_relay_is_weighted_by_bandwidth() {
+        let mut heavy = create_test_relay("heavy", vec![flags::FAST, flags::EXIT]);
+        heavy.consensus_weight = 9_900;
+        let mut light = create_test_relay("light", vec![flags::FAST, flags::EXIT]);
+        light.consensus_weight = 100;
+        let mut idle = create_test_relay("idle", vec![flags::FAST, flags::EXIT]);
+        idle.consensus_weight = 0;
+
+        let mgr = RelayManager::new(vec![light, heavy, idle]);
+        let criteria = RelayCriteria::new()
+            .with_flag(flags::EXIT)
+            .for_position(Position::Exit);
+
+        let mut heavy_picks = 0;
+        for _ in 0..1_000 {
+            let relay = mgr.select_relay(&criteria).unwrap();
+            assert_ne!(relay.fingerprint, "idle", "zero-weight relay was selected");
+            if relay.fingerprint == "heavy" {
+                heavy_picks += 1;
+            }
+        }
+
+        assert!(
+            heavy_picks > 900,
+            "heavy relay picked only {} of 1000 times",
+            heavy_picks
+        );
+    }
+
+    #[portable_test]
+    fn select_relay_falls_back_to_uniform_when_weights_are_zero() {
+        let relays = vec![
+            create_test_relay("a", vec![flags::FAST]),
+            create_test_relay("b", vec![flags::FAST]),
+        ];
+        let mgr = RelayManager::new(relays);
+
+        let relay = mgr.select_relay(&RelayCriteria::new()).unwrap();
+        assert!(relay.fingerprint == "a" || relay.fingerprint == "b");
+    }
+
+    #[portable_test]
+    fn port_policy_parses_accept_and_reject_summaries() {
+        let accept: PortPolicy = "accept 80,443,8000-8999".parse().unwrap();
+        assert!(accept.allows_port(80));
+        assert!(accept.allows_port(8545));
+        assert!(!accept.allows_port(22));
+        assert!(!accept.allows_port(0));
+
+        let reject: PortPolicy = "reject 25,119,135-139".parse().unwrap();
+        assert!(reject.allows_port(443));
+        assert!(!reject.allows_port(25));
+        assert!(!reject.allows_port(137));
+
+        let none: PortPolicy = "reject 1-65535".parse().unwrap();
+        assert!(!none.allows_port(443));
+        assert!(!PortPolicy::reject_all().allows_port(443));
+    }
+
+    #[portable_test]
+    fn port_policy_rejects_malformed_summaries() {
+        assert!("accept 80,abc".parse::<PortPolicy>().is_err());
+        assert!("accept 0-80".parse::<PortPolicy>().is_err());
+        assert!("accept 443-80".parse::<PortPolicy>().is_err());
+        assert!("accept 70000".parse::<PortPolicy>().is_err());
+    }
+
+    #[portable_test]
+    fn exit_selection_respects_required_port() {
+        let mut web_exit = create_test_relay("web", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+        web_exit.ipv4_policy = Some("accept 80,443".parse().unwrap());
+        let mut rpc_exit = create_test_relay("rpc", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+        rpc_exit.ipv4_policy = Some("reject 25".parse().unwrap());
+        let unknown_exit =
+            create_test_relay("unknown", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+
+        let mgr = RelayManager::new(vec![web_exit, rpc_exit, unknown_exit]);
+
+        for _ in 0..32 {
+            let relay = mgr
+                .select_relay(&selection::exit_relays_for_port(8545))
+                .unwrap();
+            assert_eq!(relay.fingerprint, "rpc");
+        }
+
+        let https = mgr
+            .select_relays(&selection::exit_relays_for_port(443))
+            .unwrap();
+        assert_eq!(https.len(), 2);
+        assert!(https.iter().all(|r| r.fingerprint != "unknown"));
+
+        assert!(mgr
+            .select_relay(&selection::exit_relays_for_port(25))
+            .is_err());
+    }
+
+    #[portable_test]
+    fn relays_are_related_by_mutual_family_and_subnet() {
+        let mut a = create_test_relay("AAAA", vec![]);
+        a.address = "198.51.100.7".to_string();
+        let mut b = create_test_relay("BBBB", vec![]);
+        b.address = "203.0.113.9".to_string();
+
+        // One-sided declarations don't count
+        a.family = vec!["bbbb".to_string()];
+        assert!(!a.in_same_family(&b));
+        b.family = vec!["AAAA".to_string()];
+        assert!(a.in_same_family(&b));
+        assert!(a.is_related_to(&b));
+
+        let mut c = create_test_relay("CCCC", vec![]);
+        c.address = "198.51.200.1".to_string();
+        assert!(a.in_same_subnet(&c));
+        c.address = "198.52.100.7".to_string();
+        assert!(!a.in_same_subnet(&c));
+
+        let mut v6_a = create_test_relay("DDDD", vec![]);
+        v6_a.address = "2001:db8:1::1".to_string();
+        let mut v6_b = create_test_relay("EEEE", vec![]);
+        v6_b.address = "2001:db8:ffff::2".to_string();
+        assert!(v6_a.in_same_subnet(&v6_b));
+        v6_b.address = "2001:db9::2".to_string();
+        assert!(!v6_a.in_same_subnet(&v6_b));
+
+        let mut placeholder = create_test_relay("FFFF", vec![]);
+        placeholder.address = "0.0.0.0".to_string();
+        let mut other = create_test_relay("1111", vec![]);
+        other.address = "0.0.1.1".to_string();
+        assert!(!placeholder.in_same_subnet(&other));
+    }
+
+    #[portable_test]
+    fn selection_skips_relays_related_to_the_path() {
+        let mut middle = create_test_relay("middle", vec![flags::FAST, flags::STABLE]);
+        middle.address = "198.51.100.1".to_string();
+        middle.family = vec!["sibling".to_string()];
+
+        let mut sibling =
+            create_test_relay("sibling", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+        sibling.address = "203.0.113.1".to_string();
+        sibling.family = vec!["middle".to_string()];
+        let mut neighbour =
+            create_test_relay("neighbour", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+        neighbour.address = "198.51.7.7".to_string();
+        let mut unrelated =
+            create_test_relay("unrelated", vec![flags::FAST, flags::STABLE, flags::EXIT]);
+        unrelated.address = "192.0.2.1".to_string();
+
+        let mgr = RelayManager::new(vec![middle.clone(), sibling, neighbour, unrelated]);
+        let criteria = selection::exit_relays().unrelated_to(&middle);
+
+        for _ in 0..32 {
+            assert_eq!(
+                mgr.select_relay(&criteria).unwrap().fingerprint,
+                "unrelated"
+            );
+        }
+    }
 }
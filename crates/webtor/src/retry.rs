@@ -19,6 +19,9 @@ pub struct CancellationToken {
     inner: tokio_util::sync::CancellationToken,
     #[cfg(target_arch = "wasm32")]
     cancelled: Arc<AtomicBool>,
+    /// Token this one was derived from with `child_token`
+    #[cfg(target_arch = "wasm32")]
+    parent: Option<Box<CancellationToken>>,
 }
 
 impl Default for CancellationToken {
@@ -40,6 +43,26 @@ impl CancellationToken {
         {
             Self {
                 cancelled: Arc::new(AtomicBool::new(false)),
+                parent: None,
+            }
+        }
+    }
+
+    /// Create a token that is cancelled along with this one.
+    ///
+    /// Cancelling the child leaves this token, and its other children, alone.
+    pub fn child_token(&self) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Self {
+                inner: self.inner.child_token(),
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            Self {
+                cancelled: Arc::new(AtomicBool::new(false)),
+                parent: Some(Box::new(self.clone())),
             }
         }
     }
@@ -65,6 +88,7 @@ impl CancellationToken {
         #[cfg(target_arch = "wasm32")]
         {
             self.cancelled.load(Ordering::SeqCst)
+                || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
         }
     }
 
@@ -292,6 +316,22 @@ pub async fn sleep(duration: Duration) {
     }
 }
 
+/// Yield to the executor so other tasks can make progress
+///
+/// Long CPU-bound loops call this between steps; on wasm32 there is only one
+/// thread, so the loop would otherwise starve the event loop.
+pub async fn yield_now() {
+    #[cfg(target_arch = "wasm32")]
+    {
+        gloo_timers::future::TimeoutFuture::new(0).await;
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::task::yield_now().await;
+    }
+}
+
 /// Execute a future with a timeout, returning TorError::Timeout on expiry
 ///
 /// # Arguments
@@ -653,6 +693,21 @@ mod tests {
         assert!(token2.is_cancelled());
     }
 
+    #[portable_test]
+    fn child_token_follows_parent_only() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let sibling = parent.child_token();
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
     #[portable_test_async]
     async fn with_cancellation_succeeds_when_not_cancelled() {
         let token = CancellationToken::new();
@@ -11,10 +11,12 @@
 //! - Bytes 8+: payload
 
 use crate::error::{Result, TorError};
+use crate::time::{sleep, Instant, Sleep};
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tracing::{debug, trace, warn};
 
 /// SMUX protocol version
@@ -26,6 +28,13 @@ const DEFAULT_STREAM_ID: u32 = 3;
 /// Default window size (64KB)
 const DEFAULT_WINDOW: u32 = 65535;
 
+/// How often to send a NOP keepalive (smux's default)
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the peer may stay silent before the link counts as dead
+/// (smux's default)
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// SMUX commands
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -224,12 +233,28 @@ impl SmuxState {
     }
 }
 
+/// NOP keepalive state
+///
+/// The bridge sends a NOP every few seconds and drops sessions that stay
+/// silent, so both sides ping; a peer that stops sending anything at all
+/// (e.g. a Snowflake proxy that vanished) fails reads with `TimedOut`.
+struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    timer: Option<Sleep>,
+    /// A NOP is due but the inner stream wasn't ready to take it
+    nop_pending: bool,
+}
+
 /// SMUX multiplexed stream
 pub struct SmuxStream<S> {
     inner: S,
     state: SmuxState,
     read_buffer: Vec<u8>,
     data_buffer: Vec<u8>,
+    /// When the peer last sent anything
+    last_recv: Instant,
+    keepalive: Option<Keepalive>,
 }
 
 impl<S> SmuxStream<S> {
@@ -243,7 +268,82 @@ impl<S> SmuxStream<S> {
             state: SmuxState::new(stream_id),
             read_buffer: Vec::with_capacity(4096),
             data_buffer: Vec::new(),
+            last_recv: Instant::now(),
+            keepalive: None,
+        }
+        .with_keepalive(DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT)
+    }
+
+    /// Send a NOP every `interval`, and fail reads once the peer has been
+    /// silent for `timeout`
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some(Keepalive {
+            interval,
+            timeout,
+            timer: None,
+            nop_pending: false,
+        });
+        self
+    }
+
+    /// Disable NOP keepalives and the silence timeout
+    pub fn without_keepalive(mut self) -> Self {
+        self.keepalive = None;
+        self
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SmuxStream<S> {
+    /// Send due NOPs, and fail once the peer has gone quiet for too long
+    ///
+    /// Registers the keepalive timer with `cx`, so call it before returning
+    /// `Poll::Pending` from a read.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(keepalive) = self.keepalive.as_mut() else {
+            return Ok(());
+        };
+
+        let interval = keepalive.interval;
+        loop {
+            let timer = keepalive.timer.get_or_insert_with(|| sleep(interval));
+            if timer.as_mut().poll(cx).is_pending() {
+                break;
+            }
+            keepalive.timer = None;
+
+            if self.last_recv.elapsed() >= keepalive.timeout {
+                warn!(
+                    "SMUX keepalive: nothing received for {:?}",
+                    self.last_recv.elapsed()
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "SMUX keepalive timeout",
+                ));
+            }
+            keepalive.nop_pending = true;
+        }
+
+        if keepalive.nop_pending {
+            let nop = SmuxSegment::nop(0).encode();
+            match Pin::new(&mut self.inner).poll_write(cx, &nop) {
+                Poll::Ready(Ok(n)) if n == nop.len() => {
+                    trace!("SMUX keepalive: sent NOP");
+                    keepalive.nop_pending = false;
+                    let _ = Pin::new(&mut self.inner).poll_flush(cx);
+                }
+                Poll::Ready(Ok(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "Partial SMUX keepalive write",
+                    ));
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => {}
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -350,6 +450,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> SmuxStream<S> {
                 return Ok(0); // EOF
             }
 
+            self.last_recv = Instant::now();
             self.read_buffer.extend_from_slice(&temp[..n]);
         }
     }
@@ -363,6 +464,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> SmuxStream<S> {
             .await
         {
             Ok(n) if n > 0 => {
+                self.last_recv = Instant::now();
                 self.read_buffer.extend_from_slice(&temp[..n]);
             }
             _ => {}
@@ -472,7 +574,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> SmuxStream<S> {
     }
 }
 
-impl<S: AsyncRead + Unpin> AsyncRead for SmuxStream<S> {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for SmuxStream<S> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -569,6 +671,10 @@ impl<S: AsyncRead + Unpin> AsyncRead for SmuxStream<S> {
             }
         }
 
+        if let Err(e) = self.poll_keepalive(cx) {
+            return Poll::Ready(Err(e));
+        }
+
         // Read more data
         trace!("SMUX poll_read: need more data, reading from inner");
         let mut temp = [0u8; 4096];
@@ -583,6 +689,7 @@ impl<S: AsyncRead + Unpin> AsyncRead for SmuxStream<S> {
                     n,
                     &temp[..n]
                 );
+                self.last_recv = Instant::now();
                 self.read_buffer.extend_from_slice(&temp[..n]);
                 cx.waker().wake_by_ref();
                 Poll::Pending
@@ -666,7 +773,7 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for SmuxStream<S> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_util::portable_test;
+    use crate::test_util::{portable_test, portable_test_async};
     use rand::Rng;
 
     #[portable_test]
@@ -724,6 +831,73 @@ mod tests {
         assert!(SmuxSegment::decode(&buf).is_err());
     }
 
+    /// Takes every write and never has anything to read
+    #[derive(Default)]
+    struct SilentPeer {
+        written: Vec<u8>,
+        /// Accept only one byte per write
+        short_writes: bool,
+    }
+
+    impl AsyncRead for SilentPeer {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for SilentPeer {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = if self.short_writes { 1 } else { buf.len() };
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[portable_test_async]
+    async fn keepalive_pings_then_gives_up_on_silent_peer() {
+        let mut stream = SmuxStream::new(SilentPeer::default())
+            .with_keepalive(Duration::from_millis(10), Duration::from_millis(35));
+
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let nop = SmuxSegment::nop(0).encode();
+        let written = &stream.inner.written;
+        assert!(written.len() >= 2 * nop.len());
+        assert!(written.chunks(nop.len()).all(|frame| frame == nop));
+    }
+
+    #[portable_test_async]
+    async fn keepalive_fails_on_partial_nop_write() {
+        let peer = SilentPeer {
+            short_writes: true,
+            ..SilentPeer::default()
+        };
+        let mut stream = SmuxStream::new(peer)
+            .with_keepalive(Duration::from_millis(10), Duration::from_secs(60));
+
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
     const FUZZ_ITERATIONS: usize = 256;
 
     fn random_smux_command(rng: &mut impl rand::Rng) -> SmuxCommand {
@@ -1,5 +1,7 @@
 //! Platform-independent time utilities for WASM and native builds.
 
+use std::future::Future;
+use std::pin::Pin;
 use std::time::Duration;
 
 #[cfg(target_arch = "wasm32")]
@@ -93,3 +95,16 @@ mod platform {
 
 pub use platform::system_time_now;
 pub use platform::Instant;
+
+/// A boxed timer for poll-based streams that need to wake themselves up
+#[cfg(target_arch = "wasm32")]
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A boxed timer for poll-based streams that need to wake themselves up
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Start a [`Sleep`] that completes after `duration`
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Box::pin(crate::retry::sleep(duration))
+}
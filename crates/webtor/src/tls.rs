@@ -27,6 +27,12 @@ use tracing::{debug, info};
 /// Create a TLS connector with the default root certificates
 #[cfg(not(target_arch = "wasm32"))]
 pub fn create_tls_connector() -> Result<TlsConnector> {
+    create_tls_connector_with_alpn(&[])
+}
+
+/// Create a TLS connector that offers `alpn_protocols`, most preferred first
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_tls_connector_with_alpn(alpn_protocols: &[&str]) -> Result<TlsConnector> {
     let mut root_store = RootCertStore::empty();
 
     // Add webpki root certificates
@@ -34,25 +40,30 @@ pub fn create_tls_connector() -> Result<TlsConnector> {
 
     debug!("Loaded {} root certificates", root_store.len());
 
-    let config = ClientConfig::builder()
+    let mut config = ClientConfig::builder()
         .with_root_certificates(root_store)
         .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
 
     Ok(TlsConnector::from(Arc::new(config)))
 }
 
-/// Wrap an async stream with TLS encryption
+/// Wrap an async stream with TLS encryption, offering `alpn_protocols`
 #[cfg(not(target_arch = "wasm32"))]
 pub async fn wrap_with_tls<S>(
     stream: S,
     domain: &str,
+    alpn_protocols: &[&str],
 ) -> Result<futures_rustls::client::TlsStream<S>>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     info!("Initiating TLS handshake with {}", domain);
 
-    let connector = create_tls_connector()?;
+    let connector = create_tls_connector_with_alpn(alpn_protocols)?;
 
     let server_name = ServerName::try_from(domain.to_string())
         .map_err(|e| TorError::tls(format!("Invalid server name '{}': {}", domain, e)))?;
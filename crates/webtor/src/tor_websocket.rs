@@ -0,0 +1,794 @@
+//! WebSocket client over Tor streams (RFC 6455)
+//!
+//! The opening handshake is an HTTP/1.1 upgrade on a connection of its own,
+//! opened the way `TorHttpClient` opens any other, so the socket runs on a
+//! circuit picked by the usual isolation policy. Afterwards the stream is
+//! split: `recv` reads frames, answering pings and the closing handshake as
+//! they arrive, while `send` writes through the other half.
+
+use crate::circuit::Circuit;
+use crate::error::{Result, TorError};
+use crate::http::{title_case, HttpTimeouts, RequestDeadline};
+use crate::http_conn::{has_token, touch_circuit, HttpConnection, ResponseHead, SplittableStream};
+use base64::Engine;
+use futures::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use http::header::{self, HeaderMap, HeaderName};
+use sha1::{Digest, Sha1};
+use std::sync::{Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, RwLock};
+use tracing::{debug, info};
+use url::{Position, Url};
+
+/// Appended to our key before hashing to get `Sec-WebSocket-Accept`
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Opcodes
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Close code for a normal closure
+pub const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Reported when a Close frame carried no code
+pub const CLOSE_NO_STATUS: u16 = 1005;
+/// Reported when the stream ended without a Close frame
+pub const CLOSE_ABNORMAL: u16 = 1006;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Largest payload of a ping, pong or close frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Handshake headers we set ourselves
+const HANDSHAKE_HEADERS: [HeaderName; 5] = [
+    header::HOST,
+    header::UPGRADE,
+    header::CONNECTION,
+    header::SEC_WEBSOCKET_KEY,
+    header::SEC_WEBSOCKET_VERSION,
+];
+
+/// Options for opening a `TorWebSocket`
+#[derive(Debug, Clone)]
+pub struct WebSocketOptions {
+    /// Subprotocols offered in `Sec-WebSocket-Protocol`, most preferred first
+    pub protocols: Vec<String>,
+    /// Sent with the handshake, e.g. `Origin`
+    pub headers: HeaderMap,
+    /// Deadline for the opening handshake
+    pub timeout: Duration,
+    pub phase_timeouts: HttpTimeouts,
+    /// Largest message accepted; longer ones close the socket with 1009
+    pub max_message_size: usize,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            protocols: Vec::new(),
+            headers: HeaderMap::new(),
+            timeout: Duration::from_secs(30),
+            phase_timeouts: HttpTimeouts::default(),
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A complete WebSocket message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Code and reason a WebSocket was closed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Payload of a Close frame carrying this code and reason
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = self.code.to_be_bytes().to_vec();
+        payload.extend_from_slice(self.reason.as_bytes());
+        payload
+    }
+
+    /// Parse a received Close frame's payload
+    fn parse(payload: &[u8]) -> std::result::Result<Self, CloseFrame> {
+        match payload {
+            [] => Ok(Self::new(CLOSE_NO_STATUS, "")),
+            [_] => Err(Self::new(CLOSE_PROTOCOL_ERROR, "truncated close code")),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                if !is_valid_close_code(code) {
+                    return Err(Self::new(CLOSE_PROTOCOL_ERROR, "invalid close code"));
+                }
+                let reason = std::str::from_utf8(reason)
+                    .map_err(|_| Self::new(CLOSE_INVALID_DATA, "invalid UTF-8 in close reason"))?;
+                Ok(Self::new(code, reason))
+            }
+        }
+    }
+}
+
+/// Codes a Close frame may carry; the rest are reserved or only reported
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+/// One frame from the server
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Decode a server frame from the front of `buf`
+    ///
+    /// `None` until the whole frame has arrived; otherwise the frame and the
+    /// bytes it took. A frame breaking the protocol gives the Close frame to
+    /// answer it with.
+    fn decode(
+        buf: &[u8],
+        max_size: usize,
+    ) -> std::result::Result<Option<(Self, usize)>, CloseFrame> {
+        let [first, second, ..] = *buf else {
+            return Ok(None);
+        };
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        if first & 0x70 != 0 {
+            return Err(CloseFrame::new(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if second & 0x80 != 0 {
+            return Err(CloseFrame::new(
+                CLOSE_PROTOCOL_ERROR,
+                "masked frame from server",
+            ));
+        }
+        if !matches!(
+            opcode,
+            OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+        ) {
+            return Err(CloseFrame::new(CLOSE_PROTOCOL_ERROR, "unknown opcode"));
+        }
+
+        let (len, header_len) = match second & 0x7F {
+            126 => match buf.get(2..4) {
+                Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (len > MAX_CONTROL_PAYLOAD as u64 || !fin) {
+            return Err(CloseFrame::new(
+                CLOSE_PROTOCOL_ERROR,
+                "invalid control frame",
+            ));
+        }
+        if len > max_size as u64 {
+            return Err(CloseFrame::new(CLOSE_TOO_BIG, "message too big"));
+        }
+
+        let end = header_len + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let frame = Self {
+            fin,
+            opcode,
+            payload: buf[header_len..end].to_vec(),
+        };
+        Ok(Some((frame, end)))
+    }
+}
+
+/// Encode one unfragmented frame, masked as clients must
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = rand::random();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+/// `Sec-WebSocket-Accept` a server must answer `key` with
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+/// The `http`/`https` URL a `ws`/`wss` URL is fetched from
+pub(crate) fn handshake_url(url: &Url) -> Result<Url> {
+    let scheme = match url.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        other => {
+            return Err(TorError::configuration(format!(
+                "WebSocket URLs must be ws:// or wss://, not {}://",
+                other
+            )))
+        }
+    };
+    if url.fragment().is_some() {
+        return Err(TorError::configuration(
+            "WebSocket URLs can't have a fragment",
+        ));
+    }
+    let mut http_url = url.clone();
+    http_url
+        .set_scheme(scheme)
+        .map_err(|_| TorError::configuration(format!("Invalid WebSocket URL {}", url)))?;
+    Ok(http_url)
+}
+
+/// Build the upgrade request for `url` (already `http`/`https`)
+fn handshake_request(url: &Url, key: &str, options: &WebSocketOptions) -> Vec<u8> {
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {}\r\n\
+        Sec-WebSocket-Version: 13\r\n",
+        &url[Position::BeforePath..Position::AfterQuery],
+        &url[Position::BeforeHost..Position::AfterPort],
+        key
+    );
+    if !options.protocols.is_empty()
+        && !options.headers.contains_key(header::SEC_WEBSOCKET_PROTOCOL)
+    {
+        request.push_str(&format!(
+            "Sec-WebSocket-Protocol: {}\r\n",
+            options.protocols.join(", ")
+        ));
+    }
+    if !options.headers.contains_key(header::USER_AGENT) {
+        request.push_str("User-Agent: webtor-rs/0.1.0\r\n");
+    }
+    for (name, value) in &options.headers {
+        if !HANDSHAKE_HEADERS.contains(name) {
+            request.push_str(&format!(
+                "{}: {}\r\n",
+                title_case(name),
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+/// Check the server accepted the upgrade; returns the chosen subprotocol
+fn check_handshake(head: &ResponseHead, key: &str, protocols: &[String]) -> Result<Option<String>> {
+    let fail = |reason: &str| {
+        Err(TorError::http_request(format!(
+            "WebSocket handshake failed: {}",
+            reason
+        )))
+    };
+    if head.status != 101 {
+        return fail(&format!("server answered HTTP {}", head.status));
+    }
+    if !has_token(&head.headers, header::UPGRADE, "websocket")
+        || !has_token(&head.headers, header::CONNECTION, "upgrade")
+    {
+        return fail("missing Upgrade: websocket");
+    }
+    let accept = head
+        .headers
+        .get(header::SEC_WEBSOCKET_ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    if accept != Some(accept_key(key).as_str()) {
+        return fail("wrong Sec-WebSocket-Accept");
+    }
+    if head.headers.contains_key(header::SEC_WEBSOCKET_EXTENSIONS) {
+        return fail("server enabled an extension we didn't offer");
+    }
+    match head
+        .headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .map(|value| value.to_str().unwrap_or_default())
+    {
+        None => Ok(None),
+        Some(protocol) if protocols.iter().any(|p| p == protocol) => Ok(Some(protocol.to_string())),
+        Some(_) => fail("server chose a subprotocol we didn't offer"),
+    }
+}
+
+/// Why reading a frame stopped
+enum ReadError {
+    Io(std::io::Error),
+    Violation(CloseFrame),
+}
+
+/// Reading side of the socket
+struct Reader {
+    half: ReadHalf<SplittableStream>,
+    /// Bytes read past the last whole frame
+    buffer: Vec<u8>,
+    /// Scratch space for one read, kept off the stack of the read futures
+    chunk: Box<[u8]>,
+    /// Opcode and data so far of a fragmented message
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Reader {
+    /// Read the next frame; `None` at EOF
+    async fn next_frame(
+        &mut self,
+        max_size: usize,
+    ) -> std::result::Result<Option<Frame>, ReadError> {
+        loop {
+            if let Some((frame, used)) =
+                Frame::decode(&self.buffer, max_size).map_err(ReadError::Violation)?
+            {
+                self.buffer.drain(..used);
+                return Ok(Some(frame));
+            }
+            let n = self
+                .half
+                .read(&mut self.chunk)
+                .await
+                .map_err(ReadError::Io)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&self.chunk[..n]);
+        }
+    }
+}
+
+/// Progress of the closing handshake
+#[derive(Default)]
+struct CloseState {
+    /// Whether we sent a Close frame
+    sent: bool,
+    /// How the socket closed, once it has
+    closed: Option<CloseFrame>,
+}
+
+/// A WebSocket connection through Tor
+///
+/// `send` and `recv` take `&self`, so one task can wait for messages while
+/// others send. Pings are answered inside `recv`, so keep calling it until
+/// it returns `None`.
+pub struct TorWebSocket {
+    url: Url,
+    protocol: Option<String>,
+    headers: HeaderMap,
+    max_message_size: usize,
+    reader: AsyncMutex<Reader>,
+    writer: AsyncMutex<WriteHalf<SplittableStream>>,
+    close: Mutex<CloseState>,
+    /// Circuit carrying the stream; `None` for onion services
+    circuit: Option<Weak<RwLock<Circuit>>>,
+}
+
+impl TorWebSocket {
+    /// Run the opening handshake for `url` on a fresh connection
+    pub(crate) async fn handshake(
+        mut connection: HttpConnection,
+        url: Url,
+        options: WebSocketOptions,
+        deadline: &RequestDeadline,
+    ) -> Result<Self> {
+        let http_url = handshake_url(&url)?;
+        let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+        let request = handshake_request(&http_url, &key, &options);
+        connection.start_request(&request, deadline).await?;
+        let head = connection.read_response_head(deadline).await?;
+        let protocol = check_handshake(&head, &key, &options.protocols)?;
+
+        let (stream, buffer, circuit) = connection.into_parts();
+        let (read_half, write_half) = stream
+            .into_splittable()
+            .ok_or_else(|| TorError::http_request("WebSockets need a TLS 1.3 connection"))?
+            .split();
+        info!("WebSocket open to {}", url);
+
+        Ok(Self {
+            url,
+            protocol,
+            headers: head.headers,
+            max_message_size: options.max_message_size,
+            reader: AsyncMutex::new(Reader {
+                half: read_half,
+                buffer,
+                chunk: vec![0; 8192].into_boxed_slice(),
+                message: None,
+            }),
+            writer: AsyncMutex::new(write_half),
+            close: Mutex::new(CloseState::default()),
+            circuit,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Subprotocol the server picked from `WebSocketOptions.protocols`
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Headers of the server's handshake response
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// How the socket closed, once `recv` has returned `None` or failed
+    pub fn close_frame(&self) -> Option<CloseFrame> {
+        self.close.lock().unwrap().closed.clone()
+    }
+
+    /// Send a message
+    pub async fn send(&self, message: WebSocketMessage) -> Result<()> {
+        if self.close.lock().unwrap().sent {
+            return Err(TorError::http_request("WebSocket is closing"));
+        }
+        match &message {
+            WebSocketMessage::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()).await,
+            WebSocketMessage::Binary(data) => self.write_frame(OP_BINARY, data).await,
+        }
+    }
+
+    /// Send a ping; the server's pong is consumed by `recv`
+    pub async fn ping(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(TorError::configuration(format!(
+                "Ping payload is limited to {} bytes",
+                MAX_CONTROL_PAYLOAD
+            )));
+        }
+        self.write_frame(OP_PING, payload).await
+    }
+
+    /// Start the closing handshake
+    ///
+    /// `code` is 1000 or in 3000-4999, as browsers allow. Keep calling `recv`
+    /// until it returns `None` to let the server answer.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<()> {
+        if code != CLOSE_NORMAL && !(3000..=4999).contains(&code) {
+            return Err(TorError::configuration(format!(
+                "Invalid WebSocket close code {}",
+                code
+            )));
+        }
+        if reason.len() > MAX_CONTROL_PAYLOAD - 2 {
+            return Err(TorError::configuration(
+                "WebSocket close reason is limited to 123 bytes",
+            ));
+        }
+        self.send_close(&CloseFrame::new(code, reason)).await
+    }
+
+    /// Receive the next message; `None` once the socket has closed
+    pub async fn recv(&self) -> Result<Option<WebSocketMessage>> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            if self.close.lock().unwrap().closed.is_some() {
+                return Ok(None);
+            }
+
+            let frame = match reader.next_frame(self.max_message_size).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    debug!("WebSocket stream ended without a close frame");
+                    self.closed(CloseFrame::new(CLOSE_ABNORMAL, ""));
+                    return Ok(None);
+                }
+                Err(ReadError::Io(e)) => {
+                    self.closed(CloseFrame::new(CLOSE_ABNORMAL, ""));
+                    return Err(TorError::http_request(format!(
+                        "Failed to read WebSocket frame: {}",
+                        e
+                    )));
+                }
+                Err(ReadError::Violation(close)) => return Err(self.fail(close).await),
+            };
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close.lock().unwrap().sent {
+                        self.write_frame(OP_PONG, &frame.payload).await?;
+                    }
+                }
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let close = match CloseFrame::parse(&frame.payload) {
+                        Ok(close) => close,
+                        Err(violation) => return Err(self.fail(violation).await),
+                    };
+                    debug!("WebSocket closed by server with {}", close.code);
+                    // Echo the code back unless we started the handshake
+                    let echo = match close.code {
+                        CLOSE_NO_STATUS => Vec::new(),
+                        code => code.to_be_bytes().to_vec(),
+                    };
+                    if !std::mem::replace(&mut self.close.lock().unwrap().sent, true) {
+                        // The server may already be gone; the close stands
+                        let _ = self.write_frame(OP_CLOSE, &echo).await;
+                    }
+                    self.closed(close);
+                    return Ok(None);
+                }
+                OP_TEXT | OP_BINARY if reader.message.is_some() => {
+                    let violation = CloseFrame::new(CLOSE_PROTOCOL_ERROR, "interleaved message");
+                    return Err(self.fail(violation).await);
+                }
+                OP_TEXT | OP_BINARY if !frame.fin => {
+                    reader.message = Some((frame.opcode, frame.payload));
+                }
+                OP_TEXT | OP_BINARY => return self.deliver(frame.opcode, frame.payload).await,
+                _ => {
+                    let Some((_, data)) = reader.message.as_mut() else {
+                        let violation = CloseFrame::new(CLOSE_PROTOCOL_ERROR, "stray continuation");
+                        return Err(self.fail(violation).await);
+                    };
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        let violation = CloseFrame::new(CLOSE_TOO_BIG, "message too big");
+                        return Err(self.fail(violation).await);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = reader.message.take().unwrap();
+                        return self.deliver(opcode, data).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Turn a complete message's bytes into a `WebSocketMessage`
+    async fn deliver(&self, opcode: u8, data: Vec<u8>) -> Result<Option<WebSocketMessage>> {
+        touch_circuit(self.circuit.as_ref());
+        if opcode == OP_BINARY {
+            return Ok(Some(WebSocketMessage::Binary(data)));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Some(WebSocketMessage::Text(text))),
+            Err(_) => {
+                let violation =
+                    CloseFrame::new(CLOSE_INVALID_DATA, "invalid UTF-8 in text message");
+                Err(self.fail(violation).await)
+            }
+        }
+    }
+
+    async fn send_close(&self, close: &CloseFrame) -> Result<()> {
+        if std::mem::replace(&mut self.close.lock().unwrap().sent, true) {
+            return Ok(());
+        }
+        debug!("Closing WebSocket with {}", close.code);
+        self.write_frame(OP_CLOSE, &close.payload()).await
+    }
+
+    /// Close the socket over a protocol violation by the server
+    async fn fail(&self, close: CloseFrame) -> TorError {
+        let error = TorError::Protocol(format!("WebSocket: {}", close.reason));
+        let _ = self.send_close(&close).await;
+        self.closed(close);
+        error
+    }
+
+    fn closed(&self, close: CloseFrame) {
+        self.close.lock().unwrap().closed.get_or_insert(close);
+    }
+
+    async fn write_frame(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let frame = encode_frame(opcode, payload);
+        let mut writer = self.writer.lock().await;
+        let write = async {
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        };
+        write.await.map_err(|e| {
+            TorError::http_request(format!("Failed to write WebSocket frame: {}", e))
+        })?;
+        touch_circuit(self.circuit.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::portable_test;
+
+    /// Unmask a frame we encoded, as a server would
+    fn unmask(frame: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(frame[1] & 0x80, 0x80, "client frames must be masked");
+        let (len, offset) = match frame[1] & 0x7F {
+            126 => (u16::from_be_bytes([frame[2], frame[3]]) as usize, 4),
+            127 => (
+                u64::from_be_bytes(frame[2..10].try_into().unwrap()) as usize,
+                10,
+            ),
+            len => (len as usize, 2),
+        };
+        let mask = &frame[offset..offset + 4];
+        let payload = frame[offset + 4..]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect::<Vec<_>>();
+        assert_eq!(payload.len(), len);
+        (frame[0], payload)
+    }
+
+    #[portable_test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[portable_test]
+    fn test_encode_frame_masks_and_sizes() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload = vec![0x5A; len];
+            let frame = encode_frame(OP_BINARY, &payload);
+            let (first, unmasked) = unmask(&frame);
+            assert_eq!(first, 0x80 | OP_BINARY);
+            assert_eq!(unmasked, payload);
+        }
+    }
+
+    #[portable_test]
+    fn test_decode_frame() {
+        // RFC 6455 section 5.7: unmasked "Hello", then its two fragments
+        let hello = b"\x81\x05Hello";
+        let (frame, used) = Frame::decode(hello, 1024).unwrap().unwrap();
+        assert_eq!(
+            (frame.fin, frame.opcode, &frame.payload[..]),
+            (true, OP_TEXT, &b"Hello"[..])
+        );
+        assert_eq!(used, 7);
+        assert!(Frame::decode(&hello[..6], 1024).unwrap().is_none());
+
+        let (first, _) = Frame::decode(b"\x01\x03Hel", 1024).unwrap().unwrap();
+        assert!(!first.fin);
+        let (last, _) = Frame::decode(b"\x80\x02lo", 1024).unwrap().unwrap();
+        assert_eq!((last.fin, last.opcode), (true, OP_CONTINUATION));
+
+        // 16-bit length
+        let mut long = vec![0x82, 126, 0x01, 0x00];
+        long.extend_from_slice(&[7; 256]);
+        let (frame, used) = Frame::decode(&long, 1024).unwrap().unwrap();
+        assert_eq!((frame.payload.len(), used), (256, 260));
+        assert!(Frame::decode(&long[..3], 1024).unwrap().is_none());
+
+        let code = |buf: &[u8]| Frame::decode(buf, 100).unwrap_err().code;
+        assert_eq!(code(b"\x81\x85abcdHello"), CLOSE_PROTOCOL_ERROR); // masked
+        assert_eq!(code(b"\xC1\x00"), CLOSE_PROTOCOL_ERROR); // RSV1
+        assert_eq!(code(b"\x83\x00"), CLOSE_PROTOCOL_ERROR); // reserved opcode
+        assert_eq!(code(b"\x09\x00"), CLOSE_PROTOCOL_ERROR); // fragmented ping
+        assert_eq!(code(b"\x89\x7E\x00\x7E"), CLOSE_PROTOCOL_ERROR); // long ping
+        assert_eq!(code(&long), CLOSE_TOO_BIG);
+    }
+
+    #[portable_test]
+    fn test_close_frames() {
+        let close = CloseFrame::new(CLOSE_NORMAL, "bye");
+        assert_eq!(close.payload(), b"\x03\xE8bye");
+        assert_eq!(CloseFrame::parse(&close.payload()), Ok(close));
+        assert_eq!(CloseFrame::parse(b"").unwrap().code, CLOSE_NO_STATUS);
+        assert_eq!(
+            CloseFrame::parse(b"\x03").unwrap_err().code,
+            CLOSE_PROTOCOL_ERROR
+        );
+        // 1005 and 1006 are only ever reported, never sent
+        assert_eq!(
+            CloseFrame::parse(b"\x03\xED").unwrap_err().code,
+            CLOSE_PROTOCOL_ERROR
+        );
+        assert_eq!(
+            CloseFrame::parse(b"\x03\xE8\xFF").unwrap_err().code,
+            CLOSE_INVALID_DATA
+        );
+    }
+
+    #[portable_test]
+    fn test_handshake_request_and_response() {
+        let url =
+            handshake_url(&Url::parse("wss://example.com:8443/chat?room=1").unwrap()).unwrap();
+        assert_eq!(url.as_str(), "https://example.com:8443/chat?room=1");
+        assert!(handshake_url(&Url::parse("https://example.com/").unwrap()).is_err());
+
+        let mut options = WebSocketOptions {
+            protocols: vec!["chat".into(), "superchat".into()],
+            ..Default::default()
+        };
+        options
+            .headers
+            .append(header::ORIGIN, "https://example.com".parse().unwrap());
+        options
+            .headers
+            .append(header::HOST, "ignored".parse().unwrap());
+        let request = String::from_utf8(handshake_request(&url, "key==", &options)).unwrap();
+        assert!(request.starts_with("GET /chat?room=1 HTTP/1.1\r\nHost: example.com:8443\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: key==\r\n"));
+        assert!(request.contains("Sec-WebSocket-Protocol: chat, superchat\r\n"));
+        assert!(request.contains("Origin: https://example.com\r\n"));
+        assert!(!request.contains("ignored"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let response = |extra: &str| {
+            ResponseHead::parse(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}",
+                    accept_key(key),
+                    extra
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+        let protocols = &options.protocols;
+        assert_eq!(
+            check_handshake(&response(""), key, protocols).unwrap(),
+            None
+        );
+        assert_eq!(
+            check_handshake(
+                &response("Sec-WebSocket-Protocol: superchat\r\n"),
+                key,
+                protocols
+            )
+            .unwrap()
+            .as_deref(),
+            Some("superchat")
+        );
+        assert!(check_handshake(
+            &response("Sec-WebSocket-Protocol: other\r\n"),
+            key,
+            protocols
+        )
+        .is_err());
+        assert!(check_handshake(&response(""), "other key", protocols).is_err());
+        let refused = ResponseHead::parse(b"HTTP/1.1 403 Forbidden\r\n").unwrap();
+        assert!(check_handshake(&refused, key, protocols).is_err());
+    }
+}